@@ -0,0 +1,465 @@
+use chrono::{DateTime, Utc};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::interval;
+
+use crate::discovery::{DiscoveryBackend, DiscoveryBackendKind, PeerRegistry};
+use crate::error::{AppError, AppResult};
+use crate::identity::{self, DeviceIdentity};
+use crate::models::{User, MAX_STATUS_TEXT_LEN};
+use crate::network;
+
+/// UDP port beacons are broadcast to and received on
+const BEACON_PORT: u16 = 8767;
+/// How often we announce ourselves, well within the discovery peer timeout
+const BEACON_INTERVAL: Duration = Duration::from_secs(30);
+/// Oldest beacon accepted, also bounds clock skew between peers
+const BEACON_MAX_AGE_SECS: i64 = 300;
+/// Largest beacon datagram accepted
+const MAX_BEACON_SIZE: usize = 4096;
+/// Version of the beacon payload
+const BEACON_VERSION: u32 = 1;
+
+/// What a beacon announces, signed by the sender's identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BeaconPayload {
+    version: u32,
+    user: User,
+    sent_at: DateTime<Utc>,
+    /// The sender is going offline and should be forgotten
+    #[serde(default)]
+    leaving: bool,
+    /// Peers should answer even if they already know the sender
+    #[serde(default)]
+    solicit: bool,
+}
+
+/// A beacon as sent on the wire; the payload is kept as sent so the signature covers its exact bytes
+#[derive(Debug, Serialize, Deserialize)]
+struct Beacon {
+    payload: String,
+    /// Hex-encoded Ed25519 signature of the payload
+    signature: String,
+}
+
+/// Discovery through signed UDP broadcast beacons on every IPv4 subnet.
+///
+/// Works on networks that filter the multicast traffic mDNS relies on.
+pub struct BeaconBackend {
+    /// Long-term identity key beacons are signed with
+    identity: Arc<DeviceIdentity>,
+    /// Our user as currently announced
+    local_user: Arc<Mutex<Option<User>>>,
+    /// Socket beacons are sent and received on
+    socket: Option<Arc<UdpSocket>>,
+    /// Triggers an announcement before the next interval, soliciting answers if true
+    announce_tx: Option<mpsc::Sender<bool>>,
+    /// Channel for stopping the beacon task
+    stop_tx: Option<mpsc::Sender<()>>,
+}
+
+impl BeaconBackend {
+    pub fn new(identity: Arc<DeviceIdentity>) -> Self {
+        BeaconBackend {
+            identity,
+            local_user: Arc::new(Mutex::new(None)),
+            socket: None,
+            announce_tx: None,
+            stop_tx: None,
+        }
+    }
+
+    /// Binds the beacon socket and spawns the task announcing us and handling beacons
+    async fn start_beacons(&mut self, local_user: &User, registry: PeerRegistry) -> AppResult<()> {
+        let socket = bind_beacon_socket()
+            .map_err(|e| AppError::DiscoveryError(format!("Failed to bind beacon port {BEACON_PORT}: {e}")))?;
+        let socket = Arc::new(socket);
+        *self.local_user.lock().unwrap() = Some(local_user.clone());
+
+        let (announce_tx, mut announce_rx) = mpsc::channel::<bool>(4);
+        let (stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
+        self.socket = Some(Arc::clone(&socket));
+        self.announce_tx = Some(announce_tx);
+        self.stop_tx = Some(stop_tx);
+
+        let identity = Arc::clone(&self.identity);
+        let local_user = Arc::clone(&self.local_user);
+        tokio::spawn(async move {
+            let mut announce_interval = interval(BEACON_INTERVAL);
+            let mut buf = vec![0u8; MAX_BEACON_SIZE];
+            // Everyone answers the first beacon, so peers are found right away
+            let mut solicit = true;
+
+            loop {
+                tokio::select! {
+                    // Check for stop signal
+                    _ = stop_rx.recv() => {
+                        debug!("Stopping beacon discovery");
+                        break;
+                    }
+
+                    // Periodic announcement
+                    _ = announce_interval.tick() => {
+                        announce(&socket, &identity, &local_user, solicit, false).await;
+                        solicit = false;
+                    }
+
+                    // Announcement requested by a refresh or user update
+                    Some(solicit) = announce_rx.recv() => {
+                        announce(&socket, &identity, &local_user, solicit, false).await;
+                    }
+
+                    received = socket.recv_from(&mut buf) => {
+                        match received {
+                            Ok((len, source)) => {
+                                handle_beacon(&buf[..len], source, &socket, &identity, &local_user, &registry).await;
+                            }
+                            Err(e) => debug!("Failed to receive beacon: {e}"),
+                        }
+                    }
+                }
+            }
+
+            debug!("Beacon task exiting");
+        });
+
+        info!("Broadcasting discovery beacons on UDP port {BEACON_PORT}");
+        Ok(())
+    }
+
+    /// Tells peers we are leaving and stops the beacon task
+    async fn stop_beacons(&mut self) -> AppResult<()> {
+        if let Some(socket) = self.socket.take() {
+            announce(&socket, &self.identity, &self.local_user, false, true).await;
+        }
+        self.announce_tx = None;
+
+        // Send stop signal
+        if let Some(tx) = self.stop_tx.take() {
+            match tx.send(()).await {
+                Ok(_) => debug!("Stop signal sent successfully"),
+                Err(_) => debug!("Stop signal channel already closed"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Asks the beacon task to announce us right away
+    async fn request_announce(&self, solicit: bool) -> AppResult<()> {
+        if let Some(tx) = &self.announce_tx {
+            tx.send(solicit)
+                .await
+                .map_err(|_| AppError::DiscoveryError("Beacon task is not running".to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+impl DiscoveryBackend for BeaconBackend {
+    fn kind(&self) -> DiscoveryBackendKind {
+        DiscoveryBackendKind::Broadcast
+    }
+
+    fn start<'a>(&'a mut self, local_user: &'a User, registry: PeerRegistry) -> BoxFuture<'a, AppResult<()>> {
+        self.start_beacons(local_user, registry).boxed()
+    }
+
+    fn stop(&mut self) -> BoxFuture<'_, AppResult<()>> {
+        self.stop_beacons().boxed()
+    }
+
+    fn update_local_user<'a>(&'a mut self, local_user: &'a User) -> BoxFuture<'a, AppResult<()>> {
+        *self.local_user.lock().unwrap() = Some(local_user.clone());
+        self.request_announce(false).boxed()
+    }
+
+    fn refresh(&self) -> BoxFuture<'_, AppResult<()>> {
+        self.request_announce(true).boxed()
+    }
+}
+
+/// Binds the beacon port with broadcast enabled.
+///
+/// The port is shared with other instances on this machine: they all receive
+/// broadcasts, while a direct answer reaches only one of them, so the others
+/// learn about the peer from its next announcement.
+fn bind_beacon_socket() -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, BEACON_PORT)).into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Serializes and signs a beacon announcing `user`
+fn signed_beacon(identity: &DeviceIdentity, user: &User, solicit: bool, leaving: bool) -> AppResult<Vec<u8>> {
+    let payload = serde_json::to_string(&BeaconPayload {
+        version: BEACON_VERSION,
+        user: user.clone(),
+        sent_at: Utc::now(),
+        leaving,
+        solicit,
+    })?;
+    let signature = identity.sign_beacon(&payload);
+    Ok(serde_json::to_vec(&Beacon { payload, signature })?)
+}
+
+/// Broadcasts a beacon for our user on every subnet
+async fn announce(
+    socket: &UdpSocket,
+    identity: &DeviceIdentity,
+    local_user: &Mutex<Option<User>>,
+    solicit: bool,
+    leaving: bool,
+) {
+    let Some(user) = local_user.lock().unwrap().clone() else {
+        return;
+    };
+    let beacon = match signed_beacon(identity, &user, solicit, leaving) {
+        Ok(beacon) => beacon,
+        Err(e) => {
+            warn!("Failed to build discovery beacon: {e}");
+            return;
+        }
+    };
+
+    for address in broadcast_addresses() {
+        if let Err(e) = socket.send_to(&beacon, (address, BEACON_PORT)).await {
+            debug!("Failed to send beacon to {address}: {e}");
+        }
+    }
+}
+
+/// Broadcast address of every IPv4 subnet we are on, or the limited broadcast address if none is found
+fn broadcast_addresses() -> Vec<Ipv4Addr> {
+    let mut addresses: Vec<Ipv4Addr> = default_net::get_interfaces()
+        .iter()
+        .filter(|interface| interface.is_up() && !interface.is_loopback())
+        .flat_map(|interface| interface.ipv4.iter())
+        // Point-to-point links have no broadcast address
+        .filter(|net| net.prefix_len < 31)
+        .map(|net| Ipv4Addr::from(u32::from(net.addr) | !u32::from(net.netmask)))
+        .collect();
+    addresses.sort();
+    addresses.dedup();
+
+    if addresses.is_empty() {
+        addresses.push(Ipv4Addr::BROADCAST);
+    }
+    addresses
+}
+
+/// Checks a received beacon and passes the peer it announces to the registry
+async fn handle_beacon(
+    datagram: &[u8],
+    source: SocketAddr,
+    socket: &UdpSocket,
+    identity: &DeviceIdentity,
+    local_user: &Mutex<Option<User>>,
+    registry: &PeerRegistry,
+) {
+    let payload = match verify_beacon(datagram) {
+        Ok(payload) => payload,
+        Err(e) => {
+            debug!("Ignoring beacon from {source}: {e}");
+            return;
+        }
+    };
+    let mut user = payload.user;
+
+    // Our own beacons come back on every interface
+    if local_user.lock().unwrap().as_ref().is_some_and(|local| local.id == user.id) {
+        return;
+    }
+
+    if payload.leaving {
+        let public_key = user.public_key.as_deref().unwrap_or_default();
+        if registry.peer_left(&user.id, public_key) {
+            info!("Peer {} left (beacon from {})", user.id, source);
+        }
+        return;
+    }
+
+    // The address the beacon came from is the one that reaches the peer
+    let is_new_peer = !registry.contains(&user.id);
+    user.ip = network::format_address(source);
+    user.addresses = network::observed_addresses(source, &user.addresses);
+    user.last_seen = Utc::now();
+    if user.status_text.as_ref().is_some_and(|text| text.is_empty() || text.len() > MAX_STATUS_TEXT_LEN) {
+        user.status_text = None;
+    }
+    if !registry.peer_found(user) {
+        return;
+    }
+
+    // Answer directly so the peer does not have to wait for our next announcement
+    if is_new_peer || payload.solicit {
+        let Some(local) = local_user.lock().unwrap().clone() else {
+            return;
+        };
+        match signed_beacon(identity, &local, false, false) {
+            Ok(reply) => {
+                if let Err(e) = socket.send_to(&reply, (source.ip(), BEACON_PORT)).await {
+                    debug!("Failed to answer beacon from {source}: {e}");
+                }
+            }
+            Err(e) => warn!("Failed to build discovery beacon: {e}"),
+        }
+    }
+}
+
+/// Parses a beacon, checking its signature and age
+fn verify_beacon(datagram: &[u8]) -> AppResult<BeaconPayload> {
+    let beacon: Beacon = serde_json::from_slice(datagram)?;
+    let payload: BeaconPayload = serde_json::from_str(&beacon.payload)?;
+    if payload.version != BEACON_VERSION {
+        return Err(AppError::InvalidOperation(format!(
+            "Unsupported beacon version {}",
+            payload.version
+        )));
+    }
+
+    let public_key = payload
+        .user
+        .public_key
+        .as_deref()
+        .ok_or_else(|| AppError::AuthenticationError("Beacon has no public key".to_string()))?;
+    identity::verify_beacon(&beacon.payload, &beacon.signature, public_key)?;
+
+    // Otherwise anyone could sign a beacon, leave included, in another peer's name
+    if identity::user_id_for_public_key(public_key)? != payload.user.id {
+        return Err(AppError::AuthenticationError(format!(
+            "Beacon user ID {} does not match its public key",
+            payload.user.id
+        )));
+    }
+
+    // Replayed beacons must not bring a peer that left back
+    let age = Utc::now().signed_duration_since(payload.sent_at).num_seconds();
+    if age.abs() > BEACON_MAX_AGE_SECS {
+        return Err(AppError::AuthenticationError(format!("Beacon is {age}s old")));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Presence;
+    use crate::trust::TrustStore;
+
+    fn user(identity: &DeviceIdentity) -> User {
+        User {
+            id: identity.user_id(),
+            name: "Alice".to_string(),
+            ip: "192.168.1.20".to_string(),
+            addresses: vec!["192.168.1.20".to_string()],
+            last_seen: Utc::now(),
+            public_key: Some(identity.public_key_hex()),
+            presence: Presence::Online,
+            status_text: None,
+            port: None,
+            file_port: None,
+        }
+    }
+
+    /// Re-signs a beacon after `tamper` changed its payload
+    fn resigned(identity: &DeviceIdentity, tamper: impl FnOnce(&mut BeaconPayload)) -> Vec<u8> {
+        let mut payload = BeaconPayload {
+            version: BEACON_VERSION,
+            user: user(identity),
+            sent_at: Utc::now(),
+            leaving: false,
+            solicit: false,
+        };
+        tamper(&mut payload);
+        let payload = serde_json::to_string(&payload).unwrap();
+        let signature = identity.sign_beacon(&payload);
+        serde_json::to_vec(&Beacon { payload, signature }).unwrap()
+    }
+
+    #[test]
+    fn signed_beacons_verify() {
+        let identity = DeviceIdentity::generate();
+        let datagram = signed_beacon(&identity, &user(&identity), true, false).unwrap();
+
+        let payload = verify_beacon(&datagram).unwrap();
+        assert_eq!(payload.user.id, identity.user_id());
+        assert!(payload.solicit);
+        assert!(!payload.leaving);
+    }
+
+    #[test]
+    fn tampered_beacons_are_rejected() {
+        let identity = DeviceIdentity::generate();
+        let datagram = signed_beacon(&identity, &user(&identity), false, false).unwrap();
+        let mut beacon: Beacon = serde_json::from_slice(&datagram).unwrap();
+        beacon.payload = beacon.payload.replace("\"leaving\":false", "\"leaving\":true");
+        assert!(beacon.payload.contains("\"leaving\":true"));
+
+        assert!(verify_beacon(&serde_json::to_vec(&beacon).unwrap()).is_err());
+    }
+
+    #[test]
+    fn beacons_signed_by_another_key_are_rejected() {
+        let identity = DeviceIdentity::generate();
+        let impostor = DeviceIdentity::generate();
+        let datagram = signed_beacon(&impostor, &user(&identity), false, false).unwrap();
+
+        assert!(verify_beacon(&datagram).is_err());
+    }
+
+    #[test]
+    fn stale_and_unsigned_beacons_are_rejected() {
+        let identity = DeviceIdentity::generate();
+        let replayed = resigned(&identity, |payload| {
+            payload.sent_at = Utc::now() - chrono::Duration::seconds(BEACON_MAX_AGE_SECS + 60);
+        });
+        let keyless = resigned(&identity, |payload| payload.user.public_key = None);
+        let future = resigned(&identity, |payload| payload.version = BEACON_VERSION + 1);
+
+        assert!(verify_beacon(&replayed).is_err());
+        assert!(verify_beacon(&keyless).is_err());
+        assert!(verify_beacon(&future).is_err());
+        assert!(verify_beacon(b"not a beacon").is_err());
+    }
+
+    #[test]
+    fn forged_leave_beacons_are_rejected() {
+        let victim = DeviceIdentity::generate();
+        let attacker = DeviceIdentity::generate();
+        let forged = resigned(&attacker, |payload| {
+            payload.user.id = victim.user_id();
+            payload.leaving = true;
+        });
+
+        assert!(verify_beacon(&forged).is_err());
+    }
+
+    #[test]
+    fn leave_only_removes_the_peer_holding_the_pinned_key() {
+        let identity = DeviceIdentity::generate();
+        let other = DeviceIdentity::generate();
+        let trust_store = Arc::new(TrustStore::in_memory(other.public_key_hex()));
+        let registry = PeerRegistry::new(other.user_id(), trust_store);
+        assert!(registry.peer_found(user(&identity)));
+
+        assert!(!registry.peer_left(&identity.user_id(), &other.public_key_hex()));
+        assert!(registry.contains(&identity.user_id()));
+        assert!(registry.peer_left(&identity.user_id(), &identity.public_key_hex()));
+        assert!(!registry.contains(&identity.user_id()));
+    }
+}
@@ -1,86 +1,151 @@
-use log::{debug, error, info};
+use chrono::{DateTime, Utc};
+use log::{debug, error, info, warn};
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream as AsyncTcpStream;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
 use tokio::sync::mpsc;
-use uuid::Uuid;
 
-use crate::emit_event;
 use crate::error::{AppError, AppResult};
-use crate::models::{Message, User};
-
-#[allow(dead_code)]
-const CHAT_PORT: u16 = 8765;
+use crate::discovery::PeerMap;
+use crate::groups::GroupStore;
+use crate::identity;
+use crate::models::{
+    HistoryCursor, Message, MessageDeletion, MessageEdit, MessagePage, MessageStatus, PageRequest, Reaction,
+    SearchResult, User,
+};
+use crate::network;
+use crate::search;
+use crate::secure_channel::ChannelKeys;
+use crate::storage::MessageStore;
+use crate::trust::TrustStore;
+
+/// Port the chat service listens on by default
+pub const CHAT_PORT: u16 = 8765;
+/// Recipient ID and history bucket of messages sent to everyone on the LAN
+pub const BROADCAST_CHANNEL_ID: &str = "broadcast";
+/// Most search results returned at once
+const MAX_SEARCH_RESULTS: usize = 100;
+/// Messages of context returned on each side of a search result
+const SEARCH_CONTEXT_MESSAGES: usize = 2;
 
 /// Manages chat functionality
 pub struct ChatManager {
     /// The local user
     local_user: User,
-    /// Map of messages by conversation ID (peer ID)
-    messages: Arc<Mutex<HashMap<String, Vec<Message>>>>,
-    /// Map of connections by peer ID
-    connections: Arc<Mutex<HashMap<String, TcpStream>>>,
-    /// Listener for incoming connections
-    #[allow(dead_code)]
-    listener: Option<TcpListener>,
+    /// Profile sent to peers that connect to us, kept current by `set_local_user`
+    listener_user: Arc<RwLock<User>>,
+    /// Persistent store of messages by conversation ID (peer ID, group ID or the broadcast channel)
+    messages: Arc<MessageStore>,
+    /// Groups the local user has been added to
+    groups: Arc<GroupStore>,
+    /// Keys used to encrypt and authenticate incoming connections
+    channel_keys: Arc<ChannelKeys>,
+    /// Discovered peers, used to authenticate incoming messages
+    peers: PeerMap,
+    /// Pinned peer keys, checked before a peer that connects to us is remembered
+    trust_store: Arc<TrustStore>,
     /// Channel for stopping the chat service
     #[allow(dead_code)]
     stop_tx: Option<mpsc::Sender<()>>,
 }
 
 impl ChatManager {
-    /// Creates a new ChatManager instance
-    pub fn new(local_user: User) -> Self {
+    /// Creates a new ChatManager instance, loading message history from `data_dir`
+    pub fn new(
+        local_user: User,
+        data_dir: &Path,
+        channel_keys: Arc<ChannelKeys>,
+        peers: PeerMap,
+        trust_store: Arc<TrustStore>,
+    ) -> Self {
+        let messages = match MessageStore::open(data_dir) {
+            Ok(store) => store,
+            Err(e) => {
+                error!("Failed to open message history, messages will not be persisted: {e}");
+                MessageStore::in_memory()
+            }
+        };
+        let groups = match GroupStore::open(data_dir) {
+            Ok(store) => store,
+            Err(e) => {
+                error!("Failed to open groups, group membership will not be persisted: {e}");
+                GroupStore::in_memory()
+            }
+        };
+
         ChatManager {
+            listener_user: Arc::new(RwLock::new(local_user.clone())),
             local_user,
-            messages: Arc::new(Mutex::new(HashMap::new())),
-            connections: Arc::new(Mutex::new(HashMap::new())),
-            listener: None,
+            messages: Arc::new(messages),
+            groups: Arc::new(groups),
+            channel_keys,
+            peers,
+            trust_store,
             stop_tx: None,
         }
     }
 
+    /// Updates the profile sent to peers that connect to us, e.g. once the file transfer port is known
+    pub fn set_local_user(&mut self, local_user: User) {
+        *self.listener_user.write().unwrap() = local_user.clone();
+        self.local_user = local_user;
+    }
+
     /// Gets a reference to the message storage for sharing with ConnectionManager
-    pub fn get_message_storage(&self) -> Arc<Mutex<HashMap<String, Vec<Message>>>> {
+    pub fn get_message_storage(&self) -> Arc<MessageStore> {
         Arc::clone(&self.messages)
     }
 
-    /// Starts the chat service
-    pub fn start_chat_service(&mut self) -> AppResult<()> {
-        use tokio::net::TcpListener as AsyncTcpListener;
+    /// Gets a reference to the group store for sharing with ConnectionManager
+    pub fn get_group_store(&self) -> Arc<GroupStore> {
+        Arc::clone(&self.groups)
+    }
+
+    /// Starts the chat service on `port`, returning the port actually listened on
+    pub async fn start_chat_service(&mut self, port: u16) -> AppResult<u16> {
+        // Bind to the chat port on IPv4 and IPv6 before advertising it
+        let (listeners, port) = network::listen_on(port, CHAT_PORT)
+            .await
+            .map_err(|e| AppError::NetworkError(format!("Failed to bind to port {port}: {e}")))?;
+        info!("Chat service listening on port {port}");
+        self.local_user.port = Some(port);
+        self.listener_user.write().unwrap().port = Some(port);
 
         let messages = Arc::clone(&self.messages);
-        let local_user = self.local_user.clone();
+        let groups = Arc::clone(&self.groups);
+        let local_user = Arc::clone(&self.listener_user);
+        let channel_keys = Arc::clone(&self.channel_keys);
+        let peers = Arc::clone(&self.peers);
+        let trust_store = Arc::clone(&self.trust_store);
 
         // Spawn async task to handle incoming connections
         tokio::spawn(async move {
-            // Bind to the chat port
-            let listener = match AsyncTcpListener::bind(format!("0.0.0.0:{CHAT_PORT}")).await {
-                Ok(listener) => {
-                    info!("Chat service listening on port {CHAT_PORT}");
-                    listener
-                }
-                Err(e) => {
-                    error!("Failed to bind to port {CHAT_PORT}: {e}");
-                    return;
-                }
-            };
-
             loop {
-                match listener.accept().await {
+                match network::accept_any(&listeners).await {
                     Ok((stream, addr)) => {
                         debug!("New chat connection from: {addr}");
 
-                        // Clone necessary values for the connection handler
-                        let local_user_clone = local_user.clone();
+                        // Clone necessary values for the connection handler, reading the
+                        // profile per connection so port and address changes are picked up
+                        let local_user_clone = local_user.read().unwrap().clone();
                         let messages_clone = Arc::clone(&messages);
+                        let groups_clone = Arc::clone(&groups);
+                        let channel_keys_clone = Arc::clone(&channel_keys);
+                        let peers_clone = Arc::clone(&peers);
+                        let trust_store_clone = Arc::clone(&trust_store);
 
                         // Spawn task to handle the connection using connection manager
                         tokio::spawn(async move {
                             // Create a temporary connection manager for handling this connection
-                            let temp_conn_manager = crate::connection_manager::ConnectionManager::new(local_user_clone, messages_clone);
+                            let temp_conn_manager = crate::connection_manager::ConnectionManager::new(
+                                local_user_clone,
+                                messages_clone,
+                                groups_clone,
+                                channel_keys_clone,
+                                peers_clone,
+                                trust_store_clone,
+                            );
                             if let Err(e) = temp_conn_manager.handle_incoming_connection(stream, addr).await {
                                 error!("Error handling incoming connection: {e}");
                             }
@@ -95,10 +160,11 @@ impl ChatManager {
         });
 
         info!("Chat service started");
-        Ok(())
+        Ok(port)
     }
 
     /// Stops the chat service
+    #[allow(dead_code)]
     pub fn stop_chat_service(&mut self) -> AppResult<()> {
         // Send stop signal
         if let Some(tx) = &self.stop_tx {
@@ -108,145 +174,20 @@ impl ChatManager {
         }
 
         // Clear state
-        self.listener = None;
         self.stop_tx = None;
 
-        // Clear connections
-        {
-            let mut connections = self.connections.lock().unwrap();
-            connections.clear();
-        }
-
         info!("Chat service stopped");
         Ok(())
     }
 
-    /// Sends a message to a peer
-    pub async fn send_message(&mut self, _peer_id: &str, _content: &str) -> AppResult<Message> {
-        // This method is kept for backward compatibility
-        // Use send_message_with_peer_ip instead
-        Err(AppError::NetworkError(
-            "Use send_message_with_peer_ip method instead".to_string(),
-        ))
+    /// Gets a page of the messages exchanged with a specific peer
+    pub fn get_messages_for_peer(&self, peer_id: &str, page: &PageRequest) -> AppResult<MessagePage> {
+        paginate(self.peer_messages(peer_id), page)
     }
 
-    /// Sends a message to a peer with a specific IP
-    pub async fn send_message_with_peer_ip(
-        &mut self,
-        peer_id: &str,
-        content: &str,
-        peer_ip: &str,
-    ) -> AppResult<Message> {
-        info!(
-            "ChatManager: Creating message from {} to {} with IP {}",
-            self.local_user.id, peer_id, peer_ip
-        );
-
-        // Create message
-        let message = Message {
-            id: Uuid::new_v4().to_string(),
-            sender_id: self.local_user.id.clone(),
-            recipient_id: peer_id.to_string(),
-            content: content.to_string(),
-            timestamp: chrono::Utc::now(),
-            read: false,
-        };
-
-        info!("ChatManager: Created message with ID: {}", message.id);
-
-        // Store message in our sent messages
-        {
-            let mut messages = self.messages.lock().unwrap();
-            let sent_messages = messages.entry(self.local_user.id.clone()).or_default();
-            sent_messages.push(message.clone());
-            info!(
-                "ChatManager: Stored message locally, total sent messages: {}",
-                sent_messages.len()
-            );
-        }
-
-        // Send message to peer
-        info!("ChatManager: Attempting to send message to peer over network");
-        self.send_message_to_peer(peer_id, &message, Some(peer_ip))
-            .await?;
-        info!("ChatManager: Message sent successfully over network");
-
-        Ok(message)
-    }
-
-    /// Sends a message to a peer over the network
-    async fn send_message_to_peer(
-        &self,
-        peer_id: &str,
-        message: &Message,
-        peer_ip: Option<&str>,
-    ) -> AppResult<()> {
-        let target_addr = if let Some(ip) = peer_ip {
-            // Use the provided IP address to establish a new connection
-            format!("{}:{}", ip, CHAT_PORT)
-        } else {
-            // No IP provided
-            error!("ChatManager: No IP address provided for peer {}", peer_id);
-            return Err(AppError::NetworkError(
-                "No IP address provided for peer".to_string(),
-            ));
-        };
-
-        info!(
-            "ChatManager: Connecting to {} to send message: {}",
-            target_addr, message.content
-        );
-        info!(
-            "ChatManager: Message details - ID: {}, From: {} To: {}",
-            message.id, message.sender_id, message.recipient_id
-        );
-
-        // Send the message
-        let message_json = serde_json::to_string(message).map_err(|e| {
-            error!("ChatManager: Failed to serialize message: {}", e);
-            AppError::SerializationError(e)
-        })?;
-
-        info!(
-            "ChatManager: Serialized message JSON (length: {})",
-            message_json.len()
-        );
-
-        let mut stream = AsyncTcpStream::connect(&target_addr).await.map_err(|e| {
-            error!("ChatManager: Failed to connect to {}: {}", target_addr, e);
-            AppError::NetworkError(format!("Failed to connect to {}: {e}", target_addr))
-        })?;
-
-        info!("ChatManager: Successfully connected to {}", target_addr);
-
-        stream
-            .write_all(message_json.as_bytes())
-            .await
-            .map_err(|e| {
-                error!(
-                    "ChatManager: Failed to write message to {}: {}",
-                    target_addr, e
-                );
-                AppError::NetworkError(format!("Failed to send message: {e}"))
-            })?;
-
-        info!("ChatManager: Message data written to stream");
-
-        stream.flush().await.map_err(|e| {
-            error!(
-                "ChatManager: Failed to flush message to {}: {}",
-                target_addr, e
-            );
-            AppError::NetworkError(format!("Failed to flush message: {e}"))
-        })?;
-
-        info!("ChatManager: Message sent successfully to {}", target_addr);
-        Ok(())
-    }
-
-    /// Gets messages for a specific peer
-    pub fn get_messages_for_peer(&self, peer_id: &str) -> Vec<Message> {
-        let messages = self.messages.lock().unwrap();
+    /// Gets every message exchanged with a peer, sorted by timestamp
+    fn peer_messages(&self, peer_id: &str) -> Vec<Message> {
+        let messages = self.messages.lock();
         let mut peer_messages = Vec::new();
 
         // Get messages where we are the sender and peer is recipient
@@ -270,13 +211,51 @@ impl ChatManager {
         }
 
         // Sort by timestamp
-        peer_messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        peer_messages.sort_by_key(|msg| msg.timestamp);
         peer_messages
     }
 
-    /// Gets all messages
-    pub fn get_all_messages(&self) -> Vec<Message> {
-        let messages = self.messages.lock().unwrap();
+    /// Gets the messages posted to a group
+    pub fn get_group_messages(&self, group_id: &str) -> Vec<Message> {
+        self.get_bucket_messages(group_id)
+    }
+
+    /// Gets the messages sent to the broadcast channel by anyone
+    pub fn get_broadcast_messages(&self) -> Vec<Message> {
+        self.get_bucket_messages(BROADCAST_CHANNEL_ID)
+    }
+
+    /// Gets every message of a conversation with a peer, a group or the broadcast channel
+    pub fn get_conversation(&self, conversation_id: &str) -> Vec<Message> {
+        if self.is_shared_conversation(conversation_id) {
+            self.get_bucket_messages(conversation_id)
+        } else {
+            self.peer_messages(conversation_id)
+        }
+    }
+
+    /// Whether a conversation is a group or the broadcast channel rather than a peer
+    fn is_shared_conversation(&self, conversation_id: &str) -> bool {
+        conversation_id == BROADCAST_CHANNEL_ID || self.groups.get(conversation_id).is_some()
+    }
+
+    /// Gets every message of a shared conversation, sorted by timestamp
+    fn get_bucket_messages(&self, bucket: &str) -> Vec<Message> {
+        let mut bucket_messages = self
+            .messages
+            .lock()
+            .get(bucket)
+            .cloned()
+            .unwrap_or_default();
+
+        // Sort by timestamp
+        bucket_messages.sort_by_key(|msg| msg.timestamp);
+        bucket_messages
+    }
+
+    /// Gets a page of all messages, across every conversation
+    pub fn get_all_messages(&self, page: &PageRequest) -> AppResult<MessagePage> {
+        let messages = self.messages.lock();
         let mut all_messages = Vec::new();
 
         for peer_messages in messages.values() {
@@ -284,113 +263,509 @@ impl ChatManager {
         }
 
         // Sort by timestamp
-        all_messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-        all_messages
+        all_messages.sort_by_key(|msg| msg.timestamp);
+        paginate(all_messages, page)
+    }
+
+    /// Counts unread messages by conversation, without loading their content
+    pub fn get_unread_counts(&self) -> HashMap<String, usize> {
+        let local_id = &self.local_user.id;
+        self.messages
+            .lock()
+            .iter()
+            .filter(|(bucket, _)| *bucket != local_id)
+            .filter_map(|(conversation_id, messages)| {
+                let unread = messages
+                    .iter()
+                    .filter(|msg| msg.sender_id != *local_id && !msg.read)
+                    .count();
+                (unread > 0).then(|| (conversation_id.clone(), unread))
+            })
+            .collect()
+    }
+
+    /// Looks up a message by ID in any conversation
+    pub fn get_message(&self, message_id: &str) -> Option<Message> {
+        self.messages.find(message_id).map(|(_, message)| message)
+    }
+
+    /// Gets the thread a message belongs to: its root and every reply, sorted by timestamp
+    pub fn get_thread(&self, message_id: &str) -> AppResult<Vec<Message>> {
+        let (_, message) = self
+            .messages
+            .find(message_id)
+            .ok_or_else(|| AppError::InvalidOperation(format!("Message {message_id} not found")))?;
+        let root = message.thread_root.unwrap_or(message.id);
+
+        let messages = self.messages.lock();
+        let mut thread: Vec<Message> = messages
+            .values()
+            .flatten()
+            .filter(|msg| msg.id == root || msg.thread_root.as_ref() == Some(&root))
+            .cloned()
+            .collect();
+
+        // Sort by timestamp
+        thread.sort_by_key(|msg| msg.timestamp);
+        Ok(thread)
+    }
+
+    /// Searches message content, most recent matches first.
+    ///
+    /// Every word of the query must match the start of a word in the message,
+    /// ignoring case. Results can be limited to one conversation and a time range.
+    pub fn search_messages(
+        &self,
+        query: &str,
+        conversation_id: Option<&str>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+    ) -> Vec<SearchResult> {
+        let query_tokens = search::tokenize(query);
+        if query_tokens.is_empty() {
+            return Vec::new();
+        }
+
+        let local_id = &self.local_user.id;
+        let mut matches: Vec<Message> = self
+            .messages
+            .search(&query_tokens)
+            .into_iter()
+            .filter(|msg| conversation_id.is_none_or(|id| msg.conversation_id(local_id) == id))
+            .filter(|msg| from.is_none_or(|from| msg.timestamp >= from))
+            .filter(|msg| to.is_none_or(|to| msg.timestamp <= to))
+            .collect();
+        matches.sort_by_key(|msg| Reverse(msg.timestamp));
+        matches.truncate(MAX_SEARCH_RESULTS);
+
+        // Conversations are loaded once however many matches they hold
+        let mut conversations: HashMap<String, Vec<Message>> = HashMap::new();
+        matches
+            .into_iter()
+            .map(|message| {
+                let conversation_id = message.conversation_id(local_id).to_string();
+                let conversation = conversations
+                    .entry(conversation_id.clone())
+                    .or_insert_with(|| self.get_conversation_messages(&message));
+                let position = conversation
+                    .iter()
+                    .position(|msg| msg.id == message.id)
+                    .unwrap_or(0);
+
+                let before_start = position.saturating_sub(SEARCH_CONTEXT_MESSAGES);
+                let after_end = (position + 1 + SEARCH_CONTEXT_MESSAGES).min(conversation.len());
+                SearchResult {
+                    snippet: search::snippet(&message.content, &query_tokens),
+                    context_before: conversation[before_start..position].to_vec(),
+                    context_after: conversation[(position + 1).min(after_end)..after_end].to_vec(),
+                    conversation_id,
+                    message,
+                }
+            })
+            .collect()
+    }
+
+    /// Gets every message of the conversation `message` belongs to, sorted by timestamp
+    fn get_conversation_messages(&self, message: &Message) -> Vec<Message> {
+        match &message.group_id {
+            Some(group_id) => self.get_group_messages(group_id),
+            None if message.recipient_id == BROADCAST_CHANNEL_ID => self.get_broadcast_messages(),
+            None => self.peer_messages(message.conversation_id(&self.local_user.id)),
+        }
+    }
+
+    /// Applies an edit of one of our messages, returning the edited message
+    pub fn edit_message(&self, edit: &MessageEdit) -> AppResult<Option<Message>> {
+        self.messages.apply_edit(edit)
+    }
+
+    /// Applies a deletion of one of our messages, returning the tombstone
+    pub fn delete_message(&self, deletion: &MessageDeletion) -> AppResult<Option<Message>> {
+        self.messages.apply_deletion(deletion)
+    }
+
+    /// Applies a reaction by the local user, returning the updated message
+    pub fn react_to_message(&self, reaction: &Reaction) -> AppResult<Option<Message>> {
+        self.messages.apply_reaction(reaction)
     }
 
     /// Stores a sent message locally
     pub fn store_sent_message(&self, message: &Message) -> AppResult<()> {
-        let mut messages = self.messages.lock().unwrap();
-        let sent_messages = messages.entry(self.local_user.id.clone()).or_default();
-        sent_messages.push(message.clone());
-        info!("Stored sent message locally, total sent messages: {}", sent_messages.len());
+        let sent_count = self.messages.insert(&self.local_user.id, message)?;
+        info!("Stored sent message locally, total sent messages: {}", sent_count);
+        Ok(())
+    }
+
+    /// Stores a message posted to a group, sent or received
+    pub fn store_group_message(&self, group_id: &str, message: &Message) -> AppResult<()> {
+        let group_count = self.messages.insert(group_id, message)?;
+        info!("Stored group message locally, total messages in group {}: {}", group_id, group_count);
+        Ok(())
+    }
+
+    /// Stores a message we sent to the broadcast channel
+    pub fn store_broadcast_message(&self, message: &Message) -> AppResult<()> {
+        let broadcast_count = self.messages.insert(BROADCAST_CHANNEL_ID, message)?;
+        info!("Stored broadcast locally, total broadcasts: {}", broadcast_count);
         Ok(())
     }
 
     /// Stores a received message locally
+    #[allow(dead_code)]
     pub fn store_received_message(&self, message: &Message) -> AppResult<()> {
-        let mut messages = self.messages.lock().unwrap();
-        let peer_messages = messages.entry(message.sender_id.clone()).or_default();
-        peer_messages.push(message.clone());
+        let peer_count = self.messages.insert(&message.sender_id, message)?;
         info!(
             "Stored received message locally from {}, total messages from peer: {}",
-            message.sender_id,
-            peer_messages.len()
+            message.sender_id, peer_count
         );
         Ok(())
     }
 
-    /// Marks messages from a peer as read
-    pub fn mark_messages_as_read(&mut self, peer_id: &str) -> AppResult<()> {
-        let mut messages = self.messages.lock().unwrap();
+    /// Merges messages from an export into history, returning how many were new.
+    ///
+    /// Messages already stored (by ID) are skipped, as are direct messages
+    /// the local user was not part of, group messages of groups it was never
+    /// added to and messages not signed by us or a peer with a pinned key.
+    pub fn import_messages(&self, messages: Vec<Message>) -> AppResult<usize> {
+        let local_id = &self.local_user.id;
+        let mut imported = 0;
+
+        for message in messages {
+            let bucket = match &message.group_id {
+                // Groups we left still count, their history stays readable
+                Some(group_id) if self.groups.get(group_id).is_some_and(|group| group.is_invited(local_id)) => {
+                    group_id.clone()
+                }
+                Some(group_id) => {
+                    warn!("Skipping imported message {} of unknown group {}", message.id, group_id);
+                    continue;
+                }
+                None if message.recipient_id == BROADCAST_CHANNEL_ID => BROADCAST_CHANNEL_ID.to_string(),
+                None if message.sender_id == *local_id => local_id.clone(),
+                None if message.recipient_id == *local_id => message.sender_id.clone(),
+                None => {
+                    warn!("Skipping imported message {} between other users", message.id);
+                    continue;
+                }
+            };
+            if self.messages.find(&message.id).is_some() {
+                continue;
+            }
 
-        // Mark messages in the peer's conversation (received messages)
-        if let Some(peer_messages) = messages.get_mut(peer_id) {
-            for message in peer_messages.iter_mut() {
-                if message.recipient_id == self.local_user.id {
-                    message.read = true;
+            let message = match self.signed_version(&message) {
+                Ok(signed) => signed,
+                Err(e) => {
+                    warn!("Skipping imported message {} from {}: {}", message.id, message.sender_id, e);
+                    continue;
                 }
+            };
+            self.messages.insert(&bucket, &message)?;
+            imported += 1;
+        }
+
+        info!("Imported {} message(s) into history", imported);
+        Ok(imported)
+    }
+
+    /// Reverts an imported message to the version its sender signed, checked against
+    /// our own or the sender's pinned key.
+    ///
+    /// Edits are not signed in an export, so edited messages come back as first sent,
+    /// and deleted ones have nothing left to check.
+    fn signed_version(&self, message: &Message) -> AppResult<Message> {
+        if message.deleted {
+            return Err(AppError::AuthenticationError("Message was deleted".to_string()));
+        }
+        let public_key = if message.sender_id == self.local_user.id {
+            self.local_user.public_key.clone()
+        } else {
+            self.trust_store.pinned_key(&message.sender_id)
+        }
+        .ok_or_else(|| AppError::AuthenticationError("Sender's key is unknown".to_string()))?;
+
+        let mut signed = message.clone();
+        if let Some(original) = signed.edit_history.first() {
+            signed.content = original.content.clone();
+            signed.edit_history.clear();
+            signed.edited_at = None;
+        }
+        identity::verify_message(&signed, &public_key)?;
+        Ok(signed)
+    }
+
+    /// Marks the messages of a conversation as read.
+    ///
+    /// Returns the IDs of newly read direct messages, for the read receipt; group
+    /// and broadcast messages have many recipients and get no receipts.
+    pub fn mark_messages_as_read(&mut self, conversation_id: &str) -> AppResult<Vec<String>> {
+        let local_id = &self.local_user.id;
+
+        // Our own messages live in a separate bucket, except in shared conversations
+        let read_messages = self.messages.update(conversation_id, |message| {
+            if message.sender_id != *local_id && !message.read {
+                message.read = true;
+                message.status = MessageStatus::Read;
+                true
+            } else {
+                false
             }
+        })?;
+
+        if self.is_shared_conversation(conversation_id) {
+            return Ok(Vec::new());
         }
+        // It's valid to have no messages for a peer
+        Ok(read_messages.into_iter().map(|message| message.id).collect())
+    }
 
-        // Always return Ok - it's valid to have no messages for a peer
-        Ok(())
+    /// Updates the delivery status of a message we sent, never moving it backwards
+    pub fn update_message_status(&self, sent: &Message, status: MessageStatus) -> AppResult<Option<Message>> {
+        let bucket = match &sent.group_id {
+            Some(group_id) => group_id,
+            None if sent.recipient_id == BROADCAST_CHANNEL_ID => BROADCAST_CHANNEL_ID,
+            None => &self.local_user.id,
+        };
+        let updated = self.messages.update(bucket, |message| {
+            if message.id == sent.id && message.status.can_advance_to(status) {
+                message.status = status;
+                true
+            } else {
+                false
+            }
+        })?;
+        Ok(updated.into_iter().next())
     }
 }
 
-/// Handles an incoming message
-async fn handle_incoming_message(
-    mut stream: AsyncTcpStream,
-    messages: Arc<Mutex<HashMap<String, Vec<Message>>>>,
-    local_user: User,
-) -> AppResult<()> {
-    info!("ChatManager: Handling incoming connection from peer");
-
-    // Read message
-    let mut buffer = Vec::new();
-    stream.read_to_end(&mut buffer).await.map_err(|e| {
-        error!("ChatManager: Failed to read incoming message: {}", e);
-        AppError::NetworkError(format!("Failed to read message: {e}"))
-    })?;
-
-    info!(
-        "ChatManager: Read {} bytes from incoming connection",
-        buffer.len()
-    );
-
-    // Parse message
-    let message: Message = serde_json::from_slice(&buffer).map_err(|e| {
-        error!("ChatManager: Failed to parse incoming message: {}", e);
-        error!(
-            "ChatManager: Raw message data: {:?}",
-            String::from_utf8_lossy(&buffer)
-        );
-        AppError::SerializationError(e)
-    })?;
-
-    info!(
-        "ChatManager: Received message from {}: {}",
-        message.sender_id, message.content
-    );
-    info!(
-        "ChatManager: Message details - ID: {}, To: {}, Timestamp: {}",
-        message.id, message.recipient_id, message.timestamp
-    );
-
-    // Verify the message is intended for us
-    if message.recipient_id != local_user.id {
-        error!(
-            "ChatManager: Received message not intended for us (recipient: {}, our ID: {})",
-            message.recipient_id, local_user.id
-        );
-        return Ok(()); // Don't error, just ignore
+/// Index in `messages` (sorted by timestamp) where a cursor falls.
+///
+/// With `before` the index is the cursor itself, so the page ends there;
+/// otherwise it is just past the cursor, where the page starts.
+fn cursor_index(messages: &[Message], cursor: &HistoryCursor, before: bool) -> AppResult<usize> {
+    match cursor {
+        HistoryCursor::MessageId(id) => messages
+            .iter()
+            .position(|msg| msg.id == *id)
+            .map(|index| if before { index } else { index + 1 })
+            .ok_or_else(|| AppError::InvalidOperation(format!("Message {id} not found in this conversation"))),
+        HistoryCursor::Timestamp(timestamp) if before => Ok(messages.partition_point(|msg| msg.timestamp < *timestamp)),
+        HistoryCursor::Timestamp(timestamp) => Ok(messages.partition_point(|msg| msg.timestamp <= *timestamp)),
     }
+}
 
-    // Store message in the sender's conversation (for received messages)
-    {
-        let mut messages = messages.lock().unwrap();
-        let peer_messages = messages.entry(message.sender_id.clone()).or_default();
-        peer_messages.push(message.clone());
-        info!(
-            "ChatManager: Stored incoming message, total messages from {}: {}",
-            message.sender_id,
-            peer_messages.len()
+/// Cuts the page described by `page` out of messages sorted by timestamp
+fn paginate(mut messages: Vec<Message>, page: &PageRequest) -> AppResult<MessagePage> {
+    let end = match &page.before {
+        Some(cursor) => cursor_index(&messages, cursor, true)?,
+        None => messages.len(),
+    };
+    let start = match &page.after {
+        Some(cursor) => cursor_index(&messages, cursor, false)?.min(end),
+        None => 0,
+    };
+
+    let in_range = end - start;
+    let limit = page.limit.unwrap_or(in_range).min(in_range);
+    // Scrolling forward from `after` returns the oldest messages, otherwise the newest
+    let (start, end) = if page.after.is_some() && page.before.is_none() {
+        (start, start + limit)
+    } else {
+        (end - limit, end)
+    };
+
+    messages.truncate(end);
+    messages.drain(..start);
+    Ok(MessagePage {
+        messages,
+        has_more: in_range > limit,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::DeviceIdentity;
+    use crate::models::{Group, Presence};
+    use chrono::Duration;
+    use uuid::Uuid;
+
+    /// Ten messages a minute apart, `m0` being the oldest
+    fn history() -> Vec<Message> {
+        let start = Utc::now();
+        (0..10)
+            .map(|i| {
+                let mut message = Message::new("alice", "bob", &format!("message {i}"));
+                message.id = format!("m{i}");
+                message.timestamp = start + Duration::minutes(i);
+                message
+            })
+            .collect()
+    }
+
+    fn ids(page: &MessagePage) -> Vec<&str> {
+        page.messages.iter().map(|msg| msg.id.as_str()).collect()
+    }
+
+    fn request(before: Option<&str>, after: Option<&str>, limit: Option<usize>) -> PageRequest {
+        PageRequest {
+            before: before.map(|id| HistoryCursor::MessageId(id.to_string())),
+            after: after.map(|id| HistoryCursor::MessageId(id.to_string())),
+            limit,
+        }
+    }
+
+    #[test]
+    fn first_page_holds_the_newest_messages() {
+        let page = paginate(history(), &request(None, None, Some(3))).unwrap();
+        assert_eq!(ids(&page), ["m7", "m8", "m9"]);
+        assert!(page.has_more);
+
+        let everything = paginate(history(), &PageRequest::default()).unwrap();
+        assert_eq!(everything.messages.len(), 10);
+        assert!(!everything.has_more);
+    }
+
+    #[test]
+    fn pages_scroll_back_from_a_cursor() {
+        let page = paginate(history(), &request(Some("m7"), None, Some(3))).unwrap();
+        assert_eq!(ids(&page), ["m4", "m5", "m6"]);
+        assert!(page.has_more);
+
+        let last = paginate(history(), &request(Some("m2"), None, Some(3))).unwrap();
+        assert_eq!(ids(&last), ["m0", "m1"]);
+        assert!(!last.has_more);
+    }
+
+    #[test]
+    fn pages_scroll_forward_from_a_cursor() {
+        let page = paginate(history(), &request(None, Some("m2"), Some(3))).unwrap();
+        assert_eq!(ids(&page), ["m3", "m4", "m5"]);
+        assert!(page.has_more);
+
+        let between = paginate(history(), &request(Some("m6"), Some("m2"), None)).unwrap();
+        assert_eq!(ids(&between), ["m3", "m4", "m5"]);
+        assert!(!between.has_more);
+    }
+
+    #[test]
+    fn timestamp_cursors_are_exclusive() {
+        let messages = history();
+        let request = PageRequest {
+            before: Some(HistoryCursor::Timestamp(messages[5].timestamp)),
+            after: Some(HistoryCursor::Timestamp(messages[2].timestamp)),
+            limit: None,
+        };
+
+        let page = paginate(messages, &request).unwrap();
+        assert_eq!(ids(&page), ["m3", "m4"]);
+    }
+
+    #[test]
+    fn unknown_cursors_are_rejected() {
+        assert!(paginate(history(), &request(Some("missing"), None, None)).is_err());
+        assert!(paginate(history(), &request(None, Some("missing"), None)).is_err());
+    }
+
+    /// A manager for `alice`, storing its history in a fresh temporary directory
+    fn manager() -> (ChatManager, std::path::PathBuf) {
+        manager_for(&DeviceIdentity::generate())
+    }
+
+    /// Like `manager`, with `identity` as alice's key
+    fn manager_for(identity: &DeviceIdentity) -> (ChatManager, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("ip-chat-chat-{}", Uuid::new_v4()));
+        let local_user = User {
+            id: "alice".to_string(),
+            name: "Alice".to_string(),
+            ip: "192.168.1.10".to_string(),
+            addresses: Vec::new(),
+            last_seen: Utc::now(),
+            public_key: Some(identity.public_key_hex()),
+            presence: Presence::Online,
+            status_text: None,
+            port: None,
+            file_port: None,
+        };
+        let manager = ChatManager::new(
+            local_user,
+            &dir,
+            Arc::new(ChannelKeys::new(identity).unwrap()),
+            PeerMap::default(),
+            Arc::new(TrustStore::in_memory(identity.public_key_hex())),
         );
+        (manager, dir)
     }
 
-    // Emit message received event
-    emit_event("message_received", message);
+    #[test]
+    fn unread_counts_cover_shared_conversations() {
+        let (mut manager, dir) = manager();
+        let direct = Message::new("bob", "alice", "hi alice");
+        manager.store_received_message(&direct).unwrap();
+        manager.messages.insert(BROADCAST_CHANNEL_ID, &Message::new("bob", BROADCAST_CHANNEL_ID, "hi all")).unwrap();
+        manager.store_broadcast_message(&Message::new("alice", BROADCAST_CHANNEL_ID, "hi bob")).unwrap();
+
+        let counts = manager.get_unread_counts();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["bob"], 1);
+        assert_eq!(counts[BROADCAST_CHANNEL_ID], 1);
+
+        // Broadcasts are marked read without a receipt, direct messages get one
+        assert!(manager.mark_messages_as_read(BROADCAST_CHANNEL_ID).unwrap().is_empty());
+        assert!(!manager.get_unread_counts().contains_key(BROADCAST_CHANNEL_ID));
+        assert_eq!(manager.mark_messages_as_read("bob").unwrap(), [direct.id]);
+        assert!(manager.get_unread_counts().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_signed_messages_of_our_conversations_are_imported() {
+        let local = DeviceIdentity::generate();
+        let peer = DeviceIdentity::generate();
+        let stranger = DeviceIdentity::generate();
+        let (manager, dir) = manager_for(&local);
+        manager.trust_store.check_key(&peer.user_id(), &peer.public_key_hex()).unwrap();
+        let signed = |identity: &DeviceIdentity, sender_id: &str, recipient_id: &str, group_id: Option<&str>| {
+            let mut message = Message::new(sender_id, recipient_id, "hello");
+            message.group_id = group_id.map(String::from);
+            identity.sign_message(&mut message).unwrap();
+            message
+        };
 
-    info!("ChatManager: Successfully processed incoming message");
-    Ok(())
+        let sent = signed(&local, "alice", &peer.user_id(), None);
+        let received = signed(&peer, &peer.user_id(), "alice", None);
+        let mut edited = signed(&peer, &peer.user_id(), "alice", None);
+        edited.apply_edit(&MessageEdit {
+            message_id: edited.id.clone(),
+            sender_id: peer.user_id(),
+            content: "unsigned edit".to_string(),
+            edited_at: Utc::now() + Duration::minutes(1),
+            signature: None,
+        });
+        let mut tampered = signed(&peer, &peer.user_id(), "alice", None);
+        tampered.content = "tampered".to_string();
+        let unpinned = signed(&stranger, &stranger.user_id(), "alice", None);
+        let other_group = signed(&peer, &peer.user_id(), "group-1", Some("group-1"));
+        let members = vec!["alice".to_string(), peer.user_id()];
+        manager
+            .groups
+            .insert(&Group {
+                id: "group-2".to_string(),
+                name: "Lunch".to_string(),
+                members: members.clone(),
+                invited: members,
+                created_by: peer.user_id(),
+                created_at: Utc::now(),
+            })
+            .unwrap();
+        let our_group = signed(&peer, &peer.user_id(), "group-2", Some("group-2"));
+
+        let imported = manager
+            .import_messages(vec![sent, received, edited.clone(), tampered, unpinned, other_group, our_group])
+            .unwrap();
+        assert_eq!(imported, 4);
+        let (_, stored) = manager.messages.find(&edited.id).unwrap();
+        assert_eq!(stored.content, "hello");
+        assert!(stored.edit_history.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -1,16 +1,25 @@
 use log::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use std::time::{Duration, Instant};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
 use tokio::time::{interval, timeout};
 
+use crate::chat::BROADCAST_CHANNEL_ID;
+use crate::discovery::{self, PeerMap};
 use crate::error::{AppError, AppResult};
-use crate::models::{Message, User};
+use crate::groups::GroupStore;
+use crate::identity;
+use crate::models::{Group, Message, MessageDeletion, MessageEdit, MessageStatus, Reaction, TypingIndicator, User};
+use crate::network;
+use crate::protocol::{self, MembershipChange, WireFrame, PROTOCOL_VERSION};
+use crate::secure_channel::{self, ChannelKeys, PeerIdentity, SecureStream};
+use crate::storage::MessageStore;
+use crate::trust::TrustStore;
 
 const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
@@ -18,19 +27,30 @@ const CONNECTION_IDLE_TIMEOUT: Duration = Duration::from_secs(300); // 5 minutes
 
 #[derive(Debug, Clone)]
 pub struct PeerConnection {
-    pub stream: Arc<Mutex<TcpStream>>,
+    pub writer: Arc<Mutex<WriteHalf<SecureStream>>>,
     pub peer_id: String,
     pub peer_addr: SocketAddr,
+    /// Our end of the connection, which goes stale when that address disappears
+    pub local_addr: SocketAddr,
+    pub protocol_version: u16,
     pub last_activity: Arc<Mutex<Instant>>,
     pub is_active: Arc<Mutex<bool>>,
 }
 
 impl PeerConnection {
-    pub fn new(stream: TcpStream, peer_id: String, peer_addr: SocketAddr) -> Self {
+    pub fn new(
+        writer: WriteHalf<SecureStream>,
+        peer_id: String,
+        peer_addr: SocketAddr,
+        local_addr: SocketAddr,
+        protocol_version: u16,
+    ) -> Self {
         Self {
-            stream: Arc::new(Mutex::new(stream)),
+            writer: Arc::new(Mutex::new(writer)),
             peer_id,
             peer_addr,
+            local_addr,
+            protocol_version,
             last_activity: Arc::new(Mutex::new(Instant::now())),
             is_active: Arc::new(Mutex::new(true)),
         }
@@ -61,19 +81,65 @@ pub struct ConnectionManager {
     connections: Arc<Mutex<HashMap<String, PeerConnection>>>,
     local_user: User,
     heartbeat_tx: Option<mpsc::Sender<()>>,
-    message_storage: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<Message>>>>,
+    message_storage: Arc<MessageStore>,
+    /// Groups the local user belongs to, used to accept group messages
+    groups: Arc<GroupStore>,
+    channel_keys: Arc<ChannelKeys>,
+    /// Discovered peers, used to authenticate message senders
+    peers: PeerMap,
+    /// Pinned peer keys, checked before a peer that introduced itself is remembered
+    trust_store: Arc<TrustStore>,
+    /// Told the ID of every peer that answers a heartbeat
+    peer_online_tx: Option<mpsc::UnboundedSender<String>>,
 }
 
 impl ConnectionManager {
-    pub fn new(local_user: User, message_storage: std::sync::Arc<std::sync::Mutex<std::collections::HashMap<String, Vec<Message>>>>) -> Self {
+    pub fn new(
+        local_user: User,
+        message_storage: Arc<MessageStore>,
+        groups: Arc<GroupStore>,
+        channel_keys: Arc<ChannelKeys>,
+        peers: PeerMap,
+        trust_store: Arc<TrustStore>,
+    ) -> Self {
         Self {
             connections: Arc::new(Mutex::new(HashMap::new())),
             local_user,
             heartbeat_tx: None,
             message_storage,
+            groups,
+            channel_keys,
+            peers,
+            trust_store,
+            peer_online_tx: None,
         }
     }
 
+    /// Updates the profile sent to peers in our hello, e.g. after the local addresses changed
+    pub fn set_local_user(&mut self, local_user: User) {
+        self.local_user = local_user;
+    }
+
+    /// Handle sharing this manager's connections, to send without holding the app state
+    pub fn handle(&self) -> Self {
+        Self {
+            connections: Arc::clone(&self.connections),
+            local_user: self.local_user.clone(),
+            heartbeat_tx: None,
+            message_storage: Arc::clone(&self.message_storage),
+            groups: Arc::clone(&self.groups),
+            channel_keys: Arc::clone(&self.channel_keys),
+            peers: Arc::clone(&self.peers),
+            trust_store: Arc::clone(&self.trust_store),
+            peer_online_tx: self.peer_online_tx.clone(),
+        }
+    }
+
+    /// Sets the channel notified whenever a peer answers a heartbeat
+    pub fn set_peer_online_notifier(&mut self, tx: mpsc::UnboundedSender<String>) {
+        self.peer_online_tx = Some(tx);
+    }
+
     pub fn start_heartbeat_service(&mut self) {
         let connections = Arc::clone(&self.connections);
         let (tx, mut rx) = mpsc::channel::<()>(1);
@@ -130,20 +196,14 @@ impl ConnectionManager {
     }
 
     async fn send_heartbeat(connection: &PeerConnection) -> AppResult<()> {
-        let heartbeat_msg = serde_json::json!({
-            "type": "heartbeat",
-            "timestamp": chrono::Utc::now().timestamp()
-        });
+        let heartbeat = WireFrame::Heartbeat {
+            timestamp: chrono::Utc::now().timestamp(),
+        };
 
-        let heartbeat_data = heartbeat_msg.to_string();
-        
         {
-            let mut stream = connection.stream.lock().await;
-            match timeout(Duration::from_secs(5), stream.write_all(heartbeat_data.as_bytes())).await {
-                Ok(Ok(_)) => {
-                    connection.update_activity().await;
-                    Ok(())
-                }
+            let mut writer = connection.writer.lock().await;
+            match timeout(Duration::from_secs(5), protocol::write_frame(&mut *writer, &heartbeat)).await {
+                Ok(Ok(_)) => Ok(()),
                 Ok(Err(e)) => {
                     connection.set_inactive().await;
                     Err(AppError::NetworkError(format!("Heartbeat write failed: {}", e)))
@@ -156,13 +216,16 @@ impl ConnectionManager {
         }
     }
 
-    pub async fn get_or_create_connection(&self, peer_id: &str, peer_ip: &str, port: u16) -> AppResult<PeerConnection> {
+    pub async fn get_or_create_connection(&self, peer_id: &str, peer_addrs: &[SocketAddr]) -> AppResult<PeerConnection> {
         // Check if we already have an active connection
         {
             let connections = self.connections.lock().await;
             if let Some(connection) = connections.get(peer_id) {
                 if connection.is_active().await && !connection.is_idle().await {
-                    debug!("Reusing existing connection to peer: {}", peer_id);
+                    debug!(
+                        "Reusing existing connection to peer: {} (protocol v{})",
+                        peer_id, connection.protocol_version
+                    );
                     return Ok(connection.clone());
                 } else {
                     debug!("Existing connection to {} is inactive or idle", peer_id);
@@ -170,70 +233,223 @@ impl ConnectionManager {
             }
         }
 
-        // Create new connection
-        info!("Creating new connection to peer {} at {}:{}", peer_id, peer_ip, port);
-        let addr: SocketAddr = format!("{}:{}", peer_ip, port).parse()
-            .map_err(|e| AppError::NetworkError(format!("Invalid address: {}", e)))?;
+        // Create new connection, racing the peer's addresses
+        info!("Creating new connection to peer {} at {:?}", peer_id, peer_addrs);
+        let (stream, addr) = match network::connect_any(peer_addrs, CONNECTION_TIMEOUT).await {
+            Ok(connected) => connected,
+            Err(e) => {
+                error!("Failed to connect to {}: {}", peer_id, e);
+                return Err(e);
+            }
+        };
 
-        let stream = match timeout(CONNECTION_TIMEOUT, TcpStream::connect(&addr)).await {
-            Ok(Ok(stream)) => stream,
+        let local_addr = stream.local_addr()?;
+
+        // Nothing is exchanged until the peer has proven it holds the expected identity key
+        let (mut stream, peer) = match secure_channel::connect(stream, &self.channel_keys).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                error!("Secure handshake with {} failed: {}", addr, e);
+                return Err(e);
+            }
+        };
+        if peer.user_id != peer_id {
+            error!("Peer at {} identified as {}, expected {}", addr, peer.user_id, peer_id);
+            return Err(AppError::AuthenticationError(format!(
+                "Peer at {} is not {}",
+                addr, peer_id
+            )));
+        }
+
+        let protocol_version = match timeout(CONNECTION_TIMEOUT, self.perform_handshake(&mut stream)).await {
+            Ok(Ok(version)) => version,
             Ok(Err(e)) => {
-                error!("Failed to connect to {}:{}: {}", peer_ip, port, e);
-                return Err(AppError::NetworkError(format!("Connection failed: {}", e)));
+                error!("Protocol handshake with {} failed: {}", addr, e);
+                return Err(e);
             }
             Err(_) => {
-                error!("Connection timeout to {}:{}", peer_ip, port);
-                return Err(AppError::NetworkError("Connection timeout".to_string()));
+                error!("Protocol handshake timeout with {}", addr);
+                return Err(AppError::ProtocolError("Handshake timeout".to_string()));
             }
         };
 
-        let connection = PeerConnection::new(stream, peer_id.to_string(), addr);
-        
+        let (reader, writer) = tokio::io::split(stream);
+        let connection = PeerConnection::new(writer, peer_id.to_string(), addr, local_addr, protocol_version);
+        tokio::spawn(Self::run_outbound_reader(
+            connection.clone(),
+            reader,
+            Arc::clone(&self.message_storage),
+            self.local_user.id.clone(),
+            self.peer_online_tx.clone(),
+        ));
+
         // Store the connection
         {
             let mut connections = self.connections.lock().await;
             connections.insert(peer_id.to_string(), connection.clone());
         }
 
-        info!("Successfully created connection to peer: {}", peer_id);
+        info!(
+            "Successfully created connection to peer: {} (protocol v{})",
+            peer_id, protocol_version
+        );
         Ok(connection)
     }
 
-    pub async fn send_message(&self, peer_id: &str, message: &Message, peer_ip: &str, port: u16) -> AppResult<()> {
-        let connection = self.get_or_create_connection(peer_id, peer_ip, port).await?;
-        
-        let message_json = serde_json::to_string(message)
-            .map_err(AppError::SerializationError)?;
-
-        // Add message type and length prefix for better parsing
-        let full_message = serde_json::json!({
-            "type": "message",
-            "data": message,
-            "length": message_json.len()
-        });
+    /// Sends our hello frame and waits for the peer's acknowledgement
+    async fn perform_handshake(&self, stream: &mut SecureStream) -> AppResult<u16> {
+        let (version, _) = handshake(stream, &self.local_user).await?;
+        Ok(version)
+    }
+
+    /// Reads frames the peer sends back on an outbound connection
+    async fn run_outbound_reader(
+        connection: PeerConnection,
+        mut reader: ReadHalf<SecureStream>,
+        message_storage: Arc<MessageStore>,
+        local_user_id: String,
+        peer_online_tx: Option<mpsc::UnboundedSender<String>>,
+    ) {
+        loop {
+            match protocol::read_frame(&mut reader).await {
+                Ok(Some(WireFrame::HeartbeatResponse { .. })) => {
+                    debug!("Received heartbeat response from: {}", connection.peer_addr);
+                    connection.update_activity().await;
+                    if let Some(tx) = &peer_online_tx {
+                        let _ = tx.send(connection.peer_id.clone());
+                    }
+                }
+                Ok(Some(WireFrame::Delivered { message_ids })) => {
+                    connection.update_activity().await;
+                    apply_receipt(&message_storage, &local_user_id, &connection.peer_id, &message_ids, MessageStatus::Delivered);
+                }
+                Ok(Some(WireFrame::Read { message_ids })) => {
+                    connection.update_activity().await;
+                    apply_receipt(&message_storage, &local_user_id, &connection.peer_id, &message_ids, MessageStatus::Read);
+                }
+                Ok(Some(frame)) => {
+                    debug!(
+                        "Ignoring {} frame on outbound connection to {}",
+                        frame.kind(),
+                        connection.peer_id
+                    );
+                }
+                Ok(None) => {
+                    debug!("Outbound connection closed by peer: {}", connection.peer_id);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Read error on connection to {}: {}", connection.peer_id, e);
+                    break;
+                }
+            }
+        }
+
+        connection.set_inactive().await;
+    }
+
+    pub async fn send_message(&self, peer_id: &str, message: &Message, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let frame = WireFrame::Message(message.clone());
+        self.send_frame(peer_id, &frame, peer_addrs).await?;
+        info!("Message sent successfully to peer: {}", peer_id);
+        Ok(())
+    }
+
+    /// Tells the sender of `message_ids` that we have read them
+    pub async fn send_read_receipt(&self, peer_id: &str, message_ids: Vec<String>, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let count = message_ids.len();
+        let frame = WireFrame::Read { message_ids };
+        self.send_frame(peer_id, &frame, peer_addrs).await?;
+        debug!("Sent read receipt for {} message(s) to peer: {}", count, peer_id);
+        Ok(())
+    }
+
+    /// Sends the edit of one of our messages to a peer that received it
+    pub async fn send_message_edit(&self, peer_id: &str, edit: &MessageEdit, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let frame = WireFrame::Edit(edit.clone());
+        self.send_frame(peer_id, &frame, peer_addrs).await?;
+        debug!("Sent edit of message {} to peer: {}", edit.message_id, peer_id);
+        Ok(())
+    }
+
+    /// Sends the deletion of one of our messages to a peer that received it
+    pub async fn send_message_deletion(&self, peer_id: &str, deletion: &MessageDeletion, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let frame = WireFrame::Delete(deletion.clone());
+        self.send_frame(peer_id, &frame, peer_addrs).await?;
+        debug!("Sent deletion of message {} to peer: {}", deletion.message_id, peer_id);
+        Ok(())
+    }
+
+    /// Sends a reaction of ours to a peer sharing the conversation of the message
+    pub async fn send_reaction(&self, peer_id: &str, reaction: &Reaction, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let frame = WireFrame::Reaction(reaction.clone());
+        self.send_frame(peer_id, &frame, peer_addrs).await?;
+        debug!("Sent reaction to message {} to peer: {}", reaction.message_id, peer_id);
+        Ok(())
+    }
+
+    /// Tells a peer whether we are typing to it, or in a group it is a member of
+    pub async fn send_typing(&self, peer_id: &str, group_id: Option<String>, typing: bool, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let frame = WireFrame::Typing { group_id, typing };
+        self.send_frame(peer_id, &frame, peer_addrs).await
+    }
+
+    /// Announces a membership change of `group` to one of its members
+    pub async fn send_group_update(
+        &self,
+        peer_id: &str,
+        group: &Group,
+        change: MembershipChange,
+        peer_addrs: &[SocketAddr],
+    ) -> AppResult<()> {
+        let frame = WireFrame::GroupUpdate {
+            group: group.clone(),
+            change,
+        };
+        self.send_frame(peer_id, &frame, peer_addrs).await?;
+        debug!("Sent {:?} update for group {} to peer: {}", change, group.id, peer_id);
+        Ok(())
+    }
+
+    /// Writes a frame on the connection to a peer, dropping the connection if that fails
+    async fn send_frame(&self, peer_id: &str, frame: &WireFrame, peer_addrs: &[SocketAddr]) -> AppResult<()> {
+        let connection = self.get_or_create_connection(peer_id, peer_addrs).await?;
+
+        // Older peers would not understand the frame
+        if connection.protocol_version < frame.min_version() {
+            // Dropping a message would lose it, let the caller report or retry it
+            if let WireFrame::Message(_) = frame {
+                return Err(AppError::ProtocolError(format!(
+                    "Peer {} speaks protocol v{}, this message needs v{}",
+                    peer_id,
+                    connection.protocol_version,
+                    frame.min_version()
+                )));
+            }
+            debug!(
+                "Not sending {} to {}, it speaks protocol v{}",
+                frame.kind(),
+                peer_id,
+                connection.protocol_version
+            );
+            return Ok(());
+        }
 
-        let full_message_str = full_message.to_string();
-        
         {
-            let mut stream = connection.stream.lock().await;
-            match timeout(Duration::from_secs(10), async {
-                stream.write_all(full_message_str.as_bytes()).await?;
-                stream.flush().await?;
-                Ok::<(), std::io::Error>(())
-            }).await {
+            let mut writer = connection.writer.lock().await;
+            match timeout(Duration::from_secs(10), protocol::write_frame(&mut *writer, frame)).await {
                 Ok(Ok(_)) => {
                     connection.update_activity().await;
-                    info!("Message sent successfully to peer: {}", peer_id);
                     Ok(())
                 }
                 Ok(Err(e)) => {
-                    error!("Failed to send message to {}: {}", peer_id, e);
+                    error!("Failed to send {} to {}: {}", frame.kind(), peer_id, e);
                     connection.set_inactive().await;
                     self.remove_connection(peer_id).await;
                     Err(AppError::NetworkError(format!("Message send failed: {}", e)))
                 }
                 Err(_) => {
-                    error!("Message send timeout to peer: {}", peer_id);
+                    error!("Send timeout for {} to peer: {}", frame.kind(), peer_id);
                     connection.set_inactive().await;
                     self.remove_connection(peer_id).await;
                     Err(AppError::NetworkError("Message send timeout".to_string()))
@@ -249,6 +465,30 @@ impl ConnectionManager {
         }
     }
 
+    /// Drops connections made from an address that is no longer one of `local_addresses`
+    pub async fn drop_stale_connections(&self, local_addresses: &[String]) -> usize {
+        let local_ips: Vec<IpAddr> = local_addresses
+            .iter()
+            .filter_map(|address| network::parse_address(address, 0))
+            .map(|addr| addr.ip())
+            .collect();
+
+        let mut connections = self.connections.lock().await;
+        let stale: Vec<String> = connections
+            .iter()
+            .filter(|(_, connection)| !local_ips.contains(&connection.local_addr.ip().to_canonical()))
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect();
+        for peer_id in &stale {
+            if let Some(connection) = connections.remove(peer_id) {
+                info!("Dropped connection to {} from stale address {}", peer_id, connection.local_addr);
+                connection.set_inactive().await;
+            }
+        }
+        stale.len()
+    }
+
+    #[allow(dead_code)]
     pub async fn get_active_connections(&self) -> Vec<String> {
         let connections = self.connections.lock().await;
         let mut active_connections = Vec::new();
@@ -260,6 +500,7 @@ impl ConnectionManager {
         active_connections
     }
 
+    #[allow(dead_code)]
     pub async fn close_all_connections(&self) {
         let mut connections = self.connections.lock().await;
         let peer_count = connections.len();
@@ -275,64 +516,139 @@ impl ConnectionManager {
         }
     }
 
-    pub async fn handle_incoming_connection(&self, mut stream: TcpStream, peer_addr: SocketAddr) -> AppResult<()> {
+    pub async fn handle_incoming_connection(&self, stream: TcpStream, peer_addr: SocketAddr) -> AppResult<()> {
         info!("Handling incoming connection from: {}", peer_addr);
-        
-        loop {
-            let mut buffer = vec![0; 4096];
-            
-            match timeout(Duration::from_secs(30), stream.read(&mut buffer)).await {
-                Ok(Ok(0)) => {
-                    debug!("Connection closed by peer: {}", peer_addr);
-                    break;
+
+        // Frames are only read once the encrypted channel is up and the peer is authenticated
+        let (mut stream, peer) = match secure_channel::accept(stream, &self.channel_keys).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                warn!("Secure handshake with {} failed: {}", peer_addr, e);
+                return Err(e);
+            }
+        };
+
+        // The first frame must be a hello carrying the peer's protocol version
+        let protocol_version = match timeout(CONNECTION_TIMEOUT, protocol::read_frame(&mut stream)).await {
+            Ok(Ok(Some(WireFrame::Hello { version, user_id, user }))) => {
+                if user_id != peer.user_id {
+                    warn!(
+                        "Peer at {} claimed to be {} but authenticated as {}",
+                        peer_addr, user_id, peer.user_id
+                    );
+                    return Err(AppError::AuthenticationError(format!(
+                        "Hello from {} does not match authenticated identity",
+                        user_id
+                    )));
                 }
-                Ok(Ok(n)) => {
-                    buffer.truncate(n);
-                    
-                    // Try to parse the message
-                    match serde_json::from_slice::<serde_json::Value>(&buffer) {
-                        Ok(json_value) => {
-                            if let Some(msg_type) = json_value.get("type").and_then(|v| v.as_str()) {
-                                match msg_type {
-                                    "heartbeat" => {
-                                        debug!("Received heartbeat from: {}", peer_addr);
-                                        // Send heartbeat response
-                                        let response = serde_json::json!({
-                                            "type": "heartbeat_response",
-                                            "timestamp": chrono::Utc::now().timestamp()
-                                        });
-                                        let response_str = response.to_string();
-                                        if let Err(e) = stream.write_all(response_str.as_bytes()).await {
-                                            warn!("Failed to send heartbeat response: {}", e);
-                                            break;
-                                        }
-                                    }
-                                    "message" => {
-                                        if let Some(message_data) = json_value.get("data") {
-                                            match serde_json::from_value::<Message>(message_data.clone()) {
-                                                Ok(message) => {
-                                                    info!("Received message from {}: {}", message.sender_id, message.content);
-                                                    self.handle_received_message(message).await?;
-                                                }
-                                                Err(e) => {
-                                                    warn!("Failed to parse message data: {}", e);
-                                                }
-                                            }
-                                        }
-                                    }
-                                    "heartbeat_response" => {
-                                        debug!("Received heartbeat response from: {}", peer_addr);
-                                    }
-                                    _ => {
-                                        debug!("Unknown message type: {}", msg_type);
-                                    }
-                                }
-                            }
+
+                if let Some(user) = user {
+                    self.remember_peer(user, &peer, peer_addr);
+                }
+
+                let negotiated = protocol::negotiate_version(version);
+                let ack = WireFrame::HelloAck {
+                    version: version.min(PROTOCOL_VERSION),
+                    user: Some(self.local_user.clone()),
+                };
+                protocol::write_frame(&mut stream, &ack).await?;
+                let negotiated = negotiated?;
+                info!(
+                    "Peer {} at {} connected with protocol v{}",
+                    user_id, peer_addr, negotiated
+                );
+                negotiated
+            }
+            Ok(Ok(Some(frame))) => {
+                warn!("Expected hello from {}, got {}", peer_addr, frame.kind());
+                return Err(AppError::ProtocolError(format!(
+                    "Expected hello, got {}",
+                    frame.kind()
+                )));
+            }
+            Ok(Ok(None)) => {
+                debug!("Connection closed before handshake: {}", peer_addr);
+                return Ok(());
+            }
+            Ok(Err(e)) => {
+                warn!("Handshake with {} failed: {}", peer_addr, e);
+                return Err(e);
+            }
+            Err(_) => {
+                debug!("Handshake timeout from: {}", peer_addr);
+                return Ok(());
+            }
+        };
+        debug!("Using protocol v{} with {}", protocol_version, peer_addr);
+
+        loop {
+            match timeout(CONNECTION_IDLE_TIMEOUT, protocol::read_frame(&mut stream)).await {
+                Ok(Ok(Some(frame))) => match frame {
+                    WireFrame::Heartbeat { .. } => {
+                        debug!("Received heartbeat from: {}", peer_addr);
+                        let response = WireFrame::HeartbeatResponse {
+                            timestamp: chrono::Utc::now().timestamp(),
+                        };
+                        if let Err(e) = protocol::write_frame(&mut stream, &response).await {
+                            warn!("Failed to send heartbeat response: {}", e);
+                            break;
                         }
-                        Err(e) => {
-                            warn!("Failed to parse JSON from {}: {}", peer_addr, e);
+                    }
+                    WireFrame::Message(message) => {
+                        debug!("Received message {} from {}", message.id, message.sender_id);
+                        let message_id = message.id.clone();
+                        // Receipts are per recipient, so only direct messages get one
+                        let is_direct = message.group_id.is_none() && message.recipient_id != BROADCAST_CHANNEL_ID;
+                        // Without a receipt the sender retries, no need to drop the connection
+                        let stored = match self.handle_received_message(message, peer_addr).await {
+                            Ok(stored) => stored,
+                            Err(e) => {
+                                error!("Failed to store message {} from {}: {}", message_id, peer_addr, e);
+                                false
+                            }
+                        };
+                        let receipt = WireFrame::Delivered {
+                            message_ids: vec![message_id],
+                        };
+                        if stored && is_direct && protocol_version >= receipt.min_version() {
+                            // Acknowledge so the sender can show the message as delivered
+                            if let Err(e) = protocol::write_frame(&mut stream, &receipt).await {
+                                warn!("Failed to send delivery receipt: {}", e);
+                                break;
+                            }
                         }
                     }
+                    WireFrame::Delivered { message_ids } => {
+                        apply_receipt(&self.message_storage, &self.local_user.id, &peer.user_id, &message_ids, MessageStatus::Delivered);
+                    }
+                    WireFrame::Read { message_ids } => {
+                        apply_receipt(&self.message_storage, &self.local_user.id, &peer.user_id, &message_ids, MessageStatus::Read);
+                    }
+                    WireFrame::Edit(edit) => {
+                        self.handle_message_edit(&peer, edit);
+                    }
+                    WireFrame::Delete(deletion) => {
+                        self.handle_message_deletion(&peer, deletion);
+                    }
+                    WireFrame::Typing { group_id, typing } => {
+                        self.handle_typing(&peer.user_id, group_id, typing);
+                    }
+                    WireFrame::Reaction(reaction) => {
+                        self.handle_reaction(&peer.user_id, reaction);
+                    }
+                    WireFrame::GroupUpdate { group, change } => {
+                        self.handle_group_update(&peer.user_id, group, change);
+                    }
+                    WireFrame::HeartbeatResponse { .. } => {
+                        debug!("Received heartbeat response from: {}", peer_addr);
+                    }
+                    other => {
+                        debug!("Ignoring {} frame from: {}", other.kind(), peer_addr);
+                    }
+                },
+                Ok(Ok(None)) => {
+                    debug!("Connection closed by peer: {}", peer_addr);
+                    break;
                 }
                 Ok(Err(e)) => {
                     error!("Read error from {}: {}", peer_addr, e);
@@ -349,28 +665,663 @@ impl ConnectionManager {
         Ok(())
     }
 
-    async fn handle_received_message(&self, message: Message) -> AppResult<()> {
-        // Verify the message is intended for us
-        if message.recipient_id != self.local_user.id {
-            warn!("Received message not intended for us (recipient: {}, our ID: {})", 
-                  message.recipient_id, self.local_user.id);
-            return Ok(());
-        }
+    /// Stores and announces a received message, returning whether it was accepted
+    async fn handle_received_message(&self, mut message: Message, peer_addr: SocketAddr) -> AppResult<bool> {
+        // Direct messages are kept by sender, group messages by group, broadcasts together
+        let bucket = match &message.group_id {
+            Some(group_id) => {
+                // Both ends must be members of the group the message is addressed to
+                let is_member = message.recipient_id == *group_id
+                    && self.groups.get(group_id).is_some_and(|group| {
+                        group.has_member(&self.local_user.id) && group.has_member(&message.sender_id)
+                    });
+                if !is_member {
+                    warn!(
+                        "Received message for group {} we don't share with {}",
+                        group_id, message.sender_id
+                    );
+                    return Ok(false);
+                }
+                group_id.clone()
+            }
+            // Announcements to everyone on the LAN share one conversation
+            None if message.recipient_id == BROADCAST_CHANNEL_ID => BROADCAST_CHANNEL_ID.to_string(),
+            None => {
+                // Verify the message is intended for us
+                if message.recipient_id != self.local_user.id {
+                    warn!("Received message not intended for us (recipient: {}, our ID: {})", 
+                          message.recipient_id, self.local_user.id);
+                    return Ok(false);
+                }
+                message.sender_id.clone()
+            }
+        };
 
-        // Store the received message
-        {
-            let mut storage = self.message_storage.lock().unwrap();
-            let peer_messages = storage.entry(message.sender_id.clone()).or_default();
-            peer_messages.push(message.clone());
-            info!(
-                "Stored received message from {}, total messages from peer: {}",
-                message.sender_id,
-                peer_messages.len()
+        // Drop messages whose sender can't be authenticated
+        if let Err(e) = self.authenticate_sender(&message, peer_addr) {
+            warn!(
+                "Dropping forged message {} claiming to be from {} (received from {}): {}",
+                message.id, message.sender_id, peer_addr, e
             );
+            return Ok(false);
+        }
+
+        // Replies must point at a message of the same conversation
+        if let Err(e) = self.check_reply_target(&message) {
+            warn!("Dropping message {} from {}: {}", message.id, message.sender_id, e);
+            return Ok(false);
         }
+
+        // A retried message may already have arrived, acknowledge it again without storing
+        if self.message_storage.contains(&bucket, &message.id) {
+            debug!("Ignoring duplicate message {} from {}", message.id, message.sender_id);
+            return Ok(true);
+        }
+
+        // Only the signed fields come from the sender. The status is its view of
+        // the message, and edits, deletions and reactions arrive in their own frames.
+        message.status = MessageStatus::Delivered;
+        message.read = false;
+        message.edit_history.clear();
+        message.edited_at = None;
+        message.deleted = false;
+        message.reactions.clear();
+
+        // Store the received message
+        let bucket_count = self.message_storage.insert(&bucket, &message)?;
+        info!(
+            "Stored received message from {}, total messages in conversation {}: {}",
+            message.sender_id, bucket, bucket_count
+        );
         
         // Emit the message received event
         crate::emit_event("message_received", message);
+        Ok(true)
+    }
+
+    /// Applies an edit from the author of a message and emits `message_edited`
+    fn handle_message_edit(&self, peer: &PeerIdentity, edit: MessageEdit) {
+        // Only the author may edit, and only over its own authenticated channel
+        let authenticated = if edit.sender_id == peer.user_id {
+            identity::verify_edit(&edit, &peer.public_key)
+        } else {
+            Err(AppError::AuthenticationError("Sender is not the channel peer".to_string()))
+        };
+        if let Err(e) = authenticated {
+            warn!("Dropping edit of message {} from {}: {}", edit.message_id, peer.user_id, e);
+            return;
+        }
+
+        match self.message_storage.apply_edit(&edit) {
+            Ok(Some(message)) => {
+                info!("Message {} edited by {}", message.id, message.sender_id);
+                crate::emit_event("message_edited", message);
+            }
+            Ok(None) => debug!("Ignoring stale or unknown edit of message {}", edit.message_id),
+            Err(e) => warn!("Failed to save edit of message {}: {}", edit.message_id, e),
+        }
+    }
+
+    /// Applies a deletion from the author of a message and emits `message_deleted`
+    fn handle_message_deletion(&self, peer: &PeerIdentity, deletion: MessageDeletion) {
+        // Only the author may delete, and only over its own authenticated channel
+        let authenticated = if deletion.sender_id == peer.user_id {
+            identity::verify_deletion(&deletion, &peer.public_key)
+        } else {
+            Err(AppError::AuthenticationError("Sender is not the channel peer".to_string()))
+        };
+        if let Err(e) = authenticated {
+            warn!("Dropping deletion of message {} from {}: {}", deletion.message_id, peer.user_id, e);
+            return;
+        }
+
+        match self.message_storage.apply_deletion(&deletion) {
+            Ok(Some(message)) => {
+                info!("Message {} deleted by {}", message.id, message.sender_id);
+                crate::emit_event("message_deleted", message);
+            }
+            Ok(None) => debug!("Ignoring deletion of unknown or deleted message {}", deletion.message_id),
+            Err(e) => warn!("Failed to save deletion of message {}: {}", deletion.message_id, e),
+        }
+    }
+
+    /// Relays a peer's typing indicator to the frontend as `typing_updated`
+    fn handle_typing(&self, sender_id: &str, group_id: Option<String>, typing: bool) {
+        if let Some(group_id) = &group_id {
+            let shared = self.groups.get(group_id).is_some_and(|group| {
+                group.has_member(sender_id) && group.has_member(&self.local_user.id)
+            });
+            if !shared {
+                debug!("Ignoring typing indicator from {} for group {}", sender_id, group_id);
+                return;
+            }
+        }
+
+        crate::emit_event(
+            "typing_updated",
+            TypingIndicator {
+                peer_id: sender_id.to_string(),
+                group_id,
+                typing,
+            },
+        );
+    }
+
+    /// Merges a peer's reaction into the message and emits `message_reactions_updated`
+    fn handle_reaction(&self, sender_id: &str, reaction: Reaction) {
+        // Peers may only react on their own behalf
+        if reaction.user_id != sender_id {
+            warn!("Dropping reaction from {} on behalf of {}", sender_id, reaction.user_id);
+            return;
+        }
+
+        let Some((_, message)) = self.message_storage.find(&reaction.message_id) else {
+            debug!("Ignoring reaction to unknown message {}", reaction.message_id);
+            return;
+        };
+
+        // Only peers that share the message's conversation may react to it
+        let in_conversation = match &message.group_id {
+            Some(group_id) => self
+                .groups
+                .get(group_id)
+                .is_some_and(|group| group.has_member(sender_id)),
+            None => {
+                message.recipient_id == BROADCAST_CHANNEL_ID
+                    || message.conversation_id(&self.local_user.id) == sender_id
+            }
+        };
+        if !in_conversation {
+            warn!("Dropping reaction from {} to message {} outside their conversations", sender_id, message.id);
+            return;
+        }
+
+        match self.message_storage.apply_reaction(&reaction) {
+            Ok(Some(message)) => crate::emit_event("message_reactions_updated", message),
+            Ok(None) => debug!("Reaction to message {} already applied", reaction.message_id),
+            Err(e) => warn!("Failed to save reaction to message {}: {}", reaction.message_id, e),
+        }
+    }
+
+    /// Applies a membership change announced by `sender_id` and emits `group_updated`
+    fn handle_group_update(&self, sender_id: &str, group: Group, change: MembershipChange) {
+        let group_id = group.id.clone();
+        let result = match change {
+            MembershipChange::Created => {
+                // Only the creator may announce a group, and only to its members
+                if group.created_by != sender_id
+                    || group.id == BROADCAST_CHANNEL_ID
+                    || !group.has_member(sender_id)
+                    || !group.has_member(&self.local_user.id)
+                {
+                    warn!("Ignoring invalid creation of group {} from {}", group.id, sender_id);
+                    return;
+                }
+                info!("Added to group {} ({}) by {}", group.name, group.id, sender_id);
+                self.groups
+                    .insert(&group)
+                    .map(|inserted| inserted.then_some(group))
+            }
+            MembershipChange::Joined => {
+                // Only users the creator added may join, and only on their own behalf
+                match self.groups.get(&group.id) {
+                    Some(known) if known.is_invited(sender_id) => {}
+                    Some(_) => {
+                        warn!("Ignoring join of group {} from {} who was not invited", group.id, sender_id);
+                        return;
+                    }
+                    None => {
+                        debug!("Ignoring join of unknown group {} from {}", group.id, sender_id);
+                        return;
+                    }
+                }
+                info!("Peer {} joined group {}", sender_id, group.id);
+                self.groups.add_member(&group.id, sender_id)
+            }
+            MembershipChange::Left => {
+                if self.groups.get(&group.id).is_none() {
+                    debug!("Ignoring leave of unknown group {} from {}", group.id, sender_id);
+                    return;
+                }
+                info!("Peer {} left group {}", sender_id, group.id);
+                self.groups.remove_member(&group.id, sender_id)
+            }
+        };
+
+        match result {
+            Ok(Some(group)) => crate::emit_event("group_updated", group),
+            Ok(None) => {}
+            Err(e) => warn!("Failed to save group {}: {}", group_id, e),
+        }
+    }
+
+    /// Checks that a reply's parent is known, in the same conversation and in the claimed thread
+    fn check_reply_target(&self, message: &Message) -> AppResult<()> {
+        let Some(reply_to) = &message.reply_to else {
+            if message.thread_root.is_some() {
+                return Err(AppError::ProtocolError("Thread root set without a reply".to_string()));
+            }
+            return Ok(());
+        };
+
+        let (_, parent) = self
+            .message_storage
+            .find(reply_to)
+            .ok_or_else(|| AppError::ProtocolError(format!("Replied-to message {} is unknown", reply_to)))?;
+
+        let local_id = &self.local_user.id;
+        if parent.conversation_id(local_id) != message.conversation_id(local_id) {
+            return Err(AppError::ProtocolError(format!(
+                "Replied-to message {} is in another conversation",
+                reply_to
+            )));
+        }
+
+        let expected_root = parent.thread_root.as_ref().unwrap_or(&parent.id);
+        if message.thread_root.as_ref() != Some(expected_root) {
+            return Err(AppError::ProtocolError(format!(
+                "Reply is not in the thread of message {}",
+                reply_to
+            )));
+        }
         Ok(())
     }
-}
\ No newline at end of file
+
+    /// Adds a peer that introduced itself in its hello to the peer map, e.g. one that added us by address.
+    ///
+    /// Peers that are already known, usually through mDNS, are only marked as seen at this address.
+    fn remember_peer(&self, mut user: User, peer: &PeerIdentity, peer_addr: SocketAddr) {
+        if user.id != peer.user_id {
+            warn!("Ignoring profile of {} sent by {}", user.id, peer.user_id);
+            return;
+        }
+
+        // The key proven by the connection must match the pinned one, as for discovered peers
+        user.public_key = Some(peer.public_key.clone());
+        if !discovery::is_trusted_peer(&self.trust_store, &user) {
+            return;
+        }
+
+        {
+            let mut peers = self.peers.lock().unwrap();
+            if let Some(known) = peers.get_mut(&user.id) {
+                // The connection proves the peer can also be reached at this address
+                if !known.has_address(peer_addr.ip()) {
+                    known.addresses.push(network::format_address(peer_addr));
+                }
+                known.last_seen = chrono::Utc::now();
+                return;
+            }
+
+            // Only the address and key proven by the connection are trusted first
+            user.ip = network::format_address(peer_addr);
+            user.addresses = network::observed_addresses(peer_addr, &user.addresses);
+            user.last_seen = chrono::Utc::now();
+            peers.insert(user.id.clone(), user.clone());
+        }
+
+        info!("Learned peer {} at {} from its hello", user.name, user.ip);
+        crate::emit_event("peer_discovered", user);
+    }
+
+    /// Checks a message's signature against the discovered sender's key and address
+    fn authenticate_sender(&self, message: &Message, peer_addr: SocketAddr) -> AppResult<()> {
+        let sender = self
+            .peers
+            .lock()
+            .unwrap()
+            .get(&message.sender_id)
+            .cloned()
+            .ok_or_else(|| AppError::AuthenticationError("Sender has not been discovered".to_string()))?;
+
+        if !sender.has_address(peer_addr.ip()) {
+            return Err(AppError::AuthenticationError(format!(
+                "Sender is known at {}, not {}",
+                sender.ip,
+                peer_addr.ip()
+            )));
+        }
+
+        let public_key = sender
+            .public_key
+            .as_deref()
+            .ok_or_else(|| AppError::AuthenticationError("Sender has no known public key".to_string()))?;
+        identity::verify_message(message, public_key)
+    }
+}
+
+/// Sends our hello frame and waits for the peer's acknowledgement.
+///
+/// Returns the negotiated protocol version and the peer's profile, if it sent one.
+async fn handshake(stream: &mut SecureStream, local_user: &User) -> AppResult<(u16, Option<User>)> {
+    let hello = WireFrame::Hello {
+        version: PROTOCOL_VERSION,
+        user_id: local_user.id.clone(),
+        user: Some(local_user.clone()),
+    };
+    protocol::write_frame(stream, &hello).await?;
+
+    match protocol::read_frame(stream).await? {
+        Some(WireFrame::HelloAck { version, user }) => {
+            if version > PROTOCOL_VERSION {
+                return Err(AppError::ProtocolError(format!(
+                    "Peer negotiated unknown protocol version {}",
+                    version
+                )));
+            }
+            Ok((protocol::negotiate_version(version)?, user))
+        }
+        Some(frame) => Err(AppError::ProtocolError(format!(
+            "Expected hello_ack, got {}",
+            frame.kind()
+        ))),
+        None => Err(AppError::ProtocolError(
+            "Connection closed during handshake".to_string(),
+        )),
+    }
+}
+
+/// Connects to a chat port without knowing who listens there and returns the peer's profile.
+///
+/// The ID and key come from the secure handshake, the rest from the peer's `hello_ack`.
+pub async fn probe_peer(host: &str, port: u16, local_user: &User, channel_keys: &ChannelKeys) -> AppResult<User> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| AppError::NetworkError(format!("Failed to resolve {}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(AppError::NetworkError(format!("No address found for {}", host)));
+    }
+
+    let (stream, addr) = network::connect_any(&addrs, CONNECTION_TIMEOUT).await?;
+    let (mut stream, peer) = secure_channel::connect(stream, channel_keys).await?;
+
+    let user = match timeout(CONNECTION_TIMEOUT, handshake(&mut stream, local_user)).await {
+        Ok(result) => result?.1,
+        Err(_) => return Err(AppError::ProtocolError("Handshake timeout".to_string())),
+    };
+    let mut user = user.ok_or_else(|| {
+        AppError::ProtocolError(format!("Peer at {} is too old to be added by address", addr))
+    })?;
+    if user.id != peer.user_id {
+        return Err(AppError::AuthenticationError(format!(
+            "Peer at {} introduced itself as {} but authenticated as {}",
+            addr, user.id, peer.user_id
+        )));
+    }
+
+    user.ip = network::format_address(addr);
+    user.addresses = network::observed_addresses(addr, &user.addresses);
+    user.port = Some(port);
+    user.public_key = Some(peer.public_key);
+    user.last_seen = chrono::Utc::now();
+    info!("Probed peer {} ({}) at {}", user.name, user.id, addr);
+    Ok(user)
+}
+
+/// Advances the status of messages we sent to `peer_id` and emits `message_status_updated`
+fn apply_receipt(
+    message_storage: &MessageStore,
+    local_user_id: &str,
+    peer_id: &str,
+    message_ids: &[String],
+    status: MessageStatus,
+) {
+    // Only the recipient of a direct message may acknowledge it; group and
+    // broadcast messages are kept in their own buckets and never match
+    let updated = message_storage.update(local_user_id, |message| {
+        if message.recipient_id == peer_id
+            && message_ids.contains(&message.id)
+            && message.status.can_advance_to(status)
+        {
+            message.status = status;
+            true
+        } else {
+            false
+        }
+    });
+
+    match updated {
+        Ok(messages) => {
+            debug!("Marked {} message(s) to {} as {:?}", messages.len(), peer_id, status);
+            for message in messages {
+                crate::emit_event("message_status_updated", message);
+            }
+        }
+        Err(e) => warn!("Failed to save {:?} receipt from {}: {}", status, peer_id, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::DeviceIdentity;
+    use crate::models::MessageVersion;
+    use chrono::Utc;
+
+    const PEER_ADDR: &str = "192.168.1.20:9000";
+
+    fn user(identity: &DeviceIdentity, ip: &str) -> User {
+        User {
+            id: identity.user_id(),
+            name: "Peer".to_string(),
+            ip: ip.to_string(),
+            addresses: vec![ip.to_string()],
+            last_seen: Utc::now(),
+            public_key: Some(identity.public_key_hex()),
+            presence: Default::default(),
+            status_text: None,
+            port: None,
+            file_port: None,
+        }
+    }
+
+    /// A manager for a fresh local identity that already discovered `peer` at `PEER_ADDR`
+    fn manager(peer: &DeviceIdentity) -> ConnectionManager {
+        let local = DeviceIdentity::generate();
+        let peers: PeerMap = Default::default();
+        peers.lock().unwrap().insert(peer.user_id(), user(peer, "192.168.1.20"));
+
+        ConnectionManager::new(
+            user(&local, "192.168.1.10"),
+            Arc::new(MessageStore::in_memory()),
+            Arc::new(GroupStore::in_memory()),
+            Arc::new(ChannelKeys::new(&local).unwrap()),
+            peers,
+            Arc::new(TrustStore::in_memory(local.public_key_hex())),
+        )
+    }
+
+    fn signed(peer: &DeviceIdentity, recipient_id: &str, content: &str) -> Message {
+        let mut message = Message::new(&peer.user_id(), recipient_id, content);
+        peer.sign_message(&mut message).unwrap();
+        message
+    }
+
+    fn group(created_by: &str, members: &[&str]) -> Group {
+        let members: Vec<String> = members.iter().map(|member| member.to_string()).collect();
+        Group {
+            id: "group-1".to_string(),
+            name: "Lunch".to_string(),
+            invited: members.clone(),
+            members,
+            created_by: created_by.to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn only_invited_peers_can_join_a_group() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let local_id = manager.local_user.id.clone();
+        let created = group(&local_id, &[&local_id, &peer.user_id()]);
+        manager.handle_group_update(&local_id, created.clone(), MembershipChange::Created);
+
+        // A member that left may come back
+        manager.handle_group_update(&peer.user_id(), created.clone(), MembershipChange::Left);
+        assert!(!manager.groups.get("group-1").unwrap().has_member(&peer.user_id()));
+        manager.handle_group_update(&peer.user_id(), created.clone(), MembershipChange::Joined);
+        assert!(manager.groups.get("group-1").unwrap().has_member(&peer.user_id()));
+
+        // Knowing the group ID is not enough
+        let mut forged = created.clone();
+        forged.invited.push("stranger".to_string());
+        manager.handle_group_update("stranger", forged, MembershipChange::Joined);
+        assert!(!manager.groups.get("group-1").unwrap().has_member("stranger"));
+    }
+
+    #[tokio::test]
+    async fn unauthenticated_messages_are_dropped() {
+        let peer = DeviceIdentity::generate();
+        let impostor = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let local_id = manager.local_user.id.clone();
+        let addr: SocketAddr = PEER_ADDR.parse().unwrap();
+
+        // Signed by another key than the one the sender was discovered with
+        let mut forged = Message::new(&peer.user_id(), &local_id, "forged");
+        impostor.sign_message(&mut forged).unwrap();
+        // Sent from an address the sender is not known at
+        let elsewhere = signed(&peer, &local_id, "elsewhere");
+        // Tampered with after signing
+        let mut tampered = signed(&peer, &local_id, "original");
+        tampered.content = "tampered".to_string();
+        // From a sender that was never discovered
+        let stranger = signed(&impostor, &local_id, "hi");
+
+        assert!(!manager.handle_received_message(forged, addr).await.unwrap());
+        assert!(!manager.handle_received_message(elsewhere, "192.168.1.99:9000".parse().unwrap()).await.unwrap());
+        assert!(!manager.handle_received_message(tampered, addr).await.unwrap());
+        assert!(!manager.handle_received_message(stranger, addr).await.unwrap());
+        assert!(manager.message_storage.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn messages_for_someone_else_are_dropped() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let message = signed(&peer, "someone-else", "hi");
+
+        assert!(!manager.handle_received_message(message, PEER_ADDR.parse().unwrap()).await.unwrap());
+        assert!(manager.message_storage.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_acknowledged_but_stored_once() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let message = signed(&peer, &manager.local_user.id, "hello");
+        let addr: SocketAddr = PEER_ADDR.parse().unwrap();
+
+        assert!(manager.handle_received_message(message.clone(), addr).await.unwrap());
+        assert!(manager.handle_received_message(message, addr).await.unwrap());
+        assert_eq!(manager.message_storage.lock()[&peer.user_id()].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn received_messages_only_keep_signed_fields() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let mut message = signed(&peer, &manager.local_user.id, "hello");
+        message.read = true;
+        message.status = MessageStatus::Read;
+        message.deleted = true;
+        message.edited_at = Some(Utc::now());
+        message.edit_history.push(MessageVersion {
+            content: "forged".to_string(),
+            timestamp: Utc::now(),
+        });
+        message.reactions.insert("👍".to_string(), vec![manager.local_user.id.clone()]);
+
+        assert!(manager.handle_received_message(message.clone(), PEER_ADDR.parse().unwrap()).await.unwrap());
+
+        let (_, stored) = manager.message_storage.find(&message.id).unwrap();
+        assert_eq!(stored.content, "hello");
+        assert_eq!(stored.status, MessageStatus::Delivered);
+        assert!(!stored.read);
+        assert!(!stored.deleted);
+        assert!(stored.edited_at.is_none());
+        assert!(stored.edit_history.is_empty());
+        assert!(stored.reactions.is_empty());
+    }
+
+    #[test]
+    fn groups_are_only_created_by_their_creator() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let local_id = manager.local_user.id.clone();
+
+        // Announced by a member that did not create it
+        let mut group_1 = group("someone-else", &[&local_id, &peer.user_id()]);
+        manager.handle_group_update(&peer.user_id(), group_1.clone(), MembershipChange::Created);
+        // Created by the sender, but without us in it
+        group_1.created_by = peer.user_id();
+        group_1.members.retain(|member| *member != local_id);
+        manager.handle_group_update(&peer.user_id(), group_1.clone(), MembershipChange::Created);
+        assert!(manager.groups.get("group-1").is_none());
+
+        group_1.members.push(local_id);
+        manager.handle_group_update(&peer.user_id(), group_1, MembershipChange::Created);
+        assert!(manager.groups.get("group-1").is_some());
+    }
+
+    #[tokio::test]
+    async fn group_messages_need_both_ends_in_the_group() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let local_id = manager.local_user.id.clone();
+        let addr: SocketAddr = PEER_ADDR.parse().unwrap();
+        let to_group = |group_id: &str, content: &str| {
+            let mut message = Message::new(&peer.user_id(), group_id, content);
+            message.group_id = Some(group_id.to_string());
+            peer.sign_message(&mut message).unwrap();
+            message
+        };
+
+        // Unknown group
+        assert!(!manager.handle_received_message(to_group("group-1", "hi"), addr).await.unwrap());
+
+        manager.groups.insert(&group(&local_id, &[&local_id, &peer.user_id()])).unwrap();
+        // Addressed to someone, but tagged with the group
+        let mut misaddressed = Message::new(&peer.user_id(), &local_id, "hi");
+        misaddressed.group_id = Some("group-1".to_string());
+        peer.sign_message(&mut misaddressed).unwrap();
+        assert!(!manager.handle_received_message(misaddressed, addr).await.unwrap());
+        assert!(manager.handle_received_message(to_group("group-1", "hello"), addr).await.unwrap());
+
+        // Not after the sender left
+        manager.groups.remove_member("group-1", &peer.user_id()).unwrap();
+        assert!(!manager.handle_received_message(to_group("group-1", "still here"), addr).await.unwrap());
+        assert_eq!(manager.message_storage.lock()["group-1"].len(), 1);
+    }
+
+    #[tokio::test]
+    async fn replies_must_point_into_their_conversation() {
+        let peer = DeviceIdentity::generate();
+        let manager = manager(&peer);
+        let local_id = manager.local_user.id.clone();
+        let addr: SocketAddr = PEER_ADDR.parse().unwrap();
+        let reply = |reply_to: Option<&str>, thread_root: Option<&str>| {
+            let mut message = Message::new(&peer.user_id(), &local_id, "reply");
+            message.reply_to = reply_to.map(String::from);
+            message.thread_root = thread_root.map(String::from);
+            peer.sign_message(&mut message).unwrap();
+            message
+        };
+
+        let parent = signed(&peer, &local_id, "parent");
+        assert!(manager.handle_received_message(parent.clone(), addr).await.unwrap());
+        let elsewhere = Message::new("someone-else", &local_id, "elsewhere");
+        manager.message_storage.insert("someone-else", &elsewhere).unwrap();
+
+        assert!(!manager.handle_received_message(reply(Some("unknown"), Some("unknown")), addr).await.unwrap());
+        assert!(!manager.handle_received_message(reply(Some(&elsewhere.id), Some(&elsewhere.id)), addr).await.unwrap());
+        assert!(!manager.handle_received_message(reply(Some(&parent.id), Some("another-thread")), addr).await.unwrap());
+        assert!(!manager.handle_received_message(reply(None, Some(&parent.id)), addr).await.unwrap());
+        assert_eq!(manager.message_storage.lock()[&peer.user_id()].len(), 1);
+
+        assert!(manager.handle_received_message(reply(Some(&parent.id), Some(&parent.id)), addr).await.unwrap());
+        assert_eq!(manager.message_storage.lock()[&peer.user_id()].len(), 2);
+    }
+}
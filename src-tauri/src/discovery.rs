@@ -1,43 +1,443 @@
+use futures::future::BoxFuture;
+use futures::FutureExt;
 use log::{debug, error, info, warn};
 use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::time::interval;
 
+use crate::beacon::BeaconBackend;
 use crate::emit_event;
 use crate::error::{AppError, AppResult};
-use crate::models::User;
+use crate::identity::{self, DeviceIdentity};
+use crate::models::{Presence, User, MAX_STATUS_TEXT_LEN};
+use crate::trust::{KeyCheck, TrustStore};
 
 const SERVICE_TYPE: &str = "_ip-chat._tcp.local.";
-const SERVICE_PORT: u16 = 8765;
 const DISCOVERY_INTERVAL: u64 = 30; // seconds
 const PEER_TIMEOUT: i64 = 600; // 10 minutes to be more tolerant
 const MAX_DISCOVERY_RETRIES: u8 = 3;
+/// Longest `key=value` string a TXT record can hold
+const MAX_TXT_STRING_LEN: usize = 255;
+
+/// Discovered peers by ID, shared with the components that need to look them up
+pub type PeerMap = Arc<Mutex<HashMap<String, User>>>;
+
+/// Identity advertised in the `user` TXT record, everything else has its own record
+#[derive(Debug, Serialize, Deserialize)]
+struct AdvertisedUser {
+    id: String,
+    name: String,
+    /// Preferred address, the others come from A/AAAA records
+    ip: String,
+}
+
+/// A way of finding peers on the local network
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum DiscoveryBackendKind {
+    /// mDNS service advertisement and browsing
+    Mdns,
+    /// Signed UDP broadcast beacons, for networks that filter multicast
+    Broadcast,
+}
+
+/// A discovery mechanism that reports the peers it finds to a `PeerRegistry`
+pub trait DiscoveryBackend: Send + Sync {
+    /// Which mechanism this is
+    fn kind(&self) -> DiscoveryBackendKind;
+
+    /// Starts advertising `local_user` and looking for peers
+    fn start<'a>(&'a mut self, local_user: &'a User, registry: PeerRegistry) -> BoxFuture<'a, AppResult<()>>;
+
+    /// Stops advertising and looking for peers
+    fn stop(&mut self) -> BoxFuture<'_, AppResult<()>>;
+
+    /// Advertises updated local user info, e.g. a new name or presence
+    fn update_local_user<'a>(&'a mut self, local_user: &'a User) -> BoxFuture<'a, AppResult<()>>;
+
+    /// Looks for peers right away instead of waiting for the next announcement
+    fn refresh(&self) -> BoxFuture<'_, AppResult<()>>;
+}
+
+/// The discovered peers as seen by backends, which checks peers before adding them
+#[derive(Clone)]
+pub struct PeerRegistry {
+    /// ID of the local user, never added as a peer
+    local_id: String,
+    /// Map of discovered peers by ID
+    peers: PeerMap,
+    /// Keys pinned for peers on first contact
+    trust_store: Arc<TrustStore>,
+    /// Told the ID of every peer that is (re)discovered
+    peer_online_tx: Option<mpsc::UnboundedSender<String>>,
+}
+
+impl PeerRegistry {
+    /// Creates an empty registry for the local user
+    pub fn new(local_id: String, trust_store: Arc<TrustStore>) -> Self {
+        PeerRegistry {
+            local_id,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            trust_store,
+            peer_online_tx: None,
+        }
+    }
+
+    /// Whether a peer is currently known
+    pub fn contains(&self, peer_id: &str) -> bool {
+        self.peers.lock().unwrap().contains_key(peer_id)
+    }
+
+    /// Adds or updates a discovered peer, returning whether it was accepted.
+    ///
+    /// The peer's ID must be derived from its public key, and the key must
+    /// match the one pinned on first contact.
+    pub fn peer_found(&self, user: User) -> bool {
+        // Don't add ourselves to the peer list
+        if user.id == self.local_id {
+            return false;
+        }
+
+        // The user ID must be derived from the advertised key
+        if let Some(public_key) = &user.public_key {
+            match identity::user_id_for_public_key(public_key) {
+                Ok(expected_id) if expected_id == user.id => {}
+                Ok(expected_id) => {
+                    warn!("Ignoring peer {} whose ID does not match its public key (expected {})", user.id, expected_id);
+                    return false;
+                }
+                Err(e) => {
+                    warn!("Ignoring peer {} with invalid public key: {}", user.id, e);
+                    return false;
+                }
+            }
+        }
+
+        // Never let another host take over a pinned peer
+        if !is_trusted_peer(&self.trust_store, &user) {
+            return false;
+        }
+
+        let is_new_peer = {
+            let mut peers_map = self.peers.lock().unwrap();
+            peers_map.insert(user.id.clone(), user.clone()).is_none()
+        };
+        info!("Discovered peer: {} at {} ({})", user.name, user.ip,
+              if is_new_peer { "new" } else { "updated" });
+
+        // Let queued messages for this peer go out
+        if let Some(tx) = &self.peer_online_tx {
+            let _ = tx.send(user.id.clone());
+        }
+
+        // Emit peer discovered event (for both new and updated peers)
+        emit_event("peer_discovered", user);
+        true
+    }
+
+    /// Removes a peer that announced it is leaving, returning whether it was known.
+    ///
+    /// Only the holder of the pinned key may take a peer off the list.
+    pub fn peer_left(&self, peer_id: &str, public_key: &str) -> bool {
+        if self.trust_store.pinned_key(peer_id).as_deref() != Some(public_key) {
+            warn!("Ignoring leave for {} signed with a key that is not pinned for it", peer_id);
+            return false;
+        }
+        self.remove_peers(|peer| peer.id == peer_id) > 0
+    }
+
+    /// Removes the peers matching `f`, emitting `peers_updated` if there were any
+    pub fn remove_peers<F>(&self, f: F) -> usize
+    where
+        F: Fn(&User) -> bool,
+    {
+        let mut peers_map = self.peers.lock().unwrap();
+        let before_count = peers_map.len();
+        peers_map.retain(|_, user| !f(user));
+        let removed = before_count - peers_map.len();
+        if removed > 0 {
+            let peers_list: Vec<User> = peers_map.values().cloned().collect();
+            emit_event("peers_updated", peers_list);
+        }
+        removed
+    }
+}
 
-/// Handles network discovery using mDNS
+/// Handles network discovery using one or more backends
 pub struct NetworkDiscovery {
     /// The local user
     local_user: User,
-    /// Map of discovered peers by ID
-    peers: Arc<Mutex<HashMap<String, User>>>,
+    /// Long-term identity key, signs broadcast beacons
+    identity: Arc<DeviceIdentity>,
+    /// Discovered peers, shared by all backends
+    registry: PeerRegistry,
+    /// Enabled discovery backends
+    backends: Vec<Box<dyn DiscoveryBackend>>,
+    /// Flag indicating if discovery is running
+    is_running: bool,
+    /// Channel for stopping the stale peer cleanup
+    stop_tx: Option<mpsc::Sender<()>>,
+}
+
+impl NetworkDiscovery {
+    /// Creates a new NetworkDiscovery instance using the given backends
+    pub fn new(
+        local_user: User,
+        trust_store: Arc<TrustStore>,
+        identity: Arc<DeviceIdentity>,
+        backend_kinds: &[DiscoveryBackendKind],
+    ) -> Self {
+        let registry = PeerRegistry::new(local_user.id.clone(), trust_store);
+
+        let mut discovery = NetworkDiscovery {
+            local_user,
+            identity,
+            registry,
+            backends: Vec::new(),
+            is_running: false,
+            stop_tx: None,
+        };
+        discovery.build_backends(backend_kinds);
+        discovery
+    }
+
+    /// Sets our user as advertised once discovery starts, e.g. with the ports actually listened on
+    pub fn set_local_user(&mut self, local_user: User) {
+        self.local_user = local_user;
+    }
+
+    /// Sets the channel notified whenever a peer is discovered
+    pub fn set_peer_online_notifier(&mut self, tx: mpsc::UnboundedSender<String>) {
+        self.registry.peer_online_tx = Some(tx);
+    }
+
+    /// Replaces the backends with new ones of the given kinds
+    fn build_backends(&mut self, backend_kinds: &[DiscoveryBackendKind]) {
+        self.backends.clear();
+        for kind in backend_kinds {
+            if self.backends.iter().any(|backend| backend.kind() == *kind) {
+                continue;
+            }
+            let backend: Box<dyn DiscoveryBackend> = match kind {
+                DiscoveryBackendKind::Mdns => Box::new(MdnsBackend::new()),
+                DiscoveryBackendKind::Broadcast => Box::new(BeaconBackend::new(Arc::clone(&self.identity))),
+            };
+            self.backends.push(backend);
+        }
+    }
+
+    /// Kinds of the enabled backends
+    pub fn backend_kinds(&self) -> Vec<DiscoveryBackendKind> {
+        self.backends.iter().map(|backend| backend.kind()).collect()
+    }
+
+    /// Switches to other backends, restarting discovery if it is running.
+    ///
+    /// Peers in `keep_ids`, e.g. ones added by address, stay listed meanwhile.
+    pub async fn set_backends(
+        &mut self,
+        backend_kinds: &[DiscoveryBackendKind],
+        keep_ids: &HashSet<String>,
+    ) -> AppResult<()> {
+        if backend_kinds.is_empty() {
+            return Err(AppError::InvalidOperation(
+                "At least one discovery backend must be enabled".to_string(),
+            ));
+        }
+
+        let was_running = self.is_running;
+        if was_running {
+            self.stop_discovery(keep_ids).await?;
+        }
+        self.build_backends(backend_kinds);
+        info!("Discovery backends: {:?}", self.backend_kinds());
+
+        if was_running {
+            self.start_discovery().await?;
+        }
+        Ok(())
+    }
+
+    /// Starts the network discovery service
+    pub async fn start_discovery(&mut self) -> AppResult<()> {
+        // Check if discovery is already running
+        if self.is_running {
+            return Err(AppError::DiscoveryError(
+                "Discovery already running".to_string(),
+            ));
+        }
+
+        // Discovery works as long as one of the backends does
+        let mut started = 0;
+        let mut last_error = None;
+        for backend in self.backends.iter_mut() {
+            match backend.start(&self.local_user, self.registry.clone()).await {
+                Ok(()) => {
+                    info!("Started {:?} discovery", backend.kind());
+                    started += 1;
+                }
+                Err(e) => {
+                    error!("Failed to start {:?} discovery: {}", backend.kind(), e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if started == 0 {
+            return Err(last_error.unwrap_or_else(|| {
+                AppError::DiscoveryError("No discovery backend enabled".to_string())
+            }));
+        }
+
+        // Set up channel for stopping discovery
+        let (stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
+        self.stop_tx = Some(stop_tx);
+        self.is_running = true;
+
+        // Spawn task to drop peers no backend has heard from in a while
+        let registry = self.registry.clone();
+        tokio::spawn(async move {
+            let mut cleanup_interval = interval(Duration::from_secs(DISCOVERY_INTERVAL));
+
+            loop {
+                tokio::select! {
+                    // Check for stop signal
+                    _ = stop_rx.recv() => {
+                        debug!("Stopping stale peer cleanup");
+                        break;
+                    }
+
+                    // Periodic cleanup of stale peers
+                    _ = cleanup_interval.tick() => {
+                        let now = chrono::Utc::now();
+                        let removed = registry.remove_peers(|user| {
+                            // Remove peers that haven't been seen in 10 minutes
+                            now.signed_duration_since(user.last_seen).num_seconds() >= PEER_TIMEOUT
+                        });
+                        if removed > 0 {
+                            info!("Cleaned up {removed} stale peer(s)");
+                        }
+                        info!("Current peer count: {}", registry.peers.lock().unwrap().len());
+                    }
+                }
+            }
+        });
+
+        info!("Network discovery started");
+        Ok(())
+    }
+
+    /// Stops the network discovery service, forgetting discovered peers except those in `keep_ids`
+    pub async fn stop_discovery(&mut self, keep_ids: &HashSet<String>) -> AppResult<()> {
+        info!("Attempting to stop network discovery...");
+
+        if !self.is_running {
+            info!("Discovery was not running, nothing to stop");
+            return Ok(()); // Already stopped, no error
+        }
+        self.is_running = false;
+
+        // Send stop signal
+        if let Some(tx) = self.stop_tx.take() {
+            match tx.send(()).await {
+                Ok(_) => debug!("Stop signal sent successfully"),
+                Err(_) => debug!("Stop signal channel already closed"),
+            }
+        }
+
+        for backend in self.backends.iter_mut() {
+            if let Err(e) = backend.stop().await {
+                warn!("Failed to stop {:?} discovery: {}", backend.kind(), e);
+            }
+        }
+
+        // Clear peers, those added by address are not rediscovered
+        let peer_count = self.registry.remove_peers(|peer| !keep_ids.contains(&peer.id));
+        if peer_count > 0 {
+            info!("Cleared {} discovered peers", peer_count);
+        }
+
+        info!("Network discovery stopped successfully");
+        Ok(())
+    }
+
+    /// Gets the list of discovered peers
+    pub fn get_discovered_peers(&self) -> Vec<User> {
+        let peers = self.registry.peers.lock().unwrap();
+        peers.values().cloned().collect()
+    }
+
+    /// Gets a handle to the live map of discovered peers
+    pub fn peer_map(&self) -> PeerMap {
+        Arc::clone(&self.registry.peers)
+    }
+
+    /// Gets a specific peer by ID, returns None if not found
+    pub fn get_peer_by_id(&self, peer_id: &str) -> Option<User> {
+        let peers = self.registry.peers.lock().unwrap();
+        peers.get(peer_id).cloned()
+    }
+
+    /// Adds a peer found outside discovery, e.g. by probing an address the user entered
+    pub fn add_peer(&self, user: User) -> AppResult<()> {
+        let user_id = user.id.clone();
+        if !self.registry.peer_found(user) {
+            return Err(AppError::AuthenticationError(format!(
+                "Peer {} presented a different key than the one pinned",
+                user_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Asks every backend to look for peers right away
+    pub async fn refresh_peer_discovery(&self) -> AppResult<()> {
+        for backend in &self.backends {
+            backend.refresh().await?;
+        }
+        Ok(())
+    }
+
+    /// Advertises updated local user info, e.g. a new name or presence
+    pub async fn broadcast_user_update(&mut self, local_user: &User) -> AppResult<()> {
+        self.local_user = local_user.clone();
+
+        // Check if discovery is running
+        if !self.is_running {
+            return Err(AppError::DiscoveryError(
+                "Discovery not running".to_string(),
+            ));
+        }
+
+        for backend in self.backends.iter_mut() {
+            backend.update_local_user(local_user).await?;
+        }
+        info!("Broadcast user update");
+        Ok(())
+    }
+}
+
+/// Discovery through an mDNS service advertisement
+struct MdnsBackend {
     /// mDNS service daemon
     daemon: Option<ServiceDaemon>,
     /// Service instance name
     service_name: String,
-    /// Flag indicating if discovery is running
+    /// Flag indicating if the receiver thread should keep running
     is_running: Arc<Mutex<bool>>,
-    /// Channel for stopping discovery
+    /// Channel for stopping the event handler
     stop_tx: Option<mpsc::Sender<()>>,
     /// Flag indicating if service is registered
     service_registered: Arc<Mutex<bool>>,
 }
 
-impl Drop for NetworkDiscovery {
+impl Drop for MdnsBackend {
     fn drop(&mut self) {
         debug!(
-            "NetworkDiscovery::drop called for service: {}",
+            "MdnsBackend::drop called for service: {}",
             self.service_name
         );
 
@@ -73,34 +473,78 @@ impl Drop for NetworkDiscovery {
         }
 
         debug!(
-            "NetworkDiscovery::drop completed for service: {}",
+            "MdnsBackend::drop completed for service: {}",
             self.service_name
         );
     }
 }
 
-impl NetworkDiscovery {
-    /// Creates a new NetworkDiscovery instance
-    pub fn new(local_user: User) -> Self {
-        // Each device needs a unique service name to avoid registration conflicts
-        // We use the user ID (which is hostname-based) to make it unique per device
-        let service_name = format!("ip-chat-{}", local_user.id);
-
-        NetworkDiscovery {
-            local_user,
-            peers: Arc::new(Mutex::new(HashMap::new())),
+impl MdnsBackend {
+    fn new() -> Self {
+        MdnsBackend {
             daemon: None,
-            service_name,
+            service_name: String::new(),
             is_running: Arc::new(Mutex::new(false)),
             stop_tx: None,
             service_registered: Arc::new(Mutex::new(false)),
         }
     }
 
+    /// Builds the TXT records advertised with our mDNS service
+    fn txt_records(local_user: &User) -> AppResult<HashMap<String, String>> {
+        // Each TXT string is limited to 255 bytes, so only the identity goes in the `user`
+        // record and a long name is shortened to fit
+        let mut advertised = AdvertisedUser {
+            id: local_user.id.clone(),
+            name: local_user.name.clone(),
+            ip: local_user.ip.clone(),
+        };
+        let mut user_value = serde_json::to_string(&advertised).map_err(AppError::SerializationError)?;
+        while "user=".len() + user_value.len() > MAX_TXT_STRING_LEN && advertised.name.pop().is_some() {
+            user_value = serde_json::to_string(&advertised).map_err(AppError::SerializationError)?;
+        }
+
+        let mut txt_records = HashMap::new();
+        txt_records.insert("user".to_string(), user_value);
+        if let Some(public_key) = &local_user.public_key {
+            txt_records.insert("public_key".to_string(), public_key.clone());
+        }
+        txt_records.insert("presence".to_string(), local_user.presence.as_str().to_string());
+        txt_records.insert("chat_port".to_string(), local_user.chat_port().to_string());
+        txt_records.insert("file_port".to_string(), local_user.file_transfer_port().to_string());
+        if let Some(status_text) = &local_user.status_text {
+            txt_records.insert("status".to_string(), status_text.clone());
+        }
+        Ok(txt_records)
+    }
+
+    /// Builds our service advertisement
+    fn service_info(&self, local_user: &User) -> AppResult<ServiceInfo> {
+        // Address records carry no scope ID, the receiver picks the interface
+        let mut addresses: Vec<&str> = local_user
+            .addresses
+            .iter()
+            .map(|address| address.split('%').next().unwrap_or(address))
+            .collect();
+        if addresses.is_empty() {
+            addresses.push(&local_user.ip);
+        }
+
+        ServiceInfo::new(
+            SERVICE_TYPE,
+            &self.service_name,
+            &local_user.name,
+            &addresses[..],
+            local_user.chat_port(),
+            Some(Self::txt_records(local_user)?),
+        )
+        .map_err(|e| AppError::MdnsError(format!("Failed to create service info: {e}")))
+    }
+
     /// Creates mDNS daemon with retry logic
     async fn create_mdns_daemon_with_retry(&self) -> AppResult<ServiceDaemon> {
         let mut last_error = None;
-        
+
         for attempt in 1..=MAX_DISCOVERY_RETRIES {
             match ServiceDaemon::new() {
                 Ok(daemon) => {
@@ -110,7 +554,7 @@ impl NetworkDiscovery {
                 Err(e) => {
                     warn!("Failed to create mDNS daemon on attempt {}: {}", attempt, e);
                     last_error = Some(e);
-                    
+
                     if attempt < MAX_DISCOVERY_RETRIES {
                         // Wait before retrying
                         tokio::time::sleep(Duration::from_millis(1000 * attempt as u64)).await;
@@ -118,7 +562,7 @@ impl NetworkDiscovery {
                 }
             }
         }
-        
+
         Err(AppError::MdnsError(format!(
             "Failed to create mDNS daemon after {} attempts: {}",
             MAX_DISCOVERY_RETRIES,
@@ -126,39 +570,17 @@ impl NetworkDiscovery {
         )))
     }
 
-    /// Starts the network discovery service
-    pub async fn start_discovery(&mut self) -> AppResult<()> {
-        // Check if discovery is already running
-        {
-            let is_running = self.is_running.lock().unwrap();
-            if *is_running {
-                return Err(AppError::DiscoveryError(
-                    "Discovery already running".to_string(),
-                ));
-            }
-        }
+    /// Registers our service and starts browsing for peers
+    async fn start_mdns(&mut self, local_user: &User, registry: PeerRegistry) -> AppResult<()> {
+        // Each device needs a unique service name to avoid registration conflicts
+        // We use the user ID (which is derived from the device key) to make it unique per device
+        self.service_name = format!("ip-chat-{}", local_user.id);
 
         // Try to create mDNS daemon with retries
         let daemon = self.create_mdns_daemon_with_retry().await?;
 
         // Register our service
-        let user_json =
-            serde_json::to_string(&self.local_user).map_err(AppError::SerializationError)?;
-
-        let service_info = ServiceInfo::new(
-            SERVICE_TYPE,
-            &self.service_name,
-            &self.local_user.name,
-            &self.local_user.ip,
-            SERVICE_PORT,
-            Some({
-                let mut txt_records = HashMap::new();
-                txt_records.insert("user".to_string(), user_json);
-                txt_records
-            }),
-        )
-        .map_err(|e| AppError::MdnsError(format!("Failed to create service info: {e}")))?;
-
+        let service_info = self.service_info(local_user)?;
         match daemon.register(service_info) {
             Ok(_) => {
                 info!(
@@ -191,6 +613,12 @@ impl NetworkDiscovery {
         // Set up channel for stopping discovery
         let (stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
 
+        // Set running flag before the receiver thread first checks it
+        {
+            let mut is_running = self.is_running.lock().unwrap();
+            *is_running = true;
+        }
+
         // Create a thread to handle the receiver
         let browse_handle_clone = browse_handle.clone();
         let is_running_clone = Arc::clone(&self.is_running);
@@ -218,7 +646,7 @@ impl NetworkDiscovery {
                 match receiver {
                     Ok(event) => {
                         // Try to send the event to the channel
-                        if let Err(_) = event_tx.blocking_send(event) {
+                        if event_tx.blocking_send(event).is_err() {
                             debug!("Event channel closed, stopping receiver thread");
                             break;
                         }
@@ -235,30 +663,17 @@ impl NetworkDiscovery {
             );
         });
 
-        // Set running flag
-        {
-            let mut is_running = self.is_running.lock().unwrap();
-            *is_running = true;
-        }
-
         // Store daemon and stop channel
         self.daemon = Some(daemon);
         self.stop_tx = Some(stop_tx);
 
-        // Clone necessary values for the task
-        let peers = Arc::clone(&self.peers);
-        let local_id = self.local_user.id.clone();
-        let is_running = Arc::clone(&self.is_running);
-
         // Spawn task to handle service events
         tokio::spawn(async move {
-            let mut cleanup_interval = interval(Duration::from_secs(DISCOVERY_INTERVAL));
-
             loop {
                 tokio::select! {
                     // Check for stop signal
                     _ = stop_rx.recv() => {
-                        debug!("Stopping discovery");
+                        debug!("Stopping mDNS discovery");
                         break;
                     }
 
@@ -267,58 +682,16 @@ impl NetworkDiscovery {
                         match event {
                             ServiceEvent::ServiceResolved(info) => {
                                 debug!("Service resolved: {}", info.get_fullname());
-                                // Extract user info from TXT records
-                                let txt_properties = info.get_properties();
-                                for record in txt_properties.iter() {
-                                    if record.key() == "user" {
-                                        let value = record.val_str();
-                                        match serde_json::from_str::<User>(value) {
-                                            Ok(mut user) => {
-                                                // Don't add ourselves to the peer list
-                                                if user.id != local_id {
-                                                    // Update user IP from service info
-                                                    user.ip = info.get_addresses().iter().next().map(|addr| addr.to_string()).unwrap_or(user.ip);
-                                                    user.last_seen = chrono::Utc::now();
-                                                    
-                                                    let is_new_peer = {
-                                                        let peers_map = peers.lock().unwrap();
-                                                        !peers_map.contains_key(&user.id)
-                                                    };
-                                                    
-                                                    info!("Discovered peer: {} at {} ({})", user.name, user.ip, 
-                                                          if is_new_peer { "new" } else { "updated" });
-                                                    
-                                                    {
-                                                        let mut peers_map = peers.lock().unwrap();
-                                                        peers_map.insert(user.id.clone(), user.clone());
-                                                    }
-
-                                                    // Emit peer discovered event (for both new and updated peers)
-                                                    emit_event("peer_discovered", user);
-                                                }
-                                            }
-                                            Err(e) => {
-                                                warn!("Failed to parse user data from TXT record: {e}");
-                                            }
-                                        }
-                                        break; // Found the user record
-                                    }
+                                if let Some(user) = user_from_service(&info) {
+                                    registry.peer_found(user);
                                 }
                             }
                             ServiceEvent::ServiceRemoved(service_type, name) => {
                                 info!("Service removed: {service_type} {name}");
                                 // Remove peer if it exists
-                                let mut peers_map = peers.lock().unwrap();
-                                let before_count = peers_map.len();
-                                peers_map.retain(|_, user| {
-                                    !name.contains(&user.id)
-                                });
-                                let after_count = peers_map.len();
-                                if before_count != after_count {
-                                    info!("Removed {} peer(s) from discovery", before_count - after_count);
-                                    // Emit peers updated event
-                                    let peers_list: Vec<User> = peers_map.values().cloned().collect();
-                                    emit_event("peers_updated", peers_list);
+                                let removed = registry.remove_peers(|user| name.contains(&user.id));
+                                if removed > 0 {
+                                    info!("Removed {} peer(s) from discovery", removed);
                                 }
                             }
                             ServiceEvent::SearchStarted(service_type) => {
@@ -332,60 +705,22 @@ impl NetworkDiscovery {
                             }
                         }
                     }
-
-                    // Periodic cleanup of stale peers and re-discovery
-                    _ = cleanup_interval.tick() => {
-                        let now = chrono::Utc::now();
-                        let mut peers_map = peers.lock().unwrap();
-                        let before_count = peers_map.len();
-                        peers_map.retain(|_, user| {
-                            // Remove peers that haven't been seen in 5 minutes (increased from 2)
-                            now.signed_duration_since(user.last_seen).num_seconds() < PEER_TIMEOUT
-                        });
-                        let after_count = peers_map.len();
-                        if before_count != after_count {
-                            info!("Cleaned up {} stale peer(s)", before_count - after_count);
-                            // Emit peers updated event
-                            let peers_list: Vec<User> = peers_map.values().cloned().collect();
-                            emit_event("peers_updated", peers_list);
-                        }
-                        info!("Current peer count: {after_count}");
-                    }
                 }
             }
 
-            // Set running flag to false when exiting
-            debug!("Discovery event handler task exiting");
-            let mut is_running_guard = is_running.lock().unwrap();
-            *is_running_guard = false;
-            debug!("Set running flag to false in event handler task");
+            debug!("mDNS event handler task exiting");
         });
 
-        info!("Network discovery started");
         Ok(())
     }
 
-    /// Stops the network discovery service
-    pub async fn stop_discovery(&mut self) -> AppResult<()> {
-        info!("Attempting to stop network discovery...");
-
-        // Check if discovery is running
-        let was_running = {
-            let is_running = self.is_running.lock().unwrap();
-            *is_running
-        };
-
-        if !was_running {
-            info!("Discovery was not running, nothing to stop");
-            return Ok(()); // Already stopped, no error
-        }
-
-        // Set running flag to false first to stop all background tasks
+    /// Unregisters our service and stops browsing
+    async fn stop_mdns(&mut self) -> AppResult<()> {
+        // Set running flag to false first to stop the receiver thread
         {
             let mut is_running = self.is_running.lock().unwrap();
             *is_running = false;
         }
-        info!("Set running flag to false");
 
         // Send stop signal
         if let Some(tx) = self.stop_tx.take() {
@@ -411,9 +746,6 @@ impl NetworkDiscovery {
                 match daemon.unregister(&self.service_name) {
                     Ok(_) => {
                         info!("Successfully unregistered service: {}", self.service_name);
-                        // Mark service as no longer registered
-                        let mut service_registered = self.service_registered.lock().unwrap();
-                        *service_registered = false;
                     }
                     Err(e) => {
                         // Log as warning instead of error - this is common during shutdown
@@ -421,11 +753,11 @@ impl NetworkDiscovery {
                             "Could not unregister service {} (this is normal during shutdown): {}",
                             self.service_name, e
                         );
-                        // Still mark as unregistered to avoid double unregistration
-                        let mut service_registered = self.service_registered.lock().unwrap();
-                        *service_registered = false;
                     }
                 }
+                // Mark as unregistered either way to avoid double unregistration
+                let mut service_registered = self.service_registered.lock().unwrap();
+                *service_registered = false;
             } else {
                 debug!("Service was not registered, skipping unregistration");
             }
@@ -433,112 +765,241 @@ impl NetworkDiscovery {
             // Give the daemon time to clean up
             tokio::time::sleep(Duration::from_millis(150)).await;
         }
+        Ok(())
+    }
 
-        // Clear peers
-        {
-            let mut peers = self.peers.lock().unwrap();
-            let peer_count = peers.len();
-            peers.clear();
-            if peer_count > 0 {
-                info!("Cleared {} discovered peers", peer_count);
-            }
+    /// Registers our service again with updated TXT records
+    async fn update_mdns(&mut self, local_user: &User) -> AppResult<()> {
+        let Some(daemon) = &self.daemon else {
+            return Err(AppError::DiscoveryError(
+                "Daemon not initialized".to_string(),
+            ));
+        };
+
+        // First unregister the old service if it was registered
+        let should_unregister = {
+            let service_registered = self.service_registered.lock().unwrap();
+            *service_registered
+        };
+
+        if should_unregister {
+            let _ = daemon.unregister(&self.service_name);
         }
 
-        info!("Network discovery stopped successfully");
-        Ok(())
-    }
+        // Give some time for unregistration
+        tokio::time::sleep(Duration::from_millis(100)).await;
 
-    /// Gets the list of discovered peers
-    pub fn get_discovered_peers(&self) -> Vec<User> {
-        let peers = self.peers.lock().unwrap();
-        peers.values().cloned().collect()
-    }
+        let service_info = self.service_info(local_user)?;
+        daemon
+            .register(service_info)
+            .map_err(|e| AppError::MdnsError(format!("Failed to update service: {e}")))?;
 
-    /// Gets a specific peer by ID, returns None if not found
-    pub fn get_peer_by_id(&self, peer_id: &str) -> Option<User> {
-        let peers = self.peers.lock().unwrap();
-        peers.get(peer_id).cloned()
+        // Mark service as registered again
+        {
+            let mut service_registered = self.service_registered.lock().unwrap();
+            *service_registered = true;
+        }
+        Ok(())
     }
 
-    /// Forces a refresh of mDNS discovery to find peers
-    pub async fn refresh_peer_discovery(&self) -> AppResult<()> {
+    /// Restarts browsing so peers answer again
+    async fn refresh_mdns(&self) -> AppResult<()> {
         if let Some(daemon) = &self.daemon {
             // Stop and restart search to refresh discovery
             let _ = daemon.stop_browse(SERVICE_TYPE);
             tokio::time::sleep(Duration::from_millis(100)).await;
-            
+
             let event_rx = daemon
                 .browse(SERVICE_TYPE)
                 .map_err(|e| AppError::MdnsError(format!("Failed to restart discovery: {e}")))?;
-            
+
             // The event_rx is consumed by the background task, so we don't need to handle it here
             drop(event_rx);
-            
+
             info!("Refreshed mDNS peer discovery");
         }
         Ok(())
     }
+}
 
-    /// Broadcasts an update to the local user info
-    pub async fn broadcast_user_update(&self) -> AppResult<()> {
-        // Check if discovery is running
-        {
-            let is_running = self.is_running.lock().unwrap();
-            if !*is_running {
-                return Err(AppError::DiscoveryError(
-                    "Discovery not running".to_string(),
-                ));
-            }
+impl DiscoveryBackend for MdnsBackend {
+    fn kind(&self) -> DiscoveryBackendKind {
+        DiscoveryBackendKind::Mdns
+    }
+
+    fn start<'a>(&'a mut self, local_user: &'a User, registry: PeerRegistry) -> BoxFuture<'a, AppResult<()>> {
+        self.start_mdns(local_user, registry).boxed()
+    }
+
+    fn stop(&mut self) -> BoxFuture<'_, AppResult<()>> {
+        self.stop_mdns().boxed()
+    }
+
+    fn update_local_user<'a>(&'a mut self, local_user: &'a User) -> BoxFuture<'a, AppResult<()>> {
+        self.update_mdns(local_user).boxed()
+    }
+
+    fn refresh(&self) -> BoxFuture<'_, AppResult<()>> {
+        self.refresh_mdns().boxed()
+    }
+}
+
+/// Extracts the peer advertised in a resolved service's TXT records
+fn user_from_service(info: &ServiceInfo) -> Option<User> {
+    let txt_properties = info.get_properties();
+    let value = txt_properties.get_property_val_str("user")?;
+    let advertised = match serde_json::from_str::<AdvertisedUser>(value) {
+        Ok(advertised) => advertised,
+        Err(e) => {
+            warn!("Failed to parse user data from TXT record: {e}");
+            return None;
         }
+    };
+
+    // Every resolved address is usable; the advertised preferred one first, then IPv4 before IPv6
+    let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+    let preferred = advertised.ip.parse::<IpAddr>().ok();
+    addresses.sort_by_key(|addr| (Some(*addr) != preferred, addr.is_ipv6(), *addr));
+    let addresses: Vec<String> = addresses.iter().map(|addr| addr.to_string()).collect();
+
+    Some(User {
+        id: advertised.id,
+        name: advertised.name,
+        ip: addresses.first().cloned().unwrap_or(advertised.ip),
+        addresses,
+        last_seen: chrono::Utc::now(),
+        // The registry checks the key record against the user ID
+        public_key: txt_properties
+            .get_property_val_str("public_key")
+            .map(|public_key| public_key.to_string()),
+        presence: txt_properties
+            .get_property_val_str("presence")
+            .and_then(Presence::parse)
+            .unwrap_or_default(),
+        status_text: txt_properties
+            .get_property_val_str("status")
+            .filter(|text| !text.is_empty() && text.len() <= MAX_STATUS_TEXT_LEN)
+            .map(|text| text.to_string()),
+        // Peers that predate the port records listen on the SRV port and the default file port
+        port: txt_properties
+            .get_property_val_str("chat_port")
+            .and_then(|port| port.parse().ok())
+            .or(Some(info.get_port())),
+        file_port: txt_properties
+            .get_property_val_str("file_port")
+            .and_then(|port| port.parse().ok()),
+    })
+}
 
-        // Update service TXT record with new user info
-        if let Some(daemon) = &self.daemon {
-            let user_json =
-                serde_json::to_string(&self.local_user).map_err(AppError::SerializationError)?;
+/// Checks a peer's key against the trust store, pinning it on first contact.
+///
+/// Peers without a key, or with a different key than the one pinned, are never trusted.
+pub fn is_trusted_peer(trust_store: &TrustStore, user: &User) -> bool {
+    let Some(public_key) = &user.public_key else {
+        warn!("Ignoring peer {} advertised without a public key", user.id);
+        return false;
+    };
+
+    match trust_store.check_key(&user.id, public_key) {
+        Ok(KeyCheck::Pinned) => {
+            info!("Pinned public key for new peer {}", user.id);
+            true
+        }
+        Ok(KeyCheck::Matches) => true,
+        Ok(KeyCheck::Mismatch) => {
+            warn!("Ignoring peer {} presenting a different key than the one pinned", user.id);
+            false
+        }
+        Err(e) => {
+            // The key is still pinned in memory, only saving it failed
+            error!("Failed to save pinned key for {}: {e}", user.id);
+            true
+        }
+    }
+}
 
-            // First unregister the old service if it was registered
-            let should_unregister = {
-                let service_registered = self.service_registered.lock().unwrap();
-                *service_registered
-            };
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(name: &str) -> User {
+        User {
+            id: "6f1c2a4e-93b0-4d8e-a1f7-0c5d2b9e8a31".to_string(),
+            name: name.to_string(),
+            ip: "192.168.1.20".to_string(),
+            addresses: vec![
+                "192.168.1.20".to_string(),
+                "fd00::20".to_string(),
+                "fe80::1c2b:3dff:fe4e:5f60%2".to_string(),
+            ],
+            last_seen: chrono::Utc::now(),
+            public_key: Some("ab".repeat(32)),
+            presence: Presence::Away,
+            status_text: Some("x".repeat(MAX_STATUS_TEXT_LEN)),
+            port: Some(9000),
+            file_port: Some(9001),
+        }
+    }
 
-            if should_unregister {
-                let _ = daemon.unregister(&self.service_name);
+    #[test]
+    fn txt_records_fit_in_txt_strings() {
+        for name in ["Alice", &"é".repeat(200)] {
+            let records = MdnsBackend::txt_records(&user(name)).unwrap();
+            for (key, value) in &records {
+                assert!(key.len() + 1 + value.len() <= MAX_TXT_STRING_LEN, "{key} is too long");
             }
+        }
+    }
 
-            // Give some time for unregistration
-            tokio::time::sleep(Duration::from_millis(100)).await;
+    #[test]
+    fn user_round_trips_through_the_service_info() {
+        let local_user = user("Alice");
+        let info = ServiceInfo::new(
+            SERVICE_TYPE,
+            "alice",
+            "alice.local.",
+            "192.168.1.20",
+            9000,
+            Some(MdnsBackend::txt_records(&local_user).unwrap()),
+        )
+        .unwrap();
+
+        let user = user_from_service(&info).unwrap();
+        assert_eq!(user.id, local_user.id);
+        assert_eq!(user.name, "Alice");
+        assert_eq!(user.ip, "192.168.1.20");
+        assert_eq!(user.public_key, local_user.public_key);
+        assert_eq!(user.presence, Presence::Away);
+        assert_eq!(user.status_text, local_user.status_text);
+        assert_eq!(user.port, Some(9000));
+        assert_eq!(user.file_port, Some(9001));
+    }
 
-            let service_info = ServiceInfo::new(
-                SERVICE_TYPE,
-                &self.service_name,
-                &self.local_user.name,
-                &self.local_user.ip,
-                SERVICE_PORT,
-                Some({
-                    let mut txt_records = HashMap::new();
-                    txt_records.insert("user".to_string(), user_json);
-                    txt_records
-                }),
-            )
-            .map_err(|e| AppError::MdnsError(format!("Failed to create service info: {e}")))?;
-
-            daemon
-                .register(service_info)
-                .map_err(|e| AppError::MdnsError(format!("Failed to update service: {e}")))?;
-
-            // Mark service as registered again
-            {
-                let mut service_registered = self.service_registered.lock().unwrap();
-                *service_registered = true;
-            }
+    #[test]
+    fn long_names_are_shortened_to_fit() {
+        let records = MdnsBackend::txt_records(&user(&"é".repeat(200))).unwrap();
+        let advertised: AdvertisedUser = serde_json::from_str(&records["user"]).unwrap();
+        assert!(!advertised.name.is_empty());
+        assert!(advertised.name.chars().all(|c| c == 'é'));
+    }
 
-            info!("Broadcast user update");
-            Ok(())
-        } else {
-            Err(AppError::DiscoveryError(
-                "Daemon not initialized".to_string(),
-            ))
-        }
+    #[test]
+    fn peers_without_a_key_are_not_trusted() {
+        let trust_store = TrustStore::in_memory("cd".repeat(32));
+        let mut peer = user("Alice");
+        peer.public_key = None;
+
+        assert!(!is_trusted_peer(&trust_store, &peer));
+    }
+
+    #[test]
+    fn peers_presenting_another_key_are_not_trusted() {
+        let trust_store = TrustStore::in_memory("cd".repeat(32));
+        let mut peer = user("Alice");
+        assert!(is_trusted_peer(&trust_store, &peer));
+        assert!(is_trusted_peer(&trust_store, &peer));
+
+        peer.public_key = Some("ef".repeat(32));
+        assert!(!is_trusted_peer(&trust_store, &peer));
     }
 }
@@ -29,26 +29,40 @@ pub enum AppError {
     #[error("Serialization error: {0}")]
     SerializationError(#[from] serde_json::Error),
 
+    /// Error in the chat wire protocol
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
+    /// Peer could not prove its identity or the secure handshake failed
+    #[error("Authentication failed: {0}")]
+    AuthenticationError(String),
+
+    /// Error with persistent storage
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
     /// Error with mDNS operations
     #[error("mDNS error: {0}")]
     MdnsError(String),
 
     /// User not found
     #[error("User not found: {0}")]
-    #[allow(dead_code)]
     UserNotFound(String),
 
     /// File not found
     #[error("File not found: {0}")]
     FileNotFound(String),
 
+    /// Received data does not match its checksum
+    #[error("Integrity check failed: {0}")]
+    IntegrityError(String),
+
     /// Transfer not found
     #[error("Transfer not found: {0}")]
     TransferNotFound(String),
 
     /// Invalid operation
     #[error("Invalid operation: {0}")]
-    #[allow(dead_code)]
     InvalidOperation(String),
 
     /// Other errors
@@ -0,0 +1,287 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::error::{AppError, AppResult};
+use crate::models::{FileTransfer, Message};
+
+/// Current schema version of JSON exports
+const EXPORT_SCHEMA_VERSION: u32 = 1;
+/// Time format used in transcripts
+const TRANSCRIPT_TIME_FORMAT: &str = "%Y-%m-%d %H:%M:%S UTC";
+
+/// File format of a conversation export
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// Full messages and transfers, can be imported back
+    Json,
+    /// Readable transcript with Markdown headings
+    Markdown,
+    /// Readable plain-text transcript
+    Text,
+}
+
+/// A conversation as written to a JSON export
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationExport {
+    pub schema_version: u32,
+    pub exported_at: DateTime<Utc>,
+    /// ID of the user who exported the conversation
+    pub local_user_id: String,
+    /// Peer, group or broadcast channel the conversation is with
+    pub conversation_id: String,
+    /// Display name of the conversation
+    #[serde(default)]
+    pub title: String,
+    pub messages: Vec<Message>,
+    #[serde(default)]
+    pub transfers: Vec<FileTransfer>,
+}
+
+/// A line of a transcript, in time order
+enum TranscriptEntry<'a> {
+    Message(&'a Message),
+    Transfer(&'a FileTransfer),
+}
+
+impl TranscriptEntry<'_> {
+    fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            TranscriptEntry::Message(message) => message.timestamp,
+            TranscriptEntry::Transfer(transfer) => transfer.timestamp,
+        }
+    }
+}
+
+impl ConversationExport {
+    /// Creates an export of a conversation's messages and file transfers
+    pub fn new(
+        local_user_id: &str,
+        conversation_id: &str,
+        title: &str,
+        messages: Vec<Message>,
+        transfers: Vec<FileTransfer>,
+    ) -> Self {
+        ConversationExport {
+            schema_version: EXPORT_SCHEMA_VERSION,
+            exported_at: Utc::now(),
+            local_user_id: local_user_id.to_string(),
+            conversation_id: conversation_id.to_string(),
+            title: title.to_string(),
+            messages,
+            transfers,
+        }
+    }
+
+    /// Reads a JSON export
+    pub fn read(path: &Path) -> AppResult<Self> {
+        let export: ConversationExport = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        if export.schema_version > EXPORT_SCHEMA_VERSION {
+            return Err(AppError::StorageError(format!(
+                "Export schema v{} is newer than supported v{EXPORT_SCHEMA_VERSION}",
+                export.schema_version
+            )));
+        }
+        Ok(export)
+    }
+
+    /// Writes the export to `path`, resolving user IDs with `names` in transcripts
+    pub fn write(&self, path: &Path, format: ExportFormat, names: &HashMap<String, String>) -> AppResult<()> {
+        let tmp_path = path.with_extension("export.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            match format {
+                ExportFormat::Json => serde_json::to_writer_pretty(&mut writer, self)?,
+                ExportFormat::Markdown => writer.write_all(self.markdown(names).as_bytes())?,
+                ExportFormat::Text => writer.write_all(self.text(names).as_bytes())?,
+            }
+            writer.flush()?;
+        }
+        fs::rename(&tmp_path, path).map_err(|e| {
+            AppError::StorageError(format!("Failed to write {}: {e}", path.display()))
+        })
+    }
+
+    /// Messages and transfers merged in time order
+    fn entries(&self) -> Vec<TranscriptEntry<'_>> {
+        let mut entries: Vec<TranscriptEntry<'_>> = self
+            .messages
+            .iter()
+            .map(TranscriptEntry::Message)
+            .chain(self.transfers.iter().map(TranscriptEntry::Transfer))
+            .collect();
+        entries.sort_by_key(TranscriptEntry::timestamp);
+        entries
+    }
+
+    /// Renders the conversation as a Markdown transcript
+    fn markdown(&self, names: &HashMap<String, String>) -> String {
+        let mut out = format!(
+            "# Conversation with {}\n\nExported {}\n",
+            self.title,
+            self.exported_at.format(TRANSCRIPT_TIME_FORMAT)
+        );
+        for entry in self.entries() {
+            let time = entry.timestamp().format(TRANSCRIPT_TIME_FORMAT);
+            match entry {
+                TranscriptEntry::Message(message) => {
+                    out.push_str(&format!("\n### {} · {}\n\n", display_name(names, &message.sender_id), time));
+                    if message.deleted {
+                        out.push_str("*This message was deleted*\n");
+                    } else if message.edited_at.is_some() {
+                        out.push_str(&format!("{} *(edited)*\n", message.content));
+                    } else {
+                        out.push_str(&format!("{}\n", message.content));
+                    }
+                }
+                TranscriptEntry::Transfer(transfer) => {
+                    out.push_str(&format!(
+                        "\n### {} · {}\n\n📎 `{}` ({}, {:?})\n",
+                        display_name(names, &transfer.sender_id),
+                        time,
+                        transfer.file_name,
+                        format_size(transfer.file_size),
+                        transfer.status
+                    ));
+                }
+            }
+        }
+        out
+    }
+
+    /// Renders the conversation as a plain-text transcript
+    fn text(&self, names: &HashMap<String, String>) -> String {
+        let mut out = format!(
+            "Conversation with {}\nExported {}\n\n",
+            self.title,
+            self.exported_at.format(TRANSCRIPT_TIME_FORMAT)
+        );
+        for entry in self.entries() {
+            let time = entry.timestamp().format(TRANSCRIPT_TIME_FORMAT);
+            match entry {
+                TranscriptEntry::Message(message) => {
+                    let content = if message.deleted {
+                        "[This message was deleted]".to_string()
+                    } else if message.edited_at.is_some() {
+                        format!("{} (edited)", message.content)
+                    } else {
+                        message.content.clone()
+                    };
+                    // Indent continuation lines so every entry starts with its timestamp
+                    out.push_str(&format!(
+                        "[{}] {}: {}\n",
+                        time,
+                        display_name(names, &message.sender_id),
+                        content.replace('\n', "\n    ")
+                    ));
+                }
+                TranscriptEntry::Transfer(transfer) => {
+                    out.push_str(&format!(
+                        "[{}] {} sent file {} ({}, {:?})\n",
+                        time,
+                        display_name(names, &transfer.sender_id),
+                        transfer.file_name,
+                        format_size(transfer.file_size),
+                        transfer.status
+                    ));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Name to show for a user ID, falling back to the ID itself
+fn display_name<'a>(names: &'a HashMap<String, String>, user_id: &'a str) -> &'a str {
+    names.get(user_id).map(String::as_str).unwrap_or(user_id)
+}
+
+/// Human-readable file size
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn export() -> ConversationExport {
+        let mut hello = Message::new("alice", "bob", "hello\nhow are you?");
+        hello.timestamp = "2024-05-01T10:00:00Z".parse().unwrap();
+        let mut edited = Message::new("bob", "alice", "fine");
+        edited.timestamp = "2024-05-01T10:01:00Z".parse().unwrap();
+        edited.edited_at = Some(edited.timestamp);
+        let mut deleted = Message::new("alice", "bob", "");
+        deleted.timestamp = "2024-05-01T10:02:00Z".parse().unwrap();
+        deleted.deleted = true;
+
+        ConversationExport::new("alice", "bob", "Bob", vec![hello, edited, deleted], Vec::new())
+    }
+
+    #[test]
+    fn json_exports_read_back() {
+        let path = std::env::temp_dir().join(format!("ip-chat-{}.json", uuid::Uuid::new_v4()));
+        let original = export();
+        original.write(&path, ExportFormat::Json, &HashMap::new()).unwrap();
+
+        let read = ConversationExport::read(&path);
+        let _ = fs::remove_file(&path);
+        let read = read.unwrap();
+        assert_eq!(read.conversation_id, "bob");
+        assert_eq!(read.title, "Bob");
+        let ids: Vec<&str> = read.messages.iter().map(|msg| msg.id.as_str()).collect();
+        let original_ids: Vec<&str> = original.messages.iter().map(|msg| msg.id.as_str()).collect();
+        assert_eq!(ids, original_ids);
+    }
+
+    #[test]
+    fn newer_exports_are_rejected() {
+        let path = std::env::temp_dir().join(format!("ip-chat-{}.json", uuid::Uuid::new_v4()));
+        let mut newer = export();
+        newer.schema_version = EXPORT_SCHEMA_VERSION + 1;
+        newer.write(&path, ExportFormat::Json, &HashMap::new()).unwrap();
+
+        let read = ConversationExport::read(&path);
+        let _ = fs::remove_file(&path);
+        assert!(matches!(read, Err(AppError::StorageError(_))));
+    }
+
+    #[test]
+    fn text_transcripts_show_names_edits_and_deletions() {
+        let names = HashMap::from([("alice".to_string(), "Alice".to_string())]);
+        let text = export().text(&names);
+        let lines: Vec<&str> = text.lines().skip(3).collect();
+
+        assert_eq!(
+            lines,
+            [
+                "[2024-05-01 10:00:00 UTC] Alice: hello",
+                "    how are you?",
+                "[2024-05-01 10:01:00 UTC] bob: fine (edited)",
+                "[2024-05-01 10:02:00 UTC] Alice: [This message was deleted]",
+            ]
+        );
+    }
+
+    #[test]
+    fn sizes_are_human_readable() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MB");
+    }
+}
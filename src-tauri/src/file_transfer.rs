@@ -1,72 +1,105 @@
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use std::collections::HashMap;
-use std::fs::{metadata, File};
-use std::io::{Read, Write};
+use std::fs::{metadata, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener as AsyncTcpListener, TcpStream as AsyncTcpStream};
 use tokio::sync::mpsc;
 use uuid::Uuid;
 
 use crate::error::{AppError, AppResult};
 use crate::models::{FileTransfer, TransferStatus, User};
+use crate::network;
+use crate::secure_channel::{self, ChannelKeys, SecureStream};
+use crate::storage::TransferStore;
 
-const FILE_TRANSFER_PORT: u16 = 8766;
+/// Port the file transfer service listens on by default
+pub const FILE_TRANSFER_PORT: u16 = 8766;
 const CHUNK_SIZE: usize = 1024 * 64; // 64KB chunks
+const CHUNK_CHECKSUM_SIZE: usize = 32; // SHA-256 digest per chunk
+/// Sent by the recipient after the terminator once the whole-file hash matched
+const TRANSFER_VERIFIED: u8 = 1;
+/// Sent by the recipient after the terminator when the file could not be verified
+const TRANSFER_UNVERIFIED: u8 = 0;
+/// How long the sender waits for the recipient to hash the whole file
+const VERIFICATION_TIMEOUT: Duration = Duration::from_secs(5 * 60);
 
 /// Manages file transfers
 pub struct FileTransferManager {
     /// The local user
     local_user: User,
-    /// Map of file transfers by ID
-    transfers: Arc<Mutex<HashMap<String, FileTransfer>>>,
+    /// Persistent store of file transfers by ID
+    transfers: Arc<TransferStore>,
+    /// Keys used to encrypt and authenticate transfer connections
+    channel_keys: Arc<ChannelKeys>,
+    /// Directory incoming files are saved to unless the user picks another path
+    download_dir: PathBuf,
     /// Map of connections by peer ID
     #[allow(dead_code)]
     connections: Arc<Mutex<HashMap<String, AsyncTcpStream>>>,
-    /// Listener for incoming connections
+    /// Listeners for incoming connections, one per IP version unless dual-stack
     #[allow(dead_code)]
-    listener: Option<AsyncTcpListener>,
+    listeners: Vec<AsyncTcpListener>,
     /// Channel for stopping the file transfer service
     #[allow(dead_code)]
     stop_tx: Option<mpsc::Sender<()>>,
 }
 
 impl FileTransferManager {
-    /// Creates a new FileTransferManager instance
-    pub fn new(local_user: User) -> Self {
+    /// Creates a new FileTransferManager instance, loading transfer history from `data_dir`
+    pub fn new(local_user: User, data_dir: &Path, download_dir: PathBuf, channel_keys: Arc<ChannelKeys>) -> Self {
+        let transfers = match TransferStore::open(data_dir) {
+            Ok(store) => store,
+            Err(e) => {
+                error!("Failed to open transfer history, transfers will not be persisted: {e}");
+                TransferStore::in_memory()
+            }
+        };
+
         FileTransferManager {
             local_user,
-            transfers: Arc::new(Mutex::new(HashMap::new())),
+            transfers: Arc::new(transfers),
+            channel_keys,
+            download_dir,
             connections: Arc::new(Mutex::new(HashMap::new())),
-            listener: None,
+            listeners: Vec::new(),
             stop_tx: None,
         }
     }
 
-    /// Starts the file transfer service
-    #[allow(dead_code)]
-    pub async fn start_file_transfer_service(&mut self) -> AppResult<()> {
-        // Bind to the file transfer port using async listener
-        let listener = AsyncTcpListener::bind(format!("0.0.0.0:{FILE_TRANSFER_PORT}"))
+    /// Updates our user as recorded in new transfers, e.g. after the local addresses changed
+    pub fn set_local_user(&mut self, local_user: User) {
+        self.local_user = local_user;
+    }
+
+    /// Starts the file transfer service on `port`, returning the port actually listened on
+    pub async fn start_file_transfer_service(&mut self, port: u16) -> AppResult<u16> {
+        // Bind to the file transfer port on IPv4 and IPv6
+        let (listeners, port) = network::listen_on(port, FILE_TRANSFER_PORT)
             .await
-            .map_err(|e| {
-                AppError::NetworkError(format!("Failed to bind to port {FILE_TRANSFER_PORT}: {e}"))
-            })?;
+            .map_err(|e| AppError::NetworkError(format!("Failed to bind to port {port}: {e}")))?;
+        info!("File transfer service listening on port {port}");
+        self.local_user.file_port = Some(port);
 
         // Set up channel for stopping the service
         let (stop_tx, mut stop_rx) = mpsc::channel::<()>(1);
 
-        // Store listener and stop channel
-        self.listener = Some(listener);
+        // Store listeners and stop channel
+        self.listeners = listeners;
         self.stop_tx = Some(stop_tx);
 
         // Clone necessary values for the task
-        let listener = self.listener.take().unwrap();
+        let listeners = std::mem::take(&mut self.listeners);
         let transfers = Arc::clone(&self.transfers);
         let connections = Arc::clone(&self.connections);
         let local_user = self.local_user.clone();
+        let channel_keys = Arc::clone(&self.channel_keys);
+        let download_dir = self.download_dir.clone();
 
         // Spawn task to handle incoming connections
         tokio::spawn(async move {
@@ -78,7 +111,7 @@ impl FileTransferManager {
                 }
 
                 // Accept incoming connections
-                match listener.accept().await {
+                match network::accept_any(&listeners).await {
                     Ok((stream, addr)) => {
                         debug!("New file transfer connection from: {addr}");
 
@@ -86,6 +119,8 @@ impl FileTransferManager {
                         let transfers_clone = Arc::clone(&transfers);
                         let connections_clone = Arc::clone(&connections);
                         let local_user_clone = local_user.clone();
+                        let channel_keys_clone = Arc::clone(&channel_keys);
+                        let download_dir_clone = download_dir.clone();
 
                         // Spawn task to handle the connection
                         tokio::spawn(async move {
@@ -95,6 +130,8 @@ impl FileTransferManager {
                                 transfers_clone,
                                 connections_clone,
                                 local_user_clone,
+                                channel_keys_clone,
+                                &download_dir_clone,
                             )
                             .await
                             {
@@ -115,7 +152,7 @@ impl FileTransferManager {
         });
 
         info!("File transfer service started");
-        Ok(())
+        Ok(port)
     }
 
     /// Stops the file transfer service
@@ -129,7 +166,7 @@ impl FileTransferManager {
         }
 
         // Clear state
-        self.listener = None;
+        self.listeners.clear();
         self.stop_tx = None;
 
         // Clear connections
@@ -152,11 +189,14 @@ impl FileTransferManager {
         ))
     }
 
+    /// Offers a file to a peer, `file_hash` being its SHA-256 as computed by `hash_file`
     pub async fn send_file_with_peer(
         &mut self,
         peer_id: &str,
         file_path: &str,
+        file_hash: String,
         peer_ip: &str,
+        peer_port: u16,
     ) -> AppResult<FileTransfer> {
         // Check if file exists
         let path = Path::new(file_path);
@@ -174,6 +214,8 @@ impl FileTransferManager {
             recipient_id: peer_id.to_string(),
             sender_ip: Some(self.local_user.ip.clone()),
             recipient_ip: Some(peer_ip.to_string()),
+            sender_port: Some(self.local_user.file_transfer_port()),
+            recipient_port: Some(peer_port),
             file_name: path.file_name().unwrap().to_string_lossy().to_string(),
             file_size: meta.len(),
             source_path: Some(file_path.to_string()),
@@ -182,13 +224,11 @@ impl FileTransferManager {
             bytes_transferred: 0,
             timestamp: chrono::Utc::now(),
             error: None,
+            file_hash: Some(file_hash),
         };
 
         // Store transfer
-        {
-            let mut transfers = self.transfers.lock().unwrap();
-            transfers.insert(transfer.id.clone(), transfer.clone());
-        }
+        self.transfers.insert(transfer.clone())?;
 
         // Send transfer request to peer
         self.send_transfer_request(peer_id, &transfer).await?;
@@ -199,7 +239,7 @@ impl FileTransferManager {
     /// Sends a file transfer request to a peer
     async fn send_transfer_request(
         &self,
-        _peer_id: &str,
+        peer_id: &str,
         transfer: &FileTransfer,
     ) -> AppResult<()> {
         // Get recipient IP address from transfer
@@ -207,11 +247,12 @@ impl FileTransferManager {
             AppError::FileTransferError("Recipient IP address not available".to_string())
         })?;
 
-        // Connect to the peer using their IP address
-        let addr = format!("{recipient_ip}:{FILE_TRANSFER_PORT}");
-        let mut stream = AsyncTcpStream::connect(addr)
+        // Connect to the peer using their IP address and advertised port
+        let addr = network::socket_addr(recipient_ip, transfer.recipient_port.unwrap_or(FILE_TRANSFER_PORT))?;
+        let stream = AsyncTcpStream::connect(addr)
             .await
             .map_err(|e| AppError::NetworkError(format!("Failed to connect to peer: {e}")))?;
+        let mut stream = secure_connect(stream, peer_id, &self.channel_keys).await?;
 
         // Send the transfer request as JSON
         let transfer_json =
@@ -231,19 +272,14 @@ impl FileTransferManager {
 
     /// Accepts a file transfer
     pub async fn accept_transfer(&mut self, transfer_id: &str, save_path: &str) -> AppResult<()> {
-        // Get the transfer
-        let transfer = {
-            let mut transfers = self.transfers.lock().unwrap();
-            let transfer = transfers
-                .get_mut(transfer_id)
-                .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
-
-            // Update transfer status
-            transfer.status = TransferStatus::InProgress;
-            transfer.destination_path = Some(save_path.to_string());
-
-            transfer.clone()
-        };
+        // Get the transfer and update its status
+        let transfer = self
+            .transfers
+            .update(transfer_id, |transfer| {
+                transfer.status = TransferStatus::InProgress;
+                transfer.destination_path = Some(save_path.to_string());
+            })?
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
 
         // Start the file transfer
         self.start_file_transfer(&transfer).await?;
@@ -253,18 +289,11 @@ impl FileTransferManager {
 
     /// Rejects a file transfer
     pub async fn reject_transfer(&mut self, transfer_id: &str) -> AppResult<()> {
-        // Get the transfer
-        let transfer = {
-            let mut transfers = self.transfers.lock().unwrap();
-            let transfer = transfers
-                .get_mut(transfer_id)
-                .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
-
-            // Update transfer status
-            transfer.status = TransferStatus::Rejected;
-
-            transfer.clone()
-        };
+        // Get the transfer and update its status
+        let transfer = self
+            .transfers
+            .update(transfer_id, |transfer| transfer.status = TransferStatus::Rejected)?
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
 
         // Notify the sender
         self.notify_transfer_status(&transfer).await?;
@@ -274,18 +303,11 @@ impl FileTransferManager {
 
     /// Cancels a file transfer
     pub async fn cancel_transfer(&mut self, transfer_id: &str) -> AppResult<()> {
-        // Get the transfer
-        let transfer = {
-            let mut transfers = self.transfers.lock().unwrap();
-            let transfer = transfers
-                .get_mut(transfer_id)
-                .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
-
-            // Update transfer status
-            transfer.status = TransferStatus::Cancelled;
-
-            transfer.clone()
-        };
+        // Get the transfer and update its status
+        let transfer = self
+            .transfers
+            .update(transfer_id, |transfer| transfer.status = TransferStatus::Cancelled)?
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
 
         // Notify the other party
         self.notify_transfer_status(&transfer).await?;
@@ -293,13 +315,67 @@ impl FileTransferManager {
         Ok(())
     }
 
+    /// Resumes a failed or interrupted transfer from where it stopped
+    pub async fn resume_transfer(&mut self, transfer_id: &str) -> AppResult<FileTransfer> {
+        let transfer = self
+            .transfers
+            .get(transfer_id)
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
+
+        if !matches!(
+            transfer.status,
+            TransferStatus::Failed | TransferStatus::Interrupted
+        ) {
+            return Err(AppError::InvalidOperation(format!(
+                "Transfer {transfer_id} is {:?} and cannot be resumed",
+                transfer.status
+            )));
+        }
+
+        // Only the recipient knows how much of the file actually reached the disk
+        if transfer.recipient_id != self.local_user.id {
+            return Err(AppError::InvalidOperation(
+                "Only the recipient can resume a transfer".to_string(),
+            ));
+        }
+
+        let dest_path = transfer.destination_path.as_ref().ok_or_else(|| {
+            AppError::FileTransferError("Destination path not specified".to_string())
+        })?;
+
+        // Never resume past what was written, the last progress save may lag behind
+        let bytes_on_disk = metadata(dest_path).map(|m| m.len()).unwrap_or(0);
+        let offset = transfer
+            .bytes_transferred
+            .min(bytes_on_disk)
+            .min(transfer.file_size);
+
+        info!(
+            "Resuming transfer {} from byte {} of {}",
+            transfer_id, offset, transfer.file_size
+        );
+
+        let transfer = self
+            .transfers
+            .update(transfer_id, |transfer| {
+                transfer.status = TransferStatus::InProgress;
+                transfer.bytes_transferred = offset;
+                transfer.error = None;
+            })?
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
+
+        self.start_file_transfer(&transfer).await?;
+
+        Ok(transfer)
+    }
+
     /// Notifies the other party about a transfer status change
     async fn notify_transfer_status(&self, transfer: &FileTransfer) -> AppResult<()> {
-        // Determine the recipient ID and IP (the other party)
-        let (_recipient_id, recipient_ip) = if transfer.sender_id == self.local_user.id {
-            (&transfer.recipient_id, transfer.recipient_ip.as_ref())
+        // Determine the recipient ID, IP and port (the other party)
+        let (recipient_id, recipient_ip, recipient_port) = if transfer.sender_id == self.local_user.id {
+            (&transfer.recipient_id, transfer.recipient_ip.as_ref(), transfer.recipient_port)
         } else {
-            (&transfer.sender_id, transfer.sender_ip.as_ref())
+            (&transfer.sender_id, transfer.sender_ip.as_ref(), transfer.sender_port)
         };
 
         // Get recipient IP address
@@ -308,10 +384,11 @@ impl FileTransferManager {
         })?;
 
         // Connect to the recipient using their IP address
-        let addr = format!("{recipient_ip}:{FILE_TRANSFER_PORT}");
-        let mut stream = AsyncTcpStream::connect(addr)
+        let addr = network::socket_addr(recipient_ip, recipient_port.unwrap_or(FILE_TRANSFER_PORT))?;
+        let stream = AsyncTcpStream::connect(addr)
             .await
             .map_err(|e| AppError::NetworkError(format!("Failed to connect to peer: {e}")))?;
+        let mut stream = secure_connect(stream, recipient_id, &self.channel_keys).await?;
 
         // Send the status update
         let transfer_json =
@@ -322,6 +399,10 @@ impl FileTransferManager {
             .await
             .map_err(|e| AppError::NetworkError(format!("Failed to send status update: {e}")))?;
 
+        stream.flush().await.map_err(|e| {
+            AppError::NetworkError(format!("Failed to flush status update: {e}"))
+        })?;
+
         Ok(())
     }
 
@@ -331,25 +412,28 @@ impl FileTransferManager {
         let transfer_clone = transfer.clone();
         let transfers = Arc::clone(&self.transfers);
         let local_user = self.local_user.clone();
+        let channel_keys = Arc::clone(&self.channel_keys);
 
         // Spawn task to handle the transfer
         tokio::spawn(async move {
             let result = if transfer_clone.sender_id == local_user.id {
                 // We are the sender
-                send_file_data(&transfer_clone, transfers.clone()).await
+                send_file_data(&transfer_clone, transfers.clone(), &channel_keys).await
             } else {
                 // We are the recipient
-                receive_file_data(&transfer_clone, transfers.clone()).await
+                receive_file_data(&transfer_clone, transfers.clone(), &channel_keys).await
             };
 
             if let Err(e) = result {
                 error!("Error during file transfer: {e}");
 
                 // Update transfer status
-                let mut transfers = transfers.lock().unwrap();
-                if let Some(transfer) = transfers.get_mut(&transfer_clone.id) {
+                let update = transfers.update(&transfer_clone.id, |transfer| {
                     transfer.status = TransferStatus::Failed;
                     transfer.error = Some(e.to_string());
+                });
+                if let Err(e) = update {
+                    error!("Failed to save transfer status: {e}");
                 }
             }
         });
@@ -359,7 +443,7 @@ impl FileTransferManager {
 
     /// Gets transfers for a specific peer
     pub fn get_transfers_for_peer(&self, peer_id: &str) -> Vec<FileTransfer> {
-        let transfers = self.transfers.lock().unwrap();
+        let transfers = self.transfers.lock();
         transfers
             .values()
             .filter(|t| t.sender_id == peer_id || t.recipient_id == peer_id)
@@ -369,7 +453,7 @@ impl FileTransferManager {
 
     /// Gets all transfers
     pub fn get_all_transfers(&self) -> Vec<FileTransfer> {
-        let transfers = self.transfers.lock().unwrap();
+        let transfers = self.transfers.lock();
         transfers.values().cloned().collect()
     }
 }
@@ -377,7 +461,8 @@ impl FileTransferManager {
 /// Sends file data for a transfer
 async fn send_file_data(
     transfer: &FileTransfer,
-    transfers: Arc<Mutex<HashMap<String, FileTransfer>>>,
+    transfers: Arc<TransferStore>,
+    channel_keys: &ChannelKeys,
 ) -> AppResult<()> {
     // Get the source path
     let source_path = transfer
@@ -385,8 +470,9 @@ async fn send_file_data(
         .as_ref()
         .ok_or_else(|| AppError::FileTransferError("Source path not specified".to_string()))?;
 
-    // Open the file
-    let mut file = File::open(source_path).map_err(AppError::IoError)?;
+    // Open the file at the point the recipient already has
+    let offset = transfer.bytes_transferred;
+    let mut file = open_source(source_path, offset)?;
 
     // Get recipient IP address
     let recipient_ip = transfer.recipient_ip.as_ref().ok_or_else(|| {
@@ -394,13 +480,14 @@ async fn send_file_data(
     })?;
 
     // Connect to the recipient
-    let addr = format!("{recipient_ip}:{FILE_TRANSFER_PORT}");
-    let mut stream = AsyncTcpStream::connect(addr)
+    let addr = network::socket_addr(recipient_ip, transfer.recipient_port.unwrap_or(FILE_TRANSFER_PORT))?;
+    let stream = AsyncTcpStream::connect(addr)
         .await
         .map_err(|e| AppError::NetworkError(format!("Failed to connect to recipient: {e}")))?;
+    let mut stream = secure_connect(stream, &transfer.recipient_id, channel_keys).await?;
 
     // Send a special header to indicate this is a file data transfer
-    let header = format!("FILE_DATA:{}", transfer.id);
+    let header = format!("FILE_DATA:{}:{}", transfer.id, offset);
     stream
         .write_all(header.as_bytes())
         .await
@@ -412,55 +499,16 @@ async fn send_file_data(
         .await
         .map_err(|e| AppError::NetworkError(format!("Failed to send header separator: {e}")))?;
 
-    // Read and send file data in chunks
-    let mut buffer = vec![0; CHUNK_SIZE];
-    let mut bytes_sent = 0;
-
-    loop {
-        // Read a chunk from the file
-        let bytes_read = file.read(&mut buffer).map_err(AppError::IoError)?;
-
-        if bytes_read == 0 {
-            // End of file
-            break;
-        }
-
-        // Send the chunk
-        stream
-            .write_all(&buffer[..bytes_read])
-            .await
-            .map_err(|e| AppError::NetworkError(format!("Failed to send file chunk: {e}")))?;
-
-        // Update progress
-        bytes_sent += bytes_read as u64;
-
-        // Update transfer status
-        let updated_transfer = {
-            let mut transfers = transfers.lock().unwrap();
-            if let Some(transfer) = transfers.get_mut(&transfer.id) {
-                transfer.bytes_transferred = bytes_sent;
-
-                if bytes_sent >= transfer.file_size {
-                    transfer.status = TransferStatus::Completed;
-                }
-                transfer.clone()
-            } else {
-                continue;
-            }
-        };
-
-        // Emit progress event to frontend
-        crate::emit_event("file_transfer_update", updated_transfer);
-    }
-
-    info!("File transfer completed: {}", transfer.id);
-    Ok(())
+    // Read and send file data in checksummed chunks
+    let bytes_sent = send_chunks(&mut stream, &mut file, &transfer.id, offset, &transfers).await?;
+    await_verification(&mut stream, &transfer.id, bytes_sent, &transfers).await
 }
 
 /// Receives file data for a transfer
 async fn receive_file_data(
     transfer: &FileTransfer,
-    transfers: Arc<Mutex<HashMap<String, FileTransfer>>>,
+    transfers: Arc<TransferStore>,
+    channel_keys: &ChannelKeys,
 ) -> AppResult<()> {
     // Get the destination path
     let dest_path = transfer
@@ -468,8 +516,9 @@ async fn receive_file_data(
         .as_ref()
         .ok_or_else(|| AppError::FileTransferError("Destination path not specified".to_string()))?;
 
-    // Create the destination file
-    let mut file = File::create(dest_path).map_err(AppError::IoError)?;
+    // Create the destination file, keeping already received data when resuming
+    let offset = transfer.bytes_transferred;
+    let mut file = open_destination(dest_path, offset)?;
 
     // Get sender IP address
     let sender_ip = transfer.sender_ip.as_ref().ok_or_else(|| {
@@ -477,13 +526,14 @@ async fn receive_file_data(
     })?;
 
     // Connect to the sender
-    let addr = format!("{sender_ip}:{FILE_TRANSFER_PORT}");
-    let mut stream = AsyncTcpStream::connect(addr)
+    let addr = network::socket_addr(sender_ip, transfer.sender_port.unwrap_or(FILE_TRANSFER_PORT))?;
+    let stream = AsyncTcpStream::connect(addr)
         .await
         .map_err(|e| AppError::NetworkError(format!("Failed to connect to sender: {e}")))?;
+    let mut stream = secure_connect(stream, &transfer.sender_id, channel_keys).await?;
 
     // Send a request for file data
-    let request = format!("REQUEST_FILE:{}", transfer.id);
+    let request = format!("REQUEST_FILE:{}:{}", transfer.id, offset);
     stream
         .write_all(request.as_bytes())
         .await
@@ -495,61 +545,339 @@ async fn receive_file_data(
         .await
         .map_err(|e| AppError::NetworkError(format!("Failed to send request separator: {e}")))?;
 
-    // Receive and write file data in chunks
+    // Receive, verify and write file data in chunks
+    let result = async {
+        let bytes_received = receive_chunks(&mut stream, &mut file, &transfer.id, offset, &transfers).await?;
+        drop(file);
+        complete_received_file(transfer, dest_path, bytes_received, &transfers).await
+    }
+    .await;
+
+    send_verification(&mut stream, result.is_ok()).await;
+    result
+}
+
+/// Streams the rest of `file` as `[length][SHA-256][data]` chunks followed by a zero-length terminator
+async fn send_chunks<W>(
+    stream: &mut W,
+    file: &mut File,
+    transfer_id: &str,
+    offset: u64,
+    transfers: &TransferStore,
+) -> AppResult<u64>
+where
+    W: AsyncWrite + Unpin,
+{
     let mut buffer = vec![0; CHUNK_SIZE];
-    let mut bytes_received = 0;
+    let mut bytes_sent = offset;
 
     loop {
-        // Receive a chunk
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .map_err(|e| AppError::NetworkError(format!("Failed to receive file chunk: {e}")))?;
+        // Read a chunk from the file
+        let bytes_read = file.read(&mut buffer).map_err(AppError::IoError)?;
 
         if bytes_read == 0 {
             // End of file
             break;
         }
 
-        // Write the chunk to the file
-        file.write_all(&buffer[..bytes_read])
-            .map_err(AppError::IoError)?;
+        // Send the chunk with its checksum
+        let chunk = &buffer[..bytes_read];
+        let checksum = Sha256::digest(chunk);
+        async {
+            stream.write_u32(bytes_read as u32).await?;
+            stream.write_all(&checksum).await?;
+            stream.write_all(chunk).await
+        }
+        .await
+        .map_err(|e| AppError::NetworkError(format!("Failed to send file chunk: {e}")))?;
 
         // Update progress
-        bytes_received += bytes_read as u64;
+        bytes_sent += bytes_read as u64;
 
-        // Update transfer status
-        let updated_transfer = {
-            let mut transfers = transfers.lock().unwrap();
-            if let Some(transfer) = transfers.get_mut(&transfer.id) {
-                transfer.bytes_transferred = bytes_received;
+        // Update transfer status, completion waits for the recipient to verify the file
+        let updated_transfer = transfers.update_progress(transfer_id, |transfer| {
+            transfer.bytes_transferred = bytes_sent;
+        });
 
-                if bytes_received >= transfer.file_size {
-                    transfer.status = TransferStatus::Completed;
-                }
-                transfer.clone()
-            } else {
-                continue;
+        // Emit progress event to frontend
+        if let Some(transfer) = updated_transfer {
+            crate::emit_event("file_transfer_update", transfer);
+        }
+    }
+
+    // Signal the end of the data stream
+    stream
+        .write_u32(0)
+        .await
+        .map_err(|e| AppError::NetworkError(format!("Failed to send end of file: {e}")))?;
+    stream
+        .flush()
+        .await
+        .map_err(|e| AppError::NetworkError(format!("Failed to flush file data: {e}")))?;
+
+    Ok(bytes_sent)
+}
+
+/// Waits for the recipient to confirm the whole-file hash, then marks the transfer completed
+async fn await_verification<S>(
+    stream: &mut S,
+    transfer_id: &str,
+    bytes_sent: u64,
+    transfers: &TransferStore,
+) -> AppResult<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let ack = tokio::time::timeout(VERIFICATION_TIMEOUT, stream.read_u8())
+        .await
+        .map_err(|_| AppError::FileTransferError("Recipient did not confirm the file in time".to_string()))?
+        .map_err(|e| AppError::NetworkError(format!("Recipient did not confirm the file: {e}")))?;
+    if ack != TRANSFER_VERIFIED {
+        return Err(AppError::IntegrityError(
+            "Recipient could not verify the file".to_string(),
+        ));
+    }
+
+    let updated_transfer = transfers.update(transfer_id, |transfer| {
+        transfer.bytes_transferred = bytes_sent;
+        transfer.status = TransferStatus::Completed;
+    })?;
+    if let Some(transfer) = updated_transfer {
+        crate::emit_event("file_transfer_update", transfer);
+    }
+
+    info!("File transfer completed: {}", transfer_id);
+    Ok(())
+}
+
+/// Tells the sender whether the received file matched its hash
+async fn send_verification<W>(stream: &mut W, verified: bool)
+where
+    W: AsyncWrite + Unpin,
+{
+    let ack = if verified { TRANSFER_VERIFIED } else { TRANSFER_UNVERIFIED };
+    let result = async {
+        stream.write_u8(ack).await?;
+        stream.flush().await
+    }
+    .await;
+    if let Err(e) = result {
+        debug!("Failed to send transfer verification: {e}");
+    }
+}
+
+/// Reads checksummed chunks into `file` until the terminator or the connection closes
+async fn receive_chunks<R>(
+    stream: &mut R,
+    file: &mut File,
+    transfer_id: &str,
+    offset: u64,
+    transfers: &TransferStore,
+) -> AppResult<u64>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut checksum = [0u8; CHUNK_CHECKSUM_SIZE];
+    let mut bytes_received = offset;
+
+    loop {
+        // Receive the chunk length, a dropped connection ends the transfer early
+        let chunk_len = match stream.read_u32().await {
+            Ok(0) => break,
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => {
+                return Err(AppError::NetworkError(format!(
+                    "Failed to receive file chunk: {e}"
+                )))
             }
         };
 
+        if chunk_len > CHUNK_SIZE {
+            return Err(AppError::FileTransferError(format!(
+                "Chunk of {chunk_len} bytes exceeds the {CHUNK_SIZE} byte limit"
+            )));
+        }
+
+        // Receive the checksum and the chunk itself
+        let chunk = &mut buffer[..chunk_len];
+        async {
+            stream.read_exact(&mut checksum).await?;
+            stream.read_exact(chunk).await
+        }
+        .await
+        .map_err(|e| AppError::NetworkError(format!("Failed to receive file chunk: {e}")))?;
+
+        if Sha256::digest(&*chunk).as_slice() != checksum {
+            return Err(AppError::IntegrityError(format!(
+                "Checksum mismatch in chunk at byte {bytes_received}"
+            )));
+        }
+
+        // A cancellation or another connection may have moved the transfer on meanwhile
+        let expected = transfers
+            .get(transfer_id)
+            .is_some_and(|transfer| {
+                transfer.status == TransferStatus::InProgress && transfer.bytes_transferred == bytes_received
+            });
+        if !expected {
+            return Err(AppError::FileTransferError(format!(
+                "Transfer {transfer_id} is not expecting data at byte {bytes_received}"
+            )));
+        }
+
+        // Write the chunk to the file
+        file.write_all(chunk).map_err(AppError::IoError)?;
+
+        // Update progress
+        bytes_received += chunk_len as u64;
+
+        // Update transfer status, completion waits for the whole-file hash check
+        let updated_transfer = transfers.update_progress(transfer_id, |transfer| {
+            transfer.bytes_transferred = bytes_received;
+        });
+
         // Emit progress event to frontend
-        crate::emit_event("file_transfer_update", updated_transfer);
+        if let Some(transfer) = updated_transfer {
+            crate::emit_event("file_transfer_update", transfer);
+        }
+    }
+
+    Ok(bytes_received)
+}
+
+/// Verifies a fully received file against the advertised hash and marks the transfer completed
+async fn complete_received_file(
+    transfer: &FileTransfer,
+    dest_path: &str,
+    bytes_received: u64,
+    transfers: &TransferStore,
+) -> AppResult<()> {
+    // The sender closing early leaves a partial file that can be resumed
+    if bytes_received < transfer.file_size {
+        return Err(AppError::FileTransferError(format!(
+            "Connection closed after {} of {} bytes",
+            bytes_received, transfer.file_size
+        )));
+    }
+
+    // Requests without a hash are refused, so this only trips on records from older versions
+    let expected = transfer.file_hash.as_ref().ok_or_else(|| {
+        AppError::IntegrityError(format!("No hash advertised for transfer {}", transfer.id))
+    })?;
+    let actual = hash_file(dest_path).await?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(AppError::IntegrityError(format!(
+            "{} has SHA-256 {actual}, expected {expected}",
+            transfer.file_name
+        )));
+    }
+    debug!("Verified SHA-256 of {}", transfer.file_name);
+
+    let updated_transfer = transfers.update(&transfer.id, |transfer| {
+        transfer.bytes_transferred = bytes_received;
+        transfer.status = TransferStatus::Completed;
+    })?;
+    if let Some(transfer) = updated_transfer {
+        crate::emit_event("file_transfer_update", transfer);
     }
 
     info!("File transfer completed: {}", transfer.id);
     Ok(())
 }
 
+/// Hashes a file on a blocking thread, so the recipient can verify what it received
+pub async fn hash_file(path: &str) -> AppResult<String> {
+    let path = path.to_string();
+    tokio::task::spawn_blocking(move || compute_file_hash(&path))
+        .await
+        .map_err(|e| AppError::FileTransferError(format!("Hashing task failed: {e}")))?
+}
+
+/// Computes the hex-encoded SHA-256 of a file
+fn compute_file_hash(path: &str) -> AppResult<String> {
+    let mut file = File::open(path).map_err(AppError::IoError)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+
+    loop {
+        let bytes_read = file.read(&mut buffer).map_err(AppError::IoError)?;
+        if bytes_read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..bytes_read]);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Opens the source file positioned at `offset`
+fn open_source(source_path: &str, offset: u64) -> AppResult<File> {
+    let mut file = File::open(source_path).map_err(AppError::IoError)?;
+    if offset > 0 {
+        file.seek(SeekFrom::Start(offset)).map_err(AppError::IoError)?;
+    }
+    Ok(file)
+}
+
+/// Opens the destination file for writing, keeping the first `offset` bytes when resuming
+fn open_destination(dest_path: &str, offset: u64) -> AppResult<File> {
+    if offset == 0 {
+        return File::create(dest_path).map_err(AppError::IoError);
+    }
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(dest_path)
+        .map_err(AppError::IoError)?;
+    file.set_len(offset).map_err(AppError::IoError)?;
+    file.seek(SeekFrom::Start(offset)).map_err(AppError::IoError)?;
+    Ok(file)
+}
+
+/// Splits a `<transfer_id>[:<offset>]` header value into the transfer ID and starting offset
+fn parse_transfer_header(value: &str) -> AppResult<(&str, u64)> {
+    match value.split_once(':') {
+        Some((transfer_id, offset)) => {
+            let offset = offset.parse::<u64>().map_err(|e| {
+                AppError::FileTransferError(format!("Invalid transfer offset '{offset}': {e}"))
+            })?;
+            Ok((transfer_id, offset))
+        }
+        None => Ok((value, 0)),
+    }
+}
+
+/// Runs the secure handshake on an outgoing transfer connection and checks who answered
+async fn secure_connect(
+    stream: AsyncTcpStream,
+    expected_user_id: &str,
+    channel_keys: &ChannelKeys,
+) -> AppResult<SecureStream> {
+    let (stream, peer) = secure_channel::connect(stream, channel_keys).await?;
+    if peer.user_id != expected_user_id {
+        return Err(AppError::AuthenticationError(format!(
+            "Expected {expected_user_id}, but the peer is {}",
+            peer.user_id
+        )));
+    }
+    Ok(stream)
+}
+
 /// Handles an incoming file transfer connection
-#[allow(dead_code)]
 async fn handle_file_connection(
     stream: AsyncTcpStream,
-    _addr: SocketAddr,
-    transfers: Arc<Mutex<HashMap<String, FileTransfer>>>,
+    addr: SocketAddr,
+    transfers: Arc<TransferStore>,
     _connections: Arc<Mutex<HashMap<String, AsyncTcpStream>>>,
     local_user: User,
+    channel_keys: Arc<ChannelKeys>,
+    download_dir: &Path,
 ) -> AppResult<()> {
+    // No request is read until the peer has authenticated over an encrypted channel
+    let (stream, peer) = secure_channel::accept(stream, &channel_keys).await?;
+
     // Read the first line to determine the type of request
     let mut line = String::new();
     let mut reader = tokio::io::BufReader::new(stream);
@@ -562,116 +890,130 @@ async fn handle_file_connection(
 
     if line.starts_with("FILE_DATA:") {
         // This is a file data transfer
-        let transfer_id = line.strip_prefix("FILE_DATA:").unwrap();
+        let (transfer_id, offset) = parse_transfer_header(line.strip_prefix("FILE_DATA:").unwrap())?;
 
         // Get the transfer
-        let transfer = {
-            let transfers = transfers.lock().unwrap();
-            transfers
-                .get(transfer_id)
-                .cloned()
-                .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?
-        };
+        let transfer = transfers
+            .get(transfer_id)
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
+
+        if transfer.sender_id != peer.user_id {
+            return Err(AppError::AuthenticationError(format!(
+                "{} at {addr} is not the sender of transfer {transfer_id}",
+                peer.user_id
+            )));
+        }
 
         // We are the recipient, receive the file data
         if transfer.recipient_id == local_user.id {
+            // Data is only taken for a transfer the user accepted, from where it stands
+            if transfer.status != TransferStatus::InProgress {
+                return Err(AppError::InvalidOperation(format!(
+                    "Transfer {transfer_id} is {:?}, not accepting data",
+                    transfer.status
+                )));
+            }
+            if offset != transfer.bytes_transferred {
+                return Err(AppError::FileTransferError(format!(
+                    "Data for transfer {transfer_id} starts at byte {offset}, expected {}",
+                    transfer.bytes_transferred
+                )));
+            }
+
             // Read the rest of the data and write to file
             let dest_path = transfer.destination_path.as_ref().ok_or_else(|| {
                 AppError::FileTransferError("Destination path not specified".to_string())
             })?;
 
-            let mut file = File::create(dest_path).map_err(AppError::IoError)?;
-            let mut buffer = vec![0; CHUNK_SIZE];
-            let mut bytes_received = 0;
-
-            loop {
-                let bytes_read = reader.read(&mut buffer).await.map_err(|e| {
-                    AppError::NetworkError(format!("Failed to receive file chunk: {e}"))
-                })?;
-
-                if bytes_read == 0 {
-                    break;
-                }
-
-                file.write_all(&buffer[..bytes_read])
-                    .map_err(AppError::IoError)?;
-
-                bytes_received += bytes_read as u64;
-
-                // Update transfer status
-                let updated_transfer = {
-                    let mut transfers = transfers.lock().unwrap();
-                    if let Some(transfer) = transfers.get_mut(transfer_id) {
-                        transfer.bytes_transferred = bytes_received;
-                        if bytes_received >= transfer.file_size {
-                            transfer.status = TransferStatus::Completed;
-                        }
-                        Some(transfer.clone())
-                    } else {
-                        None
-                    }
-                };
+            let mut file = open_destination(dest_path, offset)?;
+            let result = async {
+                let bytes_received =
+                    receive_chunks(&mut reader, &mut file, transfer_id, offset, &transfers).await?;
+                complete_received_file(&transfer, dest_path, bytes_received, &transfers).await
+            }
+            .await;
+            send_verification(&mut reader, result.is_ok()).await;
 
-                // Emit progress event to frontend
-                if let Some(transfer) = updated_transfer {
+            if let Err(e) = result {
+                // Record the failure so the transfer can be resumed
+                let update = transfers.update(transfer_id, |transfer| {
+                    transfer.status = TransferStatus::Failed;
+                    transfer.error = Some(e.to_string());
+                });
+                if let Some(transfer) = update.ok().flatten() {
                     crate::emit_event("file_transfer_update", transfer);
                 }
+                return Err(e);
             }
-
-            info!("File transfer completed: {}", transfer_id);
         }
     } else if line.starts_with("REQUEST_FILE:") {
-        // This is a request for file data
-        let transfer_id = line.strip_prefix("REQUEST_FILE:").unwrap();
+        // This is a request for file data, optionally starting at a byte offset
+        let (transfer_id, offset) = parse_transfer_header(line.strip_prefix("REQUEST_FILE:").unwrap())?;
 
         // Get the transfer
-        let transfer = {
-            let transfers = transfers.lock().unwrap();
-            transfers
-                .get(transfer_id)
-                .cloned()
-                .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?
-        };
+        let transfer = transfers
+            .get(transfer_id)
+            .ok_or_else(|| AppError::TransferNotFound(transfer_id.to_string()))?;
+
+        if transfer.recipient_id != peer.user_id {
+            return Err(AppError::AuthenticationError(format!(
+                "{} at {addr} is not the recipient of transfer {transfer_id}",
+                peer.user_id
+            )));
+        }
 
         // We are the sender, send the file data
         if transfer.sender_id == local_user.id {
+            if matches!(
+                transfer.status,
+                TransferStatus::Cancelled | TransferStatus::Rejected
+            ) {
+                return Err(AppError::InvalidOperation(format!(
+                    "Transfer {transfer_id} is {:?}",
+                    transfer.status
+                )));
+            }
+            if offset > transfer.file_size {
+                return Err(AppError::FileTransferError(format!(
+                    "Requested offset {offset} is past the end of the file ({} bytes)",
+                    transfer.file_size
+                )));
+            }
+
             let source_path = transfer.source_path.as_ref().ok_or_else(|| {
                 AppError::FileTransferError("Source path not specified".to_string())
             })?;
 
-            let mut file = File::open(source_path).map_err(AppError::IoError)?;
-            let mut buffer = vec![0; CHUNK_SIZE];
-            let mut bytes_sent = 0;
+            let mut file = open_source(source_path, offset)?;
+
+            if offset > 0 {
+                info!("Resuming transfer {} from byte {}", transfer_id, offset);
+            }
+            transfers.update_progress(transfer_id, |transfer| {
+                transfer.status = TransferStatus::InProgress;
+                transfer.bytes_transferred = offset;
+                transfer.error = None;
+            });
 
             // Get the underlying stream for writing
             let mut stream = reader.into_inner();
+            let result = async {
+                let bytes_sent = send_chunks(&mut stream, &mut file, transfer_id, offset, &transfers).await?;
+                await_verification(&mut stream, transfer_id, bytes_sent, &transfers).await
+            }
+            .await;
 
-            loop {
-                let bytes_read = file.read(&mut buffer).map_err(AppError::IoError)?;
-
-                if bytes_read == 0 {
-                    break;
-                }
-
-                stream.write_all(&buffer[..bytes_read]).await.map_err(|e| {
-                    AppError::NetworkError(format!("Failed to send file chunk: {e}"))
-                })?;
-
-                bytes_sent += bytes_read as u64;
-
-                // Update transfer status
-                {
-                    let mut transfers = transfers.lock().unwrap();
-                    if let Some(transfer) = transfers.get_mut(transfer_id) {
-                        transfer.bytes_transferred = bytes_sent;
-                        if bytes_sent >= transfer.file_size {
-                            transfer.status = TransferStatus::Completed;
-                        }
-                    }
+            if let Err(e) = result {
+                // The recipient resumes from what it has, this side only records the failure
+                let update = transfers.update(transfer_id, |transfer| {
+                    transfer.status = TransferStatus::Failed;
+                    transfer.error = Some(e.to_string());
+                });
+                if let Some(transfer) = update.ok().flatten() {
+                    crate::emit_event("file_transfer_update", transfer);
                 }
+                return Err(e);
             }
-
-            info!("File transfer completed: {}", transfer_id);
         }
     } else {
         // Try to parse as JSON transfer request
@@ -686,7 +1028,7 @@ async fn handle_file_connection(
         full_buffer.extend(buffer);
 
         // Parse the transfer request
-        let transfer: FileTransfer =
+        let mut transfer: FileTransfer =
             serde_json::from_slice(&full_buffer).map_err(AppError::SerializationError)?;
 
         info!(
@@ -694,12 +1036,180 @@ async fn handle_file_connection(
             transfer.file_name, transfer.sender_id
         );
 
-        // Store the transfer if we are the recipient
+        // Store the transfer if we are the recipient and it comes from its sender
         if transfer.recipient_id == local_user.id {
-            let mut transfers = transfers.lock().unwrap();
-            transfers.insert(transfer.id.clone(), transfer.clone());
+            if transfer.sender_id != peer.user_id {
+                warn!(
+                    "Ignoring transfer request for {} sent by {} at {addr}",
+                    transfer.sender_id, peer.user_id
+                );
+                return Ok(());
+            }
+
+            // A transfer we already know about can only be cancelled by its sender
+            if let Some(existing) = transfers.get(&transfer.id) {
+                if transfer.status == TransferStatus::Cancelled {
+                    if let Some(updated) = transfers.update(&transfer.id, |existing| {
+                        existing.status = TransferStatus::Cancelled;
+                    })? {
+                        crate::emit_event("file_transfer_update", updated);
+                    }
+                } else {
+                    debug!("Ignoring repeated request for transfer {}", existing.id);
+                }
+                return Ok(());
+            }
+
+            // Without a hash the received file could not be verified
+            if transfer.file_hash.is_none() {
+                return Err(AppError::IntegrityError(format!(
+                    "Transfer request {} from {} has no file hash",
+                    transfer.id, peer.user_id
+                )));
+            }
+
+            // Only the file name is taken from the sender, where it is saved and how far it got are ours
+            let file_name = Path::new(&transfer.file_name)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .ok_or_else(|| {
+                    AppError::FileTransferError(format!("Invalid file name: {}", transfer.file_name))
+                })?;
+            transfer.destination_path = Some(download_dir.join(&file_name).to_string_lossy().to_string());
+            transfer.file_name = file_name;
+            transfer.source_path = None;
+            transfer.sender_ip = Some(network::format_address(addr));
+            transfer.status = TransferStatus::Pending;
+            transfer.bytes_transferred = 0;
+            transfer.error = None;
+            transfers.insert(transfer)?;
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("ip-chat-{}-{name}", Uuid::new_v4()))
+    }
+
+    fn transfer(source_path: &Path, dest_path: &Path, file_size: u64, file_hash: String) -> FileTransfer {
+        FileTransfer {
+            id: "transfer".to_string(),
+            sender_id: "sender".to_string(),
+            recipient_id: "recipient".to_string(),
+            sender_ip: None,
+            recipient_ip: None,
+            sender_port: None,
+            recipient_port: None,
+            file_name: "data.bin".to_string(),
+            file_size,
+            source_path: Some(source_path.to_string_lossy().to_string()),
+            destination_path: Some(dest_path.to_string_lossy().to_string()),
+            status: TransferStatus::InProgress,
+            bytes_transferred: 0,
+            timestamp: Utc::now(),
+            error: None,
+            file_hash: Some(file_hash),
+        }
+    }
+
+    /// Streams `source` to a recipient expecting `advertised_hash`, returning both sides' results
+    async fn run_transfer(advertised_hash: Option<String>) -> (AppResult<()>, FileTransfer, FileTransfer) {
+        let source = temp_path("source");
+        let dest = temp_path("dest");
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 100).map(|i| i as u8).collect();
+        std::fs::write(&source, &data).unwrap();
+        let file_hash = compute_file_hash(&source.to_string_lossy()).unwrap();
+
+        let sent = transfer(&source, &dest, data.len() as u64, file_hash.clone());
+        let mut received = sent.clone();
+        received.file_hash = Some(advertised_hash.unwrap_or(file_hash));
+
+        let sender_store = TransferStore::in_memory();
+        sender_store.insert(sent.clone()).unwrap();
+        let recipient_store = TransferStore::in_memory();
+        recipient_store.insert(received.clone()).unwrap();
+
+        let (mut sender_end, mut recipient_end) = tokio::io::duplex(CHUNK_SIZE);
+        let sender = async {
+            let mut file = open_source(sent.source_path.as_deref().unwrap(), 0)?;
+            let bytes_sent = send_chunks(&mut sender_end, &mut file, &sent.id, 0, &sender_store).await?;
+            await_verification(&mut sender_end, &sent.id, bytes_sent, &sender_store).await
+        };
+        let recipient = async {
+            let dest_path = received.destination_path.as_deref().unwrap();
+            let mut file = open_destination(dest_path, 0).unwrap();
+            let result = async {
+                let bytes_received =
+                    receive_chunks(&mut recipient_end, &mut file, &received.id, 0, &recipient_store).await?;
+                complete_received_file(&received, dest_path, bytes_received, &recipient_store).await
+            }
+            .await;
+            send_verification(&mut recipient_end, result.is_ok()).await;
+        };
+        let (result, ()) = tokio::join!(sender, recipient);
+
+        let _ = std::fs::remove_file(&source);
+        let _ = std::fs::remove_file(&dest);
+        (
+            result,
+            sender_store.get(&sent.id).unwrap(),
+            recipient_store.get(&received.id).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn sender_completes_once_the_recipient_verified_the_file() {
+        let (result, sent, received) = run_transfer(None).await;
+
+        assert!(result.is_ok());
+        assert_eq!(sent.status, TransferStatus::Completed);
+        assert_eq!(sent.bytes_transferred, sent.file_size);
+        assert_eq!(received.status, TransferStatus::Completed);
+    }
+
+    #[tokio::test]
+    async fn sender_does_not_complete_when_the_recipient_rejects_the_hash() {
+        let (result, sent, received) = run_transfer(Some("0".repeat(64))).await;
+
+        assert!(matches!(result, Err(AppError::IntegrityError(_))));
+        assert_ne!(sent.status, TransferStatus::Completed);
+        assert_ne!(received.status, TransferStatus::Completed);
+    }
+
+    #[tokio::test]
+    async fn sender_does_not_complete_without_an_answer() {
+        let store = TransferStore::in_memory();
+        let source = temp_path("source");
+        store.insert(transfer(&source, &source, 0, String::new())).unwrap();
+
+        let (mut sender_end, recipient_end) = tokio::io::duplex(64);
+        drop(recipient_end);
+        let result = await_verification(&mut sender_end, "transfer", 0, &store).await;
+
+        assert!(result.is_err());
+        assert_eq!(store.get("transfer").unwrap().status, TransferStatus::InProgress);
+    }
+
+    #[tokio::test]
+    async fn files_without_an_advertised_hash_are_not_completed() {
+        let dest = temp_path("dest");
+        std::fs::write(&dest, b"data").unwrap();
+        let mut received = transfer(&dest, &dest, 4, String::new());
+        received.file_hash = None;
+        let store = TransferStore::in_memory();
+        store.insert(received.clone()).unwrap();
+
+        let result = complete_received_file(&received, &dest.to_string_lossy(), 4, &store).await;
+
+        assert!(matches!(result, Err(AppError::IntegrityError(_))));
+        assert_eq!(store.get("transfer").unwrap().status, TransferStatus::InProgress);
+        let _ = std::fs::remove_file(&dest);
+    }
+}
@@ -0,0 +1,224 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::{AppError, AppResult};
+use crate::models::Group;
+use crate::storage::JsonSnapshot;
+
+const GROUPS_FILE: &str = "groups.json";
+const GROUPS_SCHEMA_VERSION: u32 = 1;
+
+/// Known groups as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupSnapshot {
+    groups: Vec<Group>,
+}
+
+/// Groups by ID, saved as a JSON snapshot in the app data dir.
+///
+/// Groups the local user has left are kept so their history stays readable
+/// and they can be joined again.
+pub struct GroupStore {
+    groups: Mutex<HashMap<String, Group>>,
+    snapshot: Option<JsonSnapshot<GroupSnapshot>>,
+}
+
+impl GroupStore {
+    /// Creates a store that only keeps groups in memory
+    pub fn in_memory() -> Self {
+        GroupStore {
+            groups: Mutex::new(HashMap::new()),
+            snapshot: None,
+        }
+    }
+
+    /// Opens the persistent store under `data_dir`
+    pub fn open(data_dir: &Path) -> AppResult<Self> {
+        let snapshot: JsonSnapshot<GroupSnapshot> =
+            JsonSnapshot::open(data_dir, GROUPS_FILE, "Group store", GROUPS_SCHEMA_VERSION)?;
+
+        let mut groups = HashMap::new();
+        if let Some(saved) = snapshot.load()? {
+            for mut group in saved.groups {
+                invite_members(&mut group);
+                groups.insert(group.id.clone(), group);
+            }
+        }
+
+        info!("Loaded {} group(s)", groups.len());
+        Ok(GroupStore {
+            groups: Mutex::new(groups),
+            snapshot: Some(snapshot),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Group>> {
+        self.groups.lock().unwrap()
+    }
+
+    /// All known groups, oldest first
+    pub fn list(&self) -> Vec<Group> {
+        let mut groups: Vec<Group> = self.lock().values().cloned().collect();
+        groups.sort_by_key(|group| group.created_at);
+        groups
+    }
+
+    /// Looks up a group by ID
+    pub fn get(&self, group_id: &str) -> Option<Group> {
+        self.lock().get(group_id).cloned()
+    }
+
+    /// Adds a group, returning `false` if one with the same ID is already known
+    pub fn insert(&self, group: &Group) -> AppResult<bool> {
+        {
+            let mut groups = self.lock();
+            if groups.contains_key(&group.id) {
+                return Ok(false);
+            }
+            let mut group = group.clone();
+            invite_members(&mut group);
+            groups.insert(group.id.clone(), group);
+        }
+
+        self.save()?;
+        Ok(true)
+    }
+
+    /// Adds `user_id` to a group's members, returning the group if it changed
+    pub fn add_member(&self, group_id: &str, user_id: &str) -> AppResult<Option<Group>> {
+        let updated = {
+            let mut groups = self.lock();
+            let group = groups
+                .get_mut(group_id)
+                .ok_or_else(|| AppError::InvalidOperation(format!("Unknown group {group_id}")))?;
+            if group.has_member(user_id) {
+                return Ok(None);
+            }
+            group.members.push(user_id.to_string());
+            group.clone()
+        };
+
+        self.save()?;
+        Ok(Some(updated))
+    }
+
+    /// Removes `user_id` from a group's members, returning the group if it changed
+    pub fn remove_member(&self, group_id: &str, user_id: &str) -> AppResult<Option<Group>> {
+        let updated = {
+            let mut groups = self.lock();
+            let group = groups
+                .get_mut(group_id)
+                .ok_or_else(|| AppError::InvalidOperation(format!("Unknown group {group_id}")))?;
+            if !group.has_member(user_id) {
+                return Ok(None);
+            }
+            group.members.retain(|member| member != user_id);
+            group.clone()
+        };
+
+        self.save()?;
+        Ok(Some(updated))
+    }
+
+    /// Writes the known groups to disk
+    fn save(&self) -> AppResult<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+
+        // Holding the lock while writing also serializes concurrent saves
+        let groups = self.lock();
+        snapshot.save(&GroupSnapshot {
+            groups: groups.values().cloned().collect(),
+        })
+    }
+}
+
+/// Counts the members of a group as invited, for groups created before invites were kept
+fn invite_members(group: &mut Group) {
+    for member in &group.members {
+        if !group.invited.contains(member) {
+            group.invited.push(member.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    fn group(id: &str, members: &[&str]) -> Group {
+        let members: Vec<String> = members.iter().map(|member| member.to_string()).collect();
+        Group {
+            id: id.to_string(),
+            name: "Lunch".to_string(),
+            invited: members.clone(),
+            members,
+            created_by: "alice".to_string(),
+            created_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn groups_are_only_added_once() {
+        let store = GroupStore::in_memory();
+        assert!(store.insert(&group("group-1", &["alice", "bob"])).unwrap());
+
+        let mut renamed = group("group-1", &["alice"]);
+        renamed.name = "Dinner".to_string();
+        assert!(!store.insert(&renamed).unwrap());
+        assert_eq!(store.get("group-1").unwrap().name, "Lunch");
+    }
+
+    #[test]
+    fn members_come_and_go_but_stay_invited() {
+        let store = GroupStore::in_memory();
+        store.insert(&group("group-1", &["alice", "bob"])).unwrap();
+
+        assert!(store.remove_member("group-1", "bob").unwrap().is_some());
+        assert!(store.remove_member("group-1", "bob").unwrap().is_none());
+        let left = store.get("group-1").unwrap();
+        assert!(!left.has_member("bob"));
+        assert!(left.is_invited("bob"));
+
+        assert!(store.add_member("group-1", "bob").unwrap().is_some());
+        assert!(store.add_member("group-1", "bob").unwrap().is_none());
+        assert!(store.add_member("group-2", "bob").is_err());
+    }
+
+    #[test]
+    fn groups_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("ip-chat-groups-{}", Uuid::new_v4()));
+        {
+            let store = GroupStore::open(&dir).unwrap();
+            store.insert(&group("group-1", &["alice", "bob", "carol"])).unwrap();
+            store.remove_member("group-1", "carol").unwrap();
+        }
+
+        let store = GroupStore::open(&dir).unwrap();
+        let group = store.get("group-1").unwrap();
+        assert_eq!(group.members, vec!["alice", "bob"]);
+        assert!(group.is_invited("carol"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn members_of_older_groups_count_as_invited() {
+        let dir = std::env::temp_dir().join(format!("ip-chat-groups-{}", Uuid::new_v4()));
+        let mut legacy = serde_json::to_value(group("group-1", &["alice", "bob"])).unwrap();
+        legacy.as_object_mut().unwrap().remove("invited");
+        std::fs::create_dir_all(&dir).unwrap();
+        let snapshot = serde_json::json!({ "schemaVersion": 1, "groups": [legacy] });
+        std::fs::write(dir.join(GROUPS_FILE), snapshot.to_string()).unwrap();
+
+        let store = GroupStore::open(&dir).unwrap();
+        assert!(store.get("group-1").unwrap().is_invited("bob"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
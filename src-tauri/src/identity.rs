@@ -0,0 +1,364 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::info;
+use rand_core::OsRng;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256, Sha512};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::error::{AppError, AppResult};
+use crate::models::{Message, MessageDeletion, MessageEdit};
+
+const IDENTITY_FILE: &str = "identity.json";
+const IDENTITY_VERSION: u32 = 1;
+/// Number of public key hash bytes used in the device fingerprint
+const FINGERPRINT_BYTES: usize = 16;
+/// Domain separator for message signatures
+const MESSAGE_SIGNATURE_CONTEXT: &[u8] = b"ip-chat message v1";
+/// Domain separator for message edit signatures
+const EDIT_SIGNATURE_CONTEXT: &[u8] = b"ip-chat message edit v1";
+/// Domain separator for message deletion signatures
+const DELETION_SIGNATURE_CONTEXT: &[u8] = b"ip-chat message deletion v1";
+/// Domain separator for discovery beacon signatures
+const BEACON_SIGNATURE_CONTEXT: &[u8] = b"ip-chat discovery beacon v1";
+/// Domain separator for safety number hashes
+const SAFETY_NUMBER_CONTEXT: &[u8] = b"ip-chat safety number v1";
+/// Number of five-digit groups in a safety number
+const SAFETY_NUMBER_GROUPS: usize = 12;
+
+/// Fields of a message covered by the sender's signature
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignedMessageFields<'a> {
+    id: &'a str,
+    sender_id: &'a str,
+    recipient_id: &'a str,
+    content: &'a str,
+    timestamp: &'a DateTime<Utc>,
+    /// Optional fields are left out when unset so signatures match older builds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    group_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_to: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_root: Option<&'a str>,
+}
+
+/// Fields of a message edit covered by the sender's signature
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignedEditFields<'a> {
+    message_id: &'a str,
+    sender_id: &'a str,
+    content: &'a str,
+    edited_at: &'a DateTime<Utc>,
+}
+
+/// Fields of a message deletion covered by the sender's signature
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SignedDeletionFields<'a> {
+    message_id: &'a str,
+    sender_id: &'a str,
+    deleted_at: &'a DateTime<Utc>,
+}
+
+/// On-disk representation of the device identity
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IdentityFile {
+    version: u32,
+    /// Hex-encoded Ed25519 secret key
+    secret_key: String,
+}
+
+/// Long-term Ed25519 identity of this device
+pub struct DeviceIdentity {
+    signing_key: SigningKey,
+}
+
+impl DeviceIdentity {
+    /// Loads the identity from `data_dir`, generating and saving a new one on first run
+    pub fn load_or_create(data_dir: &Path) -> AppResult<Self> {
+        let path = data_dir.join(IDENTITY_FILE);
+
+        if path.exists() {
+            let identity_file: IdentityFile = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            if identity_file.version > IDENTITY_VERSION {
+                return Err(AppError::StorageError(format!(
+                    "Identity file version {} is newer than supported {IDENTITY_VERSION}",
+                    identity_file.version
+                )));
+            }
+
+            let secret: [u8; 32] = hex::decode(&identity_file.secret_key)
+                .ok()
+                .and_then(|bytes| bytes.try_into().ok())
+                .ok_or_else(|| AppError::StorageError("Corrupt identity key".to_string()))?;
+
+            let identity = DeviceIdentity {
+                signing_key: SigningKey::from_bytes(&secret),
+            };
+            info!("Loaded device identity {}", identity.fingerprint());
+            return Ok(identity);
+        }
+
+        let identity = Self::generate();
+        let identity_file = IdentityFile {
+            version: IDENTITY_VERSION,
+            secret_key: hex::encode(identity.signing_key.to_bytes()),
+        };
+
+        fs::create_dir_all(data_dir)?;
+        let mut file = create_private(&path)?;
+        file.write_all(serde_json::to_string_pretty(&identity_file)?.as_bytes())?;
+        file.sync_all()?;
+
+        info!("Generated new device identity {}", identity.fingerprint());
+        Ok(identity)
+    }
+
+    /// Generates a fresh identity that is not saved anywhere
+    pub fn generate() -> Self {
+        DeviceIdentity {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Hex-encoded public key, as advertised to peers
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Short fingerprint of the public key
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.signing_key.verifying_key())
+    }
+
+    /// User ID derived from the public key fingerprint
+    pub fn user_id(&self) -> String {
+        format!("user-{}", self.fingerprint())
+    }
+
+    /// Signs `data` with the identity key
+    pub fn sign(&self, data: &[u8]) -> Signature {
+        self.signing_key.sign(data)
+    }
+
+    /// Signs a message we are about to send, filling in its `signature`
+    pub fn sign_message(&self, message: &mut Message) -> AppResult<()> {
+        let signature = self.sign(&message_signing_bytes(message)?);
+        message.signature = Some(hex::encode(signature.to_bytes()));
+        Ok(())
+    }
+
+    /// Signs an edit of one of our messages, filling in its `signature`
+    pub fn sign_edit(&self, edit: &mut MessageEdit) -> AppResult<()> {
+        let signature = self.sign(&edit_signing_bytes(edit)?);
+        edit.signature = Some(hex::encode(signature.to_bytes()));
+        Ok(())
+    }
+
+    /// Signs a deletion of one of our messages, filling in its `signature`
+    pub fn sign_deletion(&self, deletion: &mut MessageDeletion) -> AppResult<()> {
+        let signature = self.sign(&deletion_signing_bytes(deletion)?);
+        deletion.signature = Some(hex::encode(signature.to_bytes()));
+        Ok(())
+    }
+
+    /// Signs the serialized payload of a discovery beacon, returning the hex-encoded signature
+    pub fn sign_beacon(&self, payload: &str) -> String {
+        let signature = self.sign(&[BEACON_SIGNATURE_CONTEXT, payload.as_bytes()].concat());
+        hex::encode(signature.to_bytes())
+    }
+}
+
+/// Parses a hex-encoded Ed25519 public key
+pub fn parse_public_key(public_key_hex: &str) -> AppResult<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(public_key_hex)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| AppError::InvalidOperation("Malformed public key".to_string()))?;
+
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|e| AppError::InvalidOperation(format!("Invalid public key: {e}")))
+}
+
+/// Checks that `signature` over `data` was made by `public_key`
+pub fn verify_signature(public_key: &VerifyingKey, data: &[u8], signature: &[u8]) -> AppResult<()> {
+    let signature = Signature::from_slice(signature)
+        .map_err(|e| AppError::AuthenticationError(format!("Malformed signature: {e}")))?;
+
+    public_key
+        .verify(data, &signature)
+        .map_err(|_| AppError::AuthenticationError("Signature does not match".to_string()))
+}
+
+/// Checks that `message` was signed by the holder of `public_key_hex`
+pub fn verify_message(message: &Message, public_key_hex: &str) -> AppResult<()> {
+    verify_hex_signature(message.signature.as_deref(), &message_signing_bytes(message)?, public_key_hex)
+}
+
+/// Checks that `edit` was signed by the holder of `public_key_hex`
+pub fn verify_edit(edit: &MessageEdit, public_key_hex: &str) -> AppResult<()> {
+    verify_hex_signature(edit.signature.as_deref(), &edit_signing_bytes(edit)?, public_key_hex)
+}
+
+/// Checks that `deletion` was signed by the holder of `public_key_hex`
+pub fn verify_deletion(deletion: &MessageDeletion, public_key_hex: &str) -> AppResult<()> {
+    verify_hex_signature(deletion.signature.as_deref(), &deletion_signing_bytes(deletion)?, public_key_hex)
+}
+
+/// Checks that a discovery beacon payload was signed by the holder of `public_key_hex`
+pub fn verify_beacon(payload: &str, signature: &str, public_key_hex: &str) -> AppResult<()> {
+    let data = [BEACON_SIGNATURE_CONTEXT, payload.as_bytes()].concat();
+    verify_hex_signature(Some(signature), &data, public_key_hex)
+}
+
+/// Checks an optional hex-encoded signature over `data`
+fn verify_hex_signature(signature: Option<&str>, data: &[u8], public_key_hex: &str) -> AppResult<()> {
+    let signature = signature
+        .ok_or_else(|| AppError::AuthenticationError("Not signed".to_string()))?;
+    let signature = hex::decode(signature)
+        .map_err(|e| AppError::AuthenticationError(format!("Malformed signature: {e}")))?;
+
+    let public_key = parse_public_key(public_key_hex)?;
+    verify_signature(&public_key, data, &signature)
+}
+
+/// Canonical bytes a message signature is computed over
+fn message_signing_bytes(message: &Message) -> AppResult<Vec<u8>> {
+    let fields = SignedMessageFields {
+        id: &message.id,
+        sender_id: &message.sender_id,
+        recipient_id: &message.recipient_id,
+        content: &message.content,
+        timestamp: &message.timestamp,
+        group_id: message.group_id.as_deref(),
+        reply_to: message.reply_to.as_deref(),
+        thread_root: message.thread_root.as_deref(),
+    };
+    Ok([MESSAGE_SIGNATURE_CONTEXT, &serde_json::to_vec(&fields)?].concat())
+}
+
+/// Canonical bytes an edit signature is computed over
+fn edit_signing_bytes(edit: &MessageEdit) -> AppResult<Vec<u8>> {
+    let fields = SignedEditFields {
+        message_id: &edit.message_id,
+        sender_id: &edit.sender_id,
+        content: &edit.content,
+        edited_at: &edit.edited_at,
+    };
+    Ok([EDIT_SIGNATURE_CONTEXT, &serde_json::to_vec(&fields)?].concat())
+}
+
+/// Canonical bytes a deletion signature is computed over
+fn deletion_signing_bytes(deletion: &MessageDeletion) -> AppResult<Vec<u8>> {
+    let fields = SignedDeletionFields {
+        message_id: &deletion.message_id,
+        sender_id: &deletion.sender_id,
+        deleted_at: &deletion.deleted_at,
+    };
+    Ok([DELETION_SIGNATURE_CONTEXT, &serde_json::to_vec(&fields)?].concat())
+}
+
+/// Fingerprint of a public key: the leading bytes of its SHA-256, hex-encoded
+pub fn fingerprint(public_key: &VerifyingKey) -> String {
+    let digest = Sha256::digest(public_key.as_bytes());
+    hex::encode(&digest[..FINGERPRINT_BYTES])
+}
+
+/// Safety number for a pair of devices, identical on both sides.
+///
+/// Twelve groups of five digits derived from both public keys, meant to be
+/// read aloud or compared side by side to detect a man in the middle.
+pub fn safety_number(a: &VerifyingKey, b: &VerifyingKey) -> String {
+    let (first, second) = if a.as_bytes() <= b.as_bytes() { (a, b) } else { (b, a) };
+
+    let mut hasher = Sha512::new();
+    hasher.update(SAFETY_NUMBER_CONTEXT);
+    hasher.update(first.as_bytes());
+    hasher.update(second.as_bytes());
+    let digest = hasher.finalize();
+
+    digest
+        .chunks(5)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// User ID that a peer advertising `public_key_hex` must be using
+pub fn user_id_for_public_key(public_key_hex: &str) -> AppResult<String> {
+    let public_key = parse_public_key(public_key_hex)?;
+    Ok(format!("user-{}", fingerprint(&public_key)))
+}
+
+/// Creates the identity file readable by the current user only, so the key is never exposed
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_dir() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("ip-chat-identity-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn identity_is_reloaded_after_creation() {
+        let dir = temp_dir();
+        let created = DeviceIdentity::load_or_create(&dir).unwrap();
+        let loaded = DeviceIdentity::load_or_create(&dir).unwrap();
+
+        assert_eq!(loaded.user_id(), created.user_id());
+        assert_eq!(loaded.public_key_hex(), created.public_key_hex());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir();
+        DeviceIdentity::load_or_create(&dir).unwrap();
+
+        let mode = fs::metadata(dir.join(IDENTITY_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupt_identity_is_rejected() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let corrupt = IdentityFile {
+            version: IDENTITY_VERSION,
+            secret_key: "not hex".to_string(),
+        };
+        fs::write(dir.join(IDENTITY_FILE), serde_json::to_string(&corrupt).unwrap()).unwrap();
+        assert!(DeviceIdentity::load_or_create(&dir).is_err());
+
+        fs::write(dir.join(IDENTITY_FILE), "{").unwrap();
+        assert!(DeviceIdentity::load_or_create(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,23 +1,57 @@
+mod beacon;
 mod chat;
 mod connection_manager;
 mod discovery;
 mod error;
+mod export;
 mod file_transfer;
+mod groups;
+mod identity;
 mod models;
+mod network;
+mod outbox;
+mod protocol;
+mod search;
+mod secure_channel;
+mod settings;
+mod static_peers;
+mod storage;
+mod trust;
 
-use local_ip_address::local_ip;
-use log::{error, info, warn};
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use futures::future::join_all;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tauri::{AppHandle, Emitter};
-use tokio::sync::Mutex;
+use tauri::{AppHandle, Emitter, Manager};
+use tokio::sync::{mpsc, Mutex};
 
-use crate::chat::ChatManager;
+use crate::chat::{ChatManager, BROADCAST_CHANNEL_ID, CHAT_PORT};
 use crate::connection_manager::ConnectionManager;
-use crate::discovery::NetworkDiscovery;
+use crate::discovery::{DiscoveryBackendKind, NetworkDiscovery};
+use crate::error::AppResult;
+use crate::export::{ConversationExport, ExportFormat};
 use crate::file_transfer::FileTransferManager;
-use crate::models::{AppState, FileTransfer, Message, User};
+use crate::identity::DeviceIdentity;
+use crate::models::{
+    AppState, FileTransfer, Group, HistoryCursor, Message, MessageDeletion, MessageEdit, MessagePage, MessageStatus,
+    PageRequest, PeerFingerprint, Presence, Reaction, SearchResult, User, MAX_REACTION_LEN, MAX_STATUS_TEXT_LEN,
+};
+use crate::outbox::{Outbox, OutboxItem};
+use crate::protocol::MembershipChange;
+use crate::secure_channel::ChannelKeys;
+use crate::settings::{NetworkSettings, NetworkSettingsStore};
+use crate::static_peers::{StaticPeer, StaticPeerStore};
+use crate::trust::TrustStore;
+
+/// How often queued messages are retried when no peer activity triggers a flush
+const OUTBOX_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(15);
+/// How often peers added by address are probed again, well within the discovery peer timeout
+const STATIC_PEER_PROBE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+/// How often network interfaces are checked for changed addresses
+const NETWORK_WATCH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
 
 // Global app handle for event emission
 static mut APP_HANDLE: Option<AppHandle> = None;
@@ -45,34 +79,44 @@ pub fn emit_event<T: serde::Serialize + Clone>(event: &str, payload: T) {
 async fn ensure_services_initialized(state: &mut AppState) {
     if !state.services_initialized {
         info!("Starting service initialization...");
+        let settings = state.network_settings.get();
 
-        // Start network services
-        info!("Starting network discovery service...");
-        if let Err(e) = state.discovery.start_discovery().await {
-            error!("Failed to start discovery service: {e}");
-        } else {
-            info!("Discovery service started successfully");
+        // Listeners are bound first so discovery advertises the ports actually listened on
+        info!("Starting chat service...");
+        match state.chat_manager.start_chat_service(settings.chat_port).await {
+            Ok(port) => {
+                state.local_user.port = Some(port);
+                info!("Chat service started successfully");
+            }
+            Err(e) => error!("Failed to start chat service: {e}"),
         }
 
-        // Start chat service
-        info!("Starting chat service...");
-        if let Err(e) = state.chat_manager.start_chat_service() {
-            error!("Failed to start chat service: {e}");
-        } else {
-            info!("Chat service started successfully");
+        info!("Starting file transfer service...");
+        match state.file_manager.start_file_transfer_service(settings.file_port).await {
+            Ok(port) => {
+                state.local_user.file_port = Some(port);
+                info!("File transfer service started successfully");
+            }
+            Err(e) => error!("Failed to start file transfer service: {e}"),
         }
-        
+
+        let local_user = state.local_user.clone();
+        state.chat_manager.set_local_user(local_user.clone());
+        state.connection_manager.set_local_user(local_user.clone());
+        state.file_manager.set_local_user(local_user.clone());
+        state.discovery.set_local_user(local_user);
+
         // Start connection manager heartbeat service
         info!("Starting connection manager...");
         state.connection_manager.start_heartbeat_service();
         info!("Connection manager started successfully");
 
-        // Start file transfer service
-        info!("Starting file transfer service...");
-        if let Err(e) = state.file_manager.start_file_transfer_service().await {
-            error!("Failed to start file transfer service: {e}");
+        // Start network services
+        info!("Starting network discovery service...");
+        if let Err(e) = state.discovery.start_discovery().await {
+            error!("Failed to start discovery service: {e}");
         } else {
-            info!("File transfer service started successfully");
+            info!("Discovery service started successfully");
         }
 
         state.services_initialized = true;
@@ -95,7 +139,8 @@ async fn start_discovery(state: tauri::State<'_, Arc<Mutex<AppState>>>) -> Resul
 #[tauri::command]
 async fn stop_discovery(state: tauri::State<'_, Arc<Mutex<AppState>>>) -> Result<(), String> {
     let mut state = state.lock().await;
-    match state.discovery.stop_discovery().await {
+    let static_ids = state.static_peers.peer_ids();
+    match state.discovery.stop_discovery(&static_ids).await {
         Ok(_) => Ok(()),
         Err(e) => Err(e.to_string()),
     }
@@ -114,8 +159,9 @@ async fn refresh_discovery(
     state: tauri::State<'_, Arc<Mutex<AppState>>>,
 ) -> Result<Vec<User>, String> {
     let mut state = state.lock().await;
-    // Restart discovery to refresh peer list
-    if let Err(e) = state.discovery.stop_discovery().await {
+    // Restart discovery to refresh peer list, peers added by address are not rediscovered
+    let static_ids = state.static_peers.peer_ids();
+    if let Err(e) = state.discovery.stop_discovery(&static_ids).await {
         error!("Failed to stop discovery during refresh: {e}");
     }
 
@@ -131,6 +177,111 @@ async fn refresh_discovery(
     Ok(state.discovery.get_discovered_peers())
 }
 
+#[tauri::command]
+async fn get_discovery_backends(
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Vec<DiscoveryBackendKind>, String> {
+    let state = state.lock().await;
+    Ok(state.discovery.backend_kinds())
+}
+
+#[tauri::command]
+async fn set_discovery_backends(
+    backends: Vec<DiscoveryBackendKind>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<(), String> {
+    let mut state = state.lock().await;
+    let static_ids = state.static_peers.peer_ids();
+    state
+        .discovery
+        .set_backends(&backends, &static_ids)
+        .await
+        .map_err(|e| e.to_string())?;
+    let backends = state.discovery.backend_kinds();
+    state
+        .network_settings
+        .set_discovery_backends(backends)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn get_network_settings(
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<NetworkSettings, String> {
+    let state = state.lock().await;
+    Ok(state.network_settings.get())
+}
+
+#[tauri::command]
+async fn set_listen_ports(
+    chat_port: u16,
+    file_port: u16,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<NetworkSettings, String> {
+    // The listeners are bound at startup, so new ports take effect after a restart
+    let state = state.lock().await;
+    state
+        .network_settings
+        .set_ports(chat_port, file_port)
+        .map_err(|e| e.to_string())?;
+    Ok(state.network_settings.get())
+}
+
+#[tauri::command]
+async fn add_manual_peer(
+    host: String,
+    port: Option<u16>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<User, String> {
+    let host = host.trim().to_string();
+    if host.is_empty() {
+        return Err("Host cannot be empty".to_string());
+    }
+    let port = port.unwrap_or(CHAT_PORT);
+
+    // Probing can take a while, so the app state is not held meanwhile
+    let (local_user, channel_keys) = {
+        let state = state.lock().await;
+        (state.local_user.clone(), Arc::clone(&state.channel_keys))
+    };
+    let user = connection_manager::probe_peer(&host, port, &local_user, &channel_keys)
+        .await
+        .map_err(|e| e.to_string())?;
+    if user.id == local_user.id {
+        return Err("That address belongs to this device".to_string());
+    }
+
+    let state = state.lock().await;
+    state.discovery.add_peer(user.clone()).map_err(|e| e.to_string())?;
+    state
+        .static_peers
+        .upsert(&host, port, &user.id)
+        .map_err(|e| e.to_string())?;
+
+    info!("Added peer {} at {}:{} manually", user.id, host, port);
+    Ok(user)
+}
+
+#[tauri::command]
+async fn get_manual_peers(
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Vec<StaticPeer>, String> {
+    let state = state.lock().await;
+    Ok(state.static_peers.list())
+}
+
+#[tauri::command]
+async fn remove_manual_peer(
+    host: String,
+    port: u16,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<(), String> {
+    let state = state.lock().await;
+    // The peer stays listed until it times out, unless mDNS keeps finding it
+    state.static_peers.remove(&host, port).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
 #[tauri::command]
 async fn get_local_user(state: tauri::State<'_, Arc<Mutex<AppState>>>) -> Result<User, String> {
     let state = state.lock().await;
@@ -142,6 +293,7 @@ async fn get_local_user(state: tauri::State<'_, Arc<Mutex<AppState>>>) -> Result
 async fn send_message(
     peer_id: String,
     content: String,
+    reply_to: Option<String>,
     state: tauri::State<'_, Arc<Mutex<AppState>>>,
 ) -> Result<Message, String> {
     let state = state.lock().await;
@@ -174,29 +326,33 @@ async fn send_message(
         info!("Peer {}: ID={}, Name={}, IP={}", i + 1, p.id, p.name, p.ip);
     }
 
+    // Create the message up front so it can be queued if the peer is unreachable
+    let mut message = Message::new(&state.local_user.id, &peer_id, &content);
+    set_reply_target(&state, &mut message, reply_to)?;
+
+    // Sign the message so the recipient can authenticate us
+    state.identity.sign_message(&mut message).map_err(|e| e.to_string())?;
+
+    // Store message locally first
+    if let Err(e) = state.chat_manager.store_sent_message(&message) {
+        warn!("Failed to store message locally: {}", e);
+    }
+
     match peer {
         Some(ref peer_info) => {
             info!("Found peer {} at IP: {}", peer_id, peer_info.ip);
             info!("Sending message with peer IP: {}", peer_info.ip);
 
-            // Create message and send via connection manager
-            let message = crate::models::Message {
-                id: uuid::Uuid::new_v4().to_string(),
-                sender_id: state.local_user.id.clone(),
-                recipient_id: peer_id.clone(),
-                content: content.clone(),
-                timestamp: chrono::Utc::now(),
-                read: false,
-            };
-            
-            // Store message locally first
-            if let Err(e) = state.chat_manager.store_sent_message(&message) {
-                warn!("Failed to store message locally: {}", e);
-            }
-            
             // Send via connection manager
-            match state.connection_manager.send_message(&peer_id, &message, &peer_info.ip, 8765).await {
+            match state.connection_manager.send_message(&peer_id, &message, &peer_info.chat_addrs()).await {
                 Ok(_) => {
+                    // A delivery receipt may already have moved the message past Sent
+                    match state.chat_manager.update_message_status(&message, MessageStatus::Sent) {
+                        Ok(Some(updated)) => message = updated,
+                        Ok(None) => message.status = MessageStatus::Sent,
+                        Err(e) => warn!("Failed to save message status: {}", e),
+                    }
+
                     info!(
                         "Message sent successfully - ID: {}, Sender: {}, Recipient: {}",
                         message.id, message.sender_id, message.recipient_id
@@ -213,31 +369,157 @@ async fn send_message(
                     Ok(message)
                 }
                 Err(e) => {
-                    error!("Failed to send message to peer {}: {}", peer_id, e);
-                    error!(
+                    warn!("Failed to send message to peer {}, queueing it: {}", peer_id, e);
+                    warn!(
                         "Error details: peer_ip={}, content_len={}",
                         peer_info.ip,
                         content.len()
                     );
-                    Err(e.to_string())
+                    queue_message(&state, &mut message);
+                    Ok(message)
                 }
             }
         }
         None => {
-            error!("Peer {} not found in discovered peers after refresh", peer_id);
-            error!(
+            warn!("Peer {} not found in discovered peers after refresh, queueing message", peer_id);
+            info!(
                 "Available peer IDs: {:?}",
                 peers.iter().map(|p| &p.id).collect::<Vec<_>>()
             );
-            Err(format!(
-                "Peer {} not found. The peer may have gone offline or network discovery may have failed. Available peers: {}",
-                peer_id,
-                if peers.is_empty() { 
-                    "none".to_string() 
-                } else { 
-                    peers.iter().map(|p| format!("{}({})", p.name, p.id)).collect::<Vec<_>>().join(", ")
+            queue_message(&state, &mut message);
+            Ok(message)
+        }
+    }
+}
+
+/// Makes a new message a reply to `reply_to`, which must be in the same conversation
+fn set_reply_target(state: &AppState, message: &mut Message, reply_to: Option<String>) -> Result<(), String> {
+    let Some(reply_to) = reply_to else {
+        return Ok(());
+    };
+
+    let parent = state
+        .chat_manager
+        .get_message(&reply_to)
+        .ok_or_else(|| format!("Message {} not found", reply_to))?;
+    let local_id = &state.local_user.id;
+    if parent.conversation_id(local_id) != message.conversation_id(local_id) {
+        return Err("Can only reply to a message in the same conversation".to_string());
+    }
+
+    message.set_reply_to(&parent);
+    Ok(())
+}
+
+/// Puts a message in the outbox for delivery once its recipient is reachable
+fn queue_message(state: &AppState, message: &mut Message) {
+    if let Err(e) = state.outbox.enqueue(message) {
+        error!("Failed to queue message {}: {}", message.id, e);
+        mark_message_failed(state, message);
+    }
+}
+
+/// Marks a message we gave up on as failed and tells the frontend
+fn mark_message_failed(state: &AppState, message: &mut Message) {
+    match state.chat_manager.update_message_status(message, MessageStatus::Failed) {
+        Ok(Some(failed)) => {
+            *message = failed.clone();
+            emit_event("message_status_updated", failed);
+        }
+        Ok(None) => {}
+        Err(e) => warn!("Failed to save message status: {}", e),
+    }
+}
+
+/// Sends queued messages, edits and deletions to a peer that is reachable again.
+///
+/// Connecting can time out, so the app state is only held to read the queue and record results.
+async fn flush_outbox(app_state: &Arc<Mutex<AppState>>, peer_id: &str, ignore_backoff: bool) {
+    let (connection_manager, peer, items) = {
+        let state = app_state.lock().await;
+        let Some(peer) = state.discovery.get_peer_by_id(peer_id) else {
+            return;
+        };
+        (
+            state.connection_manager.handle(),
+            peer,
+            state.outbox.pending_for(peer_id, ignore_backoff),
+        )
+    };
+
+    for item in items {
+        let addrs = peer.chat_addrs();
+        let result = match &item {
+            OutboxItem::Message(message) => connection_manager.send_message(peer_id, message, &addrs).await,
+            OutboxItem::Edit(edit) => connection_manager.send_message_edit(peer_id, edit, &addrs).await,
+            OutboxItem::Deletion(deletion) => connection_manager.send_message_deletion(peer_id, deletion, &addrs).await,
+        };
+
+        let state = app_state.lock().await;
+        match result {
+            Ok(_) => {
+                info!("Delivered queued {} of {} to {}", item.kind(), item.message_id(), peer_id);
+                if let Err(e) = state.outbox.remove(peer_id, &item) {
+                    warn!("Failed to remove {} from the outbox: {}", item.message_id(), e);
+                }
+                if let OutboxItem::Message(message) = &item {
+                    match state.chat_manager.update_message_status(message, MessageStatus::Sent) {
+                        Ok(Some(sent)) => emit_event("message_status_updated", sent),
+                        Ok(None) => {}
+                        Err(e) => warn!("Failed to save message status: {}", e),
+                    }
+                }
+            }
+            Err(e) => {
+                debug!("Queued {} of {} to {} still undeliverable: {}", item.kind(), item.message_id(), peer_id, e);
+                match state.outbox.record_failure(peer_id, &item) {
+                    Ok(true) => {
+                        if let OutboxItem::Message(mut message) = item {
+                            mark_message_failed(&state, &mut message);
+                        }
+                    }
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to save outbox: {}", e),
                 }
-            ))
+                // Keep the remaining items in order for the next attempt
+                break;
+            }
+        }
+    }
+}
+
+/// Retries queued messages when peers come online and on a backoff schedule
+async fn run_outbox(
+    app_state: Arc<Mutex<AppState>>,
+    outbox: Arc<Outbox>,
+    mut peer_online_rx: mpsc::UnboundedReceiver<String>,
+) {
+    let mut retry_interval = tokio::time::interval(OUTBOX_RETRY_INTERVAL);
+
+    loop {
+        tokio::select! {
+            Some(peer_id) = peer_online_rx.recv() => {
+                // Skip the app state lock for the common case of nothing queued
+                if outbox.has_pending(&peer_id) {
+                    flush_outbox(&app_state, &peer_id, true).await;
+                }
+            }
+            _ = retry_interval.tick() => {
+                match outbox.expire_stale() {
+                    Ok(expired) if !expired.is_empty() => {
+                        let state = app_state.lock().await;
+                        for mut message in expired {
+                            mark_message_failed(&state, &mut message);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to expire queued messages: {}", e),
+                }
+                for peer_id in outbox.peers_due() {
+                    flush_outbox(&app_state, &peer_id, false).await;
+                }
+            }
+            else => break,
         }
     }
 }
@@ -245,8 +527,11 @@ async fn send_message(
 #[tauri::command]
 async fn get_messages(
     peer_id: Option<String>,
+    before: Option<HistoryCursor>,
+    after: Option<HistoryCursor>,
+    limit: Option<usize>,
     state: tauri::State<'_, Arc<Mutex<AppState>>>,
-) -> Result<Vec<Message>, String> {
+) -> Result<MessagePage, String> {
     let mut state = state.lock().await;
 
     // Ensure services are initialized
@@ -254,15 +539,19 @@ async fn get_messages(
 
     info!("Getting messages for peer_id: {:?}", peer_id);
 
+    let page = PageRequest { before, after, limit };
     let messages = match peer_id {
         Some(id) => {
-            let msgs = state.chat_manager.get_messages_for_peer(&id);
-            info!("Retrieved {} messages for peer {}", msgs.len(), id);
+            let msgs = state
+                .chat_manager
+                .get_messages_for_peer(&id, &page)
+                .map_err(|e| e.to_string())?;
+            info!("Retrieved {} messages for peer {}", msgs.messages.len(), id);
             msgs
         }
         None => {
-            let msgs = state.chat_manager.get_all_messages();
-            info!("Retrieved {} total messages", msgs.len());
+            let msgs = state.chat_manager.get_all_messages(&page).map_err(|e| e.to_string())?;
+            info!("Retrieved {} total messages", msgs.messages.len());
             msgs
         }
     };
@@ -270,24 +559,722 @@ async fn get_messages(
     Ok(messages)
 }
 
+#[tauri::command]
+async fn get_unread_counts(
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<HashMap<String, usize>, String> {
+    let state = state.lock().await;
+    Ok(state.chat_manager.get_unread_counts())
+}
+
+#[tauri::command]
+async fn get_thread(
+    message_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Vec<Message>, String> {
+    let state = state.lock().await;
+    state.chat_manager.get_thread(&message_id).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn search_messages(
+    query: String,
+    peer_id: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Vec<SearchResult>, String> {
+    let state = state.lock().await;
+
+    // `peer_id` may also be a group ID or the broadcast channel
+    let results = state
+        .chat_manager
+        .search_messages(&query, peer_id.as_deref(), from, to);
+    info!("Found {} message(s) matching {:?}", results.len(), query);
+    Ok(results)
+}
+
+#[tauri::command]
+async fn export_conversation(
+    peer_id: String,
+    format: ExportFormat,
+    path: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<(), String> {
+    let state = state.lock().await;
+
+    // Transcripts show names rather than user IDs
+    let mut names: HashMap<String, String> = state
+        .discovery
+        .get_discovered_peers()
+        .into_iter()
+        .map(|peer| (peer.id, peer.name))
+        .collect();
+    names.insert(state.local_user.id.clone(), state.local_user.name.clone());
+
+    // `peer_id` may also be a group ID or the broadcast channel
+    let title = match state.chat_manager.get_group_store().get(&peer_id) {
+        Some(group) => group.name,
+        None if peer_id == BROADCAST_CHANNEL_ID => "Broadcast channel".to_string(),
+        None => names.get(&peer_id).cloned().unwrap_or_else(|| peer_id.clone()),
+    };
+
+    let export = ConversationExport::new(
+        &state.local_user.id,
+        &peer_id,
+        &title,
+        state.chat_manager.get_conversation(&peer_id),
+        state.file_manager.get_transfers_for_peer(&peer_id),
+    );
+    export
+        .write(std::path::Path::new(&path), format, &names)
+        .map_err(|e| e.to_string())?;
+
+    info!(
+        "Exported {} message(s) and {} transfer(s) with {} to {}",
+        export.messages.len(),
+        export.transfers.len(),
+        peer_id,
+        path
+    );
+    Ok(())
+}
+
+#[tauri::command]
+async fn import_history(
+    path: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<usize, String> {
+    let export = ConversationExport::read(std::path::Path::new(&path)).map_err(|e| e.to_string())?;
+
+    let state = state.lock().await;
+    state
+        .chat_manager
+        .import_messages(export.messages)
+        .map_err(|e| e.to_string())
+}
+
 #[tauri::command]
 async fn mark_messages_as_read(
     peer_id: String,
     state: tauri::State<'_, Arc<Mutex<AppState>>>,
 ) -> Result<(), String> {
-    let mut state = state.lock().await;
+    let (connection_manager, peer, read_ids) = {
+        let mut state = state.lock().await;
 
-    // Ensure services are initialized
-    ensure_services_initialized(&mut state).await;
+        // Ensure services are initialized
+        ensure_services_initialized(&mut state).await;
 
-    info!("Marking messages as read for peer: {}", peer_id);
-    match state.chat_manager.mark_messages_as_read(&peer_id) {
-        Ok(_) => {
-            // Emit messages read event
-            emit_event("messages_read", peer_id);
-            Ok(())
+        info!("Marking messages as read for peer: {}", peer_id);
+        let read_ids = match state.chat_manager.mark_messages_as_read(&peer_id) {
+            Ok(read_ids) => read_ids,
+            Err(e) => return Err(e.to_string()),
+        };
+        (state.connection_manager.handle(), state.discovery.get_peer_by_id(&peer_id), read_ids)
+    };
+
+    // Let the sender know, a missed receipt only affects their status display
+    if !read_ids.is_empty() {
+        match peer {
+            Some(peer) => {
+                if let Err(e) = connection_manager
+                    .send_read_receipt(&peer_id, read_ids, &peer.chat_addrs())
+                    .await
+                {
+                    warn!("Failed to send read receipt to {}: {}", peer_id, e);
+                }
+            }
+            None => debug!("Peer {} is offline, not sending read receipt", peer_id),
+        }
+    }
+
+    // Emit messages read event
+    emit_event("messages_read", peer_id);
+    Ok(())
+}
+
+#[tauri::command]
+async fn edit_message(
+    message_id: String,
+    content: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Message, String> {
+    let (connection_manager, outbox, recipients, edit, message) = {
+        let state = state.lock().await;
+        let original = get_own_message(&state, &message_id)?;
+
+        let mut edit = MessageEdit {
+            message_id,
+            sender_id: state.local_user.id.clone(),
+            content,
+            edited_at: chrono::Utc::now(),
+            signature: None,
+        };
+        state.identity.sign_edit(&mut edit).map_err(|e| e.to_string())?;
+
+        let message = match state.chat_manager.edit_message(&edit) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err("Message can no longer be edited".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // A message still waiting in the outbox is delivered with the new content instead,
+        // its recipients never saw the previous one
+        let mut queued = message.clone();
+        queued.edit_history.clear();
+        state.identity.sign_message(&mut queued).map_err(|e| e.to_string())?;
+        let queued_for = state.outbox.replace(&queued).unwrap_or_else(|e| {
+            warn!("Failed to update queued message {}: {}", message.id, e);
+            Vec::new()
+        });
+
+        let mut audience = message_audience(&state, &original);
+        audience.retain(|peer_id| !queued_for.contains(peer_id));
+        let recipients = recipients(&state, audience);
+        (state.connection_manager.handle(), Arc::clone(&state.outbox), recipients, edit, message)
+    };
+
+    // Peers that are offline get the edit once they are back
+    let results = fan_out(&recipients, |peer_id, addrs| {
+        connection_manager.send_message_edit(peer_id, &edit, addrs)
+    })
+    .await;
+    for peer_id in undelivered(&recipients, results, "edit", &message.id) {
+        if let Err(e) = outbox.enqueue_edit(&peer_id, &edit) {
+            error!("Failed to queue edit of {} for {}: {}", message.id, peer_id, e);
+        }
+    }
+
+    // Emit message edited event
+    emit_event("message_edited", message.clone());
+    Ok(message)
+}
+
+#[tauri::command]
+async fn delete_message(
+    message_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Message, String> {
+    let (connection_manager, outbox, recipients, deletion, message) = {
+        let state = state.lock().await;
+        let original = get_own_message(&state, &message_id)?;
+
+        let mut deletion = MessageDeletion {
+            message_id,
+            sender_id: state.local_user.id.clone(),
+            deleted_at: chrono::Utc::now(),
+            signature: None,
+        };
+        state.identity.sign_deletion(&mut deletion).map_err(|e| e.to_string())?;
+
+        let message = match state.chat_manager.delete_message(&deletion) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Err("Message has already been deleted".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        // A message still waiting in the outbox is simply never delivered
+        let queued_for = state.outbox.discard(&message.id).unwrap_or_else(|e| {
+            warn!("Failed to discard queued message {}: {}", message.id, e);
+            Vec::new()
+        });
+
+        let mut audience = message_audience(&state, &original);
+        audience.retain(|peer_id| !queued_for.contains(peer_id));
+        let recipients = recipients(&state, audience);
+        (state.connection_manager.handle(), Arc::clone(&state.outbox), recipients, deletion, message)
+    };
+
+    // Peers that are offline get the deletion once they are back
+    let results = fan_out(&recipients, |peer_id, addrs| {
+        connection_manager.send_message_deletion(peer_id, &deletion, addrs)
+    })
+    .await;
+    for peer_id in undelivered(&recipients, results, "deletion", &message.id) {
+        if let Err(e) = outbox.enqueue_deletion(&peer_id, &deletion) {
+            error!("Failed to queue deletion of {} for {}: {}", message.id, peer_id, e);
+        }
+    }
+
+    // Emit message deleted event
+    emit_event("message_deleted", message.clone());
+    Ok(message)
+}
+
+#[tauri::command]
+async fn send_typing(
+    conversation_id: String,
+    typing: bool,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<(), String> {
+    let (connection_manager, recipients, group_id) = {
+        let state = state.lock().await;
+
+        // The conversation is either a group we are in or a peer
+        let (group_id, peer_ids) = match state.chat_manager.get_group_store().get(&conversation_id) {
+            Some(group) if group.has_member(&state.local_user.id) => {
+                let members: Vec<String> = group
+                    .members
+                    .into_iter()
+                    .filter(|id| *id != state.local_user.id)
+                    .collect();
+                (Some(group.id), members)
+            }
+            Some(_) => return Err("Not a member of this group".to_string()),
+            None => (None, vec![conversation_id]),
+        };
+        (state.connection_manager.handle(), recipients(&state, peer_ids), group_id)
+    };
+
+    // Typing indicators are best effort and only go to peers that are online
+    let results = fan_out(&recipients, |peer_id, addrs| {
+        connection_manager.send_typing(peer_id, group_id.clone(), typing, addrs)
+    })
+    .await;
+    for (peer_id, result) in results {
+        if let Err(e) = result {
+            debug!("Failed to send typing indicator to {}: {}", peer_id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Looks up a message the local user sent, the only ones it may edit or delete
+fn get_own_message(state: &AppState, message_id: &str) -> Result<Message, String> {
+    match state.chat_manager.get_message(message_id) {
+        Some(message) if message.sender_id == state.local_user.id => Ok(message),
+        Some(_) => Err("Only your own messages can be changed".to_string()),
+        None => Err(format!("Message {} not found", message_id)),
+    }
+}
+
+#[tauri::command]
+async fn add_reaction(
+    message_id: String,
+    emoji: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Message, String> {
+    send_reaction(&state, message_id, emoji, true).await
+}
+
+#[tauri::command]
+async fn remove_reaction(
+    message_id: String,
+    emoji: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Message, String> {
+    send_reaction(&state, message_id, emoji, false).await
+}
+
+/// Applies a reaction of ours locally and sends it to everyone in the message's conversation
+async fn send_reaction(
+    app_state: &Arc<Mutex<AppState>>,
+    message_id: String,
+    emoji: String,
+    added: bool,
+) -> Result<Message, String> {
+    let (connection_manager, recipients, reaction, message) = {
+        let state = app_state.lock().await;
+        let Some(original) = state.chat_manager.get_message(&message_id) else {
+            return Err(format!("Message {} not found", message_id));
+        };
+        if original.deleted {
+            return Err("Cannot react to a deleted message".to_string());
+        }
+        if emoji.is_empty() || emoji.len() > MAX_REACTION_LEN {
+            return Err("Invalid reaction".to_string());
+        }
+
+        let reaction = Reaction {
+            message_id,
+            user_id: state.local_user.id.clone(),
+            emoji,
+            added,
+        };
+
+        // Reacting twice, or removing a missing reaction, leaves the message unchanged
+        let message = match state.chat_manager.react_to_message(&reaction) {
+            Ok(Some(message)) => message,
+            Ok(None) => return Ok(original),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let recipients = recipients(&state, message_audience(&state, &message));
+        (state.connection_manager.handle(), recipients, reaction, message)
+    };
+
+    // Peers that are offline miss the reaction
+    let results = fan_out(&recipients, |peer_id, addrs| {
+        connection_manager.send_reaction(peer_id, &reaction, addrs)
+    })
+    .await;
+    for (peer_id, result) in results {
+        if let Err(e) = result {
+            warn!("Failed to send reaction to {} to {}: {}", message.id, peer_id, e);
+        }
+    }
+
+    // Emit reactions updated event
+    emit_event("message_reactions_updated", message.clone());
+    Ok(message)
+}
+
+/// IDs of the peers that share the conversation of a message
+fn message_audience(state: &AppState, message: &Message) -> Vec<String> {
+    if let Some(group_id) = &message.group_id {
+        return state
+            .chat_manager
+            .get_group_store()
+            .get(group_id)
+            .map(|group| {
+                group
+                    .members
+                    .into_iter()
+                    .filter(|id| *id != state.local_user.id)
+                    .collect()
+            })
+            .unwrap_or_default();
+    }
+
+    if message.recipient_id == BROADCAST_CHANNEL_ID {
+        return state
+            .discovery
+            .get_discovered_peers()
+            .into_iter()
+            .map(|peer| peer.id)
+            .collect();
+    }
+
+    vec![message.conversation_id(&state.local_user.id).to_string()]
+}
+
+/// IDs of the recipients that were offline or failed to take the `kind` of `message_id`
+fn undelivered(recipients: &[Recipient], results: Vec<(&str, AppResult<()>)>, kind: &str, message_id: &str) -> Vec<String> {
+    let mut peer_ids: Vec<String> = recipients
+        .iter()
+        .filter(|(_, addrs)| addrs.is_none())
+        .map(|(peer_id, _)| peer_id.clone())
+        .collect();
+    for (peer_id, result) in results {
+        if let Err(e) = result {
+            warn!("Failed to send {} of {} to {}: {}", kind, message_id, peer_id, e);
+            peer_ids.push(peer_id.to_string());
+        }
+    }
+    peer_ids
+}
+
+/// A peer to fan out to, with its chat addresses while it is online
+type Recipient = (String, Option<Vec<SocketAddr>>);
+
+/// Looks up the chat addresses of `peer_ids` in discovery
+fn recipients(state: &AppState, peer_ids: Vec<String>) -> Vec<Recipient> {
+    peer_ids
+        .into_iter()
+        .map(|peer_id| {
+            let addrs = state.discovery.get_peer_by_id(&peer_id).map(|peer| peer.chat_addrs());
+            (peer_id, addrs)
+        })
+        .collect()
+}
+
+/// Runs `send` for every online recipient at once, returning each result by peer ID.
+///
+/// Connecting to a peer can take seconds, so callers release the app state first
+/// and only lock it again to record the results.
+async fn fan_out<'a, F, Fut>(recipients: &'a [Recipient], send: F) -> Vec<(&'a str, AppResult<()>)>
+where
+    F: Fn(&'a str, &'a [SocketAddr]) -> Fut,
+    Fut: Future<Output = AppResult<()>>,
+{
+    let send = &send;
+    let sends = recipients.iter().filter_map(|(peer_id, addrs)| {
+        let addrs = addrs.as_deref()?;
+        Some(async move { (peer_id.as_str(), send(peer_id, addrs).await) })
+    });
+    join_all(sends).await
+}
+
+// Commands for group chats
+#[tauri::command]
+async fn get_groups(state: tauri::State<'_, Arc<Mutex<AppState>>>) -> Result<Vec<Group>, String> {
+    let state = state.lock().await;
+    Ok(state.chat_manager.get_group_store().list())
+}
+
+#[tauri::command]
+async fn create_group(
+    name: String,
+    member_ids: Vec<String>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Group, String> {
+    let (connection_manager, recipients, group) = {
+        let state = state.lock().await;
+
+        let name = name.trim();
+        if name.is_empty() {
+            return Err("Group name cannot be empty".to_string());
+        }
+
+        let mut members = vec![state.local_user.id.clone()];
+        for member_id in member_ids {
+            if !members.contains(&member_id) {
+                members.push(member_id);
+            }
+        }
+
+        let group = Group {
+            id: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            invited: members.clone(),
+            members,
+            created_by: state.local_user.id.clone(),
+            created_at: chrono::Utc::now(),
+        };
+
+        if let Err(e) = state.chat_manager.get_group_store().insert(&group) {
+            return Err(e.to_string());
+        }
+        info!("Created group {} ({}) with {} member(s)", group.name, group.id, group.members.len());
+
+        let recipients = recipients(&state, other_members(&state, &group));
+        (state.connection_manager.handle(), recipients, group)
+    };
+
+    announce_group_change(&connection_manager, &recipients, &group, MembershipChange::Created).await;
+
+    // Emit group update event
+    emit_event("group_updated", group.clone());
+    Ok(group)
+}
+
+#[tauri::command]
+async fn join_group(
+    group_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Group, String> {
+    let (connection_manager, recipients, group) = {
+        let state = state.lock().await;
+        let groups = state.chat_manager.get_group_store();
+
+        // Members would ignore the join of someone the creator did not add
+        match groups.get(&group_id) {
+            Some(group) if group.is_invited(&state.local_user.id) => {}
+            Some(_) => return Err("Not invited to this group".to_string()),
+            None => return Err(format!("Unknown group {}", group_id)),
+        }
+
+        let group = match groups.add_member(&group_id, &state.local_user.id) {
+            Ok(Some(group)) => group,
+            Ok(None) => return Err("Already a member of this group".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+        info!("Joined group {} ({})", group.name, group.id);
+
+        let recipients = recipients(&state, other_members(&state, &group));
+        (state.connection_manager.handle(), recipients, group)
+    };
+
+    announce_group_change(&connection_manager, &recipients, &group, MembershipChange::Joined).await;
+
+    // Emit group update event
+    emit_event("group_updated", group.clone());
+    Ok(group)
+}
+
+#[tauri::command]
+async fn leave_group(
+    group_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Group, String> {
+    let (connection_manager, recipients, group) = {
+        let state = state.lock().await;
+        let group = match state
+            .chat_manager
+            .get_group_store()
+            .remove_member(&group_id, &state.local_user.id)
+        {
+            Ok(Some(group)) => group,
+            Ok(None) => return Err("Not a member of this group".to_string()),
+            Err(e) => return Err(e.to_string()),
+        };
+        info!("Left group {} ({})", group.name, group.id);
+
+        let recipients = recipients(&state, other_members(&state, &group));
+        (state.connection_manager.handle(), recipients, group)
+    };
+
+    // The group is kept locally so its history stays readable
+    announce_group_change(&connection_manager, &recipients, &group, MembershipChange::Left).await;
+
+    // Emit group update event
+    emit_event("group_updated", group.clone());
+    Ok(group)
+}
+
+#[tauri::command]
+async fn send_group_message(
+    group_id: String,
+    content: String,
+    reply_to: Option<String>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Message, String> {
+    let (connection_manager, outbox, recipients, mut message) = {
+        let state = state.lock().await;
+
+        let group = match state.chat_manager.get_group_store().get(&group_id) {
+            Some(group) if group.has_member(&state.local_user.id) => group,
+            Some(_) => return Err("Not a member of this group".to_string()),
+            None => return Err(format!("Unknown group {}", group_id)),
+        };
+
+        // Every member receives the same signed message, addressed to the group
+        let mut message = Message::new(&state.local_user.id, &group.id, &content);
+        message.group_id = Some(group.id.clone());
+        set_reply_target(&state, &mut message, reply_to)?;
+        state.identity.sign_message(&mut message).map_err(|e| e.to_string())?;
+
+        if let Err(e) = state.chat_manager.store_group_message(&group.id, &message) {
+            warn!("Failed to store group message locally: {}", e);
+        }
+
+        let recipients = recipients(&state, other_members(&state, &group));
+        (state.connection_manager.handle(), Arc::clone(&state.outbox), recipients, message)
+    };
+
+    // Fan out to every other member, queueing for the ones that are unreachable
+    let undelivered = fan_out_message(&connection_manager, &recipients, &message).await;
+    for peer_id in &undelivered {
+        if let Err(e) = outbox.enqueue_for(peer_id, &message) {
+            error!("Failed to queue message {} for {}: {}", message.id, peer_id, e);
+        }
+    }
+    let delivered = recipients.len() - undelivered.len();
+    info!("Group message {} sent to {} member(s) of {}", message.id, delivered, group_id);
+
+    if delivered > 0 || recipients.is_empty() {
+        let state = state.lock().await;
+        match state.chat_manager.update_message_status(&message, MessageStatus::Sent) {
+            Ok(Some(updated)) => message = updated,
+            Ok(None) => message.status = MessageStatus::Sent,
+            Err(e) => warn!("Failed to save message status: {}", e),
+        }
+    }
+
+    // Emit message update event
+    emit_event("message_sent", message.clone());
+    Ok(message)
+}
+
+/// IDs of the members of a group other than the local user
+fn other_members(state: &AppState, group: &Group) -> Vec<String> {
+    group
+        .members
+        .iter()
+        .filter(|id| **id != state.local_user.id)
+        .cloned()
+        .collect()
+}
+
+/// Sends one message to every recipient at once, returning the IDs of the peers that did not get it
+async fn fan_out_message(connection_manager: &ConnectionManager, recipients: &[Recipient], message: &Message) -> Vec<String> {
+    let results = fan_out(recipients, |peer_id, addrs| connection_manager.send_message(peer_id, message, addrs)).await;
+    undelivered(recipients, results, "message", &message.id)
+}
+
+#[tauri::command]
+async fn get_group_messages(
+    group_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Vec<Message>, String> {
+    let state = state.lock().await;
+    Ok(state.chat_manager.get_group_messages(&group_id))
+}
+
+// Commands for the LAN-wide broadcast channel
+#[tauri::command]
+async fn send_broadcast(
+    content: String,
+    reply_to: Option<String>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Message, String> {
+    let (connection_manager, recipients, mut message) = {
+        let state = state.lock().await;
+
+        // Every peer receives the same signed message, addressed to the broadcast channel
+        let mut message = Message::new(&state.local_user.id, BROADCAST_CHANNEL_ID, &content);
+        set_reply_target(&state, &mut message, reply_to)?;
+        state.identity.sign_message(&mut message).map_err(|e| e.to_string())?;
+
+        if let Err(e) = state.chat_manager.store_broadcast_message(&message) {
+            warn!("Failed to store broadcast locally: {}", e);
+        }
+
+        // Announcements only reach the peers that are online right now
+        let peer_ids: Vec<String> = state
+            .discovery
+            .get_discovered_peers()
+            .into_iter()
+            .map(|peer| peer.id)
+            .collect();
+        (state.connection_manager.handle(), recipients(&state, peer_ids), message)
+    };
+
+    let undelivered = fan_out_message(&connection_manager, &recipients, &message).await;
+    let delivered = recipients.len() - undelivered.len();
+    info!("Broadcast {} sent to {} of {} peer(s)", message.id, delivered, recipients.len());
+
+    let status = if delivered > 0 || recipients.is_empty() {
+        MessageStatus::Sent
+    } else {
+        MessageStatus::Failed
+    };
+    {
+        let state = state.lock().await;
+        match state.chat_manager.update_message_status(&message, status) {
+            Ok(Some(updated)) => message = updated,
+            Ok(None) => message.status = status,
+            Err(e) => warn!("Failed to save message status: {}", e),
+        }
+    }
+
+    // Emit message update event
+    emit_event("message_sent", message.clone());
+    Ok(message)
+}
+
+#[tauri::command]
+async fn get_broadcast_messages(
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<Vec<Message>, String> {
+    let state = state.lock().await;
+    Ok(state.chat_manager.get_broadcast_messages())
+}
+
+/// Tells the other members of a group about a membership change.
+///
+/// Members that are offline miss the update; they still accept messages from
+/// anyone in their own copy of the member list.
+async fn announce_group_change(
+    connection_manager: &ConnectionManager,
+    recipients: &[Recipient],
+    group: &Group,
+    change: MembershipChange,
+) {
+    for (member_id, addrs) in recipients {
+        if addrs.is_none() {
+            debug!("Member {} of group {} is offline, not sending {:?}", member_id, group.id, change);
+        }
+    }
+
+    let results = fan_out(recipients, |member_id, addrs| {
+        connection_manager.send_group_update(member_id, group, change, addrs)
+    })
+    .await;
+    for (member_id, result) in results {
+        if let Err(e) = result {
+            warn!("Failed to send {:?} for group {} to {}: {}", change, group.id, member_id, e);
         }
-        Err(e) => Err(e.to_string()),
     }
 }
 
@@ -298,29 +1285,29 @@ async fn send_file(
     file_path: String,
     state: tauri::State<'_, Arc<Mutex<AppState>>>,
 ) -> Result<FileTransfer, String> {
-    let mut state = state.lock().await;
-
     // Get peer information from discovery service
-    let peers = state.discovery.get_discovered_peers();
-    let peer = peers.iter().find(|p| p.id == peer_id);
+    let peer = state
+        .lock()
+        .await
+        .discovery
+        .get_peer_by_id(&peer_id)
+        .ok_or_else(|| "Peer not found in discovered peers".to_string())?;
 
-    match peer {
-        Some(peer) => {
-            // Create a modified send_file method that takes peer IP
-            match state
-                .file_manager
-                .send_file_with_peer(&peer_id, &file_path, &peer.ip)
-                .await
-            {
-                Ok(transfer) => {
-                    // Emit file transfer update event
-                    emit_event("file_transfer_update", transfer.clone());
-                    Ok(transfer)
-                }
-                Err(e) => Err(e.to_string()),
-            }
+    // Hashing a large file takes a while, so the app state is not held meanwhile
+    let file_hash = file_transfer::hash_file(&file_path).await.map_err(|e| e.to_string())?;
+
+    let mut state = state.lock().await;
+    match state
+        .file_manager
+        .send_file_with_peer(&peer_id, &file_path, file_hash, &peer.ip, peer.file_transfer_port())
+        .await
+    {
+        Ok(transfer) => {
+            // Emit file transfer update event
+            emit_event("file_transfer_update", transfer.clone());
+            Ok(transfer)
         }
-        None => Err("Peer not found in discovered peers".to_string()),
+        Err(e) => Err(e.to_string()),
     }
 }
 
@@ -392,6 +1379,52 @@ async fn cancel_file_transfer(
     }
 }
 
+#[tauri::command]
+async fn resume_file_transfer(
+    transfer_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<FileTransfer, String> {
+    let mut state = state.lock().await;
+    match state.file_manager.resume_transfer(&transfer_id).await {
+        Ok(transfer) => {
+            // Emit file transfer update event
+            emit_event("file_transfer_update", transfer.clone());
+            Ok(transfer)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+// Commands for peer verification
+#[tauri::command]
+async fn get_peer_fingerprint(
+    peer_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<PeerFingerprint, String> {
+    let state = state.lock().await;
+    state
+        .trust_store
+        .peer_fingerprint(&peer_id)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+async fn verify_peer(
+    peer_id: String,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<PeerFingerprint, String> {
+    let state = state.lock().await;
+    match state.trust_store.verify(&peer_id) {
+        Ok(fingerprint) => {
+            info!("Peer {} verified by the user", peer_id);
+            // Emit peer verified event
+            emit_event("peer_verified", fingerprint.clone());
+            Ok(fingerprint)
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 #[tauri::command]
 async fn update_username(
     username: String,
@@ -399,8 +1432,12 @@ async fn update_username(
 ) -> Result<User, String> {
     let mut state = state.lock().await;
     state.local_user.name = username;
-    // Broadcast the updated user info
-    if let Err(e) = state.discovery.broadcast_user_update().await {
+    // Hellos and outgoing messages carry the profile too, not just discovery
+    let local_user = state.local_user.clone();
+    state.chat_manager.set_local_user(local_user.clone());
+    state.connection_manager.set_local_user(local_user.clone());
+    state.file_manager.set_local_user(local_user.clone());
+    if let Err(e) = state.discovery.broadcast_user_update(&local_user).await {
         error!("Failed to broadcast user update: {e}");
     }
 
@@ -410,69 +1447,285 @@ async fn update_username(
     Ok(state.local_user.clone())
 }
 
-/// Generate a unique user ID using hostname to distinguish between devices
-fn generate_user_id() -> String {
-    let hostname = hostname::get()
-        .map(|h| h.to_string_lossy().to_string())
-        .unwrap_or_else(|_| "unknown".to_string());
-    let mut hasher = DefaultHasher::new();
-    hostname.hash(&mut hasher);
-    let hash = hasher.finish();
-    format!("user-{:x}", hash & 0xFFFFFFFF)
+#[tauri::command]
+async fn set_presence(
+    presence: Presence,
+    status_text: Option<String>,
+    state: tauri::State<'_, Arc<Mutex<AppState>>>,
+) -> Result<User, String> {
+    let status_text = status_text
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty());
+    if status_text.as_ref().is_some_and(|text| text.len() > MAX_STATUS_TEXT_LEN) {
+        return Err(format!("Status text is limited to {} bytes", MAX_STATUS_TEXT_LEN));
+    }
+
+    let mut state = state.lock().await;
+    state.local_user.presence = presence;
+    state.local_user.status_text = status_text;
+    // Hellos and outgoing messages carry the profile too, not just discovery
+    let local_user = state.local_user.clone();
+    state.chat_manager.set_local_user(local_user.clone());
+    state.connection_manager.set_local_user(local_user.clone());
+    state.file_manager.set_local_user(local_user.clone());
+    if let Err(e) = state.discovery.broadcast_user_update(&local_user).await {
+        error!("Failed to broadcast presence update: {e}");
+    }
+
+    // Emit user update event
+    emit_event("user_updated", state.local_user.clone());
+
+    Ok(state.local_user.clone())
 }
 
-#[cfg_attr(mobile, tauri::mobile_entry_point)]
-pub fn run() {
-    // Get local IP address
-    let local_ip = match local_ip() {
-        Ok(ip) => ip,
+/// Keeps peers added by address in the peer list while they are reachable
+async fn run_static_peers(app_state: Arc<Mutex<AppState>>) {
+    let mut probe_interval = tokio::time::interval(STATIC_PEER_PROBE_INTERVAL);
+
+    loop {
+        probe_interval.tick().await;
+
+        // Probes can time out, so the app state is only held to read and record results
+        let (local_user, channel_keys, static_peers) = {
+            let state = app_state.lock().await;
+            (state.local_user.clone(), Arc::clone(&state.channel_keys), state.static_peers.list())
+        };
+
+        for static_peer in static_peers {
+            let user = match connection_manager::probe_peer(&static_peer.host, static_peer.port, &local_user, &channel_keys).await {
+                Ok(user) => user,
+                Err(e) => {
+                    debug!("Static peer {}:{} is unreachable: {}", static_peer.host, static_peer.port, e);
+                    continue;
+                }
+            };
+
+            let state = app_state.lock().await;
+            if let Err(e) = state.static_peers.upsert(&static_peer.host, static_peer.port, &user.id) {
+                warn!("Failed to save static peer {}:{}: {}", static_peer.host, static_peer.port, e);
+            }
+            if let Err(e) = state.discovery.add_peer(user) {
+                warn!("Failed to add static peer {}:{}: {}", static_peer.host, static_peer.port, e);
+            }
+        }
+    }
+}
+
+/// Follows changes of the local addresses, e.g. after switching Wi-Fi or getting a new DHCP lease
+async fn run_network_watcher(app_state: Arc<Mutex<AppState>>) {
+    let mut watch_interval = tokio::time::interval(NETWORK_WATCH_INTERVAL);
+
+    loop {
+        watch_interval.tick().await;
+
+        // Listing interfaces can block for a while on some platforms
+        let (local_ip, addresses) = match tokio::task::spawn_blocking(network::local_user_addresses).await {
+            Ok(addresses) => addresses,
+            Err(e) => {
+                warn!("Failed to list network interfaces: {e}");
+                continue;
+            }
+        };
+
+        let mut state = app_state.lock().await;
+        if state.local_user.ip == local_ip && state.local_user.addresses == addresses {
+            continue;
+        }
+        info!(
+            "Local addresses changed from {} to {}",
+            state.local_user.addresses.join(", "),
+            addresses.join(", ")
+        );
+
+        state.local_user.ip = local_ip;
+        state.local_user.addresses = addresses;
+        let local_user = state.local_user.clone();
+        state.chat_manager.set_local_user(local_user.clone());
+        state.connection_manager.set_local_user(local_user.clone());
+        state.file_manager.set_local_user(local_user.clone());
+
+        // Connections from addresses we no longer have are dead, the next send reconnects
+        let dropped = state
+            .connection_manager
+            .drop_stale_connections(&local_user.addresses)
+            .await;
+        if dropped > 0 {
+            info!("Dropped {dropped} stale peer connection(s)");
+        }
+
+        // Peers must learn the new addresses before they can reach us again
+        if let Err(e) = state.discovery.broadcast_user_update(&local_user).await {
+            warn!("Failed to advertise new local addresses: {e}");
+        }
+
+        emit_event("local_network_changed", local_user);
+    }
+}
+
+/// Builds the application state, loading persisted data from `data_dir`.
+///
+/// Incoming files are saved to `download_dir` unless the user picks another path.
+/// `peer_online_tx` is told whenever a peer is discovered or answers a heartbeat.
+fn create_app_state(
+    data_dir: PathBuf,
+    download_dir: PathBuf,
+    peer_online_tx: mpsc::UnboundedSender<String>,
+) -> AppState {
+    // Get the preferred local IP address and every other usable one
+    let (local_ip, addresses) = network::local_user_addresses();
+    info!("Local IP address: {local_ip}, all addresses: {}", addresses.join(", "));
+
+    // Load the long-term device identity the user ID is derived from
+    let identity = match DeviceIdentity::load_or_create(&data_dir) {
+        Ok(identity) => identity,
         Err(e) => {
-            error!("Failed to get local IP address: {e}");
-            std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))
+            error!("Failed to load device identity, using a temporary one: {e}");
+            DeviceIdentity::generate()
         }
     };
-
-    info!("Local IP address: {local_ip}");
+    let identity = Arc::new(identity);
 
     // Create local user
     let local_user = User {
-        id: generate_user_id(),
+        id: identity.user_id(),
         name: hostname::get()
             .map(|h| h.to_string_lossy().to_string())
             .unwrap_or_else(|_| "Unknown User".to_string()),
-        ip: local_ip.to_string(),
+        ip: local_ip,
+        addresses,
         last_seen: chrono::Utc::now(),
+        public_key: Some(identity.public_key_hex()),
+        presence: Presence::Online,
+        status_text: None,
+        port: None,
+        file_port: None,
     };
 
+    // Key for the encrypted chat and file channels, signed by the device identity
+    let channel_keys = Arc::new(
+        ChannelKeys::new(&identity).expect("failed to generate secure channel keys"),
+    );
+
+    // Peer keys pinned on first contact
+    let trust_store = match TrustStore::open(&data_dir, identity.public_key_hex()) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open trust store, pinned keys will not be persisted: {e}");
+            TrustStore::in_memory(identity.public_key_hex())
+        }
+    };
+    let trust_store = Arc::new(trust_store);
+
     info!("Local user: {local_user:?}");
+    info!("App data directory: {}", data_dir.display());
+
+    // Network settings chosen by the user, e.g. which discovery backends to use
+    let network_settings = match NetworkSettingsStore::open(&data_dir) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open network settings, changes will not be persisted: {e}");
+            NetworkSettingsStore::in_memory()
+        }
+    };
 
     // Initialize app state
-    let network_discovery = NetworkDiscovery::new(local_user.clone());
-    let chat_manager = ChatManager::new(local_user.clone());
+    let mut network_discovery = NetworkDiscovery::new(
+        local_user.clone(),
+        Arc::clone(&trust_store),
+        Arc::clone(&identity),
+        &network_settings.get().discovery_backends,
+    );
+    network_discovery.set_peer_online_notifier(peer_online_tx.clone());
+    let peers = network_discovery.peer_map();
+    let chat_manager = ChatManager::new(
+        local_user.clone(),
+        &data_dir,
+        Arc::clone(&channel_keys),
+        Arc::clone(&peers),
+        Arc::clone(&trust_store),
+    );
     let message_storage = chat_manager.get_message_storage();
-    let connection_manager = ConnectionManager::new(local_user.clone(), message_storage);
-    let file_manager = FileTransferManager::new(local_user.clone());
+    let mut connection_manager = ConnectionManager::new(
+        local_user.clone(),
+        message_storage,
+        chat_manager.get_group_store(),
+        Arc::clone(&channel_keys),
+        peers,
+        Arc::clone(&trust_store),
+    );
+    connection_manager.set_peer_online_notifier(peer_online_tx);
+
+    // Messages waiting for peers that were unreachable
+    let outbox = match Outbox::open(&data_dir) {
+        Ok(outbox) => outbox,
+        Err(e) => {
+            error!("Failed to open outbox, queued messages will not be persisted: {e}");
+            Outbox::in_memory()
+        }
+    };
+    let file_manager = FileTransferManager::new(local_user.clone(), &data_dir, download_dir, Arc::clone(&channel_keys));
 
-    let app_state = Arc::new(Mutex::new(AppState {
+    // Peers added by address because mDNS does not reach them
+    let static_peers = match StaticPeerStore::open(&data_dir) {
+        Ok(store) => store,
+        Err(e) => {
+            error!("Failed to open static peers, manually added peers will not be persisted: {e}");
+            StaticPeerStore::in_memory()
+        }
+    };
+
+    AppState {
         local_user,
+        identity,
         discovery: network_discovery,
         chat_manager,
         connection_manager,
         file_manager,
+        trust_store,
+        outbox: Arc::new(outbox),
+        channel_keys,
+        static_peers,
+        network_settings,
         services_initialized: false,
-    }));
-
-    // Build and run the application
-    let app_state_clone = Arc::clone(&app_state);
+    }
+}
 
+#[cfg_attr(mobile, tauri::mobile_entry_point)]
+pub fn run() {
     tauri::Builder::default()
-        .setup(move |app| {
+        .setup(|app| {
             // Set app handle for event emission
             set_app_handle(app.handle().clone());
 
+            // Persistent data (message and transfer history) lives in the app data dir, unless
+            // IP_CHAT_DATA_DIR points elsewhere, e.g. to run a second instance with its own identity
+            let data_dir = match std::env::var_os("IP_CHAT_DATA_DIR") {
+                Some(dir) => PathBuf::from(dir),
+                None => app.path().app_data_dir()?,
+            };
+            let (peer_online_tx, peer_online_rx) = mpsc::unbounded_channel();
+            // Incoming files are saved to the downloads dir unless the user picks another path
+            let download_dir = app
+                .path()
+                .download_dir()
+                .unwrap_or_else(|_| data_dir.join("downloads"));
+            let state = create_app_state(data_dir, download_dir, peer_online_tx);
+            let outbox = Arc::clone(&state.outbox);
+            let app_state = Arc::new(Mutex::new(state));
+            app.manage(Arc::clone(&app_state));
+
+            // Deliver queued messages once their recipients are reachable
+            tauri::async_runtime::spawn(run_outbox(Arc::clone(&app_state), outbox, peer_online_rx));
+
+            // Probe peers added by address, for networks where mDNS does not get through
+            tauri::async_runtime::spawn(run_static_peers(Arc::clone(&app_state)));
+
+            // Re-advertise ourselves when the local addresses change
+            tauri::async_runtime::spawn(run_network_watcher(Arc::clone(&app_state)));
+
             // Start services automatically on app startup
             tauri::async_runtime::spawn(async move {
-                let mut state = app_state_clone.lock().await;
+                let mut state = app_state.lock().await;
                 ensure_services_initialized(&mut state).await;
             });
             Ok(())
@@ -482,22 +1735,50 @@ pub fn run() {
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_shell::init())
         .plugin(tauri_plugin_notification::init())
-        .manage(app_state)
         .invoke_handler(tauri::generate_handler![
             start_discovery,
             stop_discovery,
             get_discovered_peers,
             refresh_discovery,
+            get_discovery_backends,
+            set_discovery_backends,
+            get_network_settings,
+            set_listen_ports,
+            add_manual_peer,
+            get_manual_peers,
+            remove_manual_peer,
             get_local_user,
             send_message,
             get_messages,
+            get_unread_counts,
+            get_thread,
+            search_messages,
+            export_conversation,
+            import_history,
             mark_messages_as_read,
+            edit_message,
+            delete_message,
+            add_reaction,
+            remove_reaction,
+            send_typing,
+            get_groups,
+            create_group,
+            join_group,
+            leave_group,
+            send_group_message,
+            get_group_messages,
+            send_broadcast,
+            get_broadcast_messages,
             send_file,
             accept_file_transfer,
             reject_file_transfer,
             get_file_transfers,
             cancel_file_transfer,
+            resume_file_transfer,
+            get_peer_fingerprint,
+            verify_peer,
             update_username,
+            set_presence,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
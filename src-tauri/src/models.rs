@@ -1,10 +1,25 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
-use crate::chat::ChatManager;
+use crate::chat::{ChatManager, BROADCAST_CHANNEL_ID, CHAT_PORT};
 use crate::connection_manager::ConnectionManager;
 use crate::discovery::NetworkDiscovery;
-use crate::file_transfer::FileTransferManager;
+use crate::file_transfer::{FileTransferManager, FILE_TRANSFER_PORT};
+use crate::identity::DeviceIdentity;
+use crate::network;
+use crate::outbox::Outbox;
+use crate::secure_channel::ChannelKeys;
+use crate::settings::NetworkSettingsStore;
+use crate::static_peers::StaticPeerStore;
+use crate::trust::TrustStore;
+
+/// Longest emoji accepted as a reaction, in bytes; enough for ZWJ sequences
+pub const MAX_REACTION_LEN: usize = 32;
+/// Longest status text, in bytes; it must fit in a single mDNS TXT string
+pub const MAX_STATUS_TEXT_LEN: usize = 100;
 
 /// Represents a user in the network
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -14,10 +29,108 @@ pub struct User {
     pub id: String,
     /// Display name of the user
     pub name: String,
-    /// IP address of the user
+    /// Preferred IP address of the user, with a scope ID if it is IPv6 link-local
     pub ip: String,
+    /// Every address the user can be reached at, most preferred first
+    #[serde(default)]
+    pub addresses: Vec<String>,
     /// Last time the user was seen on the network
     pub last_seen: DateTime<Utc>,
+    /// Hex-encoded Ed25519 public key of the user's device
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// Availability chosen by the user
+    #[serde(default)]
+    pub presence: Presence,
+    /// Optional status text shown next to the presence
+    #[serde(default)]
+    pub status_text: Option<String>,
+    /// Chat port the user listens on, the default one when missing
+    #[serde(default)]
+    pub port: Option<u16>,
+    /// File transfer port the user listens on, the default one when missing
+    #[serde(default)]
+    pub file_port: Option<u16>,
+}
+
+impl User {
+    /// Port to connect to for chatting with the user
+    pub fn chat_port(&self) -> u16 {
+        self.port.unwrap_or(CHAT_PORT)
+    }
+
+    /// Port to connect to for sending files to the user
+    pub fn file_transfer_port(&self) -> u16 {
+        self.file_port.unwrap_or(FILE_TRANSFER_PORT)
+    }
+
+    /// The preferred address followed by the other known ones
+    fn known_addresses(&self) -> Vec<String> {
+        let mut addresses = vec![self.ip.clone()];
+        addresses.extend(self.addresses.iter().filter(|address| **address != self.ip).cloned());
+        addresses
+    }
+
+    /// Socket addresses to try, in order, to reach the user's chat port
+    pub fn chat_addrs(&self) -> Vec<SocketAddr> {
+        network::candidate_addrs(&self.known_addresses(), self.chat_port())
+    }
+
+    /// Whether the user is known to use `ip`
+    pub fn has_address(&self, ip: IpAddr) -> bool {
+        self.known_addresses()
+            .iter()
+            .any(|address| network::parse_address(address, 0).is_some_and(|addr| addr.ip() == ip))
+    }
+}
+
+/// Availability of a user, published with its mDNS service
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Presence {
+    /// Available
+    #[default]
+    Online,
+    /// Away from the device
+    Away,
+    /// Available but busy
+    Busy,
+    /// Not to be disturbed by notifications
+    DoNotDisturb,
+}
+
+impl Presence {
+    /// Value advertised in the `presence` TXT record
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Presence::Online => "Online",
+            Presence::Away => "Away",
+            Presence::Busy => "Busy",
+            Presence::DoNotDisturb => "DoNotDisturb",
+        }
+    }
+
+    /// Parses a `presence` TXT record value
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "Online" => Some(Presence::Online),
+            "Away" => Some(Presence::Away),
+            "Busy" => Some(Presence::Busy),
+            "DoNotDisturb" => Some(Presence::DoNotDisturb),
+            _ => None,
+        }
+    }
+}
+
+/// A peer started or stopped typing in a conversation, never persisted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TypingIndicator {
+    /// ID of the peer typing
+    pub peer_id: String,
+    /// Group the peer is typing in, `None` for the direct conversation
+    pub group_id: Option<String>,
+    /// Whether the peer is typing
+    pub typing: bool,
 }
 
 /// Represents a chat message
@@ -36,6 +149,235 @@ pub struct Message {
     pub timestamp: DateTime<Utc>,
     /// Whether the message has been read
     pub read: bool,
+    /// Hex-encoded Ed25519 signature by the sender's identity key
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Delivery status of a message we sent
+    #[serde(default)]
+    pub status: MessageStatus,
+    /// Group the message was posted to; `recipient_id` is then the group ID
+    #[serde(default)]
+    pub group_id: Option<String>,
+    /// Earlier versions of the content, oldest first
+    #[serde(default)]
+    pub edit_history: Vec<MessageVersion>,
+    /// Timestamp of the latest edit
+    #[serde(default)]
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Whether the sender retracted the message; its content has been erased
+    #[serde(default)]
+    pub deleted: bool,
+    /// ID of the message this one replies to, in the same conversation
+    #[serde(default)]
+    pub reply_to: Option<String>,
+    /// ID of the first message of the thread, set on every reply
+    #[serde(default)]
+    pub thread_root: Option<String>,
+    /// IDs of the users who reacted, by emoji
+    #[serde(default)]
+    pub reactions: HashMap<String, Vec<String>>,
+}
+
+impl Message {
+    /// Creates an unsigned message from the local user, not yet sent
+    pub fn new(sender_id: &str, recipient_id: &str, content: &str) -> Self {
+        Message {
+            id: uuid::Uuid::new_v4().to_string(),
+            sender_id: sender_id.to_string(),
+            recipient_id: recipient_id.to_string(),
+            content: content.to_string(),
+            timestamp: Utc::now(),
+            read: false,
+            signature: None,
+            status: MessageStatus::Sending,
+            group_id: None,
+            edit_history: Vec::new(),
+            edited_at: None,
+            deleted: false,
+            reply_to: None,
+            thread_root: None,
+            reactions: HashMap::new(),
+        }
+    }
+
+    /// ID of the conversation the message belongs to, as seen by `local_user_id`.
+    ///
+    /// That is the group for group messages, the broadcast channel for
+    /// broadcasts and the other party for direct messages.
+    pub fn conversation_id(&self, local_user_id: &str) -> &str {
+        match &self.group_id {
+            Some(group_id) => group_id,
+            None if self.recipient_id == BROADCAST_CHANNEL_ID => BROADCAST_CHANNEL_ID,
+            None if self.sender_id == local_user_id => &self.recipient_id,
+            None => &self.sender_id,
+        }
+    }
+
+    /// Makes this message a reply to `parent`, joining the parent's thread
+    pub fn set_reply_to(&mut self, parent: &Message) {
+        self.reply_to = Some(parent.id.clone());
+        self.thread_root = Some(parent.thread_root.clone().unwrap_or_else(|| parent.id.clone()));
+    }
+
+    /// Replaces the content with the sender's edit, keeping the previous version.
+    ///
+    /// Returns `false` if the edit doesn't apply, e.g. it is older than the current content.
+    pub fn apply_edit(&mut self, edit: &MessageEdit) -> bool {
+        if self.deleted || edit.message_id != self.id || edit.sender_id != self.sender_id {
+            return false;
+        }
+
+        // Edits may arrive late or out of order, only newer content replaces the current one
+        let current_since = self.edited_at.unwrap_or(self.timestamp);
+        if edit.edited_at <= current_since {
+            return false;
+        }
+
+        let previous = std::mem::replace(&mut self.content, edit.content.clone());
+        self.edit_history.push(MessageVersion {
+            content: previous,
+            timestamp: current_since,
+        });
+        self.edited_at = Some(edit.edited_at);
+        true
+    }
+
+    /// Turns the message into a tombstone, erasing its content, edit history and reactions.
+    ///
+    /// Returns `false` if the deletion doesn't apply or the message is already deleted.
+    pub fn apply_deletion(&mut self, deletion: &MessageDeletion) -> bool {
+        if self.deleted || deletion.message_id != self.id || deletion.sender_id != self.sender_id {
+            return false;
+        }
+
+        self.content.clear();
+        self.edit_history.clear();
+        self.reactions.clear();
+        self.deleted = true;
+        true
+    }
+
+    /// Adds or removes a user's reaction, returning whether anything changed.
+    ///
+    /// Applying the same reaction twice is a no-op, so repeated frames are harmless.
+    pub fn apply_reaction(&mut self, reaction: &Reaction) -> bool {
+        if self.deleted
+            || reaction.message_id != self.id
+            || reaction.emoji.is_empty()
+            || reaction.emoji.len() > MAX_REACTION_LEN
+        {
+            return false;
+        }
+
+        if reaction.added {
+            let users = self.reactions.entry(reaction.emoji.clone()).or_default();
+            if users.contains(&reaction.user_id) {
+                return false;
+            }
+            users.push(reaction.user_id.clone());
+            return true;
+        }
+
+        let Some(users) = self.reactions.get_mut(&reaction.emoji) else {
+            return false;
+        };
+        let before = users.len();
+        users.retain(|user_id| *user_id != reaction.user_id);
+        let changed = users.len() != before;
+        if users.is_empty() {
+            self.reactions.remove(&reaction.emoji);
+        }
+        changed
+    }
+}
+
+/// Reaction added to or removed from a message by one user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Reaction {
+    /// ID of the message reacted to
+    pub message_id: String,
+    /// ID of the user reacting
+    pub user_id: String,
+    /// The emoji, usually a single grapheme
+    pub emoji: String,
+    /// Whether the reaction is added or removed
+    pub added: bool,
+}
+
+/// A previous version of an edited message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageVersion {
+    /// Content of this version
+    pub content: String,
+    /// Timestamp when this version was written
+    pub timestamp: DateTime<Utc>,
+}
+
+/// New content for a message, signed by its sender
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageEdit {
+    /// ID of the edited message
+    pub message_id: String,
+    /// ID of the sender, who must also be the author of the message
+    pub sender_id: String,
+    /// Replacement content
+    pub content: String,
+    /// Timestamp of the edit
+    pub edited_at: DateTime<Utc>,
+    /// Hex-encoded Ed25519 signature by the sender's identity key
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// Retraction of a message, signed by its sender
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessageDeletion {
+    /// ID of the deleted message
+    pub message_id: String,
+    /// ID of the sender, who must also be the author of the message
+    pub sender_id: String,
+    /// Timestamp of the deletion
+    pub deleted_at: DateTime<Utc>,
+    /// Hex-encoded Ed25519 signature by the sender's identity key
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// Represents the delivery status of a chat message
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MessageStatus {
+    /// Message is being sent
+    Sending,
+    /// Message has been written to the peer's connection
+    #[default]
+    Sent,
+    /// Peer confirmed it stored the message
+    Delivered,
+    /// Peer opened the conversation containing the message
+    Read,
+    /// Message could not be sent
+    Failed,
+}
+
+impl MessageStatus {
+    fn rank(self) -> u8 {
+        match self {
+            MessageStatus::Sending => 0,
+            MessageStatus::Failed => 1,
+            MessageStatus::Sent => 2,
+            MessageStatus::Delivered => 3,
+            MessageStatus::Read => 4,
+        }
+    }
+
+    /// Whether moving to `next` is progress; receipts may arrive late or out of order
+    pub fn can_advance_to(self, next: MessageStatus) -> bool {
+        next.rank() > self.rank()
+    }
 }
 
 /// Represents the status of a file transfer
@@ -53,6 +395,8 @@ pub enum TransferStatus {
     Cancelled,
     /// Transfer has failed
     Failed,
+    /// Transfer was still in progress when the application exited
+    Interrupted,
 }
 
 /// Represents a file transfer
@@ -69,6 +413,12 @@ pub struct FileTransfer {
     pub sender_ip: Option<String>,
     /// IP address of the recipient
     pub recipient_ip: Option<String>,
+    /// File transfer port of the sender, the default one when missing
+    #[serde(default)]
+    pub sender_port: Option<u16>,
+    /// File transfer port of the recipient, the default one when missing
+    #[serde(default)]
+    pub recipient_port: Option<u16>,
     /// Name of the file
     pub file_name: String,
     /// Size of the file in bytes
@@ -85,12 +435,113 @@ pub struct FileTransfer {
     pub timestamp: DateTime<Utc>,
     /// Error message if the transfer failed
     pub error: Option<String>,
+    /// Hex-encoded SHA-256 of the whole file, advertised by the sender
+    #[serde(default)]
+    pub file_hash: Option<String>,
+}
+
+/// Represents a named group conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    /// Unique identifier for the group
+    pub id: String,
+    /// Display name of the group
+    pub name: String,
+    /// IDs of the current members, including the local user while joined
+    pub members: Vec<String>,
+    /// IDs of the users the creator added, the only ones who may join again after leaving
+    #[serde(default)]
+    pub invited: Vec<String>,
+    /// ID of the user who created the group
+    pub created_by: String,
+    /// Timestamp when the group was created
+    pub created_at: DateTime<Utc>,
+}
+
+impl Group {
+    /// Whether `user_id` is currently a member
+    pub fn has_member(&self, user_id: &str) -> bool {
+        self.members.iter().any(|member| member == user_id)
+    }
+
+    /// Whether `user_id` was added to the group by its creator
+    pub fn is_invited(&self, user_id: &str) -> bool {
+        self.invited.iter().any(|invited| invited == user_id)
+    }
+}
+
+/// Position in a conversation's history that a page starts or ends at
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum HistoryCursor {
+    /// Exclusive bound at a message
+    MessageId(String),
+    /// Exclusive bound at a point in time
+    Timestamp(DateTime<Utc>),
+}
+
+/// Which slice of a conversation's history to return
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PageRequest {
+    /// Only messages older than this
+    pub before: Option<HistoryCursor>,
+    /// Only messages newer than this
+    pub after: Option<HistoryCursor>,
+    /// Most messages to return, unlimited when missing
+    pub limit: Option<usize>,
+}
+
+/// A page of history, oldest message first.
+///
+/// The page holds the newest messages in range unless only `after` is set,
+/// in which case it holds the oldest ones, so it can be used to scroll either way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// Whether more messages in range were left out by the limit
+    pub has_more: bool,
+}
+
+/// A message matching a search, with the messages around it in its conversation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SearchResult {
+    pub message: Message,
+    /// Peer, group or broadcast channel the message belongs to
+    pub conversation_id: String,
+    /// Excerpt of the content around the first match
+    pub snippet: String,
+    /// Messages just before the match, oldest first
+    pub context_before: Vec<Message>,
+    /// Messages just after the match, oldest first
+    pub context_after: Vec<Message>,
+}
+
+/// Fingerprints and safety number used to verify a peer out of band
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PeerFingerprint {
+    /// ID of the peer
+    pub peer_id: String,
+    /// Fingerprint of the key pinned for the peer
+    pub fingerprint: String,
+    /// Fingerprint of our own key
+    pub local_fingerprint: String,
+    /// Safety number shared by both devices, to be compared side by side
+    pub safety_number: String,
+    /// Whether the user has verified this key
+    pub verified: bool,
 }
 
 /// Represents the application state
 pub struct AppState {
     /// The local user
     pub local_user: User,
+    /// Long-term identity key of this device
+    pub identity: Arc<DeviceIdentity>,
     /// Network discovery service
     pub discovery: NetworkDiscovery,
     /// Chat manager
@@ -99,6 +550,158 @@ pub struct AppState {
     pub connection_manager: ConnectionManager,
     /// File transfer manager
     pub file_manager: FileTransferManager,
+    /// Public keys pinned for known peers
+    pub trust_store: Arc<TrustStore>,
+    /// Messages waiting for unreachable peers
+    pub outbox: Arc<Outbox>,
+    /// Keys of the encrypted chat channel, also used to probe peers added by address
+    pub channel_keys: Arc<ChannelKeys>,
+    /// Peers added by address, probed again periodically
+    pub static_peers: StaticPeerStore,
+    /// Network settings chosen by the user
+    pub network_settings: NetworkSettingsStore,
     /// Whether services have been initialized
     pub services_initialized: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn message() -> Message {
+        Message::new("alice", "bob", "hello")
+    }
+
+    fn edit(message: &Message, content: &str, after_secs: i64) -> MessageEdit {
+        MessageEdit {
+            message_id: message.id.clone(),
+            sender_id: message.sender_id.clone(),
+            content: content.to_string(),
+            edited_at: message.timestamp + Duration::seconds(after_secs),
+            signature: None,
+        }
+    }
+
+    fn deletion(message: &Message) -> MessageDeletion {
+        MessageDeletion {
+            message_id: message.id.clone(),
+            sender_id: message.sender_id.clone(),
+            deleted_at: Utc::now(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn edits_keep_the_previous_versions() {
+        let mut message = message();
+        assert!(message.apply_edit(&edit(&message, "hello there", 1)));
+        assert!(message.apply_edit(&edit(&message, "hello again", 2)));
+
+        assert_eq!(message.content, "hello again");
+        let history: Vec<&str> = message.edit_history.iter().map(|v| v.content.as_str()).collect();
+        assert_eq!(history, ["hello", "hello there"]);
+        assert_eq!(message.edit_history[1].timestamp, message.timestamp + Duration::seconds(1));
+        assert_eq!(message.edited_at, Some(message.timestamp + Duration::seconds(2)));
+    }
+
+    #[test]
+    fn late_edits_are_ignored() {
+        let mut message = message();
+        let late = edit(&message, "hello there", 1);
+        assert!(message.apply_edit(&edit(&message, "hello again", 2)));
+
+        assert!(!message.apply_edit(&late));
+        assert!(!message.apply_edit(&edit(&message, "same time", 2)));
+        assert_eq!(message.content, "hello again");
+        assert_eq!(message.edit_history.len(), 1);
+    }
+
+    #[test]
+    fn edits_by_someone_else_are_ignored() {
+        let mut message = message();
+        let mut forged = edit(&message, "forged", 1);
+        forged.sender_id = "mallory".to_string();
+        let mut other = edit(&message, "other", 1);
+        other.message_id = "other".to_string();
+
+        assert!(!message.apply_edit(&forged));
+        assert!(!message.apply_edit(&other));
+        assert_eq!(message.content, "hello");
+    }
+
+    #[test]
+    fn deletion_erases_the_message() {
+        let mut message = message();
+        assert!(message.apply_edit(&edit(&message, "hello there", 1)));
+
+        assert!(message.apply_deletion(&deletion(&message)));
+        assert!(message.deleted);
+        assert!(message.content.is_empty());
+        assert!(message.edit_history.is_empty());
+
+        assert!(!message.apply_deletion(&deletion(&message)));
+        assert!(!message.apply_edit(&edit(&message, "back", 5)));
+        assert!(message.content.is_empty());
+    }
+
+    #[test]
+    fn deletions_by_someone_else_are_ignored() {
+        let mut message = message();
+        let mut forged = deletion(&message);
+        forged.sender_id = "mallory".to_string();
+
+        assert!(!message.apply_deletion(&forged));
+        assert!(!message.deleted);
+        assert_eq!(message.content, "hello");
+    }
+
+    fn reaction(message: &Message, user_id: &str, emoji: &str, added: bool) -> Reaction {
+        Reaction {
+            message_id: message.id.clone(),
+            user_id: user_id.to_string(),
+            emoji: emoji.to_string(),
+            added,
+        }
+    }
+
+    #[test]
+    fn reactions_are_added_once_per_user() {
+        let mut message = message();
+        assert!(message.apply_reaction(&reaction(&message, "bob", "👍", true)));
+        assert!(!message.apply_reaction(&reaction(&message, "bob", "👍", true)));
+        assert!(message.apply_reaction(&reaction(&message, "carol", "👍", true)));
+        assert!(message.apply_reaction(&reaction(&message, "bob", "🎉", true)));
+
+        assert_eq!(message.reactions["👍"], ["bob", "carol"]);
+        assert_eq!(message.reactions["🎉"], ["bob"]);
+    }
+
+    #[test]
+    fn removing_the_last_reaction_drops_the_emoji() {
+        let mut message = message();
+        assert!(message.apply_reaction(&reaction(&message, "bob", "👍", true)));
+
+        assert!(!message.apply_reaction(&reaction(&message, "carol", "👍", false)));
+        assert!(message.apply_reaction(&reaction(&message, "bob", "👍", false)));
+        assert!(!message.apply_reaction(&reaction(&message, "bob", "👍", false)));
+        assert!(message.reactions.is_empty());
+    }
+
+    #[test]
+    fn invalid_reactions_are_ignored() {
+        let mut message = message();
+        let mut other = reaction(&message, "bob", "👍", true);
+        other.message_id = "other".to_string();
+
+        assert!(!message.apply_reaction(&other));
+        assert!(!message.apply_reaction(&reaction(&message, "bob", "", true)));
+        assert!(!message.apply_reaction(&reaction(&message, "bob", &"👍".repeat(9), true)));
+        assert!(message.reactions.is_empty());
+
+        assert!(message.apply_reaction(&reaction(&message, "bob", "👍", true)));
+        assert!(message.apply_deletion(&deletion(&message)));
+        assert!(message.reactions.is_empty());
+        assert!(!message.apply_reaction(&reaction(&message, "bob", "👍", true)));
+    }
+}
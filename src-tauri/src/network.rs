@@ -0,0 +1,321 @@
+use futures::stream::{FuturesUnordered, StreamExt};
+use log::{debug, warn};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{sleep, timeout};
+
+use crate::error::{AppError, AppResult};
+
+/// Head start given to each address before the next one is tried, as recommended by RFC 8305
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Addresses this device can be reached at, those of the default interface first.
+///
+/// IPv4 addresses come before IPv6 ones on each interface, and IPv6 link-local
+/// addresses carry the interface index as their scope ID, e.g. `fe80::1%3`.
+pub fn local_addresses() -> Vec<String> {
+    let default_index = default_net::get_default_interface()
+        .ok()
+        .map(|interface| interface.index);
+    let mut interfaces: Vec<_> = default_net::get_interfaces()
+        .into_iter()
+        .filter(|interface| interface.is_up() && !interface.is_loopback())
+        .collect();
+    interfaces.sort_by_key(|interface| Some(interface.index) != default_index);
+
+    let mut addresses = Vec::new();
+    for interface in &interfaces {
+        for net in &interface.ipv4 {
+            if !net.addr.is_unspecified() {
+                addresses.push(net.addr.to_string());
+            }
+        }
+        for net in &interface.ipv6 {
+            if net.addr.is_unspecified() || net.addr.is_multicast() {
+                continue;
+            }
+            let scope_id = if is_link_local(&net.addr) { interface.index } else { 0 };
+            addresses.push(format_address(SocketAddr::V6(SocketAddrV6::new(net.addr, 0, 0, scope_id))));
+        }
+    }
+    dedup(addresses)
+}
+
+/// The preferred local address, as picked by the OS routing table, and every usable one with it first
+pub fn local_user_addresses() -> (String, Vec<String>) {
+    let mut addresses = local_addresses();
+    let ip = match local_ip_address::local_ip() {
+        Ok(ip) => ip.to_string(),
+        Err(e) => {
+            debug!("Failed to get the preferred local IP address: {e}");
+            addresses
+                .first()
+                .cloned()
+                .unwrap_or_else(|| Ipv4Addr::LOCALHOST.to_string())
+        }
+    };
+    addresses.retain(|address| *address != ip);
+    addresses.insert(0, ip.clone());
+    (ip, addresses)
+}
+
+/// Whether an IPv6 address is link-local, so it can only be reached through a given interface
+fn is_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+/// Removes repeated addresses, keeping the first occurrence
+fn dedup(addresses: Vec<String>) -> Vec<String> {
+    let mut unique: Vec<String> = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        if !unique.contains(&address) {
+            unique.push(address);
+        }
+    }
+    unique
+}
+
+/// Formats the IP of a socket address as stored in a `User`, with the scope ID of link-local IPv6 addresses
+pub fn format_address(addr: SocketAddr) -> String {
+    match addr {
+        SocketAddr::V6(addr) if addr.scope_id() != 0 => format!("{}%{}", addr.ip(), addr.scope_id()),
+        addr => addr.ip().to_canonical().to_string(),
+    }
+}
+
+/// Parses an address as stored in a `User` into the socket address of `port` on it
+pub fn parse_address(address: &str, port: u16) -> Option<SocketAddr> {
+    if let Ok(ip) = address.parse::<IpAddr>() {
+        return Some(SocketAddr::new(ip, port));
+    }
+    let (ip, scope_id) = address.split_once('%')?;
+    Some(SocketAddr::V6(SocketAddrV6::new(ip.parse().ok()?, port, 0, scope_id.parse().ok()?)))
+}
+
+/// Parses an address, failing with a `NetworkError` if it is malformed
+pub fn socket_addr(address: &str, port: u16) -> AppResult<SocketAddr> {
+    parse_address(address, port)
+        .ok_or_else(|| AppError::NetworkError(format!("Invalid address: {address}")))
+}
+
+/// Addresses to reach a peer at: the one it was just seen at, then those it advertised.
+///
+/// Advertised scope IDs name the peer's own interfaces, so they are dropped.
+pub fn observed_addresses(source: SocketAddr, advertised: &[String]) -> Vec<String> {
+    let advertised = advertised
+        .iter()
+        .map(|address| address.split('%').next().unwrap_or(address).to_string());
+    dedup(std::iter::once(format_address(source)).chain(advertised).collect())
+}
+
+/// Socket addresses to try, in order, to reach `port` on a peer.
+///
+/// Link-local IPv6 addresses without a scope ID, e.g. from mDNS, are tried
+/// on every local interface that has one.
+pub fn candidate_addrs(addresses: &[String], port: u16) -> Vec<SocketAddr> {
+    let mut candidates = Vec::new();
+    for address in addresses {
+        let Some(addr) = parse_address(address, port) else {
+            debug!("Skipping malformed peer address {address}");
+            continue;
+        };
+        match addr {
+            SocketAddr::V6(v6) if v6.scope_id() == 0 && is_link_local(v6.ip()) => {
+                candidates.extend(link_local_scopes().into_iter().map(|scope_id| {
+                    SocketAddr::V6(SocketAddrV6::new(*v6.ip(), port, 0, scope_id))
+                }));
+            }
+            addr => candidates.push(addr),
+        }
+    }
+
+    let mut unique = Vec::with_capacity(candidates.len());
+    for addr in candidates {
+        if !unique.contains(&addr) {
+            unique.push(addr);
+        }
+    }
+    unique
+}
+
+/// Indexes of the local interfaces with an IPv6 link-local address
+fn link_local_scopes() -> Vec<u32> {
+    default_net::get_interfaces()
+        .into_iter()
+        .filter(|interface| interface.is_up() && !interface.is_loopback())
+        .filter(|interface| interface.ipv6.iter().any(|net| is_link_local(&net.addr)))
+        .map(|interface| interface.index)
+        .collect()
+}
+
+/// Connects to the first of `addrs` that accepts, in the spirit of happy eyeballs.
+///
+/// Each address gets a short head start before the next one is tried in
+/// parallel, and a failed attempt starts the next one right away.
+pub async fn connect_any(addrs: &[SocketAddr], connect_timeout: Duration) -> AppResult<(TcpStream, SocketAddr)> {
+    let attempt = |addr: SocketAddr| async move {
+        let result = match timeout(connect_timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => Ok(stream),
+            Ok(Err(e)) => Err(AppError::NetworkError(format!("Connection to {addr} failed: {e}"))),
+            Err(_) => Err(AppError::NetworkError(format!("Connection to {addr} timed out"))),
+        };
+        (addr, result)
+    };
+
+    let mut remaining = addrs.iter().copied();
+    let mut pending = FuturesUnordered::new();
+    let mut last_error = AppError::NetworkError("Peer has no known address".to_string());
+    if let Some(addr) = remaining.next() {
+        pending.push(attempt(addr));
+    }
+
+    while !pending.is_empty() {
+        tokio::select! {
+            Some((addr, result)) = pending.next() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) => {
+                    debug!("{e}");
+                    last_error = e;
+                    if let Some(next) = remaining.next() {
+                        pending.push(attempt(next));
+                    }
+                }
+            },
+            _ = sleep(CONNECTION_ATTEMPT_DELAY), if remaining.len() > 0 => {
+                if let Some(next) = remaining.next() {
+                    pending.push(attempt(next));
+                }
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Listens on `port` on all IPv4 and IPv6 addresses; port 0 picks a free port.
+///
+/// Where the IPv6 socket is dual-stack, as on Linux and macOS, the IPv4 bind
+/// fails because the port is taken, and only the IPv6 listener is kept.
+pub async fn listen_dual_stack(port: u16) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    let mut port = port;
+    match TcpListener::bind((Ipv6Addr::UNSPECIFIED, port)).await {
+        Ok(listener) => {
+            port = listener.local_addr()?.port();
+            listeners.push(listener);
+        }
+        Err(e) => debug!("Not listening on IPv6 port {port}: {e}"),
+    }
+
+    match TcpListener::bind((Ipv4Addr::UNSPECIFIED, port)).await {
+        Ok(listener) => listeners.push(listener),
+        Err(e) if !listeners.is_empty() => debug!("IPv4 port {port} is served by the IPv6 listener: {e}"),
+        Err(e) => return Err(e),
+    }
+    Ok(listeners)
+}
+
+/// Listens on `port` like `listen_dual_stack`, returning the port actually bound.
+///
+/// If `port` is the `default_port` and taken, e.g. by another instance on this
+/// machine, a free port is picked instead; peers learn it from our advertisement.
+/// A port the user configured is never swapped, peers added by address expect it.
+pub async fn listen_on(port: u16, default_port: u16) -> io::Result<(Vec<TcpListener>, u16)> {
+    let listeners = match listen_dual_stack(port).await {
+        Ok(listeners) => listeners,
+        Err(e) if port == default_port && e.kind() == io::ErrorKind::AddrInUse => {
+            warn!("Port {port} is in use, listening on a free port instead");
+            listen_dual_stack(0).await?
+        }
+        Err(e) => return Err(e),
+    };
+    let port = listeners[0].local_addr()?.port();
+    Ok((listeners, port))
+}
+
+/// Accepts the next connection on any of `listeners`.
+///
+/// IPv4 peers reaching a dual-stack listener are reported with their IPv4 address.
+pub async fn accept_any(listeners: &[TcpListener]) -> io::Result<(TcpStream, SocketAddr)> {
+    let accepts = listeners.iter().map(|listener| Box::pin(listener.accept()));
+    let (accepted, _, _) = futures::future::select_all(accepts).await;
+    let (stream, addr) = accepted?;
+    Ok((stream, SocketAddr::new(addr.ip().to_canonical(), addr.port())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_round_trip_with_their_scope_id() {
+        for address in ["192.168.1.20", "fd00::20", "fe80::1c2b:3dff:fe4e:5f60%3"] {
+            let addr = parse_address(address, 8765).unwrap();
+            assert_eq!(addr.port(), 8765);
+            assert_eq!(format_address(addr), address);
+        }
+        assert!(parse_address("not an address", 8765).is_none());
+        assert!(parse_address("fe80::1%eth0", 8765).is_none());
+    }
+
+    #[test]
+    fn ipv4_mapped_addresses_are_formatted_as_ipv4() {
+        let addr: SocketAddr = "[::ffff:192.168.1.20]:8765".parse().unwrap();
+        assert_eq!(format_address(addr), "192.168.1.20");
+    }
+
+    #[test]
+    fn observed_address_comes_first_without_advertised_scopes() {
+        let source: SocketAddr = "192.168.1.30:50000".parse().unwrap();
+        let advertised = [
+            "192.168.1.20".to_string(),
+            "192.168.1.30".to_string(),
+            "fe80::1%7".to_string(),
+        ];
+
+        assert_eq!(
+            observed_addresses(source, &advertised),
+            ["192.168.1.30", "192.168.1.20", "fe80::1"]
+        );
+    }
+
+    #[test]
+    fn candidates_skip_malformed_and_repeated_addresses() {
+        let addresses = [
+            "192.168.1.20".to_string(),
+            "bogus".to_string(),
+            "fd00::20".to_string(),
+            "192.168.1.20".to_string(),
+            "fe80::1%3".to_string(),
+        ];
+
+        let candidates: Vec<String> = candidate_addrs(&addresses, 8765)
+            .iter()
+            .map(|addr| addr.to_string())
+            .collect();
+        assert_eq!(candidates, ["192.168.1.20:8765", "[fd00::20]:8765", "[fe80::1%3]:8765"]);
+    }
+
+    #[tokio::test]
+    async fn connect_any_uses_the_address_that_answers() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let open = listener.local_addr().unwrap();
+
+        let (_, connected) = connect_any(&[closed, open], Duration::from_secs(2)).await.unwrap();
+        assert_eq!(connected, open);
+        assert!(connect_any(&[closed], Duration::from_secs(2)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn only_the_default_port_falls_back_when_taken() {
+        let taken = listen_dual_stack(0).await.unwrap();
+        let port = taken[0].local_addr().unwrap().port();
+
+        let (_listeners, bound) = listen_on(port, port).await.unwrap();
+        assert_ne!(bound, port);
+        let error = listen_on(port, port.wrapping_add(1)).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+    }
+}
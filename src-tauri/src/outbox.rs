@@ -0,0 +1,537 @@
+use chrono::{DateTime, Duration, Utc};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::AppResult;
+use crate::models::{Message, MessageDeletion, MessageEdit};
+use crate::storage::JsonSnapshot;
+
+const OUTBOX_FILE: &str = "outbox.json";
+/// v2 added queued edits and deletions; v1 entries still read as queued messages
+const OUTBOX_SCHEMA_VERSION: u32 = 2;
+/// Delivery attempts before a message is given up on
+const MAX_ATTEMPTS: u32 = 10;
+/// Delay before the first retry, doubled after every failed attempt
+const BASE_RETRY_DELAY_SECS: i64 = 5;
+/// Upper bound on the delay between two attempts
+const MAX_RETRY_DELAY_SECS: i64 = 10 * 60;
+/// Messages still undelivered after this long are given up on
+const MAX_QUEUE_AGE_DAYS: i64 = 7;
+
+/// Something of ours an offline peer has not received yet
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum OutboxItem {
+    Message(Message),
+    /// Edit of a message the peer already received
+    Edit(MessageEdit),
+    /// Deletion of a message the peer already received
+    Deletion(MessageDeletion),
+}
+
+impl OutboxItem {
+    /// ID of the message the item carries or changes
+    pub fn message_id(&self) -> &str {
+        match self {
+            OutboxItem::Message(message) => &message.id,
+            OutboxItem::Edit(edit) => &edit.message_id,
+            OutboxItem::Deletion(deletion) => &deletion.message_id,
+        }
+    }
+
+    /// Name of the item kind, used for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            OutboxItem::Message(_) => "message",
+            OutboxItem::Edit(_) => "edit",
+            OutboxItem::Deletion(_) => "deletion",
+        }
+    }
+
+    /// When the item was created, which sets the delivery order
+    fn timestamp(&self) -> DateTime<Utc> {
+        match self {
+            OutboxItem::Message(message) => message.timestamp,
+            OutboxItem::Edit(edit) => edit.edited_at,
+            OutboxItem::Deletion(deletion) => deletion.deleted_at,
+        }
+    }
+
+    /// Whether both items are of the same kind and about the same message
+    fn same_as(&self, other: &OutboxItem) -> bool {
+        std::mem::discriminant(self) == std::mem::discriminant(other) && self.message_id() == other.message_id()
+    }
+}
+
+/// An item waiting to be delivered to an offline peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    /// Stored under the item kind, so v1 entries read as queued messages
+    #[serde(flatten)]
+    pub item: OutboxItem,
+    /// Peer the item is queued for, unless it is a message to that peer alone
+    #[serde(default)]
+    pub peer_id: Option<String>,
+    /// Failed delivery attempts so far
+    pub attempts: u32,
+    /// Earliest time the next periodic retry may happen
+    pub next_attempt: DateTime<Utc>,
+}
+
+impl OutboxEntry {
+    fn new(item: OutboxItem, peer_id: Option<String>) -> Self {
+        OutboxEntry {
+            item,
+            peer_id,
+            attempts: 0,
+            next_attempt: Utc::now(),
+        }
+    }
+
+    /// ID of the peer the item is waiting for
+    fn recipient(&self) -> &str {
+        match (&self.peer_id, &self.item) {
+            (Some(peer_id), _) => peer_id,
+            (None, OutboxItem::Message(message)) => &message.recipient_id,
+            (None, _) => "",
+        }
+    }
+}
+
+/// Queued items as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutboxSnapshot {
+    entries: Vec<OutboxEntry>,
+}
+
+/// Undelivered messages, edits and deletions by recipient ID, saved as a JSON snapshot in the app data dir
+pub struct Outbox {
+    entries: Mutex<HashMap<String, Vec<OutboxEntry>>>,
+    snapshot: Option<JsonSnapshot<OutboxSnapshot>>,
+}
+
+impl Outbox {
+    /// Creates an outbox that only keeps messages in memory
+    pub fn in_memory() -> Self {
+        Outbox {
+            entries: Mutex::new(HashMap::new()),
+            snapshot: None,
+        }
+    }
+
+    /// Opens the persistent outbox under `data_dir`
+    pub fn open(data_dir: &Path) -> AppResult<Self> {
+        let snapshot: JsonSnapshot<OutboxSnapshot> =
+            JsonSnapshot::open(data_dir, OUTBOX_FILE, "Outbox", OUTBOX_SCHEMA_VERSION)?;
+
+        let mut entries: HashMap<String, Vec<OutboxEntry>> = HashMap::new();
+        if let Some(saved) = snapshot.load()? {
+            for entry in saved.entries {
+                entries.entry(entry.recipient().to_string()).or_default().push(entry);
+            }
+        }
+
+        let outbox = Outbox {
+            entries: Mutex::new(entries),
+            snapshot: Some(snapshot),
+        };
+        info!("Loaded {} queued item(s) from the outbox", outbox.len());
+        Ok(outbox)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<OutboxEntry>>> {
+        self.entries.lock().unwrap()
+    }
+
+    /// Total number of queued items
+    fn len(&self) -> usize {
+        self.lock().values().map(Vec::len).sum()
+    }
+
+    /// Queues a message for later delivery to its recipient
+    pub fn enqueue(&self, message: &Message) -> AppResult<()> {
+        self.enqueue_for(&message.recipient_id, message)
+    }
+
+    /// Queues a message for later delivery to `peer_id`, e.g. one member of a group
+    pub fn enqueue_for(&self, peer_id: &str, message: &Message) -> AppResult<()> {
+        {
+            let mut entries = self.lock();
+            let queue = entries.entry(peer_id.to_string()).or_default();
+            if queue.iter().any(|entry| entry.item.message_id() == message.id) {
+                return Ok(());
+            }
+            queue.push(OutboxEntry::new(
+                OutboxItem::Message(message.clone()),
+                (peer_id != message.recipient_id).then(|| peer_id.to_string()),
+            ));
+        }
+
+        info!("Queued message {} for {}", message.id, peer_id);
+        self.save()
+    }
+
+    /// Queues the edit of a message `peer_id` already received, replacing an earlier queued edit
+    pub fn enqueue_edit(&self, peer_id: &str, edit: &MessageEdit) -> AppResult<()> {
+        {
+            let mut entries = self.lock();
+            let queue = entries.entry(peer_id.to_string()).or_default();
+            let item = OutboxItem::Edit(edit.clone());
+            queue.retain(|entry| !entry.item.same_as(&item));
+            queue.push(OutboxEntry::new(item, Some(peer_id.to_string())));
+        }
+
+        info!("Queued edit of message {} for {}", edit.message_id, peer_id);
+        self.save()
+    }
+
+    /// Queues the deletion of a message `peer_id` already received, in place of its queued edits
+    pub fn enqueue_deletion(&self, peer_id: &str, deletion: &MessageDeletion) -> AppResult<()> {
+        {
+            let mut entries = self.lock();
+            let queue = entries.entry(peer_id.to_string()).or_default();
+            queue.retain(|entry| entry.item.message_id() != deletion.message_id);
+            queue.push(OutboxEntry::new(
+                OutboxItem::Deletion(deletion.clone()),
+                Some(peer_id.to_string()),
+            ));
+        }
+
+        info!("Queued deletion of message {} for {}", deletion.message_id, peer_id);
+        self.save()
+    }
+
+    /// Whether anything is waiting for `peer_id`
+    pub fn has_pending(&self, peer_id: &str) -> bool {
+        self.lock().get(peer_id).is_some_and(|queue| !queue.is_empty())
+    }
+
+    /// IDs of peers with items whose retry delay has passed
+    pub fn peers_due(&self) -> Vec<String> {
+        let now = Utc::now();
+        self.lock()
+            .iter()
+            .filter(|(_, queue)| queue.iter().any(|entry| entry.next_attempt <= now))
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect()
+    }
+
+    /// Items to try sending to `peer_id`, oldest first.
+    ///
+    /// When the peer has just been seen online the retry delay is skipped.
+    pub fn pending_for(&self, peer_id: &str, ignore_backoff: bool) -> Vec<OutboxItem> {
+        let now = Utc::now();
+        let entries = self.lock();
+        let Some(queue) = entries.get(peer_id) else {
+            return Vec::new();
+        };
+
+        let mut items: Vec<OutboxItem> = queue
+            .iter()
+            .filter(|entry| ignore_backoff || entry.next_attempt <= now)
+            .map(|entry| entry.item.clone())
+            .collect();
+        items.sort_by_key(OutboxItem::timestamp);
+        items
+    }
+
+    /// Removes a delivered item from the outbox
+    pub fn remove(&self, peer_id: &str, item: &OutboxItem) -> AppResult<()> {
+        {
+            let mut entries = self.lock();
+            if let Some(queue) = entries.get_mut(peer_id) {
+                queue.retain(|entry| !entry.item.same_as(item));
+                if queue.is_empty() {
+                    entries.remove(peer_id);
+                }
+            }
+        }
+        self.save()
+    }
+
+    /// Replaces every queued copy of a message, keeping its retry schedule, e.g. once its sender edited it.
+    ///
+    /// Returns the IDs of the peers it was queued for, which don't need the edit itself.
+    pub fn replace(&self, message: &Message) -> AppResult<Vec<String>> {
+        let peer_ids = {
+            let mut entries = self.lock();
+            let mut peer_ids = Vec::new();
+            for (peer_id, queue) in entries.iter_mut() {
+                for entry in queue {
+                    if let OutboxItem::Message(queued) = &mut entry.item {
+                        if queued.id == message.id {
+                            *queued = message.clone();
+                            peer_ids.push(peer_id.clone());
+                        }
+                    }
+                }
+            }
+            peer_ids
+        };
+
+        if !peer_ids.is_empty() {
+            info!("Updated queued message {}", message.id);
+            self.save()?;
+        }
+        Ok(peer_ids)
+    }
+
+    /// Drops a message and its queued edits from every queue, e.g. once its sender deleted it.
+    ///
+    /// Returns the IDs of the peers the message itself was queued for, which never saw it.
+    pub fn discard(&self, message_id: &str) -> AppResult<Vec<String>> {
+        let (peer_ids, changed) = {
+            let mut entries = self.lock();
+            let mut peer_ids = Vec::new();
+            let before: usize = entries.values().map(Vec::len).sum();
+            for (peer_id, queue) in entries.iter_mut() {
+                queue.retain(|entry| {
+                    let discard = entry.item.message_id() == message_id;
+                    if discard && matches!(entry.item, OutboxItem::Message(_)) {
+                        peer_ids.push(peer_id.clone());
+                    }
+                    !discard
+                });
+            }
+            entries.retain(|_, queue| !queue.is_empty());
+            (peer_ids, entries.values().map(Vec::len).sum::<usize>() != before)
+        };
+
+        if changed {
+            info!("Discarded queued message {}", message_id);
+            self.save()?;
+        }
+        Ok(peer_ids)
+    }
+
+    /// Records a failed delivery attempt and schedules the next one.
+    ///
+    /// Returns `true` if the item has been given up on and removed.
+    pub fn record_failure(&self, peer_id: &str, item: &OutboxItem) -> AppResult<bool> {
+        let gave_up = {
+            let mut entries = self.lock();
+            let Some(queue) = entries.get_mut(peer_id) else {
+                return Ok(false);
+            };
+            let Some(entry) = queue.iter_mut().find(|entry| entry.item.same_as(item)) else {
+                return Ok(false);
+            };
+
+            entry.attempts += 1;
+            if entry.attempts >= MAX_ATTEMPTS {
+                warn!(
+                    "Giving up on {} of message {} to {} after {} attempt(s)",
+                    item.kind(),
+                    item.message_id(),
+                    peer_id,
+                    entry.attempts
+                );
+                queue.retain(|entry| !entry.item.same_as(item));
+                if queue.is_empty() {
+                    entries.remove(peer_id);
+                }
+                true
+            } else {
+                entry.next_attempt = Utc::now() + retry_delay(entry.attempts);
+                false
+            }
+        };
+
+        self.save()?;
+        Ok(gave_up)
+    }
+
+    /// Removes items that have waited longer than `MAX_QUEUE_AGE_DAYS`, returning the expired messages
+    pub fn expire_stale(&self) -> AppResult<Vec<Message>> {
+        let cutoff = Utc::now() - Duration::days(MAX_QUEUE_AGE_DAYS);
+        let mut expired = Vec::new();
+        let removed = {
+            let mut entries = self.lock();
+            let before: usize = entries.values().map(Vec::len).sum();
+            for queue in entries.values_mut() {
+                queue.retain(|entry| {
+                    let keep = entry.item.timestamp() > cutoff;
+                    if let (false, OutboxItem::Message(message)) = (keep, &entry.item) {
+                        expired.push(message.clone());
+                    }
+                    keep
+                });
+            }
+            entries.retain(|_, queue| !queue.is_empty());
+            before - entries.values().map(Vec::len).sum::<usize>()
+        };
+
+        if removed > 0 {
+            warn!("Giving up on {} item(s) queued for too long", removed);
+            self.save()?;
+        }
+        Ok(expired)
+    }
+
+    /// Writes the queued items to disk
+    fn save(&self) -> AppResult<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+
+        // Holding the lock while writing also serializes concurrent saves
+        let entries = self.lock();
+        snapshot.save(&OutboxSnapshot {
+            entries: entries.values().flatten().cloned().collect(),
+        })
+    }
+}
+
+/// Exponential backoff after `attempts` failures, capped at `MAX_RETRY_DELAY_SECS`
+fn retry_delay(attempts: u32) -> Duration {
+    let secs = BASE_RETRY_DELAY_SECS.saturating_mul(1 << attempts.saturating_sub(1).min(16));
+    Duration::seconds(secs.min(MAX_RETRY_DELAY_SECS))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next_attempt(outbox: &Outbox, peer_id: &str, message_id: &str) -> DateTime<Utc> {
+        outbox.lock()[peer_id]
+            .iter()
+            .find(|entry| entry.item.message_id() == message_id)
+            .unwrap()
+            .next_attempt
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_cap() {
+        assert_eq!(retry_delay(1), Duration::seconds(5));
+        assert_eq!(retry_delay(2), Duration::seconds(10));
+        assert_eq!(retry_delay(3), Duration::seconds(20));
+        assert_eq!(retry_delay(7), Duration::seconds(320));
+        assert_eq!(retry_delay(8), Duration::seconds(MAX_RETRY_DELAY_SECS));
+        assert_eq!(retry_delay(u32::MAX), Duration::seconds(MAX_RETRY_DELAY_SECS));
+    }
+
+    #[test]
+    fn failures_postpone_the_next_attempt() {
+        let outbox = Outbox::in_memory();
+        let message = Message::new("alice", "bob", "hello");
+        outbox.enqueue(&message).unwrap();
+        assert_eq!(outbox.peers_due(), ["bob"]);
+
+        let before = Utc::now();
+        let item = OutboxItem::Message(message.clone());
+        assert!(!outbox.record_failure("bob", &item).unwrap());
+
+        assert!(next_attempt(&outbox, "bob", &message.id) >= before + retry_delay(1));
+        assert!(outbox.peers_due().is_empty());
+        assert!(outbox.pending_for("bob", false).is_empty());
+        assert_eq!(outbox.pending_for("bob", true).len(), 1);
+    }
+
+    #[test]
+    fn messages_are_given_up_on_after_max_attempts() {
+        let outbox = Outbox::in_memory();
+        let message = Message::new("alice", "bob", "hello");
+        outbox.enqueue(&message).unwrap();
+        let item = OutboxItem::Message(message);
+
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(!outbox.record_failure("bob", &item).unwrap());
+        }
+        assert!(outbox.record_failure("bob", &item).unwrap());
+        assert!(!outbox.has_pending("bob"));
+    }
+
+    #[test]
+    fn stale_messages_expire() {
+        let outbox = Outbox::in_memory();
+        let mut stale = Message::new("alice", "bob", "old");
+        stale.timestamp = Utc::now() - Duration::days(MAX_QUEUE_AGE_DAYS + 1);
+        let fresh = Message::new("alice", "bob", "new");
+        outbox.enqueue(&stale).unwrap();
+        outbox.enqueue(&fresh).unwrap();
+
+        let expired = outbox.expire_stale().unwrap();
+
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].id, stale.id);
+        let pending: Vec<String> = outbox
+            .pending_for("bob", true)
+            .iter()
+            .map(|item| item.message_id().to_string())
+            .collect();
+        assert_eq!(pending, [fresh.id]);
+    }
+
+    fn edit(message: &Message, content: &str) -> MessageEdit {
+        MessageEdit {
+            message_id: message.id.clone(),
+            sender_id: message.sender_id.clone(),
+            content: content.to_string(),
+            edited_at: Utc::now(),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn edits_and_deletions_wait_for_offline_peers() {
+        let outbox = Outbox::in_memory();
+        let message = Message::new("alice", "bob", "hello");
+        outbox.enqueue_edit("bob", &edit(&message, "hi")).unwrap();
+        outbox.enqueue_edit("bob", &edit(&message, "hey")).unwrap();
+
+        // Only the latest edit is kept
+        let pending = outbox.pending_for("bob", true);
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(&pending[0], OutboxItem::Edit(edit) if edit.content == "hey"));
+
+        // A deletion makes the queued edits moot
+        let deletion = MessageDeletion {
+            message_id: message.id.clone(),
+            sender_id: "alice".to_string(),
+            deleted_at: Utc::now(),
+            signature: None,
+        };
+        outbox.enqueue_deletion("bob", &deletion).unwrap();
+        let pending = outbox.pending_for("bob", true);
+        assert_eq!(pending.len(), 1);
+        assert!(matches!(pending[0], OutboxItem::Deletion(_)));
+
+        outbox.remove("bob", &pending[0]).unwrap();
+        assert!(!outbox.has_pending("bob"));
+    }
+
+    #[test]
+    fn replacing_and_discarding_report_where_the_message_was_queued() {
+        let outbox = Outbox::in_memory();
+        let message = Message::new("alice", "group-1", "hello");
+        outbox.enqueue_for("bob", &message).unwrap();
+        outbox.enqueue_edit("carol", &edit(&message, "hi")).unwrap();
+
+        assert_eq!(outbox.replace(&message).unwrap(), ["bob"]);
+        assert_eq!(outbox.discard(&message.id).unwrap(), ["bob"]);
+        assert!(!outbox.has_pending("bob"));
+        assert!(!outbox.has_pending("carol"));
+        assert!(outbox.discard(&message.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn v1_entries_read_as_queued_messages() {
+        let message = Message::new("alice", "bob", "hello");
+        let v1 = serde_json::json!({
+            "message": message,
+            "peerId": null,
+            "attempts": 2,
+            "nextAttempt": Utc::now(),
+        });
+
+        let entry: OutboxEntry = serde_json::from_value(v1).unwrap();
+        assert!(matches!(&entry.item, OutboxItem::Message(queued) if queued.id == message.id));
+        assert_eq!(entry.recipient(), "bob");
+        assert_eq!(entry.attempts, 2);
+    }
+}
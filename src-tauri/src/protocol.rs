@@ -0,0 +1,331 @@
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::chat::BROADCAST_CHANNEL_ID;
+use crate::error::{AppError, AppResult};
+use crate::models::{Group, Message, MessageDeletion, MessageEdit, Reaction, User};
+
+/// Version of the chat wire protocol spoken by this build.
+///
+/// A frame is only sent to peers that negotiated the version introducing it,
+/// see `WireFrame::min_version`.
+///
+/// v2 added `delivered` and `read` receipts.
+/// v3 added group messages and `group_update` membership frames.
+/// v4 added signed `edit` and `delete` frames; broadcast messages need it too.
+/// v5 added `reaction` frames and replies.
+/// v6 added `typing` frames.
+/// v7 added the sender's profile to `hello` and `hello_ack`, so peers can be added by address.
+pub const PROTOCOL_VERSION: u16 = 7;
+/// Oldest protocol version we are still willing to talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+/// Upper bound on a single frame body, protects against garbage length prefixes
+const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024; // 16MB
+
+/// A single frame exchanged on the chat port.
+///
+/// Every frame is sent as a 4-byte big-endian length prefix followed by the
+/// JSON encoding of this enum, e.g. `{"type":"message","data":{...}}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum WireFrame {
+    /// First frame sent by the connecting side
+    Hello {
+        version: u16,
+        user_id: String,
+        /// Profile of the connecting user, missing before v7
+        #[serde(default)]
+        user: Option<User>,
+    },
+    /// Reply to `Hello` carrying the negotiated protocol version
+    HelloAck {
+        version: u16,
+        /// Profile of the accepting user, missing before v7
+        #[serde(default)]
+        user: Option<User>,
+    },
+    /// A chat message
+    Message(Message),
+    /// Receipt sent by the recipient once messages are stored
+    Delivered { message_ids: Vec<String> },
+    /// Receipt sent by the recipient once messages have been read
+    Read { message_ids: Vec<String> },
+    /// New content for a message, sent by its author to everyone who received it
+    Edit(MessageEdit),
+    /// Tombstone retracting a message, sent by its author to everyone who received it
+    Delete(MessageDeletion),
+    /// Reaction added to or removed from a message, sent to everyone in its conversation
+    Reaction(Reaction),
+    /// The sender started or stopped typing, in a group or the direct conversation
+    Typing { group_id: Option<String>, typing: bool },
+    /// Membership change of a group, sent to its other members
+    GroupUpdate { group: Group, change: MembershipChange },
+    /// Keep-alive probe
+    Heartbeat { timestamp: i64 },
+    /// Reply to a keep-alive probe
+    HeartbeatResponse { timestamp: i64 },
+    /// Any frame type introduced by a newer protocol version, never sent
+    #[serde(skip)]
+    Unknown,
+}
+
+/// Wire names of the frame types this build understands
+const KNOWN_FRAME_TYPES: &[&str] = &[
+    "hello",
+    "hello_ack",
+    "message",
+    "delivered",
+    "read",
+    "edit",
+    "delete",
+    "reaction",
+    "typing",
+    "group_update",
+    "heartbeat",
+    "heartbeat_response",
+];
+
+/// Kind of membership change announced in a `group_update` frame.
+///
+/// The member concerned is always the authenticated sender of the frame, except
+/// for `Created` which adds every listed member at once.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipChange {
+    /// The sender created the group with the listed members
+    Created,
+    /// The sender joined the group
+    Joined,
+    /// The sender left the group
+    Left,
+}
+
+impl WireFrame {
+    /// Returns the wire name of the frame, used for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            WireFrame::Hello { .. } => "hello",
+            WireFrame::HelloAck { .. } => "hello_ack",
+            WireFrame::Message(_) => "message",
+            WireFrame::Delivered { .. } => "delivered",
+            WireFrame::Read { .. } => "read",
+            WireFrame::Edit(_) => "edit",
+            WireFrame::Delete(_) => "delete",
+            WireFrame::Reaction(_) => "reaction",
+            WireFrame::Typing { .. } => "typing",
+            WireFrame::GroupUpdate { .. } => "group_update",
+            WireFrame::Heartbeat { .. } => "heartbeat",
+            WireFrame::HeartbeatResponse { .. } => "heartbeat_response",
+            WireFrame::Unknown => "unknown",
+        }
+    }
+
+    /// Oldest negotiated protocol version the peer must speak to be sent this frame
+    pub fn min_version(&self) -> u16 {
+        match self {
+            WireFrame::Message(message) => {
+                if message.reply_to.is_some() || message.thread_root.is_some() {
+                    5
+                } else if message.recipient_id == BROADCAST_CHANNEL_ID {
+                    4
+                } else if message.group_id.is_some() {
+                    3
+                } else {
+                    MIN_PROTOCOL_VERSION
+                }
+            }
+            WireFrame::Delivered { .. } | WireFrame::Read { .. } => 2,
+            WireFrame::GroupUpdate { .. } => 3,
+            WireFrame::Edit(_) | WireFrame::Delete(_) => 4,
+            WireFrame::Reaction(_) => 5,
+            WireFrame::Typing { .. } => 6,
+            _ => MIN_PROTOCOL_VERSION,
+        }
+    }
+}
+
+/// Picks the protocol version to use with a peer advertising `peer_version`
+pub fn negotiate_version(peer_version: u16) -> AppResult<u16> {
+    let version = peer_version.min(PROTOCOL_VERSION);
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(AppError::ProtocolError(format!(
+            "Unsupported protocol version {peer_version} (minimum {MIN_PROTOCOL_VERSION})"
+        )));
+    }
+    Ok(version)
+}
+
+/// Writes a single length-prefixed frame and flushes the writer
+pub async fn write_frame<W>(writer: &mut W, frame: &WireFrame) -> AppResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let body = serde_json::to_vec(frame).map_err(AppError::SerializationError)?;
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(AppError::ProtocolError(format!(
+            "Frame too large: {} bytes",
+            body.len()
+        )));
+    }
+
+    writer.write_u32(body.len() as u32).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a single length-prefixed frame.
+///
+/// Returns `Ok(None)` when the peer closed the connection cleanly between frames.
+pub async fn read_frame<R>(reader: &mut R) -> AppResult<Option<WireFrame>>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len as usize,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(AppError::IoError(e)),
+    };
+
+    if len > MAX_FRAME_SIZE {
+        return Err(AppError::ProtocolError(format!(
+            "Frame too large: {len} bytes"
+        )));
+    }
+
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+
+    decode_frame(&body).map(Some)
+}
+
+/// Decodes a frame body, mapping frame types from newer protocol versions to `Unknown`.
+///
+/// The type is looked at first, so an unknown frame is skipped whatever its payload.
+fn decode_frame(body: &[u8]) -> AppResult<WireFrame> {
+    let value: serde_json::Value = serde_json::from_slice(body)?;
+    let frame_type = value
+        .get("type")
+        .and_then(|frame_type| frame_type.as_str())
+        .ok_or_else(|| AppError::ProtocolError("Frame has no type".to_string()))?;
+    if !KNOWN_FRAME_TYPES.contains(&frame_type) {
+        return Ok(WireFrame::Unknown);
+    }
+    Ok(serde_json::from_value(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Length-prefixes a raw JSON frame body
+    fn raw_frame(body: &str) -> Vec<u8> {
+        let mut bytes = (body.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(body.as_bytes());
+        bytes
+    }
+
+    #[tokio::test]
+    async fn known_frame_round_trips() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, &WireFrame::Heartbeat { timestamp: 42 }).await.unwrap();
+
+        let frame = read_frame(&mut buf.as_slice()).await.unwrap();
+        assert!(matches!(frame, Some(WireFrame::Heartbeat { timestamp: 42 })));
+    }
+
+    #[tokio::test]
+    async fn unknown_frame_with_data_is_skipped() {
+        let mut buf = raw_frame(r#"{"type":"from_the_future","data":{"answer":42,"list":[1,2]}}"#);
+        write_frame(&mut buf, &WireFrame::Read { message_ids: vec!["m1".to_string()] })
+            .await
+            .unwrap();
+
+        let mut reader = buf.as_slice();
+        assert!(matches!(read_frame(&mut reader).await.unwrap(), Some(WireFrame::Unknown)));
+        match read_frame(&mut reader).await.unwrap() {
+            Some(WireFrame::Read { message_ids }) => assert_eq!(message_ids, ["m1"]),
+            other => panic!("expected a read frame, got {other:?}"),
+        }
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn unknown_frame_without_data_is_skipped() {
+        let buf = raw_frame(r#"{"type":"from_the_future"}"#);
+        assert!(matches!(read_frame(&mut buf.as_slice()).await.unwrap(), Some(WireFrame::Unknown)));
+    }
+
+    #[tokio::test]
+    async fn malformed_frames_are_rejected() {
+        for body in [r#"{"data":{}}"#, r#"{"type":7}"#, r#"{"type":"read","data":{}}"#, "not json"] {
+            let buf = raw_frame(body);
+            assert!(read_frame(&mut buf.as_slice()).await.is_err(), "{body} was accepted");
+        }
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_is_rejected() {
+        let buf = ((MAX_FRAME_SIZE + 1) as u32).to_be_bytes();
+        assert!(read_frame(&mut buf.as_slice()).await.is_err());
+    }
+
+    #[test]
+    fn known_frame_types_cover_every_frame() {
+        let frames = [
+            WireFrame::Hello { version: 1, user_id: String::new(), user: None },
+            WireFrame::HelloAck { version: 1, user: None },
+            WireFrame::Delivered { message_ids: Vec::new() },
+            WireFrame::Read { message_ids: Vec::new() },
+            WireFrame::Typing { group_id: None, typing: true },
+            WireFrame::Heartbeat { timestamp: 0 },
+            WireFrame::HeartbeatResponse { timestamp: 0 },
+        ];
+        for frame in &frames {
+            let value = serde_json::to_value(frame).unwrap();
+            assert_eq!(value["type"], frame.kind());
+            assert!(KNOWN_FRAME_TYPES.contains(&frame.kind()));
+        }
+        for kind in ["message", "edit", "delete", "reaction", "group_update"] {
+            assert!(KNOWN_FRAME_TYPES.contains(&kind));
+        }
+    }
+
+    #[test]
+    fn negotiates_the_lower_version() {
+        assert_eq!(negotiate_version(PROTOCOL_VERSION).unwrap(), PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(PROTOCOL_VERSION + 5).unwrap(), PROTOCOL_VERSION);
+        assert_eq!(negotiate_version(MIN_PROTOCOL_VERSION).unwrap(), MIN_PROTOCOL_VERSION);
+        assert!(negotiate_version(MIN_PROTOCOL_VERSION - 1).is_err());
+    }
+
+    #[test]
+    fn v1_peers_are_only_sent_v1_frames() {
+        let v1_frames = [
+            WireFrame::Hello { version: 1, user_id: String::new(), user: None },
+            WireFrame::Heartbeat { timestamp: 0 },
+        ];
+        for frame in &v1_frames {
+            assert_eq!(frame.min_version(), 1, "{}", frame.kind());
+        }
+        assert_eq!(WireFrame::Delivered { message_ids: Vec::new() }.min_version(), 2);
+        assert_eq!(WireFrame::Read { message_ids: Vec::new() }.min_version(), 2);
+        assert_eq!(WireFrame::Typing { group_id: None, typing: true }.min_version(), 6);
+    }
+
+    #[test]
+    fn messages_need_the_version_introducing_their_kind() {
+        let direct = Message::new("alice", "bob", "hi");
+        let mut group = Message::new("alice", "group-1", "hi all");
+        group.group_id = Some("group-1".to_string());
+        let broadcast = Message::new("alice", BROADCAST_CHANNEL_ID, "hi everyone");
+        let mut reply = Message::new("alice", "group-1", "me too");
+        reply.group_id = Some("group-1".to_string());
+        reply.set_reply_to(&group);
+
+        assert_eq!(WireFrame::Message(direct).min_version(), 1);
+        assert_eq!(WireFrame::Message(group).min_version(), 3);
+        assert_eq!(WireFrame::Message(broadcast).min_version(), 4);
+        assert_eq!(WireFrame::Message(reply).min_version(), 5);
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use crate::models::Message;
+
+/// Words kept on each side of the first match in a snippet
+const SNIPPET_RADIUS_WORDS: usize = 8;
+
+/// Splits text into lowercase alphanumeric tokens, without duplicates
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut seen = HashSet::new();
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .filter(|token| seen.insert(token.clone()))
+        .collect()
+}
+
+/// Whether any token of `word` starts with one of the query tokens
+fn word_matches(word: &str, query_tokens: &[String]) -> bool {
+    tokenize(word)
+        .iter()
+        .any(|token| query_tokens.iter().any(|query| token.starts_with(query.as_str())))
+}
+
+/// Excerpt of `content` around the first word matching the query
+pub fn snippet(content: &str, query_tokens: &[String]) -> String {
+    let words: Vec<&str> = content.split_whitespace().collect();
+    let first_match = words
+        .iter()
+        .position(|word| word_matches(word, query_tokens))
+        .unwrap_or(0);
+
+    let start = first_match.saturating_sub(SNIPPET_RADIUS_WORDS);
+    let end = (first_match + SNIPPET_RADIUS_WORDS + 1).min(words.len());
+    let mut snippet = words[start..end].join(" ");
+    if start > 0 {
+        snippet.insert_str(0, "… ");
+    }
+    if end < words.len() {
+        snippet.push_str(" …");
+    }
+    snippet
+}
+
+/// Inverted index from content tokens to message IDs, kept by `MessageStore`
+#[derive(Default)]
+pub struct SearchIndex {
+    /// Message IDs by token, ordered so prefixes can be looked up as a range
+    postings: BTreeMap<String, HashSet<String>>,
+    /// Bucket and tokens of every indexed message, used to unindex it
+    messages: HashMap<String, (String, Vec<String>)>,
+}
+
+impl SearchIndex {
+    /// Builds an index over messages by bucket
+    pub fn build(messages: &HashMap<String, Vec<Message>>) -> Self {
+        let mut index = SearchIndex::default();
+        for (bucket, bucket_messages) in messages {
+            for message in bucket_messages {
+                index.index(bucket, message);
+            }
+        }
+        index
+    }
+
+    /// Indexes the current content of a message, replacing any previous version
+    pub fn index(&mut self, bucket: &str, message: &Message) {
+        self.remove(&message.id);
+        if message.deleted {
+            return;
+        }
+
+        let tokens = tokenize(&message.content);
+        for token in &tokens {
+            self.postings
+                .entry(token.clone())
+                .or_default()
+                .insert(message.id.clone());
+        }
+        self.messages
+            .insert(message.id.clone(), (bucket.to_string(), tokens));
+    }
+
+    /// Drops a message from the index
+    pub fn remove(&mut self, message_id: &str) {
+        let Some((_, tokens)) = self.messages.remove(message_id) else {
+            return;
+        };
+        for token in tokens {
+            if let Some(ids) = self.postings.get_mut(&token) {
+                ids.remove(message_id);
+                if ids.is_empty() {
+                    self.postings.remove(&token);
+                }
+            }
+        }
+    }
+
+    /// Buckets and IDs of messages with a token starting with every query token
+    pub fn search(&self, query_tokens: &[String]) -> Vec<(String, String)> {
+        let mut matches: Option<HashSet<&String>> = None;
+        for query in query_tokens {
+            let ids: HashSet<&String> = self
+                .postings
+                .range(query.clone()..)
+                .take_while(|(token, _)| token.starts_with(query.as_str()))
+                .flat_map(|(_, ids)| ids)
+                .collect();
+
+            let narrowed = match matches {
+                Some(previous) => previous.intersection(&ids).copied().collect(),
+                None => ids,
+            };
+            if narrowed.is_empty() {
+                return Vec::new();
+            }
+            matches = Some(narrowed);
+        }
+
+        matches
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| {
+                self.messages
+                    .get(id)
+                    .map(|(bucket, _)| (bucket.clone(), id.clone()))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, content: &str) -> Message {
+        let mut message = Message::new("alice", "bob", content);
+        message.id = id.to_string();
+        message
+    }
+
+    fn ids(mut matches: Vec<(String, String)>) -> Vec<String> {
+        matches.sort();
+        matches.into_iter().map(|(_, id)| id).collect()
+    }
+
+    #[test]
+    fn tokens_are_lowercase_words_without_duplicates() {
+        assert_eq!(tokenize("Hello, hello WORLD! 42 times"), ["hello", "world", "42", "times"]);
+        assert!(tokenize(" ,.!? ").is_empty());
+    }
+
+    #[test]
+    fn every_query_token_must_prefix_a_word() {
+        let mut index = SearchIndex::default();
+        index.index("bob", &message("m1", "Lunch at the cafeteria?"));
+        index.index("bob", &message("m2", "Lunch tomorrow instead"));
+        index.index("carol", &message("m3", "The cafe is closed"));
+
+        assert_eq!(ids(index.search(&tokenize("lunch"))), ["m1", "m2"]);
+        assert_eq!(ids(index.search(&tokenize("caf"))), ["m1", "m3"]);
+        assert_eq!(ids(index.search(&tokenize("lunch caf"))), ["m1"]);
+        assert!(index.search(&tokenize("dinner")).is_empty());
+        assert_eq!(index.search(&tokenize("closed")), [("carol".to_string(), "m3".to_string())]);
+    }
+
+    #[test]
+    fn edited_and_deleted_messages_are_reindexed() {
+        let mut index = SearchIndex::default();
+        let mut edited = message("m1", "see you at noon");
+        index.index("bob", &edited);
+
+        edited.content = "see you at one".to_string();
+        index.index("bob", &edited);
+        assert!(index.search(&tokenize("noon")).is_empty());
+        assert_eq!(ids(index.search(&tokenize("one"))), ["m1"]);
+
+        edited.deleted = true;
+        index.index("bob", &edited);
+        assert!(index.search(&tokenize("see")).is_empty());
+        assert!(index.postings.is_empty());
+    }
+
+    #[test]
+    fn snippets_center_on_the_first_match() {
+        let content = (1..=30).map(|i| format!("w{i}")).collect::<Vec<_>>().join(" ");
+
+        assert_eq!(
+            snippet(&content, &tokenize("w20")),
+            "… w12 w13 w14 w15 w16 w17 w18 w19 w20 w21 w22 w23 w24 w25 w26 w27 w28 …"
+        );
+        assert_eq!(snippet("short message", &tokenize("short")), "short message");
+    }
+}
@@ -0,0 +1,407 @@
+use log::{debug, warn};
+use snow::{HandshakeState, TransportState};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::error::{AppError, AppResult};
+use crate::identity::{self, DeviceIdentity};
+
+/// Noise pattern used for every peer connection: mutual authentication of static keys
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message allowed by the spec
+const MAX_NOISE_MESSAGE: usize = 65535;
+/// Size of the ChaChaPoly authentication tag appended to each record
+const TAG_SIZE: usize = 16;
+/// Largest plaintext that fits in a single encrypted record
+const MAX_RECORD_PLAINTEXT: usize = MAX_NOISE_MESSAGE - TAG_SIZE;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Buffer size of the in-memory pipe between the application and the socket
+const PIPE_BUFFER_SIZE: usize = 256 * 1024;
+/// Domain separator for the identity signature over the Noise static key
+const STATIC_KEY_CONTEXT: &[u8] = b"ip-chat noise static key v1";
+/// Ed25519 public key followed by its signature over our Noise static key
+const IDENTITY_PAYLOAD_SIZE: usize = 32 + 64;
+
+/// Decrypted side of an encrypted peer connection.
+///
+/// Reads and writes on this stream are plaintext; background tasks encrypt
+/// them into records of `[u16 length][ciphertext]` on the TCP socket.
+pub type SecureStream = DuplexStream;
+
+/// Identity of the remote end of a secure channel, proven during the handshake
+#[derive(Debug, Clone)]
+pub struct PeerIdentity {
+    pub user_id: String,
+    pub public_key: String,
+}
+
+/// Noise static key of this process, bound to the long-term device identity.
+///
+/// The X25519 key is regenerated on every start; peers authenticate it through
+/// the Ed25519 signature carried in the handshake payload.
+pub struct ChannelKeys {
+    private_key: Vec<u8>,
+    identity_payload: Vec<u8>,
+}
+
+impl ChannelKeys {
+    /// Generates a Noise static key and signs it with `identity`
+    pub fn new(identity: &DeviceIdentity) -> AppResult<Self> {
+        let keypair = noise_builder()?
+            .generate_keypair()
+            .map_err(|e| AppError::AuthenticationError(format!("Failed to generate key: {e}")))?;
+
+        let public_key = identity::parse_public_key(&identity.public_key_hex())?;
+        let signature = identity.sign(&static_key_message(&keypair.public));
+
+        let mut identity_payload = Vec::with_capacity(IDENTITY_PAYLOAD_SIZE);
+        identity_payload.extend_from_slice(public_key.as_bytes());
+        identity_payload.extend_from_slice(&signature.to_bytes());
+
+        Ok(ChannelKeys {
+            private_key: keypair.private,
+            identity_payload,
+        })
+    }
+}
+
+/// Runs the initiator side of the handshake on an outgoing connection
+pub async fn connect(mut stream: TcpStream, keys: &ChannelKeys) -> AppResult<(SecureStream, PeerIdentity)> {
+    let handshake = noise_builder()?
+        .local_private_key(&keys.private_key)
+        .build_initiator()
+        .map_err(handshake_error)?;
+
+    let (transport, peer) = match timeout(HANDSHAKE_TIMEOUT, initiate(&mut stream, handshake, keys)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(AppError::AuthenticationError("Handshake timeout".to_string())),
+    };
+
+    debug!("Secure channel established with {} (key {})", peer.user_id, peer.public_key);
+    Ok((spawn_channel(stream, transport), peer))
+}
+
+/// Runs the responder side of the handshake on an accepted connection
+pub async fn accept(mut stream: TcpStream, keys: &ChannelKeys) -> AppResult<(SecureStream, PeerIdentity)> {
+    let handshake = noise_builder()?
+        .local_private_key(&keys.private_key)
+        .build_responder()
+        .map_err(handshake_error)?;
+
+    let (transport, peer) = match timeout(HANDSHAKE_TIMEOUT, respond(&mut stream, handshake, keys)).await {
+        Ok(result) => result?,
+        Err(_) => return Err(AppError::AuthenticationError("Handshake timeout".to_string())),
+    };
+
+    debug!("Secure channel established with {} (key {})", peer.user_id, peer.public_key);
+    Ok((spawn_channel(stream, transport), peer))
+}
+
+/// XX initiator: -> e, <- e ee s es, -> s se
+async fn initiate(
+    stream: &mut TcpStream,
+    mut handshake: HandshakeState,
+    keys: &ChannelKeys,
+) -> AppResult<(TransportState, PeerIdentity)> {
+    let mut buf = vec![0; MAX_NOISE_MESSAGE];
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+
+    let len = handshake.write_message(&[], &mut buf).map_err(handshake_error)?;
+    write_handshake_message(stream, &buf[..len]).await?;
+
+    let message = read_handshake_message(stream).await?;
+    let len = handshake.read_message(&message, &mut payload).map_err(handshake_error)?;
+    let peer = verify_identity_payload(&payload[..len], handshake.get_remote_static())?;
+
+    let len = handshake
+        .write_message(&keys.identity_payload, &mut buf)
+        .map_err(handshake_error)?;
+    write_handshake_message(stream, &buf[..len]).await?;
+
+    let transport = handshake.into_transport_mode().map_err(handshake_error)?;
+    Ok((transport, peer))
+}
+
+/// XX responder: <- e, -> e ee s es, <- s se
+async fn respond(
+    stream: &mut TcpStream,
+    mut handshake: HandshakeState,
+    keys: &ChannelKeys,
+) -> AppResult<(TransportState, PeerIdentity)> {
+    let mut buf = vec![0; MAX_NOISE_MESSAGE];
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+
+    let message = read_handshake_message(stream).await?;
+    handshake.read_message(&message, &mut payload).map_err(handshake_error)?;
+
+    let len = handshake
+        .write_message(&keys.identity_payload, &mut buf)
+        .map_err(handshake_error)?;
+    write_handshake_message(stream, &buf[..len]).await?;
+
+    let message = read_handshake_message(stream).await?;
+    let len = handshake.read_message(&message, &mut payload).map_err(handshake_error)?;
+    let peer = verify_identity_payload(&payload[..len], handshake.get_remote_static())?;
+
+    let transport = handshake.into_transport_mode().map_err(handshake_error)?;
+    Ok((transport, peer))
+}
+
+/// Checks that the peer's identity key signed the Noise static key it used
+fn verify_identity_payload(payload: &[u8], remote_static: Option<&[u8]>) -> AppResult<PeerIdentity> {
+    let remote_static = remote_static
+        .ok_or_else(|| AppError::AuthenticationError("Peer sent no static key".to_string()))?;
+
+    if payload.len() != IDENTITY_PAYLOAD_SIZE {
+        return Err(AppError::AuthenticationError(format!(
+            "Invalid identity payload of {} bytes",
+            payload.len()
+        )));
+    }
+
+    let (public_key, signature) = payload.split_at(32);
+    let public_key_hex = hex::encode(public_key);
+    let public_key = identity::parse_public_key(&public_key_hex)?;
+    identity::verify_signature(&public_key, &static_key_message(remote_static), signature)?;
+
+    Ok(PeerIdentity {
+        user_id: identity::user_id_for_public_key(&public_key_hex)?,
+        public_key: public_key_hex,
+    })
+}
+
+fn static_key_message(static_key: &[u8]) -> Vec<u8> {
+    [STATIC_KEY_CONTEXT, static_key].concat()
+}
+
+fn noise_builder() -> AppResult<snow::Builder<'static>> {
+    let params = NOISE_PARAMS
+        .parse()
+        .map_err(|e| AppError::AuthenticationError(format!("Invalid Noise parameters: {e:?}")))?;
+    Ok(snow::Builder::new(params))
+}
+
+fn handshake_error(e: snow::Error) -> AppError {
+    AppError::AuthenticationError(format!("Handshake failed: {e}"))
+}
+
+async fn write_handshake_message(stream: &mut TcpStream, message: &[u8]) -> AppResult<()> {
+    stream.write_u16(message.len() as u16).await?;
+    stream.write_all(message).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_handshake_message(stream: &mut TcpStream) -> AppResult<Vec<u8>> {
+    let len = stream.read_u16().await? as usize;
+    let mut message = vec![0; len];
+    stream.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Bridges the plaintext pipe handed to the application and the encrypted socket
+fn spawn_channel(stream: TcpStream, transport: TransportState) -> SecureStream {
+    let (app_side, channel_side) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    let (mut plain_reader, mut plain_writer) = tokio::io::split(channel_side);
+    let (mut socket_reader, mut socket_writer) = stream.into_split();
+    let transport = Arc::new(Mutex::new(transport));
+
+    // Outgoing: encrypt whatever the application writes
+    let encryptor = Arc::clone(&transport);
+    tokio::spawn(async move {
+        let mut plaintext = vec![0; MAX_RECORD_PLAINTEXT];
+        let mut record = vec![0; MAX_NOISE_MESSAGE];
+
+        loop {
+            let n = match plain_reader.read(&mut plaintext).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+
+            let len = match encryptor.lock().unwrap().write_message(&plaintext[..n], &mut record) {
+                Ok(len) => len,
+                Err(e) => {
+                    warn!("Failed to encrypt record: {e}");
+                    break;
+                }
+            };
+
+            if socket_writer.write_u16(len as u16).await.is_err()
+                || socket_writer.write_all(&record[..len]).await.is_err()
+            {
+                break;
+            }
+        }
+
+        let _ = socket_writer.shutdown().await;
+    });
+
+    // Incoming: decrypt records and hand the plaintext to the application
+    tokio::spawn(async move {
+        let mut record = vec![0; MAX_NOISE_MESSAGE];
+        let mut plaintext = vec![0; MAX_NOISE_MESSAGE];
+
+        while let Ok(len) = socket_reader.read_u16().await {
+            let len = len as usize;
+            if socket_reader.read_exact(&mut record[..len]).await.is_err() {
+                break;
+            }
+
+            let n = match transport.lock().unwrap().read_message(&record[..len], &mut plaintext) {
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("Dropping connection after undecryptable record: {e}");
+                    break;
+                }
+            };
+
+            if plain_writer.write_all(&plaintext[..n]).await.is_err() {
+                break;
+            }
+        }
+
+        let _ = plain_writer.shutdown().await;
+    });
+
+    app_side
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Two ends of a loopback TCP connection, initiator first
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), accepted.unwrap().0)
+    }
+
+    /// Runs the XX handshake over loopback, returning each side's socket, transport and view of the other
+    async fn handshake(
+        initiator: &ChannelKeys,
+        responder: &ChannelKeys,
+    ) -> ((TcpStream, TransportState, PeerIdentity), (TcpStream, TransportState, PeerIdentity)) {
+        let (mut client, mut server) = tcp_pair().await;
+        let client_handshake = noise_builder()
+            .unwrap()
+            .local_private_key(&initiator.private_key)
+            .build_initiator()
+            .unwrap();
+        let server_handshake = noise_builder()
+            .unwrap()
+            .local_private_key(&responder.private_key)
+            .build_responder()
+            .unwrap();
+
+        let (client_result, server_result) = tokio::join!(
+            initiate(&mut client, client_handshake, initiator),
+            respond(&mut server, server_handshake, responder),
+        );
+        let (client_transport, server_peer) = client_result.unwrap();
+        let (server_transport, client_peer) = server_result.unwrap();
+        ((client, client_transport, server_peer), (server, server_transport, client_peer))
+    }
+
+    #[tokio::test]
+    async fn peers_authenticate_each_other_and_exchange_data() {
+        let alice = DeviceIdentity::generate();
+        let bob = DeviceIdentity::generate();
+        let (alice_keys, bob_keys) = (ChannelKeys::new(&alice).unwrap(), ChannelKeys::new(&bob).unwrap());
+        let (client, server) = tcp_pair().await;
+
+        let (connected, accepted) = tokio::join!(connect(client, &alice_keys), accept(server, &bob_keys));
+        let (mut alice_stream, bob_seen_by_alice) = connected.unwrap();
+        let (mut bob_stream, alice_seen_by_bob) = accepted.unwrap();
+        assert_eq!(bob_seen_by_alice.user_id, bob.user_id());
+        assert_eq!(bob_seen_by_alice.public_key, bob.public_key_hex());
+        assert_eq!(alice_seen_by_bob.user_id, alice.user_id());
+
+        // Spans several records, the last one partial
+        let data: Vec<u8> = (0..3 * MAX_RECORD_PLAINTEXT + 1000).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            alice_stream.write_all(&sent).await.unwrap();
+            alice_stream.shutdown().await.unwrap();
+            alice_stream
+        });
+
+        let mut received = Vec::new();
+        bob_stream.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(received.len(), data.len());
+        assert!(received == data);
+    }
+
+    #[test]
+    fn identity_payloads_must_sign_the_static_key() {
+        let alice = DeviceIdentity::generate();
+        let mallory = DeviceIdentity::generate();
+        let keys = ChannelKeys::new(&alice).unwrap();
+        let static_key = noise_builder().unwrap().generate_keypair().unwrap().public;
+        let signature = alice.sign(&static_key_message(&static_key)).to_bytes();
+        let alice_key = identity::parse_public_key(&alice.public_key_hex()).unwrap();
+        let mallory_key = identity::parse_public_key(&mallory.public_key_hex()).unwrap();
+
+        let valid = [alice_key.as_bytes().as_slice(), &signature].concat();
+        let peer = verify_identity_payload(&valid, Some(&static_key)).unwrap();
+        assert_eq!(peer.user_id, alice.user_id());
+
+        // Alice's signature presented under Mallory's identity
+        let wrong_key = [mallory_key.as_bytes().as_slice(), &signature].concat();
+        assert!(verify_identity_payload(&wrong_key, Some(&static_key)).is_err());
+
+        // Alice's payload replayed with another static key
+        let other_static = noise_builder().unwrap().generate_keypair().unwrap().public;
+        assert!(verify_identity_payload(&keys.identity_payload, Some(&other_static)).is_err());
+
+        assert!(verify_identity_payload(&valid[..IDENTITY_PAYLOAD_SIZE - 1], Some(&static_key)).is_err());
+        assert!(verify_identity_payload(&valid, None).is_err());
+    }
+
+    #[tokio::test]
+    async fn tampered_records_close_the_channel() {
+        let alice = ChannelKeys::new(&DeviceIdentity::generate()).unwrap();
+        let bob = ChannelKeys::new(&DeviceIdentity::generate()).unwrap();
+        let ((mut client, mut transport, _), (server, server_transport, _)) = handshake(&alice, &bob).await;
+        let mut secure = spawn_channel(server, server_transport);
+
+        let mut record = vec![0; MAX_NOISE_MESSAGE];
+        let len = transport.write_message(b"hello", &mut record).unwrap();
+        record[len - 1] ^= 1;
+        client.write_u16(len as u16).await.unwrap();
+        client.write_all(&record[..len]).await.unwrap();
+
+        let mut received = Vec::new();
+        secure.read_to_end(&mut received).await.unwrap();
+        assert!(received.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_records_close_the_channel() {
+        let alice = ChannelKeys::new(&DeviceIdentity::generate()).unwrap();
+        let bob = ChannelKeys::new(&DeviceIdentity::generate()).unwrap();
+        let ((mut client, mut transport, _), (server, server_transport, _)) = handshake(&alice, &bob).await;
+        let mut secure = spawn_channel(server, server_transport);
+
+        let mut record = vec![0; MAX_NOISE_MESSAGE];
+        let len = transport.write_message(b"hello", &mut record).unwrap();
+        client.write_u16(len as u16).await.unwrap();
+        client.write_all(&record[..len]).await.unwrap();
+
+        // The next record is cut short by the connection closing
+        let len = transport.write_message(b"world", &mut record).unwrap();
+        client.write_u16(len as u16).await.unwrap();
+        client.write_all(&record[..len / 2]).await.unwrap();
+        drop(client);
+
+        let mut received = Vec::new();
+        secure.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+}
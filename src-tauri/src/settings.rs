@@ -0,0 +1,140 @@
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::chat::CHAT_PORT;
+use crate::discovery::DiscoveryBackendKind;
+use crate::error::{AppError, AppResult};
+use crate::file_transfer::FILE_TRANSFER_PORT;
+use crate::storage::JsonSnapshot;
+
+const NETWORK_SETTINGS_FILE: &str = "network_settings.json";
+const NETWORK_SETTINGS_SCHEMA_VERSION: u32 = 1;
+
+/// Network settings chosen by the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct NetworkSettings {
+    /// Mechanisms used to discover peers
+    pub discovery_backends: Vec<DiscoveryBackendKind>,
+    /// Port to listen for chat connections on, peers added by address connect to it
+    pub chat_port: u16,
+    /// Port to listen for file transfers on, 0 to pick a free one
+    pub file_port: u16,
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        NetworkSettings {
+            discovery_backends: vec![DiscoveryBackendKind::Mdns, DiscoveryBackendKind::Broadcast],
+            chat_port: CHAT_PORT,
+            file_port: FILE_TRANSFER_PORT,
+        }
+    }
+}
+
+/// Network settings as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NetworkSettingsSnapshot {
+    settings: NetworkSettings,
+}
+
+/// Network settings, saved as a JSON snapshot in the app data dir
+pub struct NetworkSettingsStore {
+    settings: Mutex<NetworkSettings>,
+    snapshot: Option<JsonSnapshot<NetworkSettingsSnapshot>>,
+}
+
+impl NetworkSettingsStore {
+    /// Creates a store that only keeps the default settings in memory
+    pub fn in_memory() -> Self {
+        NetworkSettingsStore {
+            settings: Mutex::new(NetworkSettings::default()),
+            snapshot: None,
+        }
+    }
+
+    /// Opens the persistent store under `data_dir`
+    pub fn open(data_dir: &Path) -> AppResult<Self> {
+        let snapshot: JsonSnapshot<NetworkSettingsSnapshot> = JsonSnapshot::open(
+            data_dir,
+            NETWORK_SETTINGS_FILE,
+            "Network settings",
+            NETWORK_SETTINGS_SCHEMA_VERSION,
+        )?;
+        let settings = snapshot.load()?.map(|saved| saved.settings).unwrap_or_default();
+
+        info!("Loaded network settings: {settings:?}");
+        Ok(NetworkSettingsStore {
+            settings: Mutex::new(settings),
+            snapshot: Some(snapshot),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, NetworkSettings> {
+        self.settings.lock().unwrap()
+    }
+
+    /// Current settings
+    pub fn get(&self) -> NetworkSettings {
+        self.lock().clone()
+    }
+
+    /// Sets the discovery backends to use from now on
+    pub fn set_discovery_backends(&self, backends: Vec<DiscoveryBackendKind>) -> AppResult<()> {
+        self.lock().discovery_backends = backends;
+        self.save()
+    }
+
+    /// Sets the ports to listen on from the next start, a file port of 0 picking a free one.
+    ///
+    /// The chat port must be fixed: peers added by address could not follow one picked anew on every start.
+    pub fn set_ports(&self, chat_port: u16, file_port: u16) -> AppResult<()> {
+        if chat_port == 0 {
+            return Err(AppError::InvalidOperation("Chat port must not be 0".to_string()));
+        }
+        if chat_port == file_port {
+            return Err(AppError::InvalidOperation(
+                "Chat and file transfer ports must differ".to_string(),
+            ));
+        }
+        {
+            let mut settings = self.lock();
+            settings.chat_port = chat_port;
+            settings.file_port = file_port;
+        }
+        self.save()
+    }
+
+    /// Writes the settings to disk
+    fn save(&self) -> AppResult<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+
+        // Holding the lock while writing also serializes concurrent saves
+        let settings = self.lock();
+        snapshot.save(&NetworkSettingsSnapshot {
+            settings: settings.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_port_must_be_fixed_and_distinct() {
+        let store = NetworkSettingsStore::in_memory();
+        assert!(store.set_ports(0, 0).is_err());
+        assert!(store.set_ports(0, 9001).is_err());
+        assert!(store.set_ports(9000, 9000).is_err());
+        assert_eq!(store.get().chat_port, CHAT_PORT);
+
+        store.set_ports(9000, 0).unwrap();
+        assert_eq!((store.get().chat_port, store.get().file_port), (9000, 0));
+    }
+}
@@ -0,0 +1,171 @@
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::AppResult;
+use crate::storage::JsonSnapshot;
+
+const STATIC_PEERS_FILE: &str = "static_peers.json";
+const STATIC_PEERS_SCHEMA_VERSION: u32 = 1;
+
+/// A peer added by address, for networks where mDNS does not get through
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StaticPeer {
+    /// IP address or hostname, resolved again on every probe
+    pub host: String,
+    /// Chat port of the peer
+    pub port: u16,
+    /// ID the peer had on its last successful probe
+    pub peer_id: Option<String>,
+    pub added_at: DateTime<Utc>,
+}
+
+/// Static peers as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct StaticPeersSnapshot {
+    peers: Vec<StaticPeer>,
+}
+
+/// Peers added by address, saved as a JSON snapshot in the app data dir
+pub struct StaticPeerStore {
+    peers: Mutex<Vec<StaticPeer>>,
+    snapshot: Option<JsonSnapshot<StaticPeersSnapshot>>,
+}
+
+impl StaticPeerStore {
+    /// Creates a store that only keeps static peers in memory
+    pub fn in_memory() -> Self {
+        StaticPeerStore {
+            peers: Mutex::new(Vec::new()),
+            snapshot: None,
+        }
+    }
+
+    /// Opens the persistent store under `data_dir`
+    pub fn open(data_dir: &Path) -> AppResult<Self> {
+        let snapshot: JsonSnapshot<StaticPeersSnapshot> =
+            JsonSnapshot::open(data_dir, STATIC_PEERS_FILE, "Static peers", STATIC_PEERS_SCHEMA_VERSION)?;
+        let peers = snapshot.load()?.map(|saved| saved.peers).unwrap_or_default();
+
+        info!("Loaded {} static peer(s)", peers.len());
+        Ok(StaticPeerStore {
+            peers: Mutex::new(peers),
+            snapshot: Some(snapshot),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<StaticPeer>> {
+        self.peers.lock().unwrap()
+    }
+
+    /// All static peers, oldest first
+    pub fn list(&self) -> Vec<StaticPeer> {
+        self.lock().clone()
+    }
+
+    /// IDs the static peers had on their last successful probe
+    pub fn peer_ids(&self) -> HashSet<String> {
+        self.lock().iter().filter_map(|peer| peer.peer_id.clone()).collect()
+    }
+
+    /// Adds a peer or records the ID found at its address
+    pub fn upsert(&self, host: &str, port: u16, peer_id: &str) -> AppResult<StaticPeer> {
+        let peer = {
+            let mut peers = self.lock();
+            match peers.iter_mut().find(|peer| peer.host == host && peer.port == port) {
+                Some(peer) => {
+                    if peer.peer_id.as_deref() == Some(peer_id) {
+                        return Ok(peer.clone());
+                    }
+                    peer.peer_id = Some(peer_id.to_string());
+                    peer.clone()
+                }
+                None => {
+                    let peer = StaticPeer {
+                        host: host.to_string(),
+                        port,
+                        peer_id: Some(peer_id.to_string()),
+                        added_at: Utc::now(),
+                    };
+                    peers.push(peer.clone());
+                    peer
+                }
+            }
+        };
+
+        self.save()?;
+        Ok(peer)
+    }
+
+    /// Removes the peer at an address, returning whether there was one
+    pub fn remove(&self, host: &str, port: u16) -> AppResult<bool> {
+        let removed = {
+            let mut peers = self.lock();
+            let before = peers.len();
+            peers.retain(|peer| peer.host != host || peer.port != port);
+            peers.len() != before
+        };
+
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Writes the static peers to disk
+    fn save(&self) -> AppResult<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+
+        // Holding the lock while writing also serializes concurrent saves
+        let peers = self.lock();
+        snapshot.save(&StaticPeersSnapshot { peers: peers.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    #[test]
+    fn peers_are_keyed_by_address() {
+        let store = StaticPeerStore::in_memory();
+        store.upsert("192.168.1.20", 9000, "bob").unwrap();
+        store.upsert("192.168.1.20", 9001, "carol").unwrap();
+
+        // A new ID at a known address replaces the old one
+        let updated = store.upsert("192.168.1.20", 9000, "bob-reinstalled").unwrap();
+        assert_eq!(updated.peer_id.as_deref(), Some("bob-reinstalled"));
+        assert_eq!(store.list().len(), 2);
+        assert_eq!(store.peer_ids(), HashSet::from(["bob-reinstalled".to_string(), "carol".to_string()]));
+
+        assert!(store.remove("192.168.1.20", 9001).unwrap());
+        assert!(!store.remove("192.168.1.20", 9001).unwrap());
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn peers_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("ip-chat-static-peers-{}", Uuid::new_v4()));
+        {
+            let store = StaticPeerStore::open(&dir).unwrap();
+            store.upsert("office-pc.local", 9000, "bob").unwrap();
+            store.upsert("192.168.1.30", 9000, "carol").unwrap();
+            store.remove("192.168.1.30", 9000).unwrap();
+        }
+
+        let store = StaticPeerStore::open(&dir).unwrap();
+        let peers = store.list();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].host, "office-pc.local");
+        assert_eq!(peers[0].peer_id.as_deref(), Some("bob"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
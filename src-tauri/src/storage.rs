@@ -0,0 +1,697 @@
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use crate::error::{AppError, AppResult};
+use crate::models::{FileTransfer, Message, MessageDeletion, MessageEdit, Reaction, TransferStatus};
+use crate::search::SearchIndex;
+
+/// Current schema version of the on-disk message log
+pub const SCHEMA_VERSION: u32 = 1;
+const MESSAGE_LOG_FILE: &str = "messages.jsonl";
+const TRANSFERS_FILE: &str = "transfers.json";
+/// How often in-flight transfer progress is written to disk
+const PROGRESS_SAVE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// First line of the message log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LogHeader {
+    schema_version: u32,
+}
+
+/// A single entry in the message log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogRecord {
+    /// Inserts or replaces a message (matched by ID) in a conversation bucket
+    Put { bucket: String, message: Message },
+}
+
+/// Append-only JSON lines log of messages, compacted on every load
+pub struct MessageLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl MessageLog {
+    /// Opens (or creates) the log under `data_dir` and returns it with its contents
+    pub fn open(data_dir: &Path) -> AppResult<(Self, HashMap<String, Vec<Message>>)> {
+        fs::create_dir_all(data_dir)?;
+        let path = data_dir.join(MESSAGE_LOG_FILE);
+
+        let messages = if path.exists() {
+            Self::load(&path)?
+        } else {
+            HashMap::new()
+        };
+
+        // Rewrite the log so it only holds the latest version of every message
+        Self::compact(&path, &messages)?;
+
+        let file = OpenOptions::new().append(true).open(&path)?;
+        info!(
+            "Opened message log {} ({} conversation(s))",
+            path.display(),
+            messages.len()
+        );
+
+        Ok((
+            MessageLog {
+                path,
+                file: Mutex::new(file),
+            },
+            messages,
+        ))
+    }
+
+    /// Replays the log into a map of messages by conversation bucket
+    fn load(path: &Path) -> AppResult<HashMap<String, Vec<Message>>> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut lines = reader.lines();
+
+        let schema_version = match lines.next() {
+            Some(line) => serde_json::from_str::<LogHeader>(&line?)?.schema_version,
+            None => return Ok(HashMap::new()),
+        };
+
+        if schema_version > SCHEMA_VERSION {
+            return Err(AppError::StorageError(format!(
+                "Message log schema v{schema_version} is newer than supported v{SCHEMA_VERSION}"
+            )));
+        }
+        if schema_version < SCHEMA_VERSION {
+            info!("Migrating message log from schema v{schema_version} to v{SCHEMA_VERSION}");
+        }
+
+        let mut messages: HashMap<String, Vec<Message>> = HashMap::new();
+        for (index, line) in lines.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record = serde_json::from_str::<serde_json::Value>(&line)
+                .map_err(AppError::from)
+                .and_then(|value| migrate_record(schema_version, value));
+
+            match record {
+                Ok(LogRecord::Put { bucket, message }) => {
+                    let bucket_messages = messages.entry(bucket).or_default();
+                    match bucket_messages.iter_mut().find(|m| m.id == message.id) {
+                        Some(existing) => *existing = message,
+                        None => bucket_messages.push(message),
+                    }
+                }
+                Err(e) => {
+                    // A torn final write after a crash should not lose the whole history
+                    warn!("Skipping unreadable message log entry {}: {}", index + 2, e);
+                }
+            }
+        }
+
+        Ok(messages)
+    }
+
+    /// Atomically rewrites the log with a header and one record per message
+    fn compact(path: &Path, messages: &HashMap<String, Vec<Message>>) -> AppResult<()> {
+        let tmp_path = path.with_extension("jsonl.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            let header = LogHeader {
+                schema_version: SCHEMA_VERSION,
+            };
+            serde_json::to_writer(&mut writer, &header)?;
+            writer.write_all(b"\n")?;
+
+            for (bucket, bucket_messages) in messages {
+                for message in bucket_messages {
+                    let record = LogRecord::Put {
+                        bucket: bucket.clone(),
+                        message: message.clone(),
+                    };
+                    serde_json::to_writer(&mut writer, &record)?;
+                    writer.write_all(b"\n")?;
+                }
+            }
+
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Appends the current version of a message to the log and syncs it to disk
+    pub fn put(&self, bucket: &str, message: &Message) -> AppResult<()> {
+        let record = LogRecord::Put {
+            bucket: bucket.to_string(),
+            message: message.clone(),
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+        file.write_all(&line)
+            .and_then(|_| file.sync_data())
+            .map_err(|e| AppError::StorageError(format!("Failed to write {}: {e}", self.path.display())))?;
+        Ok(())
+    }
+}
+
+/// Upgrades a raw log record written with an older schema version
+fn migrate_record(schema_version: u32, value: serde_json::Value) -> AppResult<LogRecord> {
+    if schema_version == SCHEMA_VERSION {
+        return Ok(serde_json::from_value(value)?);
+    }
+
+    // v1 is the first persisted format, older schemas get their upgrade steps here
+    Err(AppError::StorageError(format!(
+        "No migration from message log schema v{schema_version}"
+    )))
+}
+
+/// Messages by conversation bucket, backed by the on-disk message log.
+///
+/// Buckets follow the `ChatManager` layout: sent messages live under the local
+/// user ID, received messages under the sender's ID.
+pub struct MessageStore {
+    messages: Mutex<HashMap<String, Vec<Message>>>,
+    /// Full-text index over message content, updated on every write
+    index: Mutex<SearchIndex>,
+    log: Option<MessageLog>,
+}
+
+impl MessageStore {
+    /// Creates a store that only keeps messages in memory
+    pub fn in_memory() -> Self {
+        MessageStore {
+            messages: Mutex::new(HashMap::new()),
+            index: Mutex::new(SearchIndex::default()),
+            log: None,
+        }
+    }
+
+    /// Opens the persistent store under `data_dir`, loading existing history
+    pub fn open(data_dir: &Path) -> AppResult<Self> {
+        let (log, messages) = MessageLog::open(data_dir)?;
+        let index = SearchIndex::build(&messages);
+        Ok(MessageStore {
+            messages: Mutex::new(messages),
+            index: Mutex::new(index),
+            log: Some(log),
+        })
+    }
+
+    /// Locks the message map for reading
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<String, Vec<Message>>> {
+        self.messages.lock().unwrap()
+    }
+
+    /// Whether a bucket already holds a message with `message_id`
+    pub fn contains(&self, bucket: &str, message_id: &str) -> bool {
+        self.lock()
+            .get(bucket)
+            .is_some_and(|messages| messages.iter().any(|message| message.id == message_id))
+    }
+
+    /// Finds a message by ID in any bucket, returning the bucket with it
+    pub fn find(&self, message_id: &str) -> Option<(String, Message)> {
+        self.lock().iter().find_map(|(bucket, messages)| {
+            messages
+                .iter()
+                .find(|message| message.id == message_id)
+                .map(|message| (bucket.clone(), message.clone()))
+        })
+    }
+
+    /// Messages whose content has a word starting with every query token
+    pub fn search(&self, query_tokens: &[String]) -> Vec<Message> {
+        let matches = self.index.lock().unwrap().search(query_tokens);
+
+        let messages = self.lock();
+        matches
+            .into_iter()
+            .filter_map(|(bucket, message_id)| {
+                messages
+                    .get(&bucket)?
+                    .iter()
+                    .find(|message| message.id == message_id)
+                    .cloned()
+            })
+            .collect()
+    }
+
+    /// Applies an edit to the message it targets, returning the message if it changed
+    pub fn apply_edit(&self, edit: &MessageEdit) -> AppResult<Option<Message>> {
+        let Some((bucket, _)) = self.find(&edit.message_id) else {
+            return Ok(None);
+        };
+        let updated = self.update(&bucket, |message| message.apply_edit(edit))?;
+        Ok(updated.into_iter().next())
+    }
+
+    /// Applies a deletion to the message it targets, returning the tombstone if it changed
+    pub fn apply_deletion(&self, deletion: &MessageDeletion) -> AppResult<Option<Message>> {
+        let Some((bucket, _)) = self.find(&deletion.message_id) else {
+            return Ok(None);
+        };
+        let updated = self.update(&bucket, |message| message.apply_deletion(deletion))?;
+        Ok(updated.into_iter().next())
+    }
+
+    /// Applies a reaction to the message it targets, returning the message if it changed
+    pub fn apply_reaction(&self, reaction: &Reaction) -> AppResult<Option<Message>> {
+        let Some((bucket, _)) = self.find(&reaction.message_id) else {
+            return Ok(None);
+        };
+        let updated = self.update(&bucket, |message| message.apply_reaction(reaction))?;
+        Ok(updated.into_iter().next())
+    }
+
+    /// Appends a message to a bucket, returning the new bucket size.
+    ///
+    /// The log is written first, so a message is never shown that would be lost on restart.
+    pub fn insert(&self, bucket: &str, message: &Message) -> AppResult<usize> {
+        let mut messages = self.lock();
+        if let Some(log) = &self.log {
+            log.put(bucket, message)?;
+        }
+
+        let bucket_messages = messages.entry(bucket.to_string()).or_default();
+        bucket_messages.push(message.clone());
+        let count = bucket_messages.len();
+        self.index.lock().unwrap().index(bucket, message);
+        Ok(count)
+    }
+
+    /// Applies `f` to every message in a bucket, persisting and returning the ones it reports as changed
+    pub fn update<F>(&self, bucket: &str, mut f: F) -> AppResult<Vec<Message>>
+    where
+        F: FnMut(&mut Message) -> bool,
+    {
+        let mut messages = self.lock();
+        let mut changed = Vec::new();
+
+        if let Some(bucket_messages) = messages.get_mut(bucket) {
+            for message in bucket_messages.iter_mut() {
+                // Changes are logged before they are kept, like inserts
+                let mut updated = message.clone();
+                if !f(&mut updated) {
+                    continue;
+                }
+                if let Some(log) = &self.log {
+                    log.put(bucket, &updated)?;
+                }
+                self.index.lock().unwrap().index(bucket, &updated);
+                *message = updated;
+                changed.push(message.clone());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+/// Contents of a snapshot file as read back, next to their schema version
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Versioned<T> {
+    schema_version: u32,
+    #[serde(flatten)]
+    contents: T,
+}
+
+/// Contents of a snapshot file as written, next to their schema version
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VersionedRef<'a, T> {
+    schema_version: u32,
+    #[serde(flatten)]
+    contents: &'a T,
+}
+
+/// A JSON file in the app data dir holding `T` next to its schema version.
+///
+/// Saves go to a temp file that is synced and then renamed over the old one,
+/// so a crash leaves either the previous or the new contents, never a torn file.
+/// Callers serialize their own saves.
+pub struct JsonSnapshot<T> {
+    path: PathBuf,
+    /// What the file holds, used in errors
+    name: &'static str,
+    schema_version: u32,
+    contents: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned> JsonSnapshot<T> {
+    /// Points at `file_name` under `data_dir`, creating the directory
+    pub fn open(data_dir: &Path, file_name: &str, name: &'static str, schema_version: u32) -> AppResult<Self> {
+        fs::create_dir_all(data_dir)?;
+        Ok(JsonSnapshot {
+            path: data_dir.join(file_name),
+            name,
+            schema_version,
+            contents: PhantomData,
+        })
+    }
+
+    /// Reads the saved contents, `None` if nothing has been saved yet
+    pub fn load(&self) -> AppResult<Option<T>> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let snapshot: Versioned<T> = serde_json::from_reader(BufReader::new(File::open(&self.path)?))?;
+        if snapshot.schema_version > self.schema_version {
+            return Err(AppError::StorageError(format!(
+                "{} schema v{} is newer than supported v{}",
+                self.name, snapshot.schema_version, self.schema_version
+            )));
+        }
+        Ok(Some(snapshot.contents))
+    }
+
+    /// Replaces the saved contents
+    pub fn save(&self, contents: &T) -> AppResult<()> {
+        let snapshot = VersionedRef {
+            schema_version: self.schema_version,
+            contents,
+        };
+
+        let tmp_path = self.path.with_extension("json.tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(&mut writer, &snapshot)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path).map_err(|e| {
+            AppError::StorageError(format!("Failed to write {}: {e}", self.path.display()))
+        })
+    }
+}
+
+/// All file transfers as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransferSnapshot {
+    transfers: Vec<FileTransfer>,
+}
+
+/// File transfers by ID, saved as a JSON snapshot in the app data dir
+pub struct TransferStore {
+    transfers: Mutex<HashMap<String, FileTransfer>>,
+    snapshot: Option<JsonSnapshot<TransferSnapshot>>,
+    last_saved: Mutex<Instant>,
+}
+
+impl TransferStore {
+    /// Creates a store that only keeps transfers in memory
+    pub fn in_memory() -> Self {
+        TransferStore {
+            transfers: Mutex::new(HashMap::new()),
+            snapshot: None,
+            last_saved: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Opens the persistent store under `data_dir`.
+    ///
+    /// Transfers that were still running when the app last exited are marked
+    /// as `Interrupted` so they can be resumed or discarded by the user.
+    pub fn open(data_dir: &Path) -> AppResult<Self> {
+        let snapshot: JsonSnapshot<TransferSnapshot> =
+            JsonSnapshot::open(data_dir, TRANSFERS_FILE, "Transfer history", SCHEMA_VERSION)?;
+
+        let mut transfers = HashMap::new();
+        if let Some(saved) = snapshot.load()? {
+            for mut transfer in saved.transfers {
+                if transfer.status == TransferStatus::InProgress {
+                    info!("Marking interrupted transfer: {} ({})", transfer.id, transfer.file_name);
+                    transfer.status = TransferStatus::Interrupted;
+                }
+                transfers.insert(transfer.id.clone(), transfer);
+            }
+        }
+
+        let store = TransferStore {
+            transfers: Mutex::new(transfers),
+            snapshot: Some(snapshot),
+            last_saved: Mutex::new(Instant::now()),
+        };
+        store.save()?;
+        info!("Loaded {} file transfer(s) from history", store.lock().len());
+        Ok(store)
+    }
+    /// Locks the transfer map for reading
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<String, FileTransfer>> {
+        self.transfers.lock().unwrap()
+    }
+
+    /// Gets a copy of a transfer by ID
+    pub fn get(&self, transfer_id: &str) -> Option<FileTransfer> {
+        self.lock().get(transfer_id).cloned()
+    }
+
+    /// Inserts or replaces a transfer and saves the store
+    pub fn insert(&self, transfer: FileTransfer) -> AppResult<()> {
+        self.lock().insert(transfer.id.clone(), transfer);
+        self.save()
+    }
+
+    /// Applies `f` to a transfer and saves the store, returning the updated transfer
+    pub fn update<F>(&self, transfer_id: &str, f: F) -> AppResult<Option<FileTransfer>>
+    where
+        F: FnOnce(&mut FileTransfer),
+    {
+        let updated = {
+            let mut transfers = self.lock();
+            transfers.get_mut(transfer_id).map(|transfer| {
+                f(transfer);
+                transfer.clone()
+            })
+        };
+
+        if updated.is_some() {
+            self.save()?;
+        }
+        Ok(updated)
+    }
+
+    /// Like `update`, but only saves progress-only changes every `PROGRESS_SAVE_INTERVAL`.
+    ///
+    /// Status changes are always saved. Save failures are logged, not returned,
+    /// so a full disk never aborts a running transfer.
+    pub fn update_progress<F>(&self, transfer_id: &str, f: F) -> Option<FileTransfer>
+    where
+        F: FnOnce(&mut FileTransfer),
+    {
+        let (updated, status_changed) = {
+            let mut transfers = self.lock();
+            let transfer = transfers.get_mut(transfer_id)?;
+            let old_status = transfer.status;
+            f(transfer);
+            (transfer.clone(), transfer.status != old_status)
+        };
+
+        let due = self.last_saved.lock().unwrap().elapsed() >= PROGRESS_SAVE_INTERVAL;
+        if status_changed || due {
+            if let Err(e) = self.save() {
+                warn!("Failed to save transfer progress: {e}");
+            }
+        }
+        Some(updated)
+    }
+
+    /// Writes the current transfers to disk
+    fn save(&self) -> AppResult<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+        // Also serializes concurrent saves so an older snapshot never lands last
+        let mut last_saved = self.last_saved.lock().unwrap();
+
+        let transfers = TransferSnapshot {
+            transfers: self.lock().values().cloned().collect(),
+        };
+        snapshot.save(&transfers)?;
+
+        *last_saved = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uuid::Uuid;
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("ip-chat-storage-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn history_is_replayed_on_reopen() {
+        let dir = temp_dir();
+        let first = Message::new("alice", "bob", "hello");
+        let second = Message::new("alice", "bob", "are you there?");
+        {
+            let store = MessageStore::open(&dir).unwrap();
+            store.insert("alice", &first).unwrap();
+            store.insert("alice", &second).unwrap();
+            store
+                .update("alice", |message| {
+                    message.read = message.id == first.id;
+                    message.read
+                })
+                .unwrap();
+        }
+
+        let store = MessageStore::open(&dir).unwrap();
+        let messages = store.lock()["alice"].clone();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().find(|message| message.id == first.id).unwrap().read);
+        assert!(!messages.iter().find(|message| message.id == second.id).unwrap().read);
+        assert_eq!(store.search(&["hello".to_string()]).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopening_compacts_the_log() {
+        let dir = temp_dir();
+        let message = Message::new("alice", "bob", "hello");
+        {
+            let store = MessageStore::open(&dir).unwrap();
+            store.insert("alice", &message).unwrap();
+            for _ in 0..3 {
+                store.update("alice", |message| {
+                    message.read = !message.read;
+                    true
+                })
+                .unwrap();
+            }
+        }
+        let log = dir.join(MESSAGE_LOG_FILE);
+        assert_eq!(fs::read_to_string(&log).unwrap().lines().count(), 5);
+
+        MessageStore::open(&dir).unwrap();
+        let lines: Vec<String> = fs::read_to_string(&log).unwrap().lines().map(String::from).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(serde_json::from_str::<LogHeader>(&lines[0]).unwrap().schema_version, SCHEMA_VERSION);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped() {
+        let dir = temp_dir();
+        let message = Message::new("alice", "bob", "hello");
+        {
+            let store = MessageStore::open(&dir).unwrap();
+            store.insert("alice", &message).unwrap();
+        }
+        let mut file = OpenOptions::new().append(true).open(dir.join(MESSAGE_LOG_FILE)).unwrap();
+        file.write_all(br#"{"op":"put","bucket":"alice","mess"#).unwrap();
+        drop(file);
+
+        let store = MessageStore::open(&dir).unwrap();
+        assert_eq!(store.lock()["alice"].len(), 1);
+        assert!(store.contains("alice", &message.id));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newer_logs_are_refused() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let header = format!("{{\"schemaVersion\":{}}}\n", SCHEMA_VERSION + 1);
+        fs::write(dir.join(MESSAGE_LOG_FILE), header).unwrap();
+
+        assert!(MessageStore::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn records_are_migrated_from_known_schemas_only() {
+        let record = serde_json::to_value(LogRecord::Put {
+            bucket: "alice".to_string(),
+            message: Message::new("alice", "bob", "hello"),
+        })
+        .unwrap();
+
+        let LogRecord::Put { bucket, message } = migrate_record(SCHEMA_VERSION, record.clone()).unwrap();
+        assert_eq!(bucket, "alice");
+        assert_eq!(message.content, "hello");
+        assert!(migrate_record(SCHEMA_VERSION - 1, record).is_err());
+    }
+
+    fn transfer(id: &str, status: TransferStatus) -> FileTransfer {
+        FileTransfer {
+            id: id.to_string(),
+            sender_id: "alice".to_string(),
+            recipient_id: "bob".to_string(),
+            sender_ip: None,
+            recipient_ip: None,
+            sender_port: None,
+            recipient_port: None,
+            file_name: format!("{id}.bin"),
+            file_size: 1024,
+            source_path: None,
+            destination_path: None,
+            status,
+            bytes_transferred: 0,
+            timestamp: chrono::Utc::now(),
+            error: None,
+            file_hash: None,
+        }
+    }
+
+    #[test]
+    fn running_transfers_are_interrupted_on_reopen() {
+        let dir = temp_dir();
+        {
+            let store = TransferStore::open(&dir).unwrap();
+            store.insert(transfer("running", TransferStatus::InProgress)).unwrap();
+            store.insert(transfer("done", TransferStatus::Completed)).unwrap();
+        }
+
+        let store = TransferStore::open(&dir).unwrap();
+        assert_eq!(store.get("running").unwrap().status, TransferStatus::Interrupted);
+        assert_eq!(store.get("done").unwrap().status, TransferStatus::Completed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transfer_offsets_are_kept_for_resuming() {
+        let dir = temp_dir();
+        {
+            let store = TransferStore::open(&dir).unwrap();
+            store.insert(transfer("running", TransferStatus::InProgress)).unwrap();
+            store.update("running", |transfer| transfer.bytes_transferred = 512).unwrap();
+            // Progress right after a save waits for the next interval
+            store.update_progress("running", |transfer| transfer.bytes_transferred = 768);
+        }
+
+        let store = TransferStore::open(&dir).unwrap();
+        assert_eq!(store.get("running").unwrap().bytes_transferred, 512);
+        assert!(!dir.join(TRANSFERS_FILE).with_extension("json.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn newer_snapshots_are_refused() {
+        let dir = temp_dir();
+        fs::create_dir_all(&dir).unwrap();
+        let snapshot = format!("{{\"schemaVersion\":{},\"transfers\":[]}}", SCHEMA_VERSION + 1);
+        fs::write(dir.join(TRANSFERS_FILE), snapshot).unwrap();
+
+        assert!(TransferStore::open(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
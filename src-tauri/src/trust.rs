@@ -0,0 +1,235 @@
+use chrono::{DateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::error::{AppError, AppResult};
+use crate::identity;
+use crate::models::PeerFingerprint;
+use crate::storage::JsonSnapshot;
+
+const TRUST_FILE: &str = "trusted_peers.json";
+const TRUST_SCHEMA_VERSION: u32 = 1;
+
+/// Public key pinned for a peer on first contact
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinnedPeer {
+    pub peer_id: String,
+    /// Hex-encoded Ed25519 public key seen on first contact
+    pub public_key: String,
+    pub first_seen: DateTime<Utc>,
+    /// Whether the user compared safety numbers out of band
+    pub verified: bool,
+}
+
+/// Result of checking a peer's advertised key against the trust store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyCheck {
+    /// First contact, the key has just been pinned
+    Pinned,
+    /// The key matches the pinned one
+    Matches,
+    /// The key differs from the pinned one
+    Mismatch,
+}
+
+/// Pinned peers as written to disk
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TrustSnapshot {
+    peers: Vec<PinnedPeer>,
+}
+
+/// Trust-on-first-use store of peer public keys, saved in the app data dir.
+///
+/// User IDs are derived from the public key, so a peer that reinstalls shows
+/// up as a new peer with its own pin rather than as a key change. A mismatch
+/// can only come from an impostor, and is rejected outright.
+pub struct TrustStore {
+    /// Our own public key, used for safety numbers
+    local_public_key: String,
+    peers: Mutex<HashMap<String, PinnedPeer>>,
+    snapshot: Option<JsonSnapshot<TrustSnapshot>>,
+}
+
+impl TrustStore {
+    /// Creates a store that only keeps pinned keys in memory
+    pub fn in_memory(local_public_key: String) -> Self {
+        TrustStore {
+            local_public_key,
+            peers: Mutex::new(HashMap::new()),
+            snapshot: None,
+        }
+    }
+
+    /// Opens the persistent store under `data_dir`
+    pub fn open(data_dir: &Path, local_public_key: String) -> AppResult<Self> {
+        let snapshot: JsonSnapshot<TrustSnapshot> =
+            JsonSnapshot::open(data_dir, TRUST_FILE, "Trust store", TRUST_SCHEMA_VERSION)?;
+
+        let mut peers = HashMap::new();
+        if let Some(saved) = snapshot.load()? {
+            for peer in saved.peers {
+                peers.insert(peer.peer_id.clone(), peer);
+            }
+        }
+
+        info!("Loaded {} pinned peer key(s)", peers.len());
+        Ok(TrustStore {
+            local_public_key,
+            peers: Mutex::new(peers),
+            snapshot: Some(snapshot),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<String, PinnedPeer>> {
+        self.peers.lock().unwrap()
+    }
+
+    /// Checks `public_key` against the key pinned for `peer_id`, pinning it on first contact
+    pub fn check_key(&self, peer_id: &str, public_key: &str) -> AppResult<KeyCheck> {
+        let check = {
+            let mut peers = self.lock();
+            match peers.get_mut(peer_id) {
+                None => {
+                    peers.insert(
+                        peer_id.to_string(),
+                        PinnedPeer {
+                            peer_id: peer_id.to_string(),
+                            public_key: public_key.to_string(),
+                            first_seen: Utc::now(),
+                            verified: false,
+                        },
+                    );
+                    KeyCheck::Pinned
+                }
+                Some(peer) if peer.public_key == public_key => return Ok(KeyCheck::Matches),
+                Some(_) => return Ok(KeyCheck::Mismatch),
+            }
+        };
+
+        self.save()?;
+        Ok(check)
+    }
+
+    /// Key pinned for a peer, if it has been seen before
+    pub fn pinned_key(&self, peer_id: &str) -> Option<String> {
+        self.lock().get(peer_id).map(|peer| peer.public_key.clone())
+    }
+
+    /// Marks a peer's pinned key as verified
+    pub fn verify(&self, peer_id: &str) -> AppResult<PeerFingerprint> {
+        self.lock()
+            .get_mut(peer_id)
+            .ok_or_else(|| AppError::UserNotFound(peer_id.to_string()))?
+            .verified = true;
+
+        self.save()?;
+        self.peer_fingerprint(peer_id)
+    }
+
+    /// Fingerprints and safety number for the key pinned for a peer
+    pub fn peer_fingerprint(&self, peer_id: &str) -> AppResult<PeerFingerprint> {
+        let peer = self
+            .lock()
+            .get(peer_id)
+            .cloned()
+            .ok_or_else(|| AppError::UserNotFound(peer_id.to_string()))?;
+
+        let peer_key = identity::parse_public_key(&peer.public_key)?;
+        let local_key = identity::parse_public_key(&self.local_public_key)?;
+
+        Ok(PeerFingerprint {
+            peer_id: peer.peer_id,
+            fingerprint: identity::fingerprint(&peer_key),
+            local_fingerprint: identity::fingerprint(&local_key),
+            safety_number: identity::safety_number(&local_key, &peer_key),
+            verified: peer.verified,
+        })
+    }
+
+    /// Writes the pinned keys to disk
+    fn save(&self) -> AppResult<()> {
+        let Some(snapshot) = &self.snapshot else {
+            return Ok(());
+        };
+
+        // Holding the lock while writing also serializes concurrent saves
+        let peers = self.lock();
+        snapshot.save(&TrustSnapshot {
+            peers: peers.values().cloned().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::DeviceIdentity;
+    use uuid::Uuid;
+
+    #[test]
+    fn keys_are_pinned_on_first_use() {
+        let local = DeviceIdentity::generate();
+        let peer = DeviceIdentity::generate();
+        let store = TrustStore::in_memory(local.public_key_hex());
+
+        assert_eq!(store.pinned_key(&peer.user_id()), None);
+        assert_eq!(store.check_key(&peer.user_id(), &peer.public_key_hex()).unwrap(), KeyCheck::Pinned);
+        assert_eq!(store.check_key(&peer.user_id(), &peer.public_key_hex()).unwrap(), KeyCheck::Matches);
+        assert_eq!(store.pinned_key(&peer.user_id()), Some(peer.public_key_hex()));
+    }
+
+    #[test]
+    fn other_keys_do_not_replace_the_pin() {
+        let local = DeviceIdentity::generate();
+        let peer = DeviceIdentity::generate();
+        let impostor = DeviceIdentity::generate();
+        let store = TrustStore::in_memory(local.public_key_hex());
+        store.check_key(&peer.user_id(), &peer.public_key_hex()).unwrap();
+
+        assert_eq!(store.check_key(&peer.user_id(), &impostor.public_key_hex()).unwrap(), KeyCheck::Mismatch);
+        assert_eq!(store.check_key(&peer.user_id(), &impostor.public_key_hex()).unwrap(), KeyCheck::Mismatch);
+        assert_eq!(store.pinned_key(&peer.user_id()), Some(peer.public_key_hex()));
+    }
+
+    #[test]
+    fn verifying_marks_the_pinned_key() {
+        let local = DeviceIdentity::generate();
+        let peer = DeviceIdentity::generate();
+        let store = TrustStore::in_memory(local.public_key_hex());
+        assert!(store.verify(&peer.user_id()).is_err());
+
+        store.check_key(&peer.user_id(), &peer.public_key_hex()).unwrap();
+        let before = store.peer_fingerprint(&peer.user_id()).unwrap();
+        assert!(!before.verified);
+
+        let after = store.verify(&peer.user_id()).unwrap();
+        assert!(after.verified);
+        assert_eq!(after.safety_number, before.safety_number);
+        assert_eq!(after.fingerprint, identity::fingerprint(&identity::parse_public_key(&peer.public_key_hex()).unwrap()));
+    }
+
+    #[test]
+    fn pins_and_verification_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("ip-chat-trust-{}", Uuid::new_v4()));
+        let local = DeviceIdentity::generate();
+        let verified = DeviceIdentity::generate();
+        let pinned = DeviceIdentity::generate();
+        {
+            let store = TrustStore::open(&dir, local.public_key_hex()).unwrap();
+            store.check_key(&verified.user_id(), &verified.public_key_hex()).unwrap();
+            store.check_key(&pinned.user_id(), &pinned.public_key_hex()).unwrap();
+            store.verify(&verified.user_id()).unwrap();
+        }
+
+        let store = TrustStore::open(&dir, local.public_key_hex()).unwrap();
+        assert!(store.peer_fingerprint(&verified.user_id()).unwrap().verified);
+        assert!(!store.peer_fingerprint(&pinned.user_id()).unwrap().verified);
+        assert_eq!(store.check_key(&pinned.user_id(), &pinned.public_key_hex()).unwrap(), KeyCheck::Matches);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -156,7 +156,12 @@ const ChatPanel: Component = () => {
                     >
                       <VStack spacing="$1">
                         <Show when={item.type === 'message'}>
-                          <Text>{item.content}</Text>
+                          <Text fontStyle={item.deleted ? "italic" : "normal"} opacity={item.deleted ? "0.7" : "1"}>
+                            {item.content}
+                          </Text>
+                          <Show when={item.edited}>
+                            <Text fontSize="$xs" opacity="0.7">edited</Text>
+                          </Show>
                         </Show>
                         
                         <Show when={item.type === 'file'}>
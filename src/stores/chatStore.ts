@@ -2,7 +2,8 @@ import { createSignal } from 'solid-js';
 import { invoke } from '@tauri-apps/api/core';
 import { listen } from '@tauri-apps/api/event';
 import { sendNotification, isPermissionGranted, requestPermission } from '@tauri-apps/plugin-notification';
-import { Message, Conversation, FileTransfer } from '../types';
+import { open, save } from '@tauri-apps/plugin-dialog';
+import { Message, MessagePage, HistoryCursor, ExportFormat, Conversation, FileTransfer, SearchResult, TypingIndicator, BROADCAST_CHANNEL_ID } from '../types';
 import { createConversations } from '../utils';
 import toast from 'solid-toast';
 
@@ -13,10 +14,34 @@ const [conversations, setConversations] = createSignal<Conversation[]>([]);
 const [activeConversationId, setActiveConversationId] = createSignal<string | null>(null);
 const [isLoading, setIsLoading] = createSignal(true);
 const [error, setError] = createSignal<string | null>(null);
+// Peers currently typing, by conversation ID (peer or group)
+const [typingPeers, setTypingPeers] = createSignal<Record<string, string[]>>({});
+
+// A typing indicator is dropped if the peer goes quiet without sending "stopped"
+const TYPING_TIMEOUT_MS = 5000;
+const typingTimers = new Map<string, ReturnType<typeof setTimeout>>();
 
 // Track initialization state
 let isInitialized = false;
 
+// Add or remove a peer from the typing list of a conversation
+function setPeerTyping(conversationId: string, peerId: string, typing: boolean) {
+  const key = `${conversationId}:${peerId}`;
+  const timer = typingTimers.get(key);
+  if (timer) {
+    clearTimeout(timer);
+    typingTimers.delete(key);
+  }
+  if (typing) {
+    typingTimers.set(key, setTimeout(() => setPeerTyping(conversationId, peerId, false), TYPING_TIMEOUT_MS));
+  }
+
+  setTypingPeers(prev => {
+    const others = (prev[conversationId] ?? []).filter(id => id !== peerId);
+    return { ...prev, [conversationId]: typing ? [...others, peerId] : others };
+  });
+}
+
 // Notification helper functions
 async function showNotification(title: string, body: string) {
   try {
@@ -150,6 +175,41 @@ async function initChatStore() {
         }
       });
       
+      // Listen for delivery and read receipts on messages we sent
+      const unlistenMessageStatusUpdated = await listen<Message>('message_status_updated', (event) => {
+        const message = event.payload;
+        setMessages(prev => {
+          // The receipt can arrive before the message_sent event
+          const exists = prev.some(m => m.id === message.id);
+          if (!exists) {
+            return [...prev, message];
+          }
+          return prev.map(m => m.id === message.id ? message : m);
+        });
+      });
+
+      // Listen for edits and deletions of messages, by us or by the peer
+      const onMessageChanged = (event: { payload: Message }) => {
+        const message = event.payload;
+        setMessages(prev => prev.map(m => m.id === message.id ? message : m));
+        updateConversations(messages(), fileTransfers());
+      };
+      const unlistenMessageEdited = await listen<Message>('message_edited', onMessageChanged);
+      const unlistenMessageDeleted = await listen<Message>('message_deleted', onMessageChanged);
+      const unlistenMessageReactionsUpdated = await listen<Message>('message_reactions_updated', onMessageChanged);
+
+      // Listen for peers starting or stopping to type
+      const unlistenTypingUpdated = await listen<TypingIndicator>('typing_updated', (event) => {
+        const { peerId, groupId, typing } = event.payload;
+        setPeerTyping(groupId ?? peerId, peerId, typing);
+      });
+
+      // A peer that sent a message has stopped typing
+      const unlistenTypingMessageReceived = await listen<Message>('message_received', (event) => {
+        const message = event.payload;
+        setPeerTyping(message.groupId ?? message.senderId, message.senderId, false);
+      });
+
       // Listen for file transfer update events
       const unlistenFileTransferUpdate = await listen<FileTransfer>('file_transfer_update', (event) => {
         const transfer = event.payload;
@@ -195,7 +255,15 @@ async function initChatStore() {
         unlistenMessageSent();
         unlistenMessageReceived();
         unlistenMessagesRead();
+        unlistenMessageStatusUpdated();
+        unlistenMessageEdited();
+        unlistenMessageDeleted();
+        unlistenMessageReactionsUpdated();
+        unlistenTypingUpdated();
+        unlistenTypingMessageReceived();
         unlistenFileTransferUpdate();
+        typingTimers.forEach(timer => clearTimeout(timer));
+        typingTimers.clear();
       };
     };
     
@@ -224,7 +292,7 @@ async function initChatStore() {
 // Refresh messages and file transfers
 async function refreshMessages() {
   try {
-    const allMessages = await invoke<Message[]>('get_messages');
+    const { messages: allMessages } = await invoke<MessagePage>('get_messages');
     const allFileTransfers = await invoke<FileTransfer[]>('get_file_transfers');
     
     setMessages(allMessages);
@@ -315,9 +383,9 @@ function ensureConversationForPeer(peerId: string, peerData?: any): Conversation
 }
 
 // Send a message to a peer
-async function sendMessage(peerId: string, content: string) {
+async function sendMessage(peerId: string, content: string, replyTo?: string) {
   try {
-    const message = await invoke<Message>('send_message', { peerId, content });
+    const message = await invoke<Message>('send_message', { peerId, content, replyTo });
     
     // Don't add message here - the backend will emit message_sent event
     // which will add it to the UI automatically to prevent duplicates
@@ -332,15 +400,203 @@ async function sendMessage(peerId: string, content: string) {
   }
 }
 
-// Mark messages from a peer as read
+// Get the thread a message belongs to, root first
+async function getThread(messageId: string) {
+  try {
+    return await invoke<Message[]>('get_thread', { messageId });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to load thread: ${errorMessage}`);
+    return [];
+  }
+}
+
+// Replace the content of one of our messages, keeping the previous version in its history
+async function editMessage(messageId: string, content: string) {
+  try {
+    // The backend emits message_edited, which updates the message in the list
+    return await invoke<Message>('edit_message', { messageId, content });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to edit message: ${errorMessage}`);
+    toast.error(`Failed to edit message: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Retract one of our messages for everyone who received it
+async function deleteMessage(messageId: string) {
+  try {
+    // The backend emits message_deleted, which updates the message in the list
+    return await invoke<Message>('delete_message', { messageId });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to delete message: ${errorMessage}`);
+    toast.error(`Failed to delete message: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Load a page of older messages exchanged with a peer, before the oldest one already loaded
+async function loadOlderMessages(peerId: string, limit = 50) {
+  try {
+    const loaded = messages()
+      .filter(m => !m.groupId && (m.senderId === peerId || m.recipientId === peerId))
+      .sort((a, b) => new Date(a.timestamp).getTime() - new Date(b.timestamp).getTime());
+    const before: HistoryCursor | undefined = loaded.length > 0 ? { messageId: loaded[0].id } : undefined;
+
+    const page = await invoke<MessagePage>('get_messages', { peerId, before, limit });
+    setMessages(prev => [...page.messages.filter(m => !prev.some(p => p.id === m.id)), ...prev]);
+    updateConversations(messages(), fileTransfers());
+    return page.hasMore;
+  } catch (err) {
+    console.error('Failed to load older messages:', err);
+    setError(`Failed to load older messages: ${err instanceof Error ? err.message : String(err)}`);
+    return false;
+  }
+}
+
+// Number of unread messages by peer ID, without loading the messages
+async function getUnreadCounts() {
+  try {
+    return await invoke<Record<string, number>>('get_unread_counts');
+  } catch (err) {
+    console.error('Failed to get unread counts:', err);
+    return {};
+  }
+}
+
+// Search message content, most recent matches first, optionally within one conversation and time range
+async function searchMessages(query: string, peerId?: string, from?: Date, to?: Date) {
+  try {
+    return await invoke<SearchResult[]>('search_messages', {
+      query,
+      peerId,
+      from: from?.toISOString(),
+      to: to?.toISOString(),
+    });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    toast.error(`Search failed: ${errorMessage}`);
+    return [];
+  }
+}
+
+// Export a conversation with a peer, a group or the broadcast channel to a file
+async function exportConversation(peerId: string, format: ExportFormat) {
+  const extension = { [ExportFormat.Json]: 'json', [ExportFormat.Markdown]: 'md', [ExportFormat.Text]: 'txt' }[format];
+  try {
+    const path = await save({
+      defaultPath: `conversation.${extension}`,
+      filters: [{ name: format, extensions: [extension] }]
+    });
+    if (!path) return false; // User cancelled
+
+    await invoke('export_conversation', { peerId, format, path });
+    toast.success('Conversation exported');
+    return true;
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    toast.error(`Failed to export conversation: ${errorMessage}`);
+    return false;
+  }
+}
+
+// Merge a JSON export back into the history, skipping messages we already have
+async function importHistory() {
+  try {
+    const selected = await open({
+      multiple: false,
+      directory: false,
+      filters: [{ name: 'JSON', extensions: ['json'] }],
+      title: 'Select Conversation Export'
+    });
+    if (!selected) return 0; // User cancelled
+
+    const path = Array.isArray(selected) ? selected[0] : selected;
+    const imported = await invoke<number>('import_history', { path });
+    await refreshMessages();
+    toast.success(`Imported ${imported} message(s)`);
+    return imported;
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    toast.error(`Failed to import history: ${errorMessage}`);
+    return 0;
+  }
+}
+
+// Add our reaction to a message
+async function addReaction(messageId: string, emoji: string) {
+  try {
+    // The backend emits message_reactions_updated, which updates the message in the list
+    return await invoke<Message>('add_reaction', { messageId, emoji });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    toast.error(`Failed to add reaction: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Remove our reaction from a message
+async function removeReaction(messageId: string, emoji: string) {
+  try {
+    // The backend emits message_reactions_updated, which updates the message in the list
+    return await invoke<Message>('remove_reaction', { messageId, emoji });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    toast.error(`Failed to remove reaction: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Send an announcement to everyone on the LAN
+async function sendBroadcast(content: string, replyTo?: string) {
+  try {
+    // The backend emits message_sent, which adds the message to the list
+    return await invoke<Message>('send_broadcast', { content, replyTo });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to send broadcast: ${errorMessage}`);
+    toast.error(`Failed to send broadcast: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Messages of the broadcast channel, oldest first
+function getBroadcastMessages() {
+  return messages()
+    .filter(msg => msg.recipientId === BROADCAST_CHANNEL_ID)
+    .sort((a, b) => new Date(a.timestamp).getTime() - new Date(b.timestamp).getTime());
+}
+
+// Tell the peer or group members whether we are typing
+async function sendTyping(conversationId: string, typing: boolean) {
+  try {
+    await invoke('send_typing', { conversationId, typing });
+  } catch (err) {
+    // Typing indicators are best effort
+    console.error('Failed to send typing indicator:', err);
+  }
+}
+
+// IDs of the peers typing in a conversation
+function getTypingPeers(conversationId: string): string[] {
+  return typingPeers()[conversationId] ?? [];
+}
+
+// Mark messages of a conversation with a peer, a group or the broadcast channel as read
 async function markMessagesAsRead(peerId: string) {
   try {
     await invoke('mark_messages_as_read', { peerId });
     
     // Update messages
+    const inConversation = (msg: Message) =>
+      msg.groupId === peerId ||
+      (peerId === BROADCAST_CHANNEL_ID && msg.recipientId === BROADCAST_CHANNEL_ID) ||
+      (!msg.groupId && msg.senderId === peerId);
     setMessages(prev => 
       prev.map(msg => 
-        msg.senderId === peerId && !msg.read
+        inConversation(msg) && !msg.read
           ? { ...msg, read: true }
           : msg
       )
@@ -386,7 +642,22 @@ export const chatStore = {
   error,
   initChatStore,
   refreshMessages,
+  loadOlderMessages,
+  getUnreadCounts,
   sendMessage,
+  getThread,
+  searchMessages,
+  exportConversation,
+  importHistory,
+  editMessage,
+  deleteMessage,
+  addReaction,
+  removeReaction,
+  sendBroadcast,
+  getBroadcastMessages,
+  typingPeers,
+  sendTyping,
+  getTypingPeers,
   markMessagesAsRead,
   getConversationByPeerId,
   getActiveConversation,
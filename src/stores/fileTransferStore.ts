@@ -225,6 +225,32 @@ async function cancelFileTransfer(transferId: string) {
   }
 }
 
+// Resume a failed or interrupted file transfer
+async function resumeFileTransfer(transferId: string) {
+  try {
+    // Get the transfer
+    const transfer = getTransferById(transferId);
+    if (!transfer) {
+      throw new Error(`Transfer not found: ${transferId}`);
+    }
+
+    // Resume the transfer from the bytes already received
+    await invoke<FileTransfer>('resume_file_transfer', { transferId });
+
+    // Update transfers
+    await refreshTransfers();
+
+    toast.success(`Resumed file transfer: ${transfer.fileName}`);
+
+    return true;
+  } catch (err) {
+    console.error('Failed to resume file transfer:', err);
+    setError(`Failed to resume file transfer: ${err instanceof Error ? err.message : String(err)}`);
+    toast.error(`Failed to resume file transfer: ${err instanceof Error ? err.message : String(err)}`);
+    return false;
+  }
+}
+
 // Get a transfer by ID
 function getTransferById(id: string): FileTransfer | undefined {
   return transfers().find(transfer => transfer.id === id);
@@ -269,6 +295,7 @@ export const fileTransferStore = {
   acceptFileTransfer,
   rejectFileTransfer,
   cancelFileTransfer,
+  resumeFileTransfer,
   getTransferById,
   getTransfersForPeer,
   getPendingTransfers,
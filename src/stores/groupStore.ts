@@ -0,0 +1,204 @@
+import { createSignal } from 'solid-js';
+import { invoke } from '@tauri-apps/api/core';
+import { listen } from '@tauri-apps/api/event';
+import { Group, Message } from '../types';
+import toast from 'solid-toast';
+
+// Create signals for group state
+const [groups, setGroups] = createSignal<Group[]>([]);
+const [groupMessages, setGroupMessages] = createSignal<Message[]>([]);
+const [isLoading, setIsLoading] = createSignal(true);
+const [error, setError] = createSignal<string | null>(null);
+
+// Track initialization state
+let isInitialized = false;
+
+// Add or replace a group
+function upsertGroup(group: Group) {
+  setGroups(prev => {
+    const exists = prev.some(g => g.id === group.id);
+    if (!exists) {
+      return [...prev, group];
+    }
+    return prev.map(g => g.id === group.id ? group : g);
+  });
+}
+
+// Add or replace a group message
+function upsertGroupMessage(message: Message) {
+  setGroupMessages(prev => {
+    const exists = prev.some(m => m.id === message.id);
+    if (!exists) {
+      return [...prev, message];
+    }
+    return prev.map(m => m.id === message.id ? message : m);
+  });
+}
+
+// Initialize the group store
+async function initGroupStore() {
+  // Prevent multiple initialization
+  if (isInitialized) {
+    return;
+  }
+
+  try {
+    setIsLoading(true);
+    setError(null);
+    isInitialized = true;
+
+    await refreshGroups();
+
+    // Set up event listeners for membership changes and group messages
+    const setupEventListeners = async () => {
+      // Listen for group created, joined or left, by us or another member
+      const unlistenGroupUpdated = await listen<Group>('group_updated', (event) => {
+        upsertGroup(event.payload);
+      });
+
+      // Group messages arrive on the same events as direct messages
+      const onMessage = (event: { payload: Message }) => {
+        if (event.payload.groupId) {
+          upsertGroupMessage(event.payload);
+        }
+      };
+      const unlistenMessageSent = await listen<Message>('message_sent', onMessage);
+      const unlistenMessageReceived = await listen<Message>('message_received', onMessage);
+      const unlistenMessageStatusUpdated = await listen<Message>('message_status_updated', onMessage);
+      const unlistenMessageEdited = await listen<Message>('message_edited', onMessage);
+      const unlistenMessageDeleted = await listen<Message>('message_deleted', onMessage);
+      const unlistenMessageReactionsUpdated = await listen<Message>('message_reactions_updated', onMessage);
+
+      // Store cleanup functions
+      (window as any).__groupStoreCleanup = () => {
+        unlistenGroupUpdated();
+        unlistenMessageSent();
+        unlistenMessageReceived();
+        unlistenMessageStatusUpdated();
+        unlistenMessageEdited();
+        unlistenMessageDeleted();
+        unlistenMessageReactionsUpdated();
+      };
+    };
+
+    // Setup event listeners
+    setupEventListeners().catch(err => {
+      console.error('Failed to setup group event listeners:', err);
+    });
+
+    // Clean up on window unload
+    window.addEventListener('beforeunload', () => {
+      if ((window as any).__groupStoreCleanup) {
+        (window as any).__groupStoreCleanup();
+      }
+    });
+  } catch (err) {
+    console.error('Failed to initialize group store:', err);
+    setError(`Failed to initialize: ${err instanceof Error ? err.message : String(err)}`);
+  } finally {
+    setIsLoading(false);
+  }
+}
+
+// Reload the known groups from the backend
+async function refreshGroups() {
+  try {
+    const groupsList = await invoke<Group[]>('get_groups');
+    setGroups(groupsList);
+  } catch (err) {
+    console.error('Failed to load groups:', err);
+    setError(`Failed to load groups: ${err instanceof Error ? err.message : String(err)}`);
+  }
+}
+
+// Load the messages of a group
+async function loadGroupMessages(groupId: string) {
+  try {
+    const messagesList = await invoke<Message[]>('get_group_messages', { groupId });
+    setGroupMessages(prev => [...prev.filter(m => m.groupId !== groupId), ...messagesList]);
+    return messagesList;
+  } catch (err) {
+    console.error('Failed to load group messages:', err);
+    setError(`Failed to load group messages: ${err instanceof Error ? err.message : String(err)}`);
+    return [];
+  }
+}
+
+// Create a group with the given peers as members
+async function createGroup(name: string, memberIds: string[]) {
+  try {
+    const group = await invoke<Group>('create_group', { name, memberIds });
+    upsertGroup(group);
+    toast.success(`Group "${group.name}" created`);
+    return group;
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to create group: ${errorMessage}`);
+    toast.error(`Failed to create group: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Join a known group again
+async function joinGroup(groupId: string) {
+  try {
+    const group = await invoke<Group>('join_group', { groupId });
+    upsertGroup(group);
+    return group;
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to join group: ${errorMessage}`);
+    toast.error(`Failed to join group: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Leave a group, its history is kept
+async function leaveGroup(groupId: string) {
+  try {
+    const group = await invoke<Group>('leave_group', { groupId });
+    upsertGroup(group);
+    return group;
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to leave group: ${errorMessage}`);
+    toast.error(`Failed to leave group: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Send a message to every member of a group
+async function sendGroupMessage(groupId: string, content: string, replyTo?: string) {
+  try {
+    // The backend emits message_sent, which adds the message to the list
+    return await invoke<Message>('send_group_message', { groupId, content, replyTo });
+  } catch (err) {
+    const errorMessage = err instanceof Error ? err.message : String(err);
+    setError(`Failed to send message: ${errorMessage}`);
+    toast.error(`Failed to send message: ${errorMessage}`);
+    throw err;
+  }
+}
+
+// Get the loaded messages of a group, oldest first
+function getGroupMessages(groupId: string) {
+  return groupMessages()
+    .filter(m => m.groupId === groupId)
+    .sort((a, b) => new Date(a.timestamp).getTime() - new Date(b.timestamp).getTime());
+}
+
+// Export the group store
+export const groupStore = {
+  groups,
+  groupMessages,
+  isLoading,
+  error,
+  initGroupStore,
+  refreshGroups,
+  loadGroupMessages,
+  createGroup,
+  joinGroup,
+  leaveGroup,
+  sendGroupMessage,
+  getGroupMessages,
+};
@@ -2,10 +2,11 @@
 import { userStore } from './userStore';
 import { chatStore } from './chatStore';
 import { fileTransferStore } from './fileTransferStore';
+import { groupStore } from './groupStore';
 import { settingsStore } from './settingsStore';
 
 // Re-export the stores
-export { userStore, chatStore, fileTransferStore, settingsStore };
+export { userStore, chatStore, fileTransferStore, groupStore, settingsStore };
 
 // Initialize all stores
 export async function initializeStores() {
@@ -32,6 +33,9 @@ export async function initializeStores() {
     
     // Initialize file transfer store
     await fileTransferStore.initFileTransferStore();
+
+    // Initialize group store
+    await groupStore.initGroupStore();
     
   } catch (error) {
     console.error('Failed to initialize stores:', error);
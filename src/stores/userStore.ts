@@ -1,7 +1,7 @@
 import { createSignal } from 'solid-js';
 import { invoke } from '@tauri-apps/api/core';
 import { listen } from '@tauri-apps/api/event';
-import { User } from '../types';
+import { DiscoveryBackend, NetworkSettings, PeerFingerprint, Presence, StaticPeer, User } from '../types';
 import toast from 'solid-toast';
 
 // Create signals for user state
@@ -74,11 +74,19 @@ async function initUserStore() {
         setLocalUser(user);
       });
       
+      // Listen for our addresses changing, e.g. after switching networks
+      const unlistenLocalNetworkChanged = await listen<User>('local_network_changed', (event) => {
+        const user = event.payload;
+        setLocalUser(user);
+        toast(`Network changed, now reachable at ${user.ip}`);
+      });
+
       // Store cleanup functions
       (window as any).__userStoreCleanup = () => {
         unlistenPeerDiscovered();
         unlistenPeersUpdated();
         unlistenUserUpdated();
+        unlistenLocalNetworkChanged();
       };
     };
     
@@ -193,6 +201,128 @@ async function updateUsername(username: string) {
   }
 }
 
+// Set our presence and optional status text, advertised to peers over mDNS
+async function setPresence(presence: Presence, statusText?: string) {
+  try {
+    const updatedUser = await invoke<User>('set_presence', { presence, statusText });
+    setLocalUser(updatedUser);
+    return true;
+  } catch (err) {
+    console.error('Failed to update presence:', err);
+    setError(`Failed to update presence: ${err instanceof Error ? err.message : String(err)}`);
+    toast.error(`Failed to update presence: ${err instanceof Error ? err.message : String(err)}`);
+    return false;
+  }
+}
+
+// Get the fingerprints and safety number for a peer
+async function getPeerFingerprint(peerId: string) {
+  try {
+    return await invoke<PeerFingerprint>('get_peer_fingerprint', { peerId });
+  } catch (err) {
+    console.error('Failed to get peer fingerprint:', err);
+    setError(`Failed to get peer fingerprint: ${err instanceof Error ? err.message : String(err)}`);
+    return null;
+  }
+}
+
+// Mark a peer as verified after comparing safety numbers
+async function verifyPeer(peerId: string) {
+  try {
+    const fingerprint = await invoke<PeerFingerprint>('verify_peer', { peerId });
+    toast.success('Peer verified');
+    return fingerprint;
+  } catch (err) {
+    console.error('Failed to verify peer:', err);
+    setError(`Failed to verify peer: ${err instanceof Error ? err.message : String(err)}`);
+    toast.error(`Failed to verify peer: ${err instanceof Error ? err.message : String(err)}`);
+    return null;
+  }
+}
+
+// Get the enabled discovery mechanisms
+async function getDiscoveryBackends() {
+  try {
+    return await invoke<DiscoveryBackend[]>('get_discovery_backends');
+  } catch (err) {
+    console.error('Failed to get discovery backends:', err);
+    return [];
+  }
+}
+
+// Choose the discovery mechanisms, restarting discovery with them
+async function setDiscoveryBackends(backends: DiscoveryBackend[]) {
+  try {
+    await invoke('set_discovery_backends', { backends });
+    await refreshPeers();
+    return true;
+  } catch (err) {
+    console.error('Failed to set discovery backends:', err);
+    toast.error(`Failed to set discovery backends: ${err instanceof Error ? err.message : String(err)}`);
+    return false;
+  }
+}
+
+// Get the network settings, including the configured ports
+async function getNetworkSettings() {
+  try {
+    return await invoke<NetworkSettings>('get_network_settings');
+  } catch (err) {
+    console.error('Failed to get network settings:', err);
+    return null;
+  }
+}
+
+// Set the chat and file transfer ports, 0 picking a free one; they apply after a restart
+async function setListenPorts(chatPort: number, filePort: number) {
+  try {
+    const settings = await invoke<NetworkSettings>('set_listen_ports', { chatPort, filePort });
+    toast.success('Ports saved, restart IP Chat to apply them');
+    return settings;
+  } catch (err) {
+    console.error('Failed to set ports:', err);
+    toast.error(`Failed to set ports: ${err instanceof Error ? err.message : String(err)}`);
+    return null;
+  }
+}
+
+// Add a peer by IP address or hostname, for networks where mDNS does not get through
+async function addManualPeer(host: string, port?: number) {
+  try {
+    // The backend emits peer_discovered, which adds the peer to the list
+    const peer = await invoke<User>('add_manual_peer', { host, port });
+    toast.success(`Added ${peer.name}`);
+    return peer;
+  } catch (err) {
+    console.error('Failed to add peer:', err);
+    setError(`Failed to add peer: ${err instanceof Error ? err.message : String(err)}`);
+    toast.error(`Failed to add peer: ${err instanceof Error ? err.message : String(err)}`);
+    return null;
+  }
+}
+
+// Get the peers added by address
+async function getManualPeers() {
+  try {
+    return await invoke<StaticPeer[]>('get_manual_peers');
+  } catch (err) {
+    console.error('Failed to get manual peers:', err);
+    return [];
+  }
+}
+
+// Stop probing a peer added by address
+async function removeManualPeer(host: string, port: number) {
+  try {
+    await invoke('remove_manual_peer', { host, port });
+    return true;
+  } catch (err) {
+    console.error('Failed to remove peer:', err);
+    toast.error(`Failed to remove peer: ${err instanceof Error ? err.message : String(err)}`);
+    return false;
+  }
+}
+
 // Get a peer by ID
 function getPeerById(id: string): User | undefined {
   return peers().find(peer => peer.id === id);
@@ -219,6 +349,16 @@ export const userStore = {
   stopDiscovery,
   refreshPeers,
   updateUsername,
+  setPresence,
+  getPeerFingerprint,
+  verifyPeer,
+  getDiscoveryBackends,
+  setDiscoveryBackends,
+  getNetworkSettings,
+  setListenPorts,
+  addManualPeer,
+  getManualPeers,
+  removeManualPeer,
   getPeerById,
   cleanup,
 };
@@ -4,8 +4,59 @@
 export interface User {
   id: string;
   name: string;
-  ip: string;
+  ip: string; // preferred address
+  addresses?: string[]; // every known address, preferred first; IPv6 link-local ones carry a %scope
   lastSeen: string; // ISO date string
+  publicKey?: string; // hex-encoded Ed25519 public key
+  presence?: Presence; // Online when missing
+  statusText?: string;
+  port?: number; // chat port, the default one when missing
+  filePort?: number; // file transfer port, the default one when missing
+}
+
+// Mechanisms peers are discovered with
+export enum DiscoveryBackend {
+  Mdns = "Mdns",
+  Broadcast = "Broadcast"
+}
+
+// Network settings; a port of 0 picks a free one
+export interface NetworkSettings {
+  discoveryBackends: DiscoveryBackend[];
+  chatPort: number;
+  filePort: number;
+}
+
+// A peer added by address, for networks where mDNS does not get through
+export interface StaticPeer {
+  host: string;
+  port: number;
+  peerId?: string;
+  addedAt: string;
+}
+
+// Availability of a user
+export enum Presence {
+  Online = "Online",
+  Away = "Away",
+  Busy = "Busy",
+  DoNotDisturb = "DoNotDisturb"
+}
+
+// A peer started or stopped typing, in a group or the direct conversation
+export interface TypingIndicator {
+  peerId: string;
+  groupId?: string;
+  typing: boolean;
+}
+
+// Fingerprints and safety number for verifying a peer out of band
+export interface PeerFingerprint {
+  peerId: string;
+  fingerprint: string;
+  localFingerprint: string;
+  safetyNumber: string; // twelve groups of five digits
+  verified: boolean;
 }
 
 // Message type
@@ -16,6 +67,68 @@ export interface Message {
   content: string;
   timestamp: string; // ISO date string
   read: boolean;
+  signature?: string; // hex-encoded Ed25519 signature by the sender
+  status: MessageStatus;
+  groupId?: string; // set for messages posted to a group, recipientId is then the group ID
+  editHistory: MessageVersion[]; // earlier versions, oldest first
+  editedAt?: string; // ISO date string of the latest edit
+  deleted: boolean; // retracted by the sender, content is empty
+  replyTo?: string; // ID of the message this one replies to
+  threadRoot?: string; // ID of the first message of the thread
+  reactions: Record<string, string[]>; // user IDs by emoji
+}
+
+// Previous version of an edited message
+export interface MessageVersion {
+  content: string;
+  timestamp: string; // ISO date string
+}
+
+// Recipient ID of messages sent to everyone on the LAN
+export const BROADCAST_CHANNEL_ID = 'broadcast';
+
+// Group conversation
+export interface Group {
+  id: string;
+  name: string;
+  members: string[]; // user IDs, including ours while joined
+  invited: string[]; // user IDs the creator added, who may join again
+  createdBy: string;
+  createdAt: string; // ISO date string
+}
+
+// Exclusive bound of a page of history, at a message or a point in time
+export type HistoryCursor = { messageId: string } | { timestamp: string };
+
+// A page of history, oldest message first
+export interface MessagePage {
+  messages: Message[];
+  hasMore: boolean; // more messages in range were left out by the limit
+}
+
+// File format of a conversation export
+export enum ExportFormat {
+  Json = "Json",
+  Markdown = "Markdown",
+  Text = "Text"
+}
+
+// A message matching a search, with the messages around it in its conversation
+export interface SearchResult {
+  message: Message;
+  conversationId: string; // peer, group or broadcast channel ID
+  snippet: string;
+  contextBefore: Message[];
+  contextAfter: Message[];
+}
+
+// Message delivery status
+export enum MessageStatus {
+  Sending = "Sending",
+  Sent = "Sent",
+  Delivered = "Delivered",
+  Read = "Read",
+  Failed = "Failed"
 }
 
 // File transfer status
@@ -25,7 +138,8 @@ export enum TransferStatus {
   Completed = "Completed",
   Rejected = "Rejected",
   Cancelled = "Cancelled",
-  Failed = "Failed"
+  Failed = "Failed",
+  Interrupted = "Interrupted"
 }
 
 // File transfer type
@@ -35,6 +149,8 @@ export interface FileTransfer {
   recipientId: string;
   senderIp?: string;
   recipientIp?: string;
+  senderPort?: number; // file transfer port of the sender, the default one when missing
+  recipientPort?: number; // file transfer port of the recipient, the default one when missing
   fileName: string;
   fileSize: number;
   sourcePath?: string;
@@ -43,6 +159,7 @@ export interface FileTransfer {
   bytesTransferred: number;
   timestamp: string; // ISO date string
   error?: string;
+  fileHash?: string; // hex-encoded SHA-256
 }
 
 // Conversation item type - can be either a message or file transfer
@@ -55,6 +172,8 @@ export interface ConversationItem {
   read?: boolean;
   // Message-specific fields
   content?: string;
+  edited?: boolean;
+  deleted?: boolean;
   // File transfer-specific fields
   fileName?: string;
   fileSize?: number;
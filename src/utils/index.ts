@@ -1,6 +1,6 @@
 import { format, formatDistanceToNow } from 'date-fns';
 import { filesize } from 'filesize';
-import { TransferStatus, Message, User, Conversation, ConversationItem, FileTransfer } from '../types';
+import { TransferStatus, Message, User, Conversation, ConversationItem, FileTransfer, BROADCAST_CHANNEL_ID } from '../types';
 
 /**
  * Formats a date as a string
@@ -124,7 +124,9 @@ function messageToConversationItem(message: Message): ConversationItem {
     recipientId: message.recipientId,
     timestamp: message.timestamp,
     read: message.read,
-    content: message.content,
+    content: message.deleted ? 'This message was deleted' : message.content,
+    edited: !!message.editedAt && !message.deleted,
+    deleted: message.deleted,
   };
 }
 
@@ -168,8 +170,10 @@ export function createConversations(
   // Group items by peer ID
   const conversationMap = new Map<string, Conversation>();
 
-  // Convert messages to conversation items
-  const messageItems = messages.map(messageToConversationItem);
+  // Convert messages to conversation items, group messages and broadcasts have their own views
+  const messageItems = messages
+    .filter(message => !message.groupId && message.recipientId !== BROADCAST_CHANNEL_ID)
+    .map(messageToConversationItem);
   
   // Convert file transfers to conversation items
   const fileItems = fileTransfers.map(fileTransferToConversationItem);